@@ -16,9 +16,13 @@
 //! Supports sending NCI commands to the HAL and receiving
 //! NCI events from the HAL
 
+use log::debug;
 use nfc_packets::nci::{DataPacket, NciPacket};
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::{oneshot, Mutex};
@@ -43,11 +47,81 @@ pub struct Hal {
     pub out_data_tx: UnboundedSender<DataPacket>,
     /// HAL inbound channel for Data messages
     pub in_data_rx: UnboundedReceiver<DataPacket>,
+    /// Number of inbound frames dropped because they could not be parsed
+    /// as either control or data packets
+    pub parse_errors: Arc<AtomicUsize>,
 }
 
 /// Initialize the module and connect the channels
-pub async fn init() -> Hal {
-    ihal::init().await
+pub async fn init() -> Result<Hal> {
+    init_with_packet_sink(Arc::new(NoopPacketSink)).await
+}
+
+/// Initialize the module and connect the channels. Every raw packet
+/// exchanged with the NFCC is reported to `packet_sink`.
+pub async fn init_with_packet_sink(packet_sink: Arc<dyn PacketSink>) -> Result<Hal> {
+    ihal::init(packet_sink).await
+}
+
+/// Direction of a raw packet exchanged with the NFCC
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum PacketDirection {
+    /// Packet received from the NFCC
+    Inbound,
+    /// Packet sent to the NFCC
+    Outbound,
+}
+
+impl fmt::Display for PacketDirection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketDirection::Inbound => write!(f, "RX"),
+            PacketDirection::Outbound => write!(f, "TX"),
+        }
+    }
+}
+
+/// Receives the raw packets exchanged with the NFCC, independently of
+/// the log level. Inbound packets are reported before being parsed,
+/// including the packets that fail to parse.
+pub trait PacketSink: Send + Sync {
+    /// Called for every packet sent to or received from the NFCC
+    fn on_packet(&self, direction: PacketDirection, packet: &[u8]);
+}
+
+/// Packet sink discarding all packets
+pub struct NoopPacketSink;
+
+impl PacketSink for NoopPacketSink {
+    fn on_packet(&self, _direction: PacketDirection, _packet: &[u8]) {}
+}
+
+/// Packet sink logging every packet with its direction and a monotonic
+/// timestamp, formatted as `<RX|TX> +<milliseconds>ms <bytes>`. The
+/// timestamps are relative to the creation of the sink, and enable
+/// measuring the latency of the NFCC.
+pub struct TimestampPacketLogger {
+    start: Instant,
+}
+
+impl TimestampPacketLogger {
+    /// Create a logger with timestamps relative to the current instant
+    pub fn new() -> Self {
+        TimestampPacketLogger { start: Instant::now() }
+    }
+}
+
+impl Default for TimestampPacketLogger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PacketSink for TimestampPacketLogger {
+    fn on_packet(&self, direction: PacketDirection, packet: &[u8]) {
+        let elapsed = self.start.elapsed().as_secs_f64() * 1000.0;
+        debug!("{} +{:.3}ms {:02x?}", direction, elapsed, packet);
+    }
 }
 
 /// NFC HAL specific events
@@ -55,6 +129,8 @@ pub async fn init() -> Hal {
 pub enum HalEvent {
     /// HAL CLOSE_CPLT event
     CloseComplete,
+    /// HAL ERROR_EVT event, reported when the transport to the NFCC fails
+    Error,
 }
 
 /// Status of a NFC HAL event
@@ -73,7 +149,7 @@ pub enum HalEventStatus {
 }
 
 /// Provides ability to register and unregister for HAL event notifications
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct HalEventRegistry {
     handlers: Arc<Mutex<HashMap<HalEvent, oneshot::Sender<HalEventStatus>>>>,
 }
@@ -92,12 +168,23 @@ impl HalEventRegistry {
     pub async fn unregister(&mut self, event: HalEvent) -> Option<oneshot::Sender<HalEventStatus>> {
         self.handlers.lock().await.remove(&event)
     }
+
+    /// Report a HAL event to its registered handler, if any. Fake HALs
+    /// can use it to inject events such as transport errors.
+    pub async fn notify(&mut self, event: HalEvent, status: HalEventStatus) {
+        if let Some(sender) = self.unregister(event).await {
+            if sender.send(status).is_err() {
+                debug!("handler for {:?} is closed", event);
+            }
+        }
+    }
 }
 
 mod internal {
-    use crate::{Hal, HalEventRegistry};
+    use crate::{Hal, HalEventRegistry, PacketSink};
     use nfc_packets::nci::{DataPacket, NciPacket};
     use std::collections::HashMap;
+    use std::sync::atomic::AtomicUsize;
     use std::sync::Arc;
     use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
     use tokio::sync::Mutex;
@@ -107,28 +194,39 @@ mod internal {
         pub in_cmd_tx: UnboundedSender<NciPacket>,
         pub out_data_rx: UnboundedReceiver<DataPacket>,
         pub in_data_tx: UnboundedSender<DataPacket>,
+        pub parse_errors: Arc<AtomicUsize>,
+        pub packet_sink: Arc<dyn PacketSink>,
     }
 
     impl InnerHal {
-        pub fn new() -> (Hal, Self) {
+        pub fn new(packet_sink: Arc<dyn PacketSink>) -> (Hal, Self) {
             let (out_cmd_tx, out_cmd_rx) = unbounded_channel();
             let (in_cmd_tx, in_cmd_rx) = unbounded_channel();
             let (out_data_tx, out_data_rx) = unbounded_channel();
             let (in_data_tx, in_data_rx) = unbounded_channel();
             let handlers = Arc::new(Mutex::new(HashMap::new()));
             let hal_events = HalEventRegistry { handlers };
+            let parse_errors = Arc::new(AtomicUsize::new(0));
             (
-                Hal { hal_events, out_cmd_tx, in_cmd_rx, out_data_tx, in_data_rx },
-                Self { out_cmd_rx, in_cmd_tx, out_data_rx, in_data_tx },
+                Hal {
+                    hal_events,
+                    out_cmd_tx,
+                    in_cmd_rx,
+                    out_data_tx,
+                    in_data_rx,
+                    parse_errors: parse_errors.clone(),
+                },
+                Self { out_cmd_rx, in_cmd_tx, out_data_rx, in_data_tx, parse_errors, packet_sink },
             )
         }
     }
 }
 
-/// Is this NCI control stream or data response
+/// Is this NCI control stream or data response.
+/// Returns false for an empty frame.
 pub fn is_control_packet(data: &[u8]) -> bool {
     // Check the MT bits
-    (data[0] >> 5) & 0x7 != 0
+    data.first().is_some_and(|header| (header >> 5) & 0x7 != 0)
 }
 
 /// Result type
@@ -143,4 +241,54 @@ pub enum HalError {
     /// Error while connecting to rootcanal
     #[error("Connection to rootcanal failed: {0}")]
     RootcanalConnectError(#[from] tokio::io::Error),
+    /// The transport to the NFCC failed
+    #[error("NFCC transport error")]
+    TransportError,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Messages logged by the tests
+    static LOGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    /// Logger recording all messages
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn timestamp_logger_logs_direction_and_timestamp() {
+        log::set_logger(&CaptureLogger).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+        let logger = TimestampPacketLogger::new();
+        logger.on_packet(PacketDirection::Outbound, &[0x20, 0x00, 0x01, 0x00]);
+        logger.on_packet(PacketDirection::Inbound, &[0x40, 0x00, 0x01, 0x00]);
+
+        let logs = LOGS.lock().unwrap();
+        let mut timestamps = vec![];
+        for (log, (direction, bytes)) in
+            logs.iter().zip([("TX", "[20, 00, 01, 00]"), ("RX", "[40, 00, 01, 00]")])
+        {
+            let fields: Vec<&str> = log.splitn(3, ' ').collect();
+            assert_eq!(fields[0], direction);
+            let timestamp = fields[1].strip_prefix('+').unwrap().strip_suffix("ms").unwrap();
+            timestamps.push(timestamp.parse::<f64>().unwrap());
+            assert_eq!(fields[2], bytes);
+        }
+        assert_eq!(timestamps.len(), 2);
+        assert!(0.0 <= timestamps[0] && timestamps[0] <= timestamps[1]);
+    }
 }
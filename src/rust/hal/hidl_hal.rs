@@ -15,25 +15,32 @@
 //! Implementation of the HAl that talks to NFC controller over Android's HIDL
 use crate::internal::InnerHal;
 #[allow(unused)]
-use crate::{is_control_packet, Hal, HalEvent, HalEventRegistry, HalEventStatus, Result};
+use crate::{
+    is_control_packet, Hal, HalEvent, HalEventRegistry, HalEventStatus, PacketDirection,
+    PacketSink, Result,
+};
 use log::{debug, error};
 use nfc_packets::nci::{DataPacket, NciPacket};
 use pdl_runtime::Packet;
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
 
 /// Initialize the module
-pub async fn init() -> Hal {
-    let (raw_hal, inner_hal) = InnerHal::new();
+pub async fn init(packet_sink: Arc<dyn PacketSink>) -> Result<Hal> {
+    let (raw_hal, inner_hal) = InnerHal::new(packet_sink);
     let (hal_open_evt_tx, hal_open_evt_rx) = oneshot::channel::<ffi::NfcStatus>();
     let (hal_close_evt_tx, hal_close_evt_rx) = oneshot::channel::<ffi::NfcStatus>();
     *CALLBACKS.lock().unwrap() = Some(Callbacks {
+        hal_events: raw_hal.hal_events.clone(),
         hal_open_evt_tx: Some(hal_open_evt_tx),
         hal_close_evt_tx: Some(hal_close_evt_tx),
         in_cmd_tx: inner_hal.in_cmd_tx,
         in_data_tx: inner_hal.in_data_tx,
+        parse_errors: inner_hal.parse_errors,
+        packet_sink: inner_hal.packet_sink.clone(),
     });
     ffi::start_hal();
     hal_open_evt_rx.await.unwrap();
@@ -42,10 +49,11 @@ pub async fn init() -> Hal {
         raw_hal.hal_events.clone(),
         inner_hal.out_cmd_rx,
         inner_hal.out_data_rx,
+        inner_hal.packet_sink,
         hal_close_evt_rx,
     ));
 
-    raw_hal
+    Ok(raw_hal)
 }
 
 #[cxx::bridge(namespace = nfc::hal)]
@@ -110,10 +118,13 @@ impl From<ffi::NfcStatus> for HalEventStatus {
 }
 
 struct Callbacks {
+    hal_events: HalEventRegistry,
     hal_open_evt_tx: Option<oneshot::Sender<ffi::NfcStatus>>,
     hal_close_evt_tx: Option<oneshot::Sender<ffi::NfcStatus>>,
     in_cmd_tx: UnboundedSender<NciPacket>,
     in_data_tx: UnboundedSender<DataPacket>,
+    parse_errors: Arc<AtomicUsize>,
+    packet_sink: Arc<dyn PacketSink>,
 }
 
 static CALLBACKS: Mutex<Option<Callbacks>> = Mutex::new(None);
@@ -132,6 +143,16 @@ fn on_event(evt: ffi::NfcEvent, status: ffi::NfcStatus) {
                 evt_tx.send(status).unwrap();
             }
         }
+        ffi::NfcEvent::ERROR => {
+            // HAL callbacks run on a HIDL thread, outside of the tokio
+            // runtime, where blocking on the registry lock is allowed.
+            let handlers = &callbacks.as_ref().unwrap().hal_events.handlers;
+            if let Some(evt_tx) = handlers.blocking_lock().remove(&HalEvent::Error) {
+                if evt_tx.send(HalEventStatus::from(status)).is_err() {
+                    debug!("handler for the HAL error event is closed");
+                }
+            }
+        }
         _ => error!("Unhandled HAL event {:?}", evt),
     }
 }
@@ -139,15 +160,30 @@ fn on_event(evt: ffi::NfcEvent, status: ffi::NfcStatus) {
 fn on_data(data: &[u8]) {
     debug!("got packet: {:02x?}", data);
     let callbacks = CALLBACKS.lock().unwrap();
+    callbacks.as_ref().unwrap().packet_sink.on_packet(PacketDirection::Inbound, data);
     if is_control_packet(data) {
         match NciPacket::parse(data) {
-            Ok(p) => callbacks.as_ref().unwrap().in_cmd_tx.send(p).unwrap(),
-            Err(e) => error!("failure to parse response: {:?} data: {:02x?}", e, data),
+            Ok(p) => {
+                if callbacks.as_ref().unwrap().in_cmd_tx.send(p).is_err() {
+                    error!("NCI layer is closed, dropping control packet: {:02x?}", data);
+                }
+            }
+            Err(e) => {
+                error!("failure to parse response: {:?} data: {:02x?}", e, data);
+                callbacks.as_ref().unwrap().parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
         }
     } else {
         match DataPacket::parse(data) {
-            Ok(p) => callbacks.as_ref().unwrap().in_data_tx.send(p).unwrap(),
-            Err(e) => error!("failure to parse response: {:?} data: {:02x?}", e, data),
+            Ok(p) => {
+                if callbacks.as_ref().unwrap().in_data_tx.send(p).is_err() {
+                    error!("NCI layer is closed, dropping data packet: {:02x?}", data);
+                }
+            }
+            Err(e) => {
+                error!("failure to parse response: {:?} data: {:02x?}", e, data);
+                callbacks.as_ref().unwrap().parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
         }
     }
 }
@@ -156,12 +192,13 @@ async fn dispatch_outgoing(
     mut hal_events: HalEventRegistry,
     mut out_cmd_rx: UnboundedReceiver<NciPacket>,
     mut out_data_rx: UnboundedReceiver<DataPacket>,
+    packet_sink: Arc<dyn PacketSink>,
     hal_close_evt_rx: oneshot::Receiver<ffi::NfcStatus>,
 ) {
     loop {
         select! {
-            Some(cmd) = out_cmd_rx.recv() => ffi::send_command(&cmd.to_bytes()),
-            Some(data) = out_data_rx.recv() => ffi::send_command(&data.to_bytes()),
+            Some(cmd) = out_cmd_rx.recv() => send_packet(&*packet_sink, &cmd.to_bytes()),
+            Some(data) = out_data_rx.recv() => send_packet(&*packet_sink, &data.to_bytes()),
             else => break,
         }
     }
@@ -171,3 +208,8 @@ async fn dispatch_outgoing(
         evt.send(HalEventStatus::from(status)).unwrap();
     }
 }
+
+fn send_packet(packet_sink: &dyn PacketSink, data: &[u8]) {
+    packet_sink.on_packet(PacketDirection::Outbound, data);
+    ffi::send_command(data);
+}
@@ -17,70 +17,159 @@
 //! Nfc chip as well as a simulated environment.
 
 use crate::internal::InnerHal;
-use crate::{is_control_packet, Hal, HalEvent, HalEventRegistry, HalEventStatus, Result};
-use bytes::{BufMut, BytesMut};
-use log::{debug, error};
+use crate::{
+    is_control_packet, Hal, HalError, HalEvent, HalEventRegistry, HalEventStatus, PacketDirection,
+    PacketSink, Result,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, error, trace};
 use nfc_packets::nci::{DataPacket, NciPacket};
 use pdl_runtime::Packet;
 use std::convert::TryInto;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::select;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep, timeout, Duration};
 
-/// Initialize the module
-pub async fn init() -> Hal {
-    let (raw_hal, inner_hal) = InnerHal::new();
-    let (reader, writer) = TcpStream::connect("127.0.0.1:7000")
-        .await
-        .expect("unable to create stream to rootcanal")
-        .into_split();
+/// Address of the rootcanal NCI server
+const ROOTCANAL_ADDRESS: &str = "127.0.0.1:7000";
+
+/// Number of connection attempts to rootcanal before giving up
+const CONNECT_ATTEMPTS: usize = 3;
+
+/// Time to wait for each connection attempt to complete
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Time to wait before retrying a failed connection attempt, to give
+/// rootcanal time to start
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Initialize the module. Fails with `HalError::RootcanalConnectError`
+/// if rootcanal cannot be reached.
+pub async fn init(packet_sink: Arc<dyn PacketSink>) -> Result<Hal> {
+    let stream = connect(ROOTCANAL_ADDRESS, CONNECT_ATTEMPTS, CONNECT_TIMEOUT).await?;
+    Ok(init_with_transport(packet_sink, stream))
+}
+
+/// Initialize the module on `transport` in place of the connection to
+/// rootcanal. The NCI packets are exchanged on `transport` prefixed
+/// with their length, as with rootcanal.
+pub fn init_with_transport<T>(packet_sink: Arc<dyn PacketSink>, transport: T) -> Hal
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (raw_hal, inner_hal) = InnerHal::new(packet_sink);
+    let (reader, writer) = tokio::io::split(transport);
 
     let reader = BufReader::new(reader);
-    tokio::spawn(dispatch_incoming(inner_hal.in_cmd_tx, inner_hal.in_data_tx, reader));
+    tokio::spawn(dispatch_incoming(
+        raw_hal.hal_events.clone(),
+        inner_hal.in_cmd_tx,
+        inner_hal.in_data_tx,
+        inner_hal.parse_errors,
+        inner_hal.packet_sink.clone(),
+        reader,
+    ));
     tokio::spawn(dispatch_outgoing(
         raw_hal.hal_events.clone(),
         inner_hal.out_cmd_rx,
         inner_hal.out_data_rx,
+        inner_hal.packet_sink,
         writer,
     ));
 
     raw_hal
 }
 
-/// Send NCI events received from the HAL to the NCI layer
+/// Connect to rootcanal at `address`, making up to `attempts` attempts
+/// which each expire after `connect_timeout`
+async fn connect(
+    address: &str,
+    attempts: usize,
+    connect_timeout: Duration,
+) -> std::result::Result<TcpStream, HalError> {
+    let mut attempt = 1;
+    loop {
+        let error = match timeout(connect_timeout, TcpStream::connect(address)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => e,
+            Err(_) => std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("no connection after {:?}", connect_timeout),
+            ),
+        };
+        if attempt >= attempts {
+            return Err(HalError::RootcanalConnectError(error));
+        }
+        debug!("connection attempt {} to rootcanal failed: {}", attempt, error);
+        attempt += 1;
+        sleep(CONNECT_RETRY_DELAY).await;
+    }
+}
+
+/// Send NCI events received from the HAL to the NCI layer.
+/// Transport errors are reported with the HAL event Error, the dispatch
+/// stops without error when rootcanal closes the connection between two
+/// frames.
 async fn dispatch_incoming<R>(
+    mut hal_events: HalEventRegistry,
     in_cmd_tx: UnboundedSender<NciPacket>,
     in_data_tx: UnboundedSender<DataPacket>,
+    parse_errors: Arc<AtomicUsize>,
+    packet_sink: Arc<dyn PacketSink>,
     mut reader: R,
 ) -> Result<()>
 where
     R: AsyncReadExt + Unpin,
 {
     loop {
-        let mut buffer = BytesMut::with_capacity(1024);
-        let len: usize = reader.read_u16().await?.into();
-        buffer.resize(len, 0);
-        reader.read_exact(&mut buffer).await?;
-        let frozen = buffer.freeze();
-        debug!("{:?}", &frozen);
+        let frozen = match read_frame(&mut reader).await {
+            Ok(Some(frozen)) => frozen,
+            Ok(None) => {
+                debug!("rootcanal closed the connection");
+                break;
+            }
+            Err(e) => {
+                error!("failed to read from rootcanal: {}", e);
+                hal_events.notify(HalEvent::Error, HalEventStatus::TransportError).await;
+                break;
+            }
+        };
+        packet_sink.on_packet(PacketDirection::Inbound, &frozen);
+        if frozen.is_empty() {
+            error!("skipping zero-length frame");
+            continue;
+        }
         if is_control_packet(&frozen[..]) {
             match NciPacket::parse(&frozen) {
                 Ok(p) => {
+                    trace!("Received {:?}", p);
                     if in_cmd_tx.send(p).is_err() {
+                        error!("NCI layer is closed, stopping inbound dispatch");
                         break;
                     }
                 }
-                Err(e) => error!("dropping invalid cmd event packet: {}: {:02x}", e, frozen),
+                Err(e) => {
+                    error!("dropping invalid cmd event packet: {}: {:02x}", e, frozen);
+                    parse_errors.fetch_add(1, Ordering::Relaxed);
+                }
             }
         } else {
             match DataPacket::parse(&frozen) {
                 Ok(p) => {
+                    trace!("Received {:?}", p);
                     if in_data_tx.send(p).is_err() {
+                        error!("NCI layer is closed, stopping inbound dispatch");
                         break;
                     }
                 }
-                Err(e) => error!("dropping invalid data event packet: {}: {:02x}", e, frozen),
+                Err(e) => {
+                    error!("dropping invalid data event packet: {}: {:02x}", e, frozen);
+                    parse_errors.fetch_add(1, Ordering::Relaxed);
+                }
             }
         }
     }
@@ -88,21 +177,44 @@ where
     Ok(())
 }
 
+/// Read a single length-prefixed NCI frame from rootcanal, or None if
+/// the connection is closed before the next frame
+async fn read_frame<R>(reader: &mut R) -> Result<Option<Bytes>>
+where
+    R: AsyncReadExt + Unpin,
+{
+    let mut length = [0; 2];
+    if reader.read(&mut length[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut length[1..]).await?;
+    let mut buffer = BytesMut::with_capacity(1024);
+    buffer.resize(u16::from_be_bytes(length).into(), 0);
+    reader.read_exact(&mut buffer).await?;
+    Ok(Some(buffer.freeze()))
+}
+
 /// Send commands received from the NCI later to rootcanal
 async fn dispatch_outgoing<W>(
     mut hal_events: HalEventRegistry,
     mut out_cmd_rx: UnboundedReceiver<NciPacket>,
     mut out_data_rx: UnboundedReceiver<DataPacket>,
+    packet_sink: Arc<dyn PacketSink>,
     mut writer: W,
 ) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
 {
     loop {
-        select! {
-            Some(cmd) = out_cmd_rx.recv() => write_nci(&mut writer, cmd).await?,
-            Some(data) = out_data_rx.recv() => write_nci(&mut writer, data).await?,
+        let result = select! {
+            Some(cmd) = out_cmd_rx.recv() => write_nci(&mut writer, &*packet_sink, cmd).await,
+            Some(data) = out_data_rx.recv() => write_nci(&mut writer, &*packet_sink, data).await,
             else => break,
+        };
+        if let Err(e) = result {
+            error!("failed to write to rootcanal: {}", e);
+            hal_events.notify(HalEvent::Error, HalEventStatus::TransportError).await;
+            return Err(e);
         }
     }
 
@@ -114,16 +226,234 @@ where
     Ok(())
 }
 
-async fn write_nci<W, P>(writer: &mut W, cmd: P) -> Result<()>
+async fn write_nci<W, P>(writer: &mut W, packet_sink: &dyn PacketSink, cmd: P) -> Result<()>
 where
     W: AsyncWriteExt + Unpin,
-    P: Packet,
+    P: Packet + std::fmt::Debug,
 {
+    trace!("Sending {:?}", cmd);
     let b = cmd.to_bytes();
+    packet_sink.on_packet(PacketDirection::Outbound, &b);
     let mut data = BytesMut::with_capacity(b.len() + 2);
     data.put_u16(b.len().try_into().unwrap());
     data.extend(b);
     writer.write_all(&data[..]).await?;
-    debug!("Sent {:?}", data);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NoopPacketSink;
+    use nfc_packets::nci::{PacketBoundaryFlag, ResetCommandBuilder, ResetType};
+    use std::sync::Mutex;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    /// Packet sink recording the packets with their direction
+    #[derive(Default)]
+    struct CapturingSink {
+        packets: Mutex<Vec<(PacketDirection, Vec<u8>)>>,
+    }
+
+    impl PacketSink for CapturingSink {
+        fn on_packet(&self, direction: PacketDirection, packet: &[u8]) {
+            self.packets.lock().unwrap().push((direction, packet.to_vec()));
+        }
+    }
+
+    /// Encode frames as sent by rootcanal, prefixed with their length
+    fn frames(frames: &[&[u8]]) -> Vec<u8> {
+        let mut stream = vec![];
+        for frame in frames {
+            stream.extend((frame.len() as u16).to_be_bytes());
+            stream.extend(*frame);
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn counts_and_drops_malformed_frames() {
+        let (in_cmd_tx, mut in_cmd_rx) = unbounded_channel();
+        let (in_data_tx, mut in_data_rx) = unbounded_channel();
+        let parse_errors = Arc::new(AtomicUsize::new(0));
+        let reset_response: &[u8] = &[0x40, 0x00, 0x01, 0x00];
+        let data: &[u8] = &[0x02, 0x00, 0x01, 0x05];
+        // The payload lengths of the malformed frames exceed their size.
+        let stream =
+            frames(&[&[0x20, 0x00, 0x05, 0x00], reset_response, &[0x02, 0x00, 0x05], data]);
+
+        dispatch_incoming(
+            HalEventRegistry::default(),
+            in_cmd_tx,
+            in_data_tx,
+            parse_errors.clone(),
+            Arc::new(NoopPacketSink),
+            &stream[..],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 2);
+        assert_eq!(in_cmd_rx.recv().await.unwrap().to_vec(), reset_response);
+        assert_eq!(in_data_rx.recv().await.unwrap().to_vec(), data);
+        assert!(in_cmd_rx.recv().await.is_none());
+        assert!(in_data_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn skips_zero_length_frames() {
+        assert!(!is_control_packet(&[]));
+        let (in_cmd_tx, mut in_cmd_rx) = unbounded_channel();
+        let (in_data_tx, _in_data_rx) = unbounded_channel();
+        let parse_errors = Arc::new(AtomicUsize::new(0));
+        let reset_response: &[u8] = &[0x40, 0x00, 0x01, 0x00];
+        let stream = frames(&[&[], reset_response]);
+
+        dispatch_incoming(
+            HalEventRegistry::default(),
+            in_cmd_tx,
+            in_data_tx,
+            parse_errors.clone(),
+            Arc::new(NoopPacketSink),
+            &stream[..],
+        )
+        .await
+        .unwrap();
+
+        // The empty frame is not counted as a parse error.
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 0);
+        assert_eq!(in_cmd_rx.recv().await.unwrap().to_vec(), reset_response);
+        assert!(in_cmd_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reports_packets_in_both_directions_to_sink() {
+        let sink = Arc::new(CapturingSink::default());
+        let reset_command: &[u8] = &[0x20, 0x00, 0x01, 0x00];
+        let reset_response: &[u8] = &[0x40, 0x00, 0x01, 0x00];
+
+        let (out_cmd_tx, out_cmd_rx) = unbounded_channel();
+        let (out_data_tx, out_data_rx) = unbounded_channel();
+        out_cmd_tx
+            .send(
+                ResetCommandBuilder {
+                    gid: 0,
+                    pbf: PacketBoundaryFlag::CompleteOrFinal,
+                    reset_type: ResetType::KeepConfig,
+                }
+                .build()
+                .into(),
+            )
+            .unwrap();
+        // Closing the channels stops the dispatch.
+        drop(out_cmd_tx);
+        drop(out_data_tx);
+        let mut written = vec![];
+        dispatch_outgoing(
+            HalEventRegistry::default(),
+            out_cmd_rx,
+            out_data_rx,
+            sink.clone(),
+            &mut written,
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, frames(&[reset_command]));
+
+        let (in_cmd_tx, _in_cmd_rx) = unbounded_channel();
+        let (in_data_tx, _in_data_rx) = unbounded_channel();
+        // The malformed frame is reported as well.
+        let stream = frames(&[reset_response, &[0x20]]);
+        dispatch_incoming(
+            HalEventRegistry::default(),
+            in_cmd_tx,
+            in_data_tx,
+            Arc::new(AtomicUsize::new(0)),
+            sink.clone(),
+            &stream[..],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            *sink.packets.lock().unwrap(),
+            vec![
+                (PacketDirection::Outbound, reset_command.to_vec()),
+                (PacketDirection::Inbound, reset_response.to_vec()),
+                (PacketDirection::Inbound, vec![0x20]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn stops_inbound_dispatch_when_nci_layer_is_closed() {
+        let (in_cmd_tx, in_cmd_rx) = unbounded_channel();
+        let (in_data_tx, in_data_rx) = unbounded_channel();
+        drop(in_cmd_rx);
+        drop(in_data_rx);
+        let mut hal_events = HalEventRegistry::default();
+        let (error_tx, mut error_rx) = tokio::sync::oneshot::channel();
+        hal_events.register(HalEvent::Error, error_tx).await;
+        let reset_response: &[u8] = &[0x40, 0x00, 0x01, 0x00];
+        let stream = frames(&[reset_response, reset_response]);
+        let mut reader = &stream[..];
+
+        dispatch_incoming(
+            hal_events,
+            in_cmd_tx,
+            in_data_tx,
+            Arc::new(AtomicUsize::new(0)),
+            Arc::new(NoopPacketSink),
+            &mut reader,
+        )
+        .await
+        .unwrap();
+
+        // The dispatch stops at the first packet, without reporting a
+        // transport error.
+        assert_eq!(reader, &frames(&[reset_response])[..]);
+        assert!(error_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_connection_is_only_an_error_within_a_frame() {
+        let reset_response: &[u8] = &[0x40, 0x00, 0x01, 0x00];
+        let stream = frames(&[reset_response]);
+        for (received, error) in
+            [(&stream[..], false), (&stream[..1], true), (&stream[..stream.len() - 1], true)]
+        {
+            let (in_cmd_tx, _in_cmd_rx) = unbounded_channel();
+            let (in_data_tx, _in_data_rx) = unbounded_channel();
+            let mut hal_events = HalEventRegistry::default();
+            let (error_tx, mut error_rx) = tokio::sync::oneshot::channel();
+            hal_events.register(HalEvent::Error, error_tx).await;
+
+            dispatch_incoming(
+                hal_events,
+                in_cmd_tx,
+                in_data_tx,
+                Arc::new(AtomicUsize::new(0)),
+                Arc::new(NoopPacketSink),
+                received,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(error_rx.try_recv().is_ok(), error, "{:02x?}", received);
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_fails_within_bound_when_rootcanal_is_down() {
+        // Nothing listens on the port of the dropped listener.
+        let address = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let connect_timeout = Duration::from_millis(100);
+        let start = std::time::Instant::now();
+        let error = connect(&address.to_string(), 2, connect_timeout).await.unwrap_err();
+        let elapsed = start.elapsed();
+        assert!(matches!(error, HalError::RootcanalConnectError(_)), "{:?}", error);
+        // The connection is retried once after the retry delay.
+        assert!(elapsed >= CONNECT_RETRY_DELAY);
+        assert!(elapsed < CONNECT_RETRY_DELAY + 2 * connect_timeout, "{:?}", elapsed);
+    }
+}
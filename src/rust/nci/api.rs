@@ -17,15 +17,21 @@
 use crate::{CommandSender, LogicalConnectionsRegistry, Result};
 use bytes::Bytes;
 use log::{debug, error};
-use nfc_hal::{HalEvent, HalEventRegistry, HalEventStatus};
+use nfc_hal::{HalEvent, HalEventRegistry, HalEventStatus, NoopPacketSink, PacketSink};
 use nfc_packets::nci::RfMappingConfiguration;
-use nfc_packets::nci::{self, CommandBuilder, DataPacket, Opcode};
+use nfc_packets::nci::{self, CommandBuilder, DataPacket, DataPacketBuilder, Opcode};
 use nfc_packets::nci::{ConnCloseCommandBuilder, ConnCreateCommandBuilder};
-use nfc_packets::nci::{DestParam, DestParamTypes, DestTypes};
+use nfc_packets::nci::{DestParam, DestTypes, NfceeProtocols, RfProtocols};
 use nfc_packets::nci::{FeatureEnable, PacketBoundaryFlag, ResetType};
 use nfc_packets::nci::{InitCommandBuilder, ResetCommandBuilder};
-use nfc_packets::nci::{InitResponse, ResponseChild};
+use nfc_packets::nci::{InitResponse, NfccFeatures, ResponseChild, RfInterface};
+use std::sync::Arc;
 use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+
+/// Time to wait for the NFCC to loop back the data sent on a loopback
+/// connection
+const LOOPBACK_TIMEOUT: Duration = Duration::from_secs(1);
 
 type ConnCallback = fn(u8, u16, &[u8]);
 
@@ -48,13 +54,22 @@ pub struct NciApi {
     /// HalEventRegistry is used to register for HAL events
     hal_events: Option<HalEventRegistry>,
     nfc_data: NfcData,
+    /// Time to wait for the looped back data in nfc_loopback()
+    loopback_timeout: Duration,
 }
 
 impl NciApi {
     /// NciApi constructor
     pub fn new() -> NciApi {
         let nfc_data = NfcData { init_response: None, rf_callback: None, hci_callback: None };
-        NciApi { commands: None, connections: None, callback: None, hal_events: None, nfc_data }
+        NciApi {
+            commands: None,
+            connections: None,
+            callback: None,
+            hal_events: None,
+            nfc_data,
+            loopback_timeout: LOOPBACK_TIMEOUT,
+        }
     }
 
     /** ****************************************************************************
@@ -77,9 +92,25 @@ impl NciApi {
      **
      *******************************************************************************/
     /// extern tNFC_STATUS NFC_Enable(tNFC_RESPONSE_CBACK* p_cback);
-    pub async fn nfc_enable(&mut self, callback: RespCallback) {
-        let nci = crate::init().await;
+    pub async fn nfc_enable(&mut self, callback: RespCallback) -> Result<()> {
+        self.nfc_enable_with_packet_sink(callback, Arc::new(NoopPacketSink)).await
+    }
+
+    /// Enable NFC like nfc_enable(), and report every raw packet exchanged
+    /// with the NFCC to `packet_sink`, e.g. a `TimestampPacketLogger`
+    pub async fn nfc_enable_with_packet_sink(
+        &mut self,
+        callback: RespCallback,
+        packet_sink: Arc<dyn PacketSink>,
+    ) -> Result<()> {
+        let nci =
+            crate::init(crate::DEFAULT_CMD_QUEUE_CAPACITY, Default::default(), packet_sink).await?;
+        self.attach(nci, callback);
+        Ok(())
+    }
 
+    /// Use the interfaces of an initialized NCI module
+    fn attach(&mut self, nci: crate::Nci, callback: RespCallback) {
         self.commands = Some(nci.commands);
         self.connections = Some(nci.connections);
         self.callback = Some(callback);
@@ -133,6 +164,13 @@ impl NciApi {
     /// extern void NFC_Init(tHAL_NFC_ENTRY* p_hal_entry_tbl);
     pub async fn nfc_init(&mut self) -> Result<()> {
         let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        // The static connections are opened by a previous initialization,
+        // and cannot be opened twice.
+        if let Some(conn) = self.connections.as_ref() {
+            if conn.is_open(0).await {
+                return Err("NFC is already initialized".into());
+            }
+        }
         if let Some(cmd) = self.commands.as_mut() {
             let reset = cmd
                 .send_and_notify(
@@ -154,7 +192,7 @@ impl NciApi {
                     // Open static RF connection
                     // TODO: use channels instead of callcacks here
                     // the data can be tranlated to c-callback at the shim level
-                    conn.open(0, self.nfc_data.rf_callback, 0, 0).await;
+                    conn.open(0, self.nfc_data.rf_callback, 0, 0).await?;
                     // Open static HCI connection
                     conn.open(
                         1, /* TODO: link constants to the c header */
@@ -162,7 +200,7 @@ impl NciApi {
                         irp.get_max_data_payload(),
                         irp.get_num_of_credits(),
                     )
-                    .await;
+                    .await?;
                 }
                 self.nfc_data.init_response = Some(irp);
             }
@@ -189,6 +227,39 @@ impl NciApi {
         }
     }
 
+    fn init_response(&self) -> Result<&InitResponse> {
+        self.nfc_data.init_response.as_ref().ok_or_else(|| "NFC is not initialized".into())
+    }
+
+    /// Maximum payload size of the control packets supported by the NFCC,
+    /// as reported in CORE_INIT_RSP.
+    pub async fn nfc_get_max_ctrl_payload(&self) -> Result<u8> {
+        Ok(self.init_response()?.get_max_ctrl_payload())
+    }
+
+    /// Initial number of credits of the static HCI connection,
+    /// as reported in CORE_INIT_RSP.
+    pub async fn nfc_get_num_credits(&self) -> Result<u8> {
+        Ok(self.init_response()?.get_num_of_credits())
+    }
+
+    /// RF interfaces supported by the NFCC, as reported in CORE_INIT_RSP.
+    pub async fn nfc_get_supported_rf_interfaces(&self) -> Result<Vec<RfInterface>> {
+        Ok(self.init_response()?.get_rf_interface().clone())
+    }
+
+    /// RF interfaces supported by the NFCC, like
+    /// `nfc_get_supported_rf_interfaces`, or an empty list if NFC is
+    /// not initialized.
+    pub async fn nfc_get_rf_interfaces(&self) -> Vec<RfInterface> {
+        self.nfc_get_supported_rf_interfaces().await.unwrap_or_default()
+    }
+
+    /// Features supported by the NFCC, as reported in CORE_INIT_RSP.
+    pub async fn nfc_get_nfcc_features(&self) -> Result<NfccFeatures> {
+        Ok(self.init_response()?.get_nfcc_features().clone())
+    }
+
     /** *****************************************************************************
      **
      ** Function         NFC_SetConfig
@@ -294,20 +365,31 @@ impl NciApi {
         callback: ConnCallback,
     ) -> Result<u8> {
         let pbf = PacketBoundaryFlag::CompleteOrFinal;
-        let mut destparams: Vec<DestParam> = vec![];
-        let dt = DestTypes::try_from(dest_type).unwrap();
-        match dt {
-            DestTypes::NfccLpbk => (),
+        let Ok(dt) = DestTypes::try_from(dest_type) else {
+            return Ok(nci::Status::InvalidParam as u8);
+        };
+        let destparams = match dt {
+            DestTypes::NfccLpbk => vec![],
             DestTypes::Remote => {
-                let parameter = vec![id, protocol];
-                destparams.push(DestParam { ptype: DestParamTypes::RfDisc, parameter });
+                let Some(destparam) = RfProtocols::try_from(protocol)
+                    .ok()
+                    .and_then(|protocol| DestParam::rf_discovery(id, protocol))
+                else {
+                    return Ok(nci::Status::InvalidParam as u8);
+                };
+                vec![destparam]
             }
             DestTypes::Nfcee => {
-                let parameter: Vec<u8> = vec![id, protocol];
-                destparams.push(DestParam { ptype: DestParamTypes::Nfcee, parameter });
+                let Some(destparam) = NfceeProtocols::try_from(protocol)
+                    .ok()
+                    .and_then(|protocol| DestParam::nfcee(id, protocol))
+                else {
+                    return Ok(nci::Status::InvalidParam as u8);
+                };
+                vec![destparam]
             }
             _ => return Ok(nci::Status::InvalidParam as u8),
-        }
+        };
         if let Some(cmd) = self.commands.as_mut() {
             let rp = cmd
                 .send(ConnCreateCommandBuilder { gid: 0, pbf, dt, destparams }.build().into())
@@ -316,13 +398,19 @@ impl NciApi {
                 let status = ccrp.get_status();
                 if status == nci::Status::Ok {
                     if let Some(conn) = self.connections.as_mut() {
-                        conn.open(
-                            ccrp.get_conn_id(),
-                            Some(callback),
-                            ccrp.get_mpps(),
-                            ccrp.get_ncreds(),
-                        )
-                        .await;
+                        let conn_id = ccrp.get_conn_id();
+                        if let Err(err) = conn
+                            .open(conn_id, Some(callback), ccrp.get_mpps(), ccrp.get_ncreds())
+                            .await
+                        {
+                            // The NFCC created the connection anyway: close it
+                            // so that its id is not leaked.
+                            cmd.send(
+                                ConnCloseCommandBuilder { gid: 0, pbf, conn_id }.build().into(),
+                            )
+                            .await?;
+                            return Err(err.into());
+                        }
                         let conn_create_evt =
                             [status as u8, dest_type, id, ccrp.get_mpps(), ccrp.get_ncreds()];
                         callback(ccrp.get_conn_id(), 0, &conn_create_evt[..]);
@@ -378,6 +466,44 @@ impl NciApi {
         Ok(nci::Status::NotInitialized as u8)
     }
 
+    /// Send `data` on a new NFCC loopback connection and return the data
+    /// looped back by the NFCC. The data is segmented and reassembled
+    /// according to the maximum payload size of the connection, which is
+    /// closed before returning. Fails if the data is not looped back
+    /// within `LOOPBACK_TIMEOUT`.
+    pub async fn nfc_loopback(&mut self, data: &[u8]) -> Result<Bytes> {
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        let (Some(cmd), Some(conn)) = (self.commands.as_mut(), self.connections.as_mut()) else {
+            return Err("NFC is not initialized".into());
+        };
+        let dt = DestTypes::NfccLpbk;
+        let rp = cmd
+            .send(ConnCreateCommandBuilder { gid: 0, pbf, dt, destparams: vec![] }.build().into())
+            .await?;
+        let ResponseChild::ConnCreateResponse(ccrp) = rp.specialize() else {
+            return Err("unexpected response to CORE_CONN_CREATE_CMD".into());
+        };
+        if ccrp.get_status() != nci::Status::Ok {
+            return Err(format!("loopback connection failed: {:?}", ccrp.get_status()).into());
+        }
+        let conn_id = ccrp.get_conn_id();
+        conn.open(conn_id, None, ccrp.get_mpps(), ccrp.get_ncreds()).await?;
+        let received = conn.receive(conn_id).await.ok_or("loopback connection closed")?;
+        conn.send_packet(
+            conn_id,
+            DataPacketBuilder { conn_id, pbf, cr: 0, payload: Some(Bytes::copy_from_slice(data)) }
+                .build(),
+        )
+        .await;
+        let looped_back = timeout(self.loopback_timeout, received).await;
+        conn.close(conn_id).await;
+        cmd.send(ConnCloseCommandBuilder { gid: 0, pbf, conn_id }.build().into()).await?;
+        match looped_back {
+            Ok(looped_back) => Ok(looped_back?),
+            Err(_) => Err(format!("no data looped back after {:?}", self.loopback_timeout).into()),
+        }
+    }
+
     /** *****************************************************************************
      **
      ** Function         NFC_SetStaticRfCback
@@ -452,8 +578,19 @@ impl NciApi {
      *******************************************************************************/
     //extern tNFC_STATUS NFC_SendData(uint8_t conn_id, NFC_HDR* p_data);
     pub async fn nfc_send_data(&mut self, conn_id: u8, data: &[u8]) -> Result<u8> {
+        const DATA_PACKET_HEADER_SIZE: usize = 3;
         if let Some(conn) = self.connections.as_mut() {
             match DataPacket::parse(data) {
+                // The payload length in the header must account for the
+                // whole buffer, trailing bytes would be silently dropped.
+                Ok(pkt) if pkt.get_payload().len() + DATA_PACKET_HEADER_SIZE != data.len() => {
+                    error!(
+                        "Data packet payload length {} does not match the buffer length {}",
+                        pkt.get_payload().len(),
+                        data.len()
+                    );
+                    return Ok(nci::Status::InvalidParam as u8);
+                }
                 Ok(pkt) => {
                     conn.send_packet(conn_id, pkt).await;
                     return Ok(nci::Status::Ok as u8);
@@ -467,6 +604,50 @@ impl NciApi {
         Ok(nci::Status::NotInitialized as u8)
     }
 
+    /// Send the HCP message `message` (message header and data) to the pipe
+    /// `pipe_id` on the static HCI connection. The message is fragmented
+    /// into HCP packets fitting the maximum data payload size reported in
+    /// CORE_INIT_RSP, each one sent in a single data packet; the chaining
+    /// bit of the packet header is set on the last fragment only. Data flow
+    /// control on the connection uses the credits reported in CORE_INIT_RSP.
+    pub async fn nfc_send_hci(&mut self, pipe_id: u8, message: &[u8]) -> Result<u8> {
+        const HCI_CONN_ID: u8 = 1;
+        const HCP_CB_LAST: u8 = 0x80;
+        const HCP_PIPE_ID_MASK: u8 = 0x7f;
+        const HCP_PACKET_HEADER_SIZE: usize = 1;
+        let (Some(conn), Some(init_response)) =
+            (self.connections.as_mut(), self.nfc_data.init_response.as_ref())
+        else {
+            return Ok(nci::Status::NotInitialized as u8);
+        };
+        if pipe_id & !HCP_PIPE_ID_MASK != 0 || message.is_empty() {
+            error!("Invalid HCP message of {} bytes for pipe {:#x}", message.len(), pipe_id);
+            return Ok(nci::Status::InvalidParam as u8);
+        }
+        let fragment_size = usize::from(init_response.get_max_data_payload())
+            .saturating_sub(HCP_PACKET_HEADER_SIZE)
+            .max(1);
+        let last_index = (message.len() - 1) / fragment_size;
+        for (index, fragment) in message.chunks(fragment_size).enumerate() {
+            let cb = if index == last_index { HCP_CB_LAST } else { 0 };
+            let mut payload = Vec::with_capacity(HCP_PACKET_HEADER_SIZE + fragment.len());
+            payload.push(cb | pipe_id);
+            payload.extend_from_slice(fragment);
+            conn.send_packet(
+                HCI_CONN_ID,
+                DataPacketBuilder {
+                    conn_id: HCI_CONN_ID,
+                    pbf: PacketBoundaryFlag::CompleteOrFinal,
+                    cr: 0,
+                    payload: Some(payload.into()),
+                }
+                .build(),
+            )
+            .await;
+        }
+        Ok(nci::Status::Ok as u8)
+    }
+
     /** ****************************************************************************
      **
      ** Function         NFC_FlushData
@@ -558,3 +739,292 @@ impl Default for NciApi {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{init_fake, FakeNfcc};
+    use nfc_hal::PacketDirection;
+    use nfc_packets::nci::{CommandChild, NciPacketChild};
+    use nfc_packets::nci::{ConnCloseResponseBuilder, ConnCreateResponseBuilder};
+
+    /// Enable the API on a fake NFCC, the dispatch loop runs in the
+    /// background.
+    async fn enable_fake() -> (NciApi, FakeNfcc) {
+        let (nci, dispatcher, nfcc) = init_fake(Default::default()).await;
+        tokio::spawn(dispatcher.run());
+        let mut api = NciApi::new();
+        api.attach(nci, |_, _| ());
+        (api, nfcc)
+    }
+
+    /// Enable and initialize the API on a fake NFCC.
+    async fn init_fake_api() -> (NciApi, FakeNfcc) {
+        let (mut api, mut nfcc) = enable_fake().await;
+        let (result, ()) = tokio::join!(api.nfc_init(), nfcc.serve_init());
+        result.unwrap();
+        (api, nfcc)
+    }
+
+    impl FakeNfcc {
+        /// Answer the creation of a loopback connection with id 2, and
+        /// return the data packets received on it until it is closed.
+        /// The data packets are looped back when `loopback` is set.
+        async fn serve_loopback(&mut self, loopback: bool) -> Vec<DataPacket> {
+            let pbf = PacketBoundaryFlag::CompleteOrFinal;
+            assert_eq!(self.command().await.get_op(), Opcode::CoreConnCreate);
+            self.send(
+                ConnCreateResponseBuilder {
+                    gid: 0,
+                    pbf,
+                    status: nci::Status::Ok,
+                    mpps: 0xff,
+                    ncreds: 8,
+                    conn_id: 2,
+                }
+                .build(),
+            );
+            let mut received = vec![];
+            loop {
+                tokio::select! {
+                    Some(cmd) = self.out_cmd_rx.recv() => {
+                        assert!(matches!(
+                            cmd.specialize(),
+                            NciPacketChild::Command(cmd) if cmd.get_op() == Opcode::CoreConnClose
+                        ));
+                        self.send(ConnCloseResponseBuilder { gid: 0, pbf, status: nci::Status::Ok }.build());
+                        return received;
+                    },
+                    Some(data) = self.out_data_rx.recv() => {
+                        if loopback {
+                            self.in_data_tx.send(data.clone()).unwrap();
+                        }
+                        received.push(data);
+                    },
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn loopback_round_trips_segmented_data() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        let data: Vec<u8> = (0..1024).map(|i| i as u8).collect();
+        let (looped_back, received) =
+            tokio::join!(api.nfc_loopback(&data), nfcc.serve_loopback(true));
+        assert_eq!(looped_back.unwrap(), data);
+        assert_eq!(received.len(), 5);
+    }
+
+    #[tokio::test]
+    async fn loopback_fails_when_nothing_is_looped_back() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        api.loopback_timeout = Duration::from_millis(10);
+        let (looped_back, received) =
+            tokio::join!(api.nfc_loopback(&[1, 2, 3]), nfcc.serve_loopback(false));
+        assert!(looped_back.is_err());
+        assert_eq!(received.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn capabilities_are_read_from_init_response() {
+        let (api, _nfcc) = enable_fake().await;
+        assert!(api.nfc_get_max_ctrl_payload().await.is_err());
+        assert!(api.nfc_get_supported_rf_interfaces().await.is_err());
+
+        let (api, _nfcc) = init_fake_api().await;
+        assert_eq!(api.nfc_get_max_ctrl_payload().await.unwrap(), 0xff);
+        assert_eq!(api.nfc_get_num_credits().await.unwrap(), 0);
+        let interfaces = api.nfc_get_supported_rf_interfaces().await.unwrap();
+        assert_eq!(interfaces.iter().map(|i| i.intf).collect::<Vec<_>>(), [1, 2, 3, 0, 0x90]);
+        let features = api.nfc_get_nfcc_features().await.unwrap();
+        assert_eq!(features.hci_net_support, nci::FeatureState::Available);
+    }
+
+    #[tokio::test]
+    async fn send_data_validates_the_payload_length() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        api.connections.as_mut().unwrap().open(2, None, 4, 8).await.unwrap();
+
+        // The header announces 2 bytes of payload, the buffer holds 4.
+        let mismatched = [0x02, 0x00, 0x02, 1, 2, 3, 4];
+        assert_eq!(
+            api.nfc_send_data(2, &mismatched).await.unwrap(),
+            nci::Status::InvalidParam as u8
+        );
+        assert!(nfcc.out_data_rx.try_recv().is_err());
+
+        // A well formed payload larger than the maximum is segmented.
+        let oversized = [0x02, 0x00, 0x0a, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        assert_eq!(api.nfc_send_data(2, &oversized).await.unwrap(), nci::Status::Ok as u8);
+        let mut payload = vec![];
+        for pbf in [
+            PacketBoundaryFlag::Incomplete,
+            PacketBoundaryFlag::Incomplete,
+            PacketBoundaryFlag::CompleteOrFinal,
+        ] {
+            let pkt = nfcc.out_data_rx.recv().await.unwrap();
+            assert_eq!(pkt.get_pbf(), pbf);
+            payload.extend_from_slice(pkt.get_payload());
+        }
+        assert_eq!(payload, oversized[3..]);
+    }
+
+    /// Packet sink recording the packets with their direction
+    #[derive(Default)]
+    struct CapturingSink {
+        packets: std::sync::Mutex<Vec<(PacketDirection, Vec<u8>)>>,
+    }
+
+    impl PacketSink for CapturingSink {
+        fn on_packet(&self, direction: PacketDirection, packet: &[u8]) {
+            self.packets.lock().unwrap().push((direction, packet.to_vec()));
+        }
+    }
+
+    #[cfg(not(target_os = "android"))]
+    #[tokio::test]
+    async fn packets_are_reported_to_sink() {
+        use tokio::io::AsyncReadExt;
+
+        // Stand in for rootcanal on an in-memory transport.
+        let (mut rootcanal, transport) = tokio::io::duplex(1024);
+        let sink = Arc::new(CapturingSink::default());
+        let hal = nfc_hal::ihal::init_with_transport(sink.clone(), transport);
+        let (nci, dispatcher) =
+            crate::init_with_hal(hal, crate::DEFAULT_CMD_QUEUE_CAPACITY, Default::default())
+                .await
+                .unwrap();
+        tokio::spawn(dispatcher.run());
+        let mut api = NciApi::new();
+        api.attach(nci, |_, _| ());
+
+        // The first frame is read while the initialization runs, which
+        // fails as the CORE_RESET_CMD is left without response.
+        let mut frame = [0; 6];
+        let (init, read) = tokio::join!(api.nfc_init(), rootcanal.read_exact(&mut frame));
+        assert!(init.is_err());
+        read.unwrap();
+
+        // CORE_RESET_CMD with the reset type Reset Configuration, prefixed
+        // with its length on the transport.
+        let reset_command = vec![0x20, 0x00, 0x01, 0x01];
+        assert_eq!(frame[2..], reset_command);
+        let packets = sink.packets.lock().unwrap();
+        assert_eq!(*packets, [(PacketDirection::Outbound, reset_command)]);
+    }
+
+    #[tokio::test]
+    async fn rf_interfaces_are_empty_before_init() {
+        let (api, _nfcc) = enable_fake().await;
+        assert!(api.nfc_get_rf_interfaces().await.is_empty());
+
+        let (api, _nfcc) = init_fake_api().await;
+        let interfaces = api.nfc_get_rf_interfaces().await;
+        assert_eq!(interfaces, api.nfc_get_supported_rf_interfaces().await.unwrap());
+        assert_eq!(interfaces.iter().map(|i| i.intf).collect::<Vec<_>>(), [1, 2, 3, 0, 0x90]);
+    }
+
+    #[tokio::test]
+    async fn conn_create_rejects_invalid_destinations() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        let invalid_param = nci::Status::InvalidParam as u8;
+        for (dest_type, id, protocol) in [
+            // RFU destination type.
+            (0x00, 0x01, 0x04),
+            // RFU RF Discovery IDs.
+            (DestTypes::Remote as u8, 0x00, 0x04),
+            (DestTypes::Remote as u8, 0xff, 0x04),
+            // Unknown RF protocol.
+            (DestTypes::Remote as u8, 0x01, 0x80),
+            // DH-NFCEE and RFU NFCEE IDs.
+            (DestTypes::Nfcee as u8, 0x00, 0x00),
+            (DestTypes::Nfcee as u8, 0xff, 0x00),
+            // Unknown NFCEE protocol.
+            (DestTypes::Nfcee as u8, 0x86, 0x80),
+        ] {
+            let status = api.nfc_conn_create(dest_type, id, protocol, |_, _, _| ()).await;
+            assert_eq!(status.unwrap(), invalid_param, "{:?}", (dest_type, id, protocol));
+        }
+        // No CORE_CONN_CREATE_CMD is sent for invalid destinations.
+        assert!(nfcc.out_cmd_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn second_init_fails_without_reset() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        let error = api.nfc_init().await.unwrap_err();
+        assert_eq!(error.to_string(), "NFC is already initialized");
+        // No CORE_RESET_CMD is sent, and the static connections stay open.
+        assert!(nfcc.out_cmd_rx.try_recv().is_err());
+        assert!(api.connections.as_ref().unwrap().is_open(0).await);
+    }
+
+    #[tokio::test]
+    async fn send_hci_fragments_hcp_messages_on_the_hci_connection() {
+        use nfc_packets::nci::{ConnCreditsNotificationBuilder, CreditsPerConn};
+
+        let (mut api, mut nfcc) = init_fake_api().await;
+        assert_eq!(api.nfc_send_hci(0x80, &[0x00]).await.unwrap(), nci::Status::InvalidParam as u8);
+        assert_eq!(api.nfc_send_hci(0x01, &[]).await.unwrap(), nci::Status::InvalidParam as u8);
+
+        // The 300 bytes message does not fit in a single 255 bytes payload.
+        let message: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(api.nfc_send_hci(0x01, &message).await.unwrap(), nci::Status::Ok as u8);
+
+        // CORE_INIT_RSP granted no credit to the HCI connection: the packets
+        // wait for the NFCC to grant some.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(nfcc.out_data_rx.try_recv().is_err());
+        nfcc.send(
+            ConnCreditsNotificationBuilder {
+                gid: 0,
+                pbf: PacketBoundaryFlag::CompleteOrFinal,
+                conns: vec![CreditsPerConn { conn_id: 1, rfu: 0, ncredits: 2 }],
+            }
+            .build(),
+        );
+        let mut payloads = vec![];
+        for _ in 0..2 {
+            let packet = nfcc.out_data_rx.recv().await.unwrap();
+            assert_eq!(packet.get_conn_id(), 1);
+            assert_eq!(packet.get_pbf(), PacketBoundaryFlag::CompleteOrFinal);
+            payloads.push(packet.get_payload().to_vec());
+        }
+        // The chaining bit is only set in the header of the last fragment.
+        assert_eq!(payloads[0], [&[0x01], &message[..254]].concat());
+        assert_eq!(payloads[1], [&[0x81], &message[254..]].concat());
+    }
+
+    #[tokio::test]
+    async fn conn_create_closes_the_connection_when_its_id_is_in_use() {
+        let (mut api, mut nfcc) = init_fake_api().await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        let nfcc = async {
+            // The NFCC assigns the id of the static HCI connection.
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreConnCreate);
+            nfcc.send(
+                ConnCreateResponseBuilder {
+                    gid: 0,
+                    pbf,
+                    status: nci::Status::Ok,
+                    mpps: 0xff,
+                    ncreds: 1,
+                    conn_id: 1,
+                }
+                .build(),
+            );
+            match nfcc.command().await.specialize() {
+                CommandChild::ConnCloseCommand(cmd) => assert_eq!(cmd.get_conn_id(), 1),
+                cmd => panic!("unexpected command {:?}", cmd),
+            }
+            nfcc.send(ConnCloseResponseBuilder { gid: 0, pbf, status: nci::Status::Ok }.build());
+        };
+        let (result, ()) = tokio::join!(
+            api.nfc_conn_create(DestTypes::Remote as u8, 0x01, 0x04, |_, _, _| ()),
+            nfcc
+        );
+        let error = result.unwrap_err();
+        assert_eq!(error.to_string(), "A logical connection with id 1 already exists");
+    }
+}
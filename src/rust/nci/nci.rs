@@ -16,48 +16,143 @@
 //! Supports sending NCI commands to the HAL and receiving
 //! NCI messages back
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use log::{debug, error};
-use nfc_hal::{Hal, HalEventRegistry};
+use nfc_hal::{Hal, HalError, HalEvent, HalEventRegistry, HalEventStatus, PacketSink};
 use nfc_packets::nci::DataPacketChild::Payload;
 use nfc_packets::nci::NciPacketChild;
 use nfc_packets::nci::NotificationChild::ConnCreditsNotification;
 use nfc_packets::nci::{Command, DataPacket, DataPacketBuilder, Notification};
 use nfc_packets::nci::{Opcode, PacketBoundaryFlag, Response};
 use pdl_runtime::Packet;
+use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::select;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender, UnboundedSender};
-use tokio::sync::{oneshot, RwLock};
-use tokio::time::{sleep, Duration, Instant};
+use tokio::sync::{broadcast, oneshot, RwLock};
+use tokio::time::{sleep, Duration, Instant, Sleep};
 
 pub mod api;
 
 /// Result type
 type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
 
-/// Initialize the module and connect the channels
-pub async fn init() -> Nci {
-    let hc = nfc_hal::init().await;
+/// Default capacity of the NCI command queue
+pub const DEFAULT_CMD_QUEUE_CAPACITY: usize = 10;
+
+/// Number of notifications buffered for each notification observer
+const NOTIFICATION_OBSERVER_CAPACITY: usize = 16;
+
+/// Default time to wait for the response to a command
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Policy applied to commands the NFCC does not respond to in time
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Number of times a command is sent before giving up
+    pub max_attempts: usize,
+    /// Time to wait for the response to each attempt
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { max_attempts: 1, timeout: DEFAULT_RESPONSE_TIMEOUT }
+    }
+}
+
+/// Initialize the module and connect the channels.
+/// `cmd_queue_capacity` is the number of commands that can be queued
+/// before senders have to wait for the dispatcher, `retry_policy`
+/// selects how commands left without response are re-sent, and every
+/// raw packet exchanged with the NFCC is reported to `packet_sink`.
+/// Fails if the HAL cannot be initialized, or with
+/// `InitError::InvalidQueueCapacity` if `cmd_queue_capacity` is 0.
+pub async fn init(
+    cmd_queue_capacity: usize,
+    retry_policy: RetryPolicy,
+    packet_sink: Arc<dyn PacketSink>,
+) -> Result<Nci> {
+    check_cmd_queue_capacity(cmd_queue_capacity)?;
+    let hc = nfc_hal::init_with_packet_sink(packet_sink).await?;
+    let (nci, dispatcher) = init_with_hal(hc, cmd_queue_capacity, retry_policy).await?;
+    tokio::spawn(dispatcher.run());
+    Ok(nci)
+}
+
+/// Connect the channels to `hc` without starting the dispatch loop.
+/// The packets are reported to the packet sink `hc` was initialized
+/// with, cf `nfc_hal::init_with_packet_sink`. The returned dispatcher
+/// must be driven by the caller, either with `Dispatcher::run` or one
+/// event at a time with `Dispatcher::step`.
+/// Once the HAL reports a transport error, the pending and queued
+/// commands fail with `HalError::TransportError`.
+/// Fails with `InitError::InvalidQueueCapacity` if `cmd_queue_capacity`
+/// is 0.
+pub async fn init_with_hal(
+    hc: Hal,
+    cmd_queue_capacity: usize,
+    retry_policy: RetryPolicy,
+) -> std::result::Result<(Nci, Dispatcher), InitError> {
+    check_cmd_queue_capacity(cmd_queue_capacity)?;
     // Channel to handle data upstream messages
     //    let (in_data_int, in_data_ext) = channel::<DataPacket>(10);
     // Internal data channels
     //    let ic = InternalChannels { in_data_int };
 
-    let (cmd_tx, cmd_rx) = channel::<QueuedCommand>(10);
-    let commands = CommandSender { cmd_tx };
-    let hal_events = hc.hal_events.clone();
+    let (cmd_tx, cmd_rx) = channel::<QueuedCommand>(cmd_queue_capacity);
+    let commands = CommandSender { cmd_tx, backpressure: Arc::new(AtomicUsize::new(0)) };
+    let mut hal_events = hc.hal_events.clone();
+    let (hal_error_tx, hal_error) = oneshot::channel::<HalEventStatus>();
+    hal_events.register(HalEvent::Error, hal_error_tx).await;
 
-    let notifications = EventRegistry { handlers: Arc::new(Mutex::new(HashMap::new())) };
+    let (observers, _) = broadcast::channel(NOTIFICATION_OBSERVER_CAPACITY);
+    let notifications = EventRegistry { handlers: Arc::new(Mutex::new(HashMap::new())), observers };
     let connections = LogicalConnectionsRegistry {
         conns: Arc::new(RwLock::new(HashMap::new())),
         sender: hc.out_data_tx.clone(),
     };
 
-    tokio::spawn(dispatch(notifications, connections.clone(), hc, cmd_rx));
-    Nci { hal_events, commands, connections }
+    let dispatcher = Dispatcher::new(
+        notifications.clone(),
+        connections.clone(),
+        hc,
+        hal_error,
+        cmd_rx,
+        retry_policy,
+    );
+    Ok((Nci { hal_events, commands, connections, notifications }, dispatcher))
+}
+
+/// Errors returned by the initialization of the module
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitError {
+    /// The command queue must hold at least one command
+    InvalidQueueCapacity(usize),
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::InvalidQueueCapacity(capacity) => {
+                write!(f, "Invalid command queue capacity {}", capacity)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+fn check_cmd_queue_capacity(cmd_queue_capacity: usize) -> std::result::Result<(), InitError> {
+    if cmd_queue_capacity == 0 {
+        return Err(InitError::InvalidQueueCapacity(cmd_queue_capacity));
+    }
+    Ok(())
 }
 
 /// NCI module external interface
@@ -68,12 +163,25 @@ pub struct Nci {
     pub commands: CommandSender,
     /// NCI logical connections
     pub connections: LogicalConnectionsRegistry,
+    /// NCI notification handlers
+    notifications: EventRegistry,
+}
+
+impl Nci {
+    /// Observe all the NCI notifications received after this call, in
+    /// addition to the handlers registered for specific notifications.
+    /// A slow observer misses the oldest notifications, and is informed
+    /// of it by a `RecvError::Lagged` error.
+    pub fn notifications(&self) -> broadcast::Receiver<Notification> {
+        self.notifications.observers.subscribe()
+    }
 }
 
 #[derive(Debug)]
 struct PendingCommand {
     cmd: Command,
-    response: oneshot::Sender<Response>,
+    /// Receives the response along with the command latency
+    response: oneshot::Sender<Result<(Response, Duration)>>,
 }
 
 #[derive(Debug)]
@@ -85,6 +193,8 @@ struct QueuedCommand {
 /// Sends raw commands. Only useful for facades & shims, or wrapped as a CommandSender.
 pub struct CommandSender {
     cmd_tx: Sender<QueuedCommand>,
+    /// Number of commands that had to wait for room in the command queue
+    backpressure: Arc<AtomicUsize>,
 }
 
 /// The data returned by send_notify() method.
@@ -98,29 +208,57 @@ pub struct ResponsePendingNotification {
 impl CommandSender {
     /// Send a command, but do not expect notification to be returned
     pub async fn send(&mut self, cmd: Command) -> Result<Response> {
-        let (tx, rx) = oneshot::channel::<Response>();
-        self.cmd_tx
-            .send(QueuedCommand {
-                pending: PendingCommand { cmd, response: tx },
-                notification: None,
-            })
-            .await?;
-        let event = rx.await?;
+        let (event, _) = self.send_timed(cmd).await?;
         Ok(event)
     }
-    /// Send a command which expects notification as a result
+    /// Send a command, and return its response with the round-trip
+    /// latency: the time elapsed between the moment the command is first
+    /// sent to the HAL and the reception of its response. Time spent in
+    /// the command queue is not included, re-sent attempts are.
+    pub async fn send_timed(&mut self, cmd: Command) -> Result<(Response, Duration)> {
+        let (tx, rx) = oneshot::channel::<Result<(Response, Duration)>>();
+        self.enqueue(QueuedCommand {
+            pending: PendingCommand { cmd, response: tx },
+            notification: None,
+        })
+        .await?;
+        rx.await?
+    }
+    /// Send a command which expects notification as a result.
+    /// The notification may be received before the response, in which case
+    /// it is held by the pending notification receiver.
+    /// Dropping the returned future cancels the command if it has not been
+    /// sent to the NFCC yet; dropping the pending notification receiver
+    /// releases its registration with the dispatcher.
+    /// Fails with `EventError::AlreadyRegistered`, without sending the
+    /// command, while another command is waiting for the same notification.
     pub async fn send_and_notify(&mut self, cmd: Command) -> Result<ResponsePendingNotification> {
-        let (tx, rx) = oneshot::channel::<Response>();
+        let (tx, rx) = oneshot::channel::<Result<(Response, Duration)>>();
         let (ntx, nrx) = oneshot::channel::<Notification>();
-        self.cmd_tx
-            .send(QueuedCommand {
-                pending: PendingCommand { cmd, response: tx },
-                notification: Some(ntx),
-            })
-            .await?;
-        let event = rx.await?;
+        self.enqueue(QueuedCommand {
+            pending: PendingCommand { cmd, response: tx },
+            notification: Some(ntx),
+        })
+        .await?;
+        let (event, _) = rx.await??;
         Ok(ResponsePendingNotification { response: event, notification: nrx })
     }
+    /// Number of times a command had to wait for room in the command queue
+    pub fn backpressure_count(&self) -> usize {
+        self.backpressure.load(Ordering::Relaxed)
+    }
+    /// Queue a command, waiting for room in the queue if it is full
+    async fn enqueue(&mut self, queued: QueuedCommand) -> Result<()> {
+        match self.cmd_tx.try_send(queued) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(queued)) => {
+                self.backpressure.fetch_add(1, Ordering::Relaxed);
+                debug!("command queue is full, waiting for capacity");
+                Ok(self.cmd_tx.send(queued).await?)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
 }
 
 impl Drop for CommandSender {
@@ -134,6 +272,12 @@ struct ConnectionParameters {
     callback: Option<fn(u8, u16, &[u8])>,
     max_payload_size: u8,
     nfcc_credits_avail: u8,
+    /// Credits for the inbound packets consumed by the DH, returned to
+    /// the NFCC with the next outbound packet
+    dh_credits_pending: u8,
+    /// Receiver of the next complete inbound message, which takes
+    /// precedence over the callback
+    receiver: Option<oneshot::Sender<Bytes>>,
     sendq: VecDeque<DataPacket>,
     recvq: VecDeque<DataPacket>,
 }
@@ -143,8 +287,36 @@ impl ConnectionParameters {
     fn flush_tx(&mut self) {
         self.sendq.clear();
     }
+    /// Take the pending credits returned by the next outbound packet, at
+    /// most as many as fit in its 2-bit cr field. The remainder is left
+    /// for the following packets.
+    fn take_dh_credits(&mut self) -> u8 {
+        const MAX_CREDITS_PER_PACKET: u8 = 3;
+        let cr = self.dh_credits_pending.min(MAX_CREDITS_PER_PACKET);
+        self.dh_credits_pending -= cr;
+        cr
+    }
+}
+
+/// Errors returned by the logical connections registry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnError {
+    /// A logical connection with the same id is already open
+    AlreadyOpen(u8),
 }
 
+impl std::fmt::Display for ConnError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnError::AlreadyOpen(conn_id) => {
+                write!(f, "A logical connection with id {:?} already exists", conn_id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnError {}
+
 /// To keep track of currentry open logical connections
 #[derive(Clone)]
 pub struct LogicalConnectionsRegistry {
@@ -153,26 +325,35 @@ pub struct LogicalConnectionsRegistry {
 }
 
 impl LogicalConnectionsRegistry {
-    /// Create a logical connection
+    /// Create a logical connection, fails if a logical connection with
+    /// the same id is already open
     pub async fn open(
         &mut self,
         conn_id: u8,
         cb: Option<fn(u8, u16, &[u8])>,
         max_payload_size: u8,
         nfcc_credits_avail: u8,
-    ) {
+    ) -> std::result::Result<(), ConnError> {
         let conn_params = ConnectionParameters {
             callback: cb,
             max_payload_size,
             nfcc_credits_avail,
+            dh_credits_pending: 0,
+            receiver: None,
             sendq: VecDeque::<DataPacket>::new(),
             recvq: VecDeque::<DataPacket>::new(),
         };
-        assert!(
-            self.conns.write().await.insert(conn_id, Mutex::new(conn_params)).is_none(),
-            "A logical connection with id {:?} already exists",
-            conn_id
-        );
+        match self.conns.write().await.entry(conn_id) {
+            Entry::Occupied(_) => Err(ConnError::AlreadyOpen(conn_id)),
+            Entry::Vacant(entry) => {
+                entry.insert(Mutex::new(conn_params));
+                Ok(())
+            }
+        }
+    }
+    /// Check whether a logical connection is open
+    pub async fn is_open(&self, conn_id: u8) -> bool {
+        self.conns.read().await.contains_key(&conn_id)
     }
     /// Set static callback
     pub async fn set_static_callback(&mut self, conn_id: u8, cb: Option<fn(u8, u16, &[u8])>) {
@@ -192,6 +373,30 @@ impl LogicalConnectionsRegistry {
             None
         }
     }
+    /// Receive the next complete message on a logical connection, instead
+    /// of delivering it to the connection callback
+    pub async fn receive(&self, conn_id: u8) -> Option<oneshot::Receiver<Bytes>> {
+        let conns = self.conns.read().await;
+        let mut conn_params = conns.get(&conn_id)?.lock().unwrap();
+        let (tx, rx) = oneshot::channel();
+        conn_params.receiver = Some(tx);
+        Some(rx)
+    }
+    /// Number of packets waiting in the send and receive queues of a
+    /// logical connection, for diagnosing stalled flow control
+    pub async fn queue_depths(&self, conn_id: u8) -> Option<(usize, usize)> {
+        let conns = self.conns.read().await;
+        let conn_params = conns.get(&conn_id)?.lock().unwrap();
+        Some((conn_params.sendq.len(), conn_params.recvq.len()))
+    }
+    /// Fragments received on a logical connection and buffered until the
+    /// last fragment of the message arrives, left in the queue
+    #[cfg(test)]
+    pub async fn peek_recvq(&self, conn_id: u8) -> Option<Vec<DataPacket>> {
+        let conns = self.conns.read().await;
+        let conn_params = conns.get(&conn_id)?.lock().unwrap();
+        Some(conn_params.recvq.iter().cloned().collect())
+    }
     /// Add credits to a logical connection
     pub async fn add_credits(&self, conn_id: u8, ncreds: u8) {
         if let Some(conn_params) = self.conns.read().await.get(&conn_id) {
@@ -215,7 +420,7 @@ impl LogicalConnectionsRegistry {
                         let part = DataPacketBuilder {
                             conn_id,
                             pbf: PacketBoundaryFlag::Incomplete,
-                            cr: 0,
+                            cr: conn_params.take_dh_credits(),
                             payload: Some(p.split_to(conn_params.max_payload_size.into())),
                         }
                         .build();
@@ -225,12 +430,21 @@ impl LogicalConnectionsRegistry {
                         let end = DataPacketBuilder {
                             conn_id,
                             pbf: PacketBoundaryFlag::CompleteOrFinal,
-                            cr: 0,
+                            cr: conn_params.take_dh_credits(),
                             payload: Some(p),
                         }
                         .build();
                         conn_params.sendq.push_back(end);
                     }
+                } else if conn_params.dh_credits_pending > 0 {
+                    let pkt = DataPacketBuilder {
+                        conn_id: pkt.get_conn_id(),
+                        pbf: pkt.get_pbf(),
+                        cr: conn_params.take_dh_credits(),
+                        payload: Some(p),
+                    }
+                    .build();
+                    conn_params.sendq.push_back(pkt);
                 } else {
                     conn_params.sendq.push_back(pkt);
                 }
@@ -250,33 +464,59 @@ impl LogicalConnectionsRegistry {
             self.add_credits(conn_id, ncreds).await;
         }
         let done = pkt.get_pbf() == PacketBoundaryFlag::CompleteOrFinal;
-        if let Some(conn_params) = self.conns.read().await.get(&conn_id) {
-            let mut conn_params = conn_params.lock().unwrap();
-            if !done && conn_params.recvq.is_empty() {
-                const NFC_DATA_START_CEVT: u16 = 5;
-                let cb = conn_params.callback.unwrap();
-                cb(conn_id, NFC_DATA_START_CEVT, &[]);
+        let conns = self.conns.read().await;
+        let Some(conn_params) = conns.get(&conn_id) else {
+            error!("Dropping data received on unknown connection {}", conn_id);
+            return;
+        };
+        let mut conn_params = conn_params.lock().unwrap();
+        // Discard the fragments received before the upper layer registered
+        // a callback, rather than delivering a truncated message later on.
+        let cb = conn_params.callback;
+        if cb.is_none() && conn_params.receiver.is_none() {
+            error!("Dropping data received on connection {} without callback", conn_id);
+            conn_params.recvq.clear();
+            return;
+        }
+        conn_params.dh_credits_pending = conn_params.dh_credits_pending.saturating_add(1);
+        if let Some(cb) = cb.filter(|_| !done && conn_params.recvq.is_empty()) {
+            const NFC_DATA_START_CEVT: u16 = 5;
+            cb(conn_id, NFC_DATA_START_CEVT, &[]);
+        }
+        conn_params.recvq.push_back(pkt);
+        if !done {
+            return;
+        }
+        if let Some(receiver) = conn_params.receiver.take() {
+            let mut buffer = BytesMut::new();
+            for pkt in conn_params.recvq.drain(..) {
+                if let Payload(p) = pkt.specialize() {
+                    buffer.put(p);
+                }
             }
-            conn_params.recvq.push_back(pkt);
-            if done {
-                const NFC_DATA_CEVT_SIZE: usize = 4; // 3 for header and 1 for status
-                let cap = conn_params.recvq.len() * conn_params.max_payload_size as usize
-                    + NFC_DATA_CEVT_SIZE;
-                let mut buffer = BytesMut::with_capacity(cap);
-                buffer.put_u8(0u8); // status
+            if receiver.send(buffer.freeze()).is_err() {
+                debug!("Dropping data received on connection {} for a closed receiver", conn_id);
+            }
+        } else if let Some(cb) = cb {
+            const NFC_DATA_CEVT_SIZE: usize = 4; // 3 for header and 1 for status
+
+            // Size the buffer from the received fragments: the negotiated
+            // max_payload_size does not bound the actual fragment sizes.
+            let cap = conn_params.recvq.iter().map(|p| p.get_payload().len()).sum::<usize>()
+                + NFC_DATA_CEVT_SIZE;
+            let mut buffer = BytesMut::with_capacity(cap);
+            buffer.put_u8(0u8); // status
+            let pkt = conn_params.recvq.pop_front().unwrap();
+            buffer.put(pkt.to_bytes());
+            while !conn_params.recvq.is_empty() {
                 let pkt = conn_params.recvq.pop_front().unwrap();
-                buffer.put(pkt.to_bytes());
-                while !conn_params.recvq.is_empty() {
-                    let pkt = conn_params.recvq.pop_front().unwrap();
-                    if let Payload(p) = pkt.specialize() {
-                        buffer.put(p);
-                    }
+                if let Payload(p) = pkt.specialize() {
+                    buffer.put(p);
                 }
-                let data_cevt = buffer.freeze();
-                let cb = conn_params.callback.unwrap();
-                const NFC_DATA_CEVT: u16 = 3;
-                cb(conn_id, NFC_DATA_CEVT, data_cevt.as_ref());
             }
+            let data_cevt = buffer.freeze();
+            const NFC_DATA_CEVT: u16 = 3;
+            cb(conn_id, NFC_DATA_CEVT, data_cevt.as_ref());
         }
     }
 
@@ -295,16 +535,49 @@ impl LogicalConnectionsRegistry {
 #[derive(Clone)]
 pub struct EventRegistry {
     handlers: Arc<Mutex<HashMap<Opcode, oneshot::Sender<Notification>>>>,
+    observers: broadcast::Sender<Notification>,
 }
 
+/// Errors returned by the notification handlers registry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventError {
+    /// A handler for the same notification is already registered
+    AlreadyRegistered(Opcode),
+}
+
+impl std::fmt::Display for EventError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventError::AlreadyRegistered(code) => {
+                write!(f, "A handler for {:?} is already registered", code)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EventError {}
+
 impl EventRegistry {
-    /// Indicate interest in specific NCI notification
-    pub async fn register(&mut self, code: Opcode, sender: oneshot::Sender<Notification>) {
-        assert!(
-            self.handlers.lock().unwrap().insert(code, sender).is_none(),
-            "A handler for {:?} is already registered",
-            code
-        );
+    /// Indicate interest in specific NCI notification.
+    /// A handler whose receiver was dropped is replaced, fails if another
+    /// handler is still waiting for the notification.
+    pub async fn register(
+        &mut self,
+        code: Opcode,
+        sender: oneshot::Sender<Notification>,
+    ) -> std::result::Result<(), EventError> {
+        let mut handlers = self.handlers.lock().unwrap();
+        if handlers.get(&code).is_some_and(|handler| handler.is_closed()) {
+            debug!("Replacing cancelled handler for {:?}", code);
+            handlers.remove(&code);
+        }
+        match handlers.entry(code) {
+            Entry::Occupied(_) => Err(EventError::AlreadyRegistered(code)),
+            Entry::Vacant(entry) => {
+                entry.insert(sender);
+                Ok(())
+            }
+        }
     }
 
     /// Remove interest in specific NCI notification
@@ -313,32 +586,96 @@ impl EventRegistry {
     }
 }
 
-async fn dispatch(
-    mut ntfs: EventRegistry,
+/// Routes the commands queued by the CommandSender to the HAL, and the
+/// messages received from the HAL to the pending command, the registered
+/// notification handlers and the logical connections.
+pub struct Dispatcher {
+    ntfs: EventRegistry,
     lcons: LogicalConnectionsRegistry,
-    mut hc: Hal,
+    hc: Hal,
+    /// Receives the status of the HAL Error event
+    hal_error: oneshot::Receiver<HalEventStatus>,
+    /// Set once the HAL Error event is received or its handler is gone
+    hal_error_done: bool,
+    /// Set once the HAL reported a transport error, after which
+    /// commands can no longer be exchanged with the NFCC
+    transport_failed: bool,
     //    ic: InternalChannels,
-    mut cmd_rx: Receiver<QueuedCommand>,
-) -> Result<()> {
-    let mut pending: Option<PendingCommand> = None;
-    let timeout = sleep(Duration::MAX);
-    // The max_deadline is used to set  the sleep() deadline to a very distant moment in
-    // the future, when the notification from the timer is not required.
-    let max_deadline = timeout.deadline();
-    tokio::pin!(timeout);
-    loop {
+    cmd_rx: Receiver<QueuedCommand>,
+    retry_policy: RetryPolicy,
+    pending: Option<PendingCommand>,
+    /// Number of times the pending command was sent
+    attempts: usize,
+    /// Time at which the pending command was first sent
+    sent_at: Instant,
+    /// Opcodes of the re-sent commands, for which a late response to an
+    /// earlier attempt may still be received, with the deadline after
+    /// which such a response is no longer expected
+    stale_responses: Vec<(Opcode, Instant)>,
+    timeout: Pin<Box<Sleep>>,
+    /// The max_deadline is used to set  the sleep() deadline to a very distant moment in
+    /// the future, when the notification from the timer is not required.
+    max_deadline: Instant,
+}
+
+impl Dispatcher {
+    fn new(
+        ntfs: EventRegistry,
+        lcons: LogicalConnectionsRegistry,
+        hc: Hal,
+        hal_error: oneshot::Receiver<HalEventStatus>,
+        cmd_rx: Receiver<QueuedCommand>,
+        retry_policy: RetryPolicy,
+    ) -> Self {
+        let timeout = Box::pin(sleep(Duration::MAX));
+        let max_deadline = timeout.deadline();
+        Dispatcher {
+            ntfs,
+            lcons,
+            hc,
+            hal_error,
+            hal_error_done: false,
+            transport_failed: false,
+            cmd_rx,
+            retry_policy,
+            pending: None,
+            attempts: 0,
+            sent_at: Instant::now(),
+            stale_responses: vec![],
+            timeout,
+            max_deadline,
+        }
+    }
+
+    /// Dispatch events until all the channels are closed
+    pub async fn run(mut self) {
+        while self.step().await {}
+        debug!("NCI dispatch is terminated.");
+    }
+
+    /// Wait for the next event and dispatch it. Returns false once the
+    /// channels are closed and no more events can be dispatched.
+    pub async fn step(&mut self) -> bool {
+        let hal_error_pending = !self.hal_error_done;
         select! {
-            Some(cmd) = hc.in_cmd_rx.recv() => {
+            Some(cmd) = self.hc.in_cmd_rx.recv() => {
                 match cmd.specialize() {
                     NciPacketChild::Response(rsp) => {
-                        timeout.as_mut().reset(max_deadline);
                         let this_opcode = rsp.get_cmd_op();
-                        match pending.take() {
+                        let now = Instant::now();
+                        self.stale_responses.retain(|(_, deadline)| *deadline > now);
+                        match self.pending.take() {
                             Some(PendingCommand{cmd, response}) if cmd.get_op() == this_opcode => {
-                                if let Err(e) = response.send(rsp) {
+                                self.timeout.as_mut().reset(self.max_deadline);
+                                if let Err(e) = response.send(Ok((rsp, self.sent_at.elapsed()))) {
                                     error!("failure dispatching command status {:?}", e);
                                 }
                             },
+                            other if self.stale_responses.iter().any(|(opcode, _)| *opcode == this_opcode) => {
+                                debug!("dropping late response {:?} to a re-sent command", this_opcode);
+                                self.stale_responses.retain(|(opcode, _)| *opcode != this_opcode);
+                                self.pending = other;
+                            },
                             Some(PendingCommand{cmd, ..}) => panic!("Waiting for {:?}, got {:?}", cmd.get_op(), this_opcode),
                             None => panic!("Unexpected status event with opcode {:?}", this_opcode),
                         }
@@ -348,17 +685,22 @@ async fn dispatch(
                             ConnCreditsNotification(ccnp) => {
                                 let conns = ccnp.get_conns();
                                 for conn in conns {
-                                    lcons.add_credits(conn.conn_id, conn.ncredits).await;
+                                    self.lcons.add_credits(conn.conn_id, conn.ncredits).await;
                                 }
                             },
                             _ => {
                                 let code = ntfy.get_cmd_op();
-                                match ntfs.unregister(code).await {
+                                let observed = self.ntfs.observers.send(ntfy.clone()).is_ok();
+                                match self.ntfs.unregister(code).await {
+                                    Some(sender) if sender.is_closed() => {
+                                        debug!("dropping notification {:?} of a cancelled command", code);
+                                    },
                                     Some(sender) => {
                                         if let Err(e) = sender.send(ntfy) {
                                             error!("notification channel closed {:?}", e);
                                         }
                                     },
+                                    None if observed => (),
                                     None => panic!("Unhandled notification {:?}", code),
                                 }
                             },
@@ -367,31 +709,617 @@ async fn dispatch(
                     _ => error!("Unexpected NCI data received {:?}", cmd),
                 }
             },
-            qc = cmd_rx.recv(), if pending.is_none() => if let Some(queued) = qc {
+            qc = self.cmd_rx.recv(), if self.pending.is_none() => if let Some(queued) = qc {
                 debug!("cmd_rx got a q");
+                if queued.pending.response.is_closed() {
+                    debug!("dropping cancelled command {:?}", queued.pending.cmd.get_op());
+                    return true;
+                }
+                if self.transport_failed {
+                    debug!("failing command {:?} after a transport error", queued.pending.cmd.get_op());
+                    let _ = queued.pending.response.send(Err(HalError::TransportError.into()));
+                    return true;
+                }
+                // Register the notification handler before sending the command:
+                // the NFCC may send the notification ahead of the response.
+                // A command expecting the same notification as a command
+                // still waiting for it is failed without being sent.
                 if let Some(nsender) = queued.notification {
-                    ntfs.register(queued.pending.cmd.get_op(), nsender).await;
+                    if let Err(e) = self.ntfs.register(queued.pending.cmd.get_op(), nsender).await {
+                        error!("failing command {:?}: {}", queued.pending.cmd.get_op(), e);
+                        let _ = queued.pending.response.send(Err(e.into()));
+                        return true;
+                    }
                 }
-                if let Err(e) = hc.out_cmd_tx.send(queued.pending.cmd.clone().into()) {
+                if let Err(e) = self.hc.out_cmd_tx.send(queued.pending.cmd.clone().into()) {
                     error!("command queue closed: {:?}", e);
                 }
-                timeout.as_mut().reset(Instant::now() + Duration::from_millis(20));
-                pending = Some(queued.pending);
+                self.sent_at = Instant::now();
+                self.timeout.as_mut().reset(self.sent_at + self.retry_policy.timeout);
+                self.attempts = 1;
+                self.pending = Some(queued.pending);
             } else {
-                break;
+                return false;
             },
-            () = &mut timeout => {
-                error!("Command processing timeout");
-                timeout.as_mut().reset(max_deadline);
-                pending = None;
+            () = &mut self.timeout => {
+                match &self.pending {
+                    Some(PendingCommand{cmd, ..}) if self.attempts < self.retry_policy.max_attempts => {
+                        // The notification handler stays registered
+                        // for the next attempt.
+                        self.attempts += 1;
+                        debug!("re-sending {:?}, attempt {}", cmd.get_op(), self.attempts);
+                        // A response to an earlier attempt arriving after
+                        // all the attempts would have timed out is no
+                        // longer expected.
+                        let deadline = Instant::now()
+                            + self.retry_policy.timeout * self.retry_policy.max_attempts as u32;
+                        self.stale_responses.retain(|(opcode, _)| *opcode != cmd.get_op());
+                        self.stale_responses.push((cmd.get_op(), deadline));
+                        if let Err(e) = self.hc.out_cmd_tx.send(cmd.clone().into()) {
+                            error!("command queue closed: {:?}", e);
+                        }
+                        self.timeout.as_mut().reset(Instant::now() + self.retry_policy.timeout);
+                    },
+                    _ => {
+                        error!("Command processing timeout");
+                        self.timeout.as_mut().reset(self.max_deadline);
+                        if let Some(PendingCommand{cmd, ..}) = self.pending.take() {
+                            self.ntfs.unregister(cmd.get_op()).await;
+                        }
+                    },
+                }
             },
-            Some(data) = hc.in_data_rx.recv() => lcons.send_callback(data).await,
+            status = &mut self.hal_error, if hal_error_pending => {
+                self.hal_error_done = true;
+                if let Ok(status) = status {
+                    error!("HAL error {:?}, failing the NCI commands", status);
+                    self.transport_failed = true;
+                    self.timeout.as_mut().reset(self.max_deadline);
+                    if let Some(PendingCommand{cmd, response}) = self.pending.take() {
+                        self.ntfs.unregister(cmd.get_op()).await;
+                        let _ = response.send(Err(HalError::TransportError.into()));
+                    }
+                }
+            },
+            Some(data) = self.hc.in_data_rx.recv() => self.lcons.send_callback(data).await,
             else => {
                 debug!("Select is done");
-                break;
+                return false;
             },
         }
+        true
+    }
+}
+
+/// Fake HAL connecting the NCI module to an NFCC emulated by the tests.
+#[cfg(test)]
+pub mod test_support {
+    use super::*;
+    use nfc_hal::HalEventRegistry;
+    use nfc_packets::nci::{
+        Command, ConfigStatus, NciPacket, NciVersion, ResetNotificationBuilder,
+        ResetResponseBuilder, ResetTrigger, Status,
+    };
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    /// CORE_INIT_RSP of an NFCC supporting one dynamic logical connection,
+    /// 255-byte payloads and five RF interfaces.
+    pub const INIT_RESPONSE: &[u8] = &[
+        0x40, 0x01, 0x18, 0x00, 0x1a, 0x7e, 0x06, 0x00, 0x01, 0x00, 0x04, 0xff, 0xff, 0x00, 0x0c,
+        0x01, 0x05, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x00, 0x00, 0x90, 0x00,
+    ];
+
+    /// NFCC end of the channels of a fake HAL
+    pub struct FakeNfcc {
+        pub out_cmd_rx: UnboundedReceiver<NciPacket>,
+        pub in_cmd_tx: UnboundedSender<NciPacket>,
+        pub out_data_rx: UnboundedReceiver<DataPacket>,
+        pub in_data_tx: UnboundedSender<DataPacket>,
+    }
+
+    impl FakeNfcc {
+        /// Wait for the next command sent to the NFCC
+        pub async fn command(&mut self) -> Command {
+            match self.out_cmd_rx.recv().await.expect("HAL is closed").specialize() {
+                NciPacketChild::Command(cmd) => cmd,
+                packet => panic!("unexpected packet {:?}", packet),
+            }
+        }
+
+        /// Send a response or notification to the DH
+        pub fn send(&self, packet: impl Into<NciPacket>) {
+            self.in_cmd_tx.send(packet.into()).unwrap();
+        }
+
+        /// Answer the reset and initialization sequence of the DH
+        pub async fn serve_init(&mut self) {
+            let pbf = PacketBoundaryFlag::CompleteOrFinal;
+            assert_eq!(self.command().await.get_op(), Opcode::CoreReset);
+            self.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            self.send(
+                ResetNotificationBuilder {
+                    gid: 0,
+                    pbf,
+                    trigger: ResetTrigger::ResetCommand,
+                    config_status: ConfigStatus::ConfigReset,
+                    nci_version: NciVersion::Version20,
+                    manufacturer_id: 0,
+                    mfsi: vec![],
+                }
+                .build(),
+            );
+            assert_eq!(self.command().await.get_op(), Opcode::CoreInit);
+            self.send(NciPacket::parse(INIT_RESPONSE).unwrap());
+        }
+    }
+
+    /// Create a fake HAL, returning the NFCC end of its channels
+    pub fn fake_hal() -> (Hal, FakeNfcc) {
+        let (out_cmd_tx, out_cmd_rx) = unbounded_channel();
+        let (in_cmd_tx, in_cmd_rx) = unbounded_channel();
+        let (out_data_tx, out_data_rx) = unbounded_channel();
+        let (in_data_tx, in_data_rx) = unbounded_channel();
+        let hal = Hal {
+            hal_events: HalEventRegistry::default(),
+            out_cmd_tx,
+            in_cmd_rx,
+            out_data_tx,
+            in_data_rx,
+            parse_errors: Arc::new(AtomicUsize::new(0)),
+        };
+        (hal, FakeNfcc { out_cmd_rx, in_cmd_tx, out_data_rx, in_data_tx })
+    }
+
+    /// Connect the NCI module to a fake HAL, returning the NFCC end of
+    /// its channels
+    pub async fn init_fake(retry_policy: RetryPolicy) -> (Nci, Dispatcher, FakeNfcc) {
+        let (hal, nfcc) = fake_hal();
+        let (nci, dispatcher) =
+            init_with_hal(hal, DEFAULT_CMD_QUEUE_CAPACITY, retry_policy).await.unwrap();
+        (nci, dispatcher, nfcc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_support::{fake_hal, init_fake};
+    use super::*;
+    use nfc_packets::nci::{
+        ConfigStatus, NciVersion, ResetCommandBuilder, ResetNotificationBuilder,
+        ResetResponseBuilder, ResetTrigger, ResetType, Status,
+    };
+
+    fn data(conn_id: u8, pbf: PacketBoundaryFlag, cr: u8, payload: &[u8]) -> DataPacket {
+        DataPacketBuilder { conn_id, pbf, cr, payload: Some(Bytes::copy_from_slice(payload)) }
+            .build()
+    }
+
+    fn reset() -> Command {
+        ResetCommandBuilder {
+            gid: 0,
+            pbf: PacketBoundaryFlag::CompleteOrFinal,
+            reset_type: ResetType::KeepConfig,
+        }
+        .build()
+        .into()
+    }
+
+    #[tokio::test]
+    async fn opening_an_open_connection_fails() {
+        let (mut nci, _dispatcher, _nfcc) = init_fake(Default::default()).await;
+        nci.connections.open(2, None, 4, 1).await.unwrap();
+        assert_eq!(nci.connections.open(2, None, 8, 2).await, Err(ConnError::AlreadyOpen(2)));
+        assert!(nci.connections.is_open(2).await);
+    }
+
+    #[tokio::test]
+    async fn reassembles_fragments_larger_than_max_payload_size() {
+        static EVENTS: Mutex<Vec<(u16, Vec<u8>)>> = Mutex::new(vec![]);
+        fn callback(_conn_id: u8, event: u16, data: &[u8]) {
+            EVENTS.lock().unwrap().push((event, data.to_vec()));
+        }
+
+        let (mut nci, mut dispatcher, nfcc) = init_fake(Default::default()).await;
+        nci.connections.open(2, Some(callback), 4, 1).await.unwrap();
+        let first = data(2, PacketBoundaryFlag::Incomplete, 0, &[1; 10]);
+        nfcc.in_data_tx.send(first.clone()).unwrap();
+        nfcc.in_data_tx.send(data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[2; 10])).unwrap();
+        assert!(dispatcher.step().await);
+        assert!(dispatcher.step().await);
+
+        let mut expected = vec![0];
+        expected.extend(first.to_vec());
+        expected.extend([2; 10]);
+        assert_eq!(*EVENTS.lock().unwrap(), vec![(5, vec![]), (3, expected)]);
+    }
+
+    #[tokio::test]
+    async fn reassembles_interleaved_fragments_per_connection() {
+        static EVENTS: Mutex<Vec<(u8, u16, Vec<u8>)>> = Mutex::new(vec![]);
+        fn callback(conn_id: u8, event: u16, data: &[u8]) {
+            EVENTS.lock().unwrap().push((conn_id, event, data.to_vec()));
+        }
+
+        let (mut nci, mut dispatcher, nfcc) = init_fake(Default::default()).await;
+        nci.connections.open(2, Some(callback), 255, 1).await.unwrap();
+        nci.connections.open(3, Some(callback), 255, 1).await.unwrap();
+        nci.connections.open(4, None, 255, 1).await.unwrap();
+        let first = [
+            data(2, PacketBoundaryFlag::Incomplete, 0, &[1]),
+            data(3, PacketBoundaryFlag::Incomplete, 0, &[3]),
+        ];
+        for pkt in [
+            first[0].clone(),
+            first[1].clone(),
+            // Fragments of connections without a callback, or of unknown
+            // connections, are dropped without disturbing the others.
+            data(4, PacketBoundaryFlag::CompleteOrFinal, 0, &[9]),
+            data(5, PacketBoundaryFlag::CompleteOrFinal, 0, &[9]),
+            data(3, PacketBoundaryFlag::CompleteOrFinal, 0, &[4]),
+            data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[2]),
+        ] {
+            nfcc.in_data_tx.send(pkt).unwrap();
+            assert!(dispatcher.step().await);
+        }
+
+        let message = |first: &DataPacket, last: u8| {
+            let mut message = vec![0];
+            message.extend(first.clone().to_vec());
+            message.push(last);
+            message
+        };
+        assert_eq!(
+            *EVENTS.lock().unwrap(),
+            vec![
+                (2, 5, vec![]),
+                (3, 5, vec![]),
+                (3, 3, message(&first[1], 4)),
+                (2, 3, message(&first[0], 2)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_empty_command_queue() {
+        let (hal, _nfcc) = fake_hal();
+        assert_eq!(
+            init_with_hal(hal, 0, Default::default()).await.err(),
+            Some(InitError::InvalidQueueCapacity(0))
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_commands_waiting_for_queue_capacity() {
+        let (hal, _nfcc) = fake_hal();
+        let (mut nci, _dispatcher) = init_with_hal(hal, 1, Default::default()).await.unwrap();
+
+        // The dispatcher is not running: the first command fills the
+        // queue, and the second one has to wait for room.
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, nci.commands.send(reset())).await.is_err());
+        assert_eq!(nci.commands.backpressure_count(), 0);
+        assert!(tokio::time::timeout(wait, nci.commands.send(reset())).await.is_err());
+        assert_eq!(nci.commands.backpressure_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn returns_consumed_credits_three_at_a_time() {
+        fn callback(_conn_id: u8, _event: u16, _data: &[u8]) {}
+
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        nci.connections.open(2, Some(callback), 255, 3).await.unwrap();
+        for _ in 0..5 {
+            nfcc.in_data_tx.send(data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[1])).unwrap();
+            assert!(dispatcher.step().await);
+        }
+
+        // Five consumed packets do not fit in the 2-bit cr field: the
+        // first outbound packet returns three credits, the next one the
+        // remaining two.
+        for expected in [3, 2, 0] {
+            let pkt = data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[2]);
+            nci.connections.send_packet(2, pkt).await;
+            let sent = nfcc.out_data_rx.recv().await.unwrap();
+            assert_eq!(sent.get_cr(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn cancelled_commands_release_their_notification_handler() {
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+
+        // A command cancelled while queued is never sent to the NFCC.
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, nci.commands.send_and_notify(reset())).await.is_err());
+        assert!(dispatcher.step().await);
+        assert!(nfcc.out_cmd_rx.try_recv().is_err());
+        assert!(dispatcher.ntfs.handlers.lock().unwrap().is_empty());
+
+        // The notification of a command whose notification receiver was
+        // dropped is discarded, instead of being reported as unhandled.
+        let (pending, ()) = tokio::join!(nci.commands.send_and_notify(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+        });
+        assert!(dispatcher.ntfs.handlers.lock().unwrap().contains_key(&Opcode::CoreReset));
+        drop(pending.unwrap().notification);
+        nfcc.send(
+            ResetNotificationBuilder {
+                gid: 0,
+                pbf,
+                trigger: ResetTrigger::ResetCommand,
+                config_status: ConfigStatus::ConfigKept,
+                nci_version: NciVersion::Version20,
+                manufacturer_id: 0,
+                mfsi: vec![],
+            }
+            .build(),
+        );
+        assert!(dispatcher.step().await);
+        assert!(dispatcher.ntfs.handlers.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn second_notification_handler_is_rejected() {
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+
+        let (first, _first_rx) = oneshot::channel();
+        let (second, _second_rx) = oneshot::channel();
+        nci.notifications.register(Opcode::CoreReset, first).await.unwrap();
+        assert_eq!(
+            nci.notifications.register(Opcode::CoreReset, second).await,
+            Err(EventError::AlreadyRegistered(Opcode::CoreReset))
+        );
+        nci.notifications.unregister(Opcode::CoreReset).await;
+
+        // A command expecting the notification still awaited by a previous
+        // command fails without being sent, the first handler is kept.
+        let (pending, ()) = tokio::join!(nci.commands.send_and_notify(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+        });
+        let pending = pending.unwrap();
+        let (second, ()) = tokio::join!(nci.commands.send_and_notify(reset()), async {
+            assert!(dispatcher.step().await);
+        });
+        let error = second.err().unwrap();
+        assert_eq!(
+            error.downcast_ref::<EventError>(),
+            Some(&EventError::AlreadyRegistered(Opcode::CoreReset))
+        );
+        assert!(nfcc.out_cmd_rx.try_recv().is_err());
+        assert!(dispatcher.ntfs.handlers.lock().unwrap().contains_key(&Opcode::CoreReset));
+        drop(pending);
+    }
+
+    #[tokio::test]
+    async fn observers_receive_unsolicited_notifications() {
+        let (nci, mut dispatcher, nfcc) = init_fake(Default::default()).await;
+        let mut observer = nci.notifications();
+        let notification: Notification = ResetNotificationBuilder {
+            gid: 0,
+            pbf: PacketBoundaryFlag::CompleteOrFinal,
+            trigger: ResetTrigger::UnrecoverableError,
+            config_status: ConfigStatus::ConfigKept,
+            nci_version: NciVersion::Version20,
+            manufacturer_id: 0,
+            mfsi: vec![],
+        }
+        .build()
+        .into();
+
+        // No handler is registered: the notification is only observed.
+        nfcc.send(notification.clone());
+        assert!(dispatcher.step().await);
+        assert_eq!(observer.try_recv().unwrap(), notification);
+    }
+
+    #[tokio::test]
+    async fn notification_received_before_response_is_delivered() {
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        let notification: Notification = ResetNotificationBuilder {
+            gid: 0,
+            pbf,
+            trigger: ResetTrigger::ResetCommand,
+            config_status: ConfigStatus::ConfigKept,
+            nci_version: NciVersion::Version20,
+            manufacturer_id: 0,
+            mfsi: vec![],
+        }
+        .build()
+        .into();
+
+        let (pending, ()) = tokio::join!(nci.commands.send_and_notify(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            nfcc.send(notification.clone());
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+            assert!(dispatcher.step().await);
+        });
+        let pending = pending.unwrap();
+        assert_eq!(pending.response.get_cmd_op(), Opcode::CoreReset);
+        assert_eq!(pending.notification.await.unwrap(), notification);
+    }
+
+    #[tokio::test]
+    async fn queue_depths_report_packets_waiting_for_credits() {
+        fn callback(_conn_id: u8, _event: u16, _data: &[u8]) {}
+
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        assert_eq!(nci.connections.queue_depths(2).await, None);
+        nci.connections.open(2, Some(callback), 255, 0).await.unwrap();
+        for depth in 1..=3 {
+            let pkt = data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[depth as u8]);
+            nci.connections.send_packet(2, pkt).await;
+            assert_eq!(nci.connections.queue_depths(2).await, Some((depth, 0)));
+        }
+        assert!(nfcc.out_data_rx.try_recv().is_err());
+
+        nci.connections.add_credits(2, 2).await;
+        assert_eq!(nci.connections.queue_depths(2).await, Some((1, 0)));
+        assert_eq!(nfcc.out_data_rx.recv().await.unwrap().get_payload(), &[1]);
+        assert_eq!(nfcc.out_data_rx.recv().await.unwrap().get_payload(), &[2]);
+
+        // Fragments are held in the receive queue until the last one.
+        nfcc.in_data_tx.send(data(2, PacketBoundaryFlag::Incomplete, 0, &[4])).unwrap();
+        assert!(dispatcher.step().await);
+        assert_eq!(nci.connections.queue_depths(2).await, Some((1, 1)));
+    }
+
+    #[tokio::test]
+    async fn resends_commands_left_without_response() {
+        let retry_policy = RetryPolicy { max_attempts: 2, timeout: Duration::from_millis(10) };
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(retry_policy).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+
+        // The response to the first attempt is lost.
+        let (response, ()) = tokio::join!(nci.commands.send(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+        });
+        assert_eq!(response.unwrap().get_cmd_op(), Opcode::CoreReset);
+
+        // A late response to the first attempt is dropped.
+        nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+        assert!(dispatcher.step().await);
+        assert!(dispatcher.stale_responses.is_empty());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Unexpected status event with opcode CoreReset")]
+    async fn late_responses_to_resent_commands_expire() {
+        let retry_policy = RetryPolicy { max_attempts: 2, timeout: Duration::from_millis(10) };
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(retry_policy).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+
+        let (response, ()) = tokio::join!(nci.commands.send(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+        });
+        response.unwrap();
+        assert_eq!(dispatcher.stale_responses.len(), 1);
+
+        // The response to the first attempt comes after all the attempts
+        // would have timed out.
+        tokio::time::sleep(retry_policy.timeout * 2).await;
+        nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+        dispatcher.step().await;
+    }
+
+    #[tokio::test]
+    async fn step_matches_responses_to_the_pending_command() {
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+
+        let (response, ()) = tokio::join!(nci.commands.send(reset()), async {
+            // The command is sent to the NFCC when the dispatcher steps.
+            assert!(nfcc.out_cmd_rx.try_recv().is_err());
+            assert!(dispatcher.step().await);
+            assert!(nfcc.out_cmd_rx.try_recv().is_ok());
+            assert!(dispatcher.pending.is_some());
+
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+            assert!(dispatcher.pending.is_none());
+        });
+        assert_eq!(response.unwrap().get_cmd_op(), Opcode::CoreReset);
+
+        // The dispatcher stops once the command queue is closed.
+        drop(nci);
+        assert!(!dispatcher.step().await);
+    }
+
+    #[tokio::test]
+    async fn send_timed_measures_the_response_latency() {
+        let retry_policy = RetryPolicy { max_attempts: 1, timeout: Duration::from_secs(1) };
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(retry_policy).await;
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        let delay = Duration::from_millis(50);
+
+        let (response, ()) = tokio::join!(nci.commands.send_timed(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+            // The NFCC takes some time to answer.
+            sleep(delay).await;
+            nfcc.send(ResetResponseBuilder { gid: 0, pbf, status: Status::Ok }.build());
+            assert!(dispatcher.step().await);
+        });
+        let (response, latency) = response.unwrap();
+        assert_eq!(response.get_cmd_op(), Opcode::CoreReset);
+        assert!(delay <= latency && latency < retry_policy.timeout, "{:?}", latency);
+    }
+
+    #[tokio::test]
+    async fn transport_error_fails_pending_and_queued_commands() {
+        let (mut nci, mut dispatcher, mut nfcc) = init_fake(Default::default()).await;
+        let is_transport_error = |result: Result<Response>| {
+            matches!(result.unwrap_err().downcast_ref(), Some(HalError::TransportError))
+        };
+
+        let mut hal_events = nci.hal_events.clone();
+        let (response, ()) = tokio::join!(nci.commands.send(reset()), async {
+            assert!(dispatcher.step().await);
+            assert_eq!(nfcc.command().await.get_op(), Opcode::CoreReset);
+
+            // The HAL reports the transport error while the command waits
+            // for its response.
+            hal_events.notify(HalEvent::Error, HalEventStatus::TransportError).await;
+            assert!(dispatcher.step().await);
+            assert!(dispatcher.pending.is_none());
+        });
+        assert!(is_transport_error(response));
+
+        // The next commands fail without being sent to the NFCC.
+        let (response, ()) = tokio::join!(nci.commands.send(reset()), async {
+            assert!(dispatcher.step().await);
+        });
+        assert!(is_transport_error(response));
+        assert!(nfcc.out_cmd_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn buffers_fragments_until_the_last_one() {
+        static EVENTS: Mutex<Vec<(u16, Vec<u8>)>> = Mutex::new(vec![]);
+        fn callback(_conn_id: u8, event: u16, data: &[u8]) {
+            EVENTS.lock().unwrap().push((event, data.to_vec()));
+        }
+
+        let (mut nci, mut dispatcher, nfcc) = init_fake(Default::default()).await;
+        nci.connections.open(2, Some(callback), 255, 1).await.unwrap();
+        assert_eq!(nci.connections.peek_recvq(2).await, Some(vec![]));
+        assert_eq!(nci.connections.peek_recvq(3).await, None);
+
+        let fragments = [
+            data(2, PacketBoundaryFlag::Incomplete, 0, &[1, 2]),
+            data(2, PacketBoundaryFlag::Incomplete, 0, &[3]),
+        ];
+        for (count, fragment) in fragments.iter().enumerate() {
+            nfcc.in_data_tx.send(fragment.clone()).unwrap();
+            assert!(dispatcher.step().await);
+            assert_eq!(nci.connections.peek_recvq(2).await.unwrap(), fragments[..=count]);
+        }
+        assert_eq!(*EVENTS.lock().unwrap(), vec![(5, vec![])]);
+
+        // The last fragment delivers the message and empties the queue.
+        nfcc.in_data_tx.send(data(2, PacketBoundaryFlag::CompleteOrFinal, 0, &[4])).unwrap();
+        assert!(dispatcher.step().await);
+        assert_eq!(nci.connections.peek_recvq(2).await, Some(vec![]));
+        assert_eq!(EVENTS.lock().unwrap().len(), 2);
     }
-    debug!("NCI dispatch is terminated.");
-    Ok(())
 }
@@ -6,4 +6,69 @@
 
 pub mod nci {
     include!(concat!(env!("OUT_DIR"), "/nci_packets.rs"));
+
+    impl DestParam {
+        /// Destination parameter addressing a remote NFC endpoint by its
+        /// RF Discovery ID. Returns None for the RFU IDs 0x00 and 0xff.
+        pub fn rf_discovery(id: u8, protocol: RfProtocols) -> Option<Self> {
+            match id {
+                0x01..=0xfe => Some(DestParam {
+                    ptype: DestParamTypes::RfDisc,
+                    parameter: vec![id, protocol.into()],
+                }),
+                _ => None,
+            }
+        }
+
+        /// Destination parameter addressing an NFCEE by its NFCEE ID.
+        /// Returns None for the DH-NFCEE ID 0x00 and the RFU ID 0xff.
+        pub fn nfcee(id: u8, protocol: NfceeProtocols) -> Option<Self> {
+            match id {
+                0x01..=0xfe => Some(DestParam {
+                    ptype: DestParamTypes::Nfcee,
+                    parameter: vec![id, protocol.into()],
+                }),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nci::*;
+    use pdl_runtime::Packet;
+
+    /// Encode a CORE_CONN_CREATE_CMD with a single destination parameter.
+    fn conn_create(dt: DestTypes, destparam: DestParam) -> Vec<u8> {
+        let pbf = PacketBoundaryFlag::CompleteOrFinal;
+        let destparams = vec![destparam];
+        let cmd: NciPacket =
+            ConnCreateCommandBuilder { gid: 0, pbf, dt, destparams }.build().into();
+        cmd.to_vec()
+    }
+
+    #[test]
+    fn rf_discovery_dest_param_is_encoded() {
+        let destparam = DestParam::rf_discovery(0x01, RfProtocols::ProtocolIsoDep).unwrap();
+        assert_eq!(
+            conn_create(DestTypes::Remote, destparam),
+            [0x20, 0x04, 0x06, 0x02, 0x01, 0x00, 0x02, 0x01, 0x04]
+        );
+        assert!(DestParam::rf_discovery(0xfe, RfProtocols::ProtocolT2t).is_some());
+        assert!(DestParam::rf_discovery(0x00, RfProtocols::ProtocolT2t).is_none());
+        assert!(DestParam::rf_discovery(0xff, RfProtocols::ProtocolT2t).is_none());
+    }
+
+    #[test]
+    fn nfcee_dest_param_is_encoded() {
+        let destparam = DestParam::nfcee(0x86, NfceeProtocols::Apdu).unwrap();
+        assert_eq!(
+            conn_create(DestTypes::Nfcee, destparam),
+            [0x20, 0x04, 0x06, 0x03, 0x01, 0x01, 0x02, 0x86, 0x00]
+        );
+        assert!(DestParam::nfcee(0x01, NfceeProtocols::Transparent).is_some());
+        assert!(DestParam::nfcee(0x00, NfceeProtocols::Apdu).is_none());
+        assert!(DestParam::nfcee(0xff, NfceeProtocols::Apdu).is_none());
+    }
 }
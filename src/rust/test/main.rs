@@ -35,7 +35,7 @@ async fn main() -> Result<()> {
     logger::init(Config::default().with_tag_on_device("lnfc").with_max_level(LevelFilter::Trace));
 
     let mut nci = NciApi::new();
-    nci.nfc_enable(nfc_callback).await;
+    nci.nfc_enable(nfc_callback).await?;
     nci.nfc_init().await?;
     let lmrts = nci.nfc_get_lmrt_size().await;
     debug!("LMRT size:{}", lmrts);
@@ -44,7 +44,7 @@ async fn main() -> Result<()> {
     let status = nci.nfc_get_config(&get_tlvs).await?;
     debug!("GET_CONFIG status:{}", status);
     nci.nfc_disable().await;
-    nci.nfc_enable(nfc_callback).await;
+    nci.nfc_enable(nfc_callback).await?;
     nci.nfc_init().await?;
     let status = nci.nfc_get_config(&get_tlvs).await?;
     debug!("GET_CONFIG status:{}", status);
@@ -0,0 +1,57 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fuzz the NCI command dispatch of the casimir controller.
+//! The input is read by the controller as the byte stream of its NCI
+//! transport. Malformed packets must stop the controller with an error,
+//! never with a panic.
+
+#![no_main]
+
+#[allow(dead_code)]
+#[path = "../../src/controller.rs"]
+mod controller;
+#[allow(dead_code)]
+#[path = "../../src/packets.rs"]
+mod packets;
+
+use controller::{Controller, ControllerOptions};
+use libfuzzer_sys::fuzz_target;
+use packets::nci;
+use tokio::sync::mpsc;
+
+fuzz_target!(|data: &[u8]| {
+    // The clock is paused so that the timers of the controller
+    // fire as soon as all the tasks are idle.
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let nci_reader = nci::Reader::new(std::io::Cursor::new(data.to_vec()));
+        let nci_writer = nci::Writer::new(tokio::io::sink());
+        // Keep the other ends of the RF channels open, RF is unavailable
+        // to the controller once they are closed.
+        let (_rf_in_tx, rf_rx) = mpsc::unbounded_channel();
+        let (rf_tx, _rf_out_rx) = mpsc::unbounded_channel();
+        // Unimplemented commands are answered with an empty response
+        // instead of panicking, and the fuzzer can reach the commands
+        // that follow them.
+        let options = ControllerOptions { lenient: true, ..Default::default() };
+        // The controller returns once the input is exhausted, or on the
+        // first packet it rejects.
+        let _ = Controller::run(0, options, nci_reader, nci_writer, rf_rx, rf_tx).await;
+    });
+});
@@ -121,6 +121,8 @@ class RfPacketType(enum.IntEnum):
     SELECT_COMMAND = 0x3
     SELECT_RESPONSE = 0x4
     DEACTIVATE_NOTIFICATION = 0x5
+    PRESENCE_CHECK_COMMAND = 0x6
+    PRESENCE_CHECK_RESPONSE = 0x7
 
     @staticmethod
     def from_int(v: int) -> Union[int, 'RfPacketType']:
@@ -161,10 +163,22 @@ class RfPacket(Packet):
             return PollCommand.parse(fields.copy(), payload)
         except Exception as exn:
             pass
+        try:
+            return T1TPollResponse.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
         try:
             return NfcAPollResponse.parse(fields.copy(), payload)
         except Exception as exn:
             pass
+        try:
+            return NfcBPollResponse.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
+        try:
+            return NfcVPollResponse.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
         try:
             return T4ATSelectCommand.parse(fields.copy(), payload)
         except Exception as exn:
@@ -173,6 +187,14 @@ class RfPacket(Packet):
             return T4ATSelectResponse.parse(fields.copy(), payload)
         except Exception as exn:
             pass
+        try:
+            return T4BTSelectCommand.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
+        try:
+            return T4BTSelectResponse.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
         try:
             return NfcDepSelectCommand.parse(fields.copy(), payload)
         except Exception as exn:
@@ -189,6 +211,14 @@ class RfPacket(Packet):
             return DeactivateNotification.parse(fields.copy(), payload)
         except Exception as exn:
             pass
+        try:
+            return IsoDepPresenceCheckCommand.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
+        try:
+            return IsoDepPresenceCheckResponse.parse(fields.copy(), payload)
+        except Exception as exn:
+            pass
         try:
             return Data.parse(fields.copy(), payload)
         except Exception as exn:
@@ -236,6 +266,40 @@ class PollCommand(RfPacket):
     def size(self) -> int:
         return 0
 
+@dataclass
+class T1TPollResponse(RfPacket):
+    hr: bytearray = field(kw_only=True, default_factory=bytearray)
+    uid: bytearray = field(kw_only=True, default_factory=bytearray)
+
+    def __post_init__(self):
+        self.technology = Technology.NFC_A
+        self.protocol = Protocol.T1T
+        self.packet_type = RfPacketType.POLL_RESPONSE
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['T1TPollResponse', bytes]:
+        if fields['technology'] != Technology.NFC_A or fields['protocol'] != Protocol.T1T or fields['packet_type'] != RfPacketType.POLL_RESPONSE:
+            raise Exception("Invalid constraint field values")
+        if len(span) < 2:
+            raise Exception('Invalid packet size')
+        fields['hr'] = list(span[:2])
+        span = span[2:]
+        if len(span) < 4:
+            raise Exception('Invalid packet size')
+        fields['uid'] = list(span[:4])
+        span = span[4:]
+        return T1TPollResponse(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        _span.extend(self.hr)
+        _span.extend(self.uid)
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 6
+
 @dataclass
 class NfcAPollResponse(RfPacket):
     nfcid1: bytearray = field(kw_only=True, default_factory=bytearray)
@@ -283,6 +347,88 @@ class NfcAPollResponse(RfPacket):
     def size(self) -> int:
         return len(self.nfcid1) * 1 + 3
 
+@dataclass
+class NfcBPollResponse(RfPacket):
+    pupi: bytearray = field(kw_only=True, default_factory=bytearray)
+    application_data: bytearray = field(kw_only=True, default_factory=bytearray)
+    protocol_info: bytearray = field(kw_only=True, default_factory=bytearray)
+
+    def __post_init__(self):
+        self.technology = Technology.NFC_B
+        self.packet_type = RfPacketType.POLL_RESPONSE
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['NfcBPollResponse', bytes]:
+        if fields['technology'] != Technology.NFC_B or fields['packet_type'] != RfPacketType.POLL_RESPONSE:
+            raise Exception("Invalid constraint field values")
+        if len(span) < 4:
+            raise Exception('Invalid packet size')
+        fields['pupi'] = list(span[:4])
+        span = span[4:]
+        if len(span) < 4:
+            raise Exception('Invalid packet size')
+        fields['application_data'] = list(span[:4])
+        span = span[4:]
+        if len(span) < 3:
+            raise Exception('Invalid packet size')
+        fields['protocol_info'] = list(span[:3])
+        span = span[3:]
+        return NfcBPollResponse(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        _span.extend(self.pupi)
+        _span.extend(self.application_data)
+        _span.extend(self.protocol_info)
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 11
+
+@dataclass
+class NfcVPollResponse(RfPacket):
+    resp_flags: int = field(kw_only=True, default=0)
+    dsfid: int = field(kw_only=True, default=0)
+    uid: bytearray = field(kw_only=True, default_factory=bytearray)
+
+    def __post_init__(self):
+        self.technology = Technology.NFC_V
+        self.protocol = Protocol.T5T
+        self.packet_type = RfPacketType.POLL_RESPONSE
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['NfcVPollResponse', bytes]:
+        if fields['technology'] != Technology.NFC_V or fields['protocol'] != Protocol.T5T or fields['packet_type'] != RfPacketType.POLL_RESPONSE:
+            raise Exception("Invalid constraint field values")
+        if len(span) < 2:
+            raise Exception('Invalid packet size')
+        fields['resp_flags'] = span[0]
+        fields['dsfid'] = span[1]
+        span = span[2:]
+        if len(span) < 8:
+            raise Exception('Invalid packet size')
+        fields['uid'] = list(span[:8])
+        span = span[8:]
+        return NfcVPollResponse(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        if self.resp_flags > 255:
+            print(f"Invalid value for field NfcVPollResponse::resp_flags: {self.resp_flags} > 255; the value will be truncated")
+            self.resp_flags &= 255
+        _span.append((self.resp_flags << 0))
+        if self.dsfid > 255:
+            print(f"Invalid value for field NfcVPollResponse::dsfid: {self.dsfid} > 255; the value will be truncated")
+            self.dsfid &= 255
+        _span.append((self.dsfid << 0))
+        _span.extend(self.uid)
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 10
+
 @dataclass
 class T4ATSelectCommand(RfPacket):
     param: int = field(kw_only=True, default=0)
@@ -347,9 +493,77 @@ class T4ATSelectResponse(RfPacket):
     def size(self) -> int:
         return len(self.rats_response) * 1 + 1
 
+@dataclass
+class T4BTSelectCommand(RfPacket):
+    nfcid0: bytearray = field(kw_only=True, default_factory=bytearray)
+    param: bytearray = field(kw_only=True, default_factory=bytearray)
+
+    def __post_init__(self):
+        self.technology = Technology.NFC_B
+        self.protocol = Protocol.ISO_DEP
+        self.packet_type = RfPacketType.SELECT_COMMAND
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['T4BTSelectCommand', bytes]:
+        if fields['technology'] != Technology.NFC_B or fields['protocol'] != Protocol.ISO_DEP or fields['packet_type'] != RfPacketType.SELECT_COMMAND:
+            raise Exception("Invalid constraint field values")
+        if len(span) < 4:
+            raise Exception('Invalid packet size')
+        fields['nfcid0'] = list(span[:4])
+        span = span[4:]
+        if len(span) < 4:
+            raise Exception('Invalid packet size')
+        fields['param'] = list(span[:4])
+        span = span[4:]
+        return T4BTSelectCommand(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        _span.extend(self.nfcid0)
+        _span.extend(self.param)
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 8
+
+@dataclass
+class T4BTSelectResponse(RfPacket):
+    attrib_response: bytearray = field(kw_only=True, default_factory=bytearray)
+
+    def __post_init__(self):
+        self.technology = Technology.NFC_B
+        self.protocol = Protocol.ISO_DEP
+        self.packet_type = RfPacketType.SELECT_RESPONSE
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['T4BTSelectResponse', bytes]:
+        if fields['technology'] != Technology.NFC_B or fields['protocol'] != Protocol.ISO_DEP or fields['packet_type'] != RfPacketType.SELECT_RESPONSE:
+            raise Exception("Invalid constraint field values")
+        if len(span) < 1:
+            raise Exception('Invalid packet size')
+        attrib_response_size = span[0]
+        span = span[1:]
+        if len(span) < attrib_response_size:
+            raise Exception('Invalid packet size')
+        fields['attrib_response'] = list(span[:attrib_response_size])
+        span = span[attrib_response_size:]
+        return T4BTSelectResponse(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        _span.append(((len(self.attrib_response) * 1) << 0))
+        _span.extend(self.attrib_response)
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return len(self.attrib_response) * 1 + 1
+
 @dataclass
 class NfcDepSelectCommand(RfPacket):
     lr: int = field(kw_only=True, default=0)
+    atr_request: bytearray = field(kw_only=True, default_factory=bytearray)
 
     def __post_init__(self):
         self.protocol = Protocol.NFC_DEP
@@ -363,6 +577,14 @@ class NfcDepSelectCommand(RfPacket):
             raise Exception('Invalid packet size')
         fields['lr'] = (span[0] >> 0) & 0x3
         span = span[1:]
+        if len(span) < 1:
+            raise Exception('Invalid packet size')
+        atr_request_size = span[0]
+        span = span[1:]
+        if len(span) < atr_request_size:
+            raise Exception('Invalid packet size')
+        fields['atr_request'] = list(span[:atr_request_size])
+        span = span[atr_request_size:]
         return NfcDepSelectCommand(**fields), span
 
     def serialize(self, payload: bytes = None) -> bytes:
@@ -371,11 +593,13 @@ class NfcDepSelectCommand(RfPacket):
             print(f"Invalid value for field NfcDepSelectCommand::lr: {self.lr} > 3; the value will be truncated")
             self.lr &= 3
         _span.append((self.lr << 0))
+        _span.append(((len(self.atr_request) * 1) << 0))
+        _span.extend(self.atr_request)
         return RfPacket.serialize(self, payload = bytes(_span))
 
     @property
     def size(self) -> int:
-        return 1
+        return len(self.atr_request) * 1 + 2
 
 @dataclass
 class NfcDepSelectResponse(RfPacket):
@@ -488,6 +712,50 @@ class DeactivateNotification(RfPacket):
     def size(self) -> int:
         return 2
 
+@dataclass
+class IsoDepPresenceCheckCommand(RfPacket):
+    
+
+    def __post_init__(self):
+        self.protocol = Protocol.ISO_DEP
+        self.packet_type = RfPacketType.PRESENCE_CHECK_COMMAND
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['IsoDepPresenceCheckCommand', bytes]:
+        if fields['protocol'] != Protocol.ISO_DEP or fields['packet_type'] != RfPacketType.PRESENCE_CHECK_COMMAND:
+            raise Exception("Invalid constraint field values")
+        return IsoDepPresenceCheckCommand(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 0
+
+@dataclass
+class IsoDepPresenceCheckResponse(RfPacket):
+    
+
+    def __post_init__(self):
+        self.protocol = Protocol.ISO_DEP
+        self.packet_type = RfPacketType.PRESENCE_CHECK_RESPONSE
+
+    @staticmethod
+    def parse(fields: dict, span: bytes) -> Tuple['IsoDepPresenceCheckResponse', bytes]:
+        if fields['protocol'] != Protocol.ISO_DEP or fields['packet_type'] != RfPacketType.PRESENCE_CHECK_RESPONSE:
+            raise Exception("Invalid constraint field values")
+        return IsoDepPresenceCheckResponse(**fields), span
+
+    def serialize(self, payload: bytes = None) -> bytes:
+        _span = bytearray()
+        return RfPacket.serialize(self, payload = bytes(_span))
+
+    @property
+    def size(self) -> int:
+        return 0
+
 @dataclass
 class Data(RfPacket):
     data: bytearray = field(kw_only=True, default_factory=bytearray)
@@ -0,0 +1,203 @@
+#!/usr/bin/env python3
+
+# Copyright 2023 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     https://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import argparse
+import asyncio
+import random
+from typing import Optional
+
+import rf_packets as rf
+from crc import crc_b
+
+# [DIGITAL] T1T command codes.
+CMD_RID = 0x78
+CMD_RALL = 0x00
+CMD_READ = 0x01
+CMD_WRITE_E = 0x53
+CMD_WRITE_NE = 0x1a
+CMD_RSEG = 0x10
+CMD_READ8 = 0x02
+CMD_WRITE_E8 = 0x54
+CMD_WRITE_NE8 = 0x1b
+
+# Header ROM byte HR0 for the static and dynamic memory models.
+HR0_STATIC = 0x11
+HR0_DYNAMIC = 0x12
+HR1 = 0x00
+
+BLOCK_SIZE = 8
+# Blocks 0h to Eh are the static memory, read by RALL and addressed by
+# the single byte commands.
+STATIC_BLOCK_COUNT = 15
+# The dynamic memory is read by segments of 16 blocks.
+SEGMENT_BLOCK_COUNT = 16
+
+
+class T1T:
+    def __init__(self, reader, writer, dynamic: bool, block_count: int, crc: bool):
+        self.uid = int.to_bytes(random.randint(0, 0xffffffff), length=4, byteorder='little')
+        self.dynamic = dynamic
+        self.hr = bytes([HR0_DYNAMIC if dynamic else HR0_STATIC, HR1])
+        block_count = block_count if dynamic else STATIC_BLOCK_COUNT
+        self.memory = bytearray(block_count * BLOCK_SIZE)
+        # Block 0 holds the UID, and is read-only.
+        self.memory[0:4] = self.uid
+        # Seed the capability container in block 1, cf [T1T] 2.2.
+        self.memory[8:12] = bytes([0xe1, 0x10, (block_count * BLOCK_SIZE) // 8 - 1, 0x00])
+        self.crc = crc
+        self.reader = reader
+        self.writer = writer
+
+    async def _read(self) -> rf.RfPacket:
+        header_bytes = await self.reader.read(2)
+        packet_length = int.from_bytes(header_bytes, byteorder='little')
+        packet_bytes = await self.reader.read(packet_length)
+
+        packet = rf.RfPacket.parse_all(packet_bytes)
+        packet.show()
+        return packet
+
+    def _write(self, packet: rf.RfPacket):
+        packet_bytes = packet.serialize()
+        header_bytes = int.to_bytes(len(packet_bytes), length=2, byteorder='little')
+        self.writer.write(header_bytes + packet_bytes)
+
+    async def listen(self):
+        """Emulate device in passive listen mode. Respond to polls with the
+        RID Response, and to the memory commands of the poller."""
+        while True:
+            packet = await self._read()
+            match packet:
+                case rf.PollCommand(technology=rf.Technology.NFC_A):
+                    self._write(rf.T1TPollResponse(
+                        receiver=packet.sender, hr=self.hr, uid=self.uid))
+                case rf.Data(technology=rf.Technology.NFC_A, protocol=rf.Protocol.T1T):
+                    request = bytes(packet.data)
+                    if self.crc:
+                        # Frames with an invalid CRC are silently ignored.
+                        if len(request) < 2 or crc_b(request[:-2]) != request[-2:]:
+                            print('Ignoring request with an invalid CRC')
+                            continue
+                        request = request[:-2]
+                    response = self.handle_request(request)
+                    if response is not None and self.crc:
+                        response += crc_b(response)
+                    if response is not None:
+                        self._write(rf.Data(
+                            receiver=packet.sender,
+                            technology=rf.Technology.NFC_A,
+                            protocol=rf.Protocol.T1T,
+                            data=response))
+                case _:
+                    pass
+
+    def handle_request(self, request: bytes) -> Optional[bytes]:
+        """Process a T1T command, returns the response to send back or None
+        if the command is not answered."""
+        # Commands are formatted as the command code, the address, one or
+        # eight data bytes, and UID0 to UID3.
+        if len(request) not in (7, 14):
+            return None
+        command, address, data, uid = request[0], request[1], request[2:-4], request[-4:]
+
+        # RID is the only command accepted regardless of the UID.
+        if command == CMD_RID:
+            return self.hr + self.uid
+        if uid != self.uid:
+            return None
+
+        # Commands of the static memory model, the address selects the
+        # block (b7-b4) and byte (b3-b1) in the static memory.
+        if len(request) == 7:
+            offset = address & 0x7f
+            if command == CMD_RALL:
+                return self.hr + bytes(self.memory[:STATIC_BLOCK_COUNT * BLOCK_SIZE])
+            if offset >= STATIC_BLOCK_COUNT * BLOCK_SIZE:
+                return None
+            if command == CMD_READ:
+                return bytes([address, self.memory[offset]])
+            if command in (CMD_WRITE_E, CMD_WRITE_NE) and offset >= BLOCK_SIZE:
+                if command == CMD_WRITE_E:
+                    self.memory[offset] = data[0]
+                else:
+                    self.memory[offset] |= data[0]
+                return bytes([address, self.memory[offset]])
+            return None
+
+        # Commands of the dynamic memory model, not supported by the
+        # static memory model.
+        if not self.dynamic:
+            return None
+        if command == CMD_RSEG:
+            start = (address >> 4) * SEGMENT_BLOCK_COUNT * BLOCK_SIZE
+            if start >= len(self.memory):
+                return None
+            segment = self.memory[start:start + SEGMENT_BLOCK_COUNT * BLOCK_SIZE]
+            return bytes([address]) + bytes(segment.ljust(SEGMENT_BLOCK_COUNT * BLOCK_SIZE, b'\0'))
+        start = address * BLOCK_SIZE
+        if start >= len(self.memory):
+            return None
+        if command == CMD_READ8:
+            return bytes([address]) + bytes(self.memory[start:start + BLOCK_SIZE])
+        if command in (CMD_WRITE_E8, CMD_WRITE_NE8) and address != 0:
+            for index in range(BLOCK_SIZE):
+                if command == CMD_WRITE_E8:
+                    self.memory[start + index] = data[index]
+                else:
+                    self.memory[start + index] |= data[index]
+            return bytes([address]) + bytes(self.memory[start:start + BLOCK_SIZE])
+        return None
+
+async def run(address: str, rf_port: int, dynamic: bool, block_count: int, crc: bool):
+    """Emulate a T1T compatible device in Listen mode."""
+    try:
+        reader, writer = await asyncio.open_connection(address, rf_port)
+        device = T1T(reader, writer, dynamic, block_count, crc)
+        await device.listen()
+    except Exception as exn:
+        print(
+            f'Failed to connect to Casimir server at address {address}:{rf_port}:\n' +
+            f'    {exn}\n' +
+            'Make sure the server is running')
+        exit(1)
+
+
+def main():
+    """Start a Casimir T1T tag emulator."""
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument('--address',
+                        type=str,
+                        default='127.0.0.1',
+                        help='Select the casimir server address')
+    parser.add_argument('--rf-port',
+                        type=int,
+                        default=7001,
+                        help='Select the casimir TCP RF port')
+    parser.add_argument('--dynamic',
+                        action='store_true',
+                        help='Emulate the dynamic memory model instead of the static memory model')
+    parser.add_argument('--block-count',
+                        type=int,
+                        default=64,
+                        help='Select the number of tag blocks of the dynamic memory model')
+    parser.add_argument('--crc',
+                        action='store_true',
+                        help='Exchange the frames with their CRC, to use with casimir --rf-crc')
+    asyncio.run(run(**vars(parser.parse_args())))
+
+
+if __name__ == '__main__':
+    main()
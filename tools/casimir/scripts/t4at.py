@@ -26,16 +26,30 @@ import requests
 import struct
 import asyncio
 from concurrent.futures import ThreadPoolExecutor
+from typing import Optional
 
 import rf_packets as rf
 
 
+# ISO/IEC 15693-3 command codes and error codes.
+NFCV_CMD_INVENTORY = 0x01
+NFCV_ERROR_NOT_SUPPORTED = 0x01
+
+
 class T4AT:
-    def __init__(self, reader, writer):
-        self.nfcid1 = bytes([0x08]) + int.to_bytes(random.randint(0, 0xffffff), length=3)
+    def __init__(self, reader, writer, link_loss_delay: Optional[float] = None, nfc_v: bool = False):
+        # The same serial number identifies the device on every technology:
+        # it makes the random single-size NFCID1 of NFC-A, and the low
+        # bytes of the NFC-V UID.
+        serial = int.to_bytes(random.randint(0, 0xffffff), length=3, byteorder='little')
+        self.nfcid1 = bytes([0x08]) + serial
+        self.uid = serial + bytes(3) + bytes([0x04, 0xe0])
+        self.dsfid = 0
+        self.nfc_v = nfc_v
         self.rats_response = bytes([0x2, 0x0])
         self.reader = reader
         self.writer = writer
+        self.link_loss_delay = link_loss_delay
 
     async def _read(self) -> rf.RfPacket:
         header_bytes = await self.reader.read(2)
@@ -53,13 +67,29 @@ class T4AT:
 
     async def listen(self):
         """Emulate device in passive listen mode. Respond to poll requests until
-        the device is activated by a select command."""
+        the device is activated by a select command. When NFC-V is enabled
+        the device also responds to NFC-V poll requests, and to the
+        ISO/IEC 15693-3 requests of the poller."""
         while True:
             packet = await self._read()
             match packet:
                 case rf.PollCommand(technology=rf.Technology.NFC_A):
                     self._write(rf.NfcAPollResponse(
                         nfcid1=self.nfcid1, int_protocol=0b01))
+                case rf.PollCommand(technology=rf.Technology.NFC_V) if self.nfc_v:
+                    self._write(rf.NfcVPollResponse(
+                        receiver=packet.sender, dsfid=self.dsfid, uid=self.uid))
+                case rf.Data(technology=rf.Technology.NFC_V, protocol=rf.Protocol.T5T) if self.nfc_v:
+                    request = bytes(packet.data)
+                    if len(request) >= 2 and request[1] == NFCV_CMD_INVENTORY:
+                        response = bytes([0x00, self.dsfid]) + self.uid
+                    else:
+                        response = bytes([0x01, NFCV_ERROR_NOT_SUPPORTED])
+                    self._write(rf.Data(
+                        receiver=packet.sender,
+                        technology=rf.Technology.NFC_V,
+                        protocol=rf.Protocol.T5T,
+                        data=response))
                 case rf.T4ATSelectCommand(_):
                     self._write(rf.T4ATSelectResponse(
                         rats_response=self.rats_response,
@@ -101,23 +131,41 @@ class T4AT:
 
     async def active(self, peer: int):
         """Active mode. Respond to data requests until the device
-        is deselected."""
+        is deselected, or until the RF link is lost if a link loss delay
+        is configured."""
+        deadline = None
+        if self.link_loss_delay is not None:
+            deadline = asyncio.get_running_loop().time() + self.link_loss_delay
         while True:
-            packet = await self._read()
+            try:
+                timeout = None
+                if deadline is not None:
+                    timeout = max(0, deadline - asyncio.get_running_loop().time())
+                packet = await asyncio.wait_for(self._read(), timeout=timeout)
+            except TimeoutError:
+                print(f"simulating RF link loss with #{peer}")
+                self._write(rf.DeactivateNotification(
+                    receiver=peer,
+                    type_=rf.DeactivateType.DISCOVERY,
+                    reason=rf.DeactivateReason.RF_LINK_LOSS))
+                return
             match packet:
                 case rf.DeactivateNotification(_):
                     return
+                case rf.IsoDepPresenceCheckCommand(_):
+                    self._write(rf.IsoDepPresenceCheckResponse(
+                        receiver=peer, technology=packet.technology))
                 case rf.Data(_):
                     pass
                 case _:
                     pass
 
 
-async def run(address: str, rf_port: int, mode: str):
+async def run(address: str, rf_port: int, mode: str, link_loss_delay: Optional[float], nfc_v: bool):
     """Emulate a T4AT compatible device in Listen mode."""
     try:
         reader, writer = await asyncio.open_connection(address, rf_port)
-        device = T4AT(reader, writer)
+        device = T4AT(reader, writer, link_loss_delay, nfc_v)
         if mode == 'poll':
             await device.poll()
         elif mode == 'listen':
@@ -148,6 +196,13 @@ def main():
                         choices=['poll', 'listen'],
                         default='poll',
                         help='Select the tag mode')
+    parser.add_argument('--link-loss-delay',
+                        type=float,
+                        default=None,
+                        help='Simulate an RF link loss after the selected number of seconds in active mode')
+    parser.add_argument('--nfc-v',
+                        action='store_true',
+                        help='Also respond to NFC-V polls in listen mode, with a UID matching the NFCID1')
     asyncio.run(run(**vars(parser.parse_args())))
 
 
@@ -0,0 +1,152 @@
+#!/usr/bin/env python3
+
+# Copyright 2024 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     https://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import argparse
+import asyncio
+import random
+
+import rf_packets as rf
+
+
+class T4BT:
+    def __init__(self, reader, writer):
+        self.pupi = int.to_bytes(random.randint(0, 0xffffffff), length=4, byteorder='little')
+        self.application_data = bytes(4)
+        # [DIGITAL] Protocol Info Format: 106 kbit/s only, maximum frame
+        # size of 256 bytes, compliant with ISO-DEP, FWI of 4.
+        self.protocol_info = bytes([0x00, 0x81, 0x40])
+        self.reader = reader
+        self.writer = writer
+
+    async def _read(self) -> rf.RfPacket:
+        header_bytes = await self.reader.read(2)
+        packet_length = int.from_bytes(header_bytes, byteorder='little')
+        packet_bytes = await self.reader.read(packet_length)
+
+        packet = rf.RfPacket.parse_all(packet_bytes)
+        packet.show()
+        return packet
+
+    def _write(self, packet: rf.RfPacket):
+        packet_bytes = packet.serialize()
+        header_bytes = int.to_bytes(len(packet_bytes), length=2, byteorder='little')
+        self.writer.write(header_bytes + packet_bytes)
+
+    async def listen(self):
+        """Emulate device in passive listen mode. Respond to NFC-B poll
+        requests until the device is activated by an ATTRIB command
+        addressed to its PUPI."""
+        while True:
+            packet = await self._read()
+            match packet:
+                case rf.PollCommand(technology=rf.Technology.NFC_B):
+                    self._write(rf.NfcBPollResponse(
+                        receiver=packet.sender,
+                        pupi=self.pupi,
+                        application_data=self.application_data,
+                        protocol_info=self.protocol_info))
+                case rf.T4BTSelectCommand(_) if bytes(packet.nfcid0) == self.pupi:
+                    # The DID of Param 4 is echoed in the ATTRIB response.
+                    self._write(rf.T4BTSelectResponse(
+                        receiver=packet.sender,
+                        attrib_response=[packet.param[3] & 0x0f]))
+                    print(f"t4bt device selected by #{packet.sender}")
+                    await self.active(packet.sender)
+                case _:
+                    pass
+
+    async def poll(self):
+        """Emulate device in passive poll mode. Automatically selects the
+        first discovered device."""
+        while True:
+            try:
+                self._write(rf.PollCommand(technology=rf.Technology.NFC_B))
+                packet = await asyncio.wait_for(self._read(), timeout=1.0)
+                match packet:
+                    case rf.NfcBPollResponse(_) if packet.protocol_info[1] & 0x0f == 0x01:
+                        pupi = bytes(packet.pupi)
+                        print(f"discovered t4bt device with pupi #{pupi.hex()}")
+                        self._write(rf.T4BTSelectCommand(
+                            receiver=packet.sender, nfcid0=pupi, param=[0x00, 0x08, 0x01, 0x00]))
+                        response = await asyncio.wait_for(
+                            self.wait_for_select_response(packet.sender), timeout=1.0)
+                        print(f"t4bt device activation complete")
+                        await self.active(response.sender)
+                    case _:
+                        pass
+                await asyncio.sleep(0.150)
+            except TimeoutError:
+                pass
+
+    async def wait_for_select_response(self, sender_id: int):
+        while True:
+            packet = await self._read()
+            if isinstance(packet, rf.T4BTSelectResponse) and packet.sender == sender_id:
+                return packet
+
+    async def active(self, peer: int):
+        """Active mode. Answer presence checks and ignore data requests
+        until the device is deselected."""
+        while True:
+            packet = await self._read()
+            match packet:
+                case rf.DeactivateNotification(_):
+                    return
+                case rf.IsoDepPresenceCheckCommand(_):
+                    self._write(rf.IsoDepPresenceCheckResponse(
+                        receiver=peer, technology=packet.technology))
+                case _:
+                    pass
+
+
+async def run(address: str, rf_port: int, mode: str):
+    """Emulate a T4BT compatible device."""
+    try:
+        reader, writer = await asyncio.open_connection(address, rf_port)
+        device = T4BT(reader, writer)
+        if mode == 'poll':
+            await device.poll()
+        else:
+            await device.listen()
+    except Exception as exn:
+        print(
+            f'Failed to connect to Casimir server at address {address}:{rf_port}:\n' +
+            f'    {exn}\n' +
+            'Make sure the server is running')
+        exit(1)
+
+
+def main():
+    """Start a Casimir T4BT device emulator."""
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument('--address',
+                        type=str,
+                        default='127.0.0.1',
+                        help='Select the casimir server address')
+    parser.add_argument('--rf-port',
+                        type=int,
+                        default=7001,
+                        help='Select the casimir TCP RF port')
+    parser.add_argument('--mode',
+                        type=str,
+                        choices=['poll', 'listen'],
+                        default='poll',
+                        help='Select the tag mode')
+    asyncio.run(run(**vars(parser.parse_args())))
+
+
+if __name__ == '__main__':
+    main()
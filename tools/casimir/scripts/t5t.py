@@ -0,0 +1,171 @@
+#!/usr/bin/env python3
+
+# Copyright 2023 Google LLC
+#
+# Licensed under the Apache License, Version 2.0 (the "License");
+# you may not use this file except in compliance with the License.
+# You may obtain a copy of the License at
+#
+#     https://www.apache.org/licenses/LICENSE-2.0
+#
+# Unless required by applicable law or agreed to in writing, software
+# distributed under the License is distributed on an "AS IS" BASIS,
+# WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+# See the License for the specific language governing permissions and
+# limitations under the License.
+
+import argparse
+import asyncio
+import random
+from typing import Optional
+
+import rf_packets as rf
+from crc import crc_b
+
+# ISO/IEC 15693-3 request flags.
+FLAG_ADDRESS = 0x20
+FLAG_OPTION = 0x40
+
+# ISO/IEC 15693-3 command codes.
+CMD_INVENTORY = 0x01
+CMD_READ_SINGLE_BLOCK = 0x20
+CMD_WRITE_SINGLE_BLOCK = 0x21
+
+# ISO/IEC 15693-3 error codes.
+ERROR_NOT_SUPPORTED = 0x01
+ERROR_BLOCK_NOT_AVAILABLE = 0x10
+
+
+class T5T:
+    def __init__(self, reader, writer, block_size: int, block_count: int, crc: bool):
+        self.uid = int.to_bytes(random.randint(0, 0xffffffffffff), length=6,
+                                byteorder='little') + bytes([0x04, 0xe0])
+        self.dsfid = 0
+        self.block_size = block_size
+        self.blocks = [bytearray(block_size) for _ in range(block_count)]
+        # Seed the capability container in block 0, cf [T5T] 4.3.1.
+        self.blocks[0][0:4] = bytes([0xe1, 0x40, (block_size * block_count) // 8, 0x00])
+        self.crc = crc
+        self.reader = reader
+        self.writer = writer
+
+    async def _read(self) -> rf.RfPacket:
+        header_bytes = await self.reader.read(2)
+        packet_length = int.from_bytes(header_bytes, byteorder='little')
+        packet_bytes = await self.reader.read(packet_length)
+
+        packet = rf.RfPacket.parse_all(packet_bytes)
+        packet.show()
+        return packet
+
+    def _write(self, packet: rf.RfPacket):
+        packet_bytes = packet.serialize()
+        header_bytes = int.to_bytes(len(packet_bytes), length=2, byteorder='little')
+        self.writer.write(header_bytes + packet_bytes)
+
+    async def listen(self):
+        """Emulate device in passive listen mode. Respond to inventory requests
+        and to the block commands of the poller."""
+        while True:
+            packet = await self._read()
+            match packet:
+                case rf.PollCommand(technology=rf.Technology.NFC_V):
+                    self._write(rf.NfcVPollResponse(
+                        receiver=packet.sender, dsfid=self.dsfid, uid=self.uid))
+                case rf.Data(technology=rf.Technology.NFC_V, protocol=rf.Protocol.T5T):
+                    request = bytes(packet.data)
+                    if self.crc:
+                        # Frames with an invalid CRC are silently ignored.
+                        if len(request) < 2 or crc_b(request[:-2]) != request[-2:]:
+                            print('Ignoring request with an invalid CRC')
+                            continue
+                        request = request[:-2]
+                    response = self.handle_request(request)
+                    if response is not None and self.crc:
+                        response += crc_b(response)
+                    if response is not None:
+                        self._write(rf.Data(
+                            receiver=packet.sender,
+                            technology=rf.Technology.NFC_V,
+                            protocol=rf.Protocol.T5T,
+                            data=response))
+                case _:
+                    pass
+
+    def handle_request(self, request: bytes) -> Optional[bytes]:
+        """Process an ISO/IEC 15693-3 request, returns the response to send
+        back or None if the request is not addressed to this tag."""
+        if len(request) < 2:
+            return None
+        flags, command, parameters = request[0], request[1], request[2:]
+
+        # Inventory requests carry the AFI and mask instead of the UID.
+        if command == CMD_INVENTORY:
+            return bytes([0x00, self.dsfid]) + self.uid
+
+        # In addressed mode the UID follows the command code, and only
+        # the tag with the matching UID responds.
+        if flags & FLAG_ADDRESS:
+            if parameters[:8] != self.uid:
+                return None
+            parameters = parameters[8:]
+
+        if command == CMD_READ_SINGLE_BLOCK and len(parameters) >= 1:
+            block = parameters[0]
+            if block >= len(self.blocks):
+                return bytes([0x01, ERROR_BLOCK_NOT_AVAILABLE])
+            # With the option flag set the block security status
+            # precedes the block data.
+            security = bytes([0x00]) if flags & FLAG_OPTION else bytes()
+            return bytes([0x00]) + security + bytes(self.blocks[block])
+
+        if command == CMD_WRITE_SINGLE_BLOCK and len(parameters) >= 1 + self.block_size:
+            block = parameters[0]
+            if block >= len(self.blocks):
+                return bytes([0x01, ERROR_BLOCK_NOT_AVAILABLE])
+            self.blocks[block][:] = parameters[1:1 + self.block_size]
+            return bytes([0x00])
+
+        return bytes([0x01, ERROR_NOT_SUPPORTED])
+
+async def run(address: str, rf_port: int, block_size: int, block_count: int, crc: bool):
+    """Emulate a T5T compatible device in Listen mode."""
+    try:
+        reader, writer = await asyncio.open_connection(address, rf_port)
+        device = T5T(reader, writer, block_size, block_count, crc)
+        await device.listen()
+    except Exception as exn:
+        print(
+            f'Failed to connect to Casimir server at address {address}:{rf_port}:\n' +
+            f'    {exn}\n' +
+            'Make sure the server is running')
+        exit(1)
+
+
+def main():
+    """Start a Casimir T5T tag emulator."""
+    parser = argparse.ArgumentParser(description=__doc__)
+    parser.add_argument('--address',
+                        type=str,
+                        default='127.0.0.1',
+                        help='Select the casimir server address')
+    parser.add_argument('--rf-port',
+                        type=int,
+                        default=7001,
+                        help='Select the casimir TCP RF port')
+    parser.add_argument('--block-size',
+                        type=int,
+                        default=4,
+                        help='Select the tag block size in bytes')
+    parser.add_argument('--block-count',
+                        type=int,
+                        default=64,
+                        help='Select the number of tag blocks')
+    parser.add_argument('--crc',
+                        action='store_true',
+                        help='Exchange the frames with their CRC, to use with casimir --rf-crc')
+    asyncio.run(run(**vars(parser.parse_args())))
+
+
+if __name__ == '__main__':
+    main()
@@ -17,14 +17,19 @@
 use crate::packets::{nci, rf};
 use anyhow::Result;
 use core::time::Duration;
-use log::{debug, error, info, trace, warn};
+use log::{debug, info, trace, warn};
 use pdl_runtime::Packet;
 use std::convert::TryFrom;
-use std::time::Instant;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
 use tokio::time;
 
+/// Version of the NCI specification implemented by the emulator. Only the
+/// NCI 2.x message formats are implemented: the NCI 1.x reset sequence,
+/// where CORE_RESET_RSP carries the NCI version and configuration status
+/// and no CORE_RESET_NTF is sent, is not supported.
 const NCI_VERSION: nci::NciVersion = nci::NciVersion::Version20;
 const MANUFACTURER_ID: u8 = 0x02;
 const MANUFACTURER_SPECIFIC_INFORMATION: [u8; 26] =
@@ -52,17 +57,73 @@ const LF_PROTOCOL_TYPE: u8 = 0x02; // Supports NFC-DEP.
 const LI_A_RATS_TB1: u8 = 0x70;
 const LI_A_RATS_TC1: u8 = 0x02;
 
+/// Default maximum number of dynamic logical connections.
 const MAX_LOGICAL_CONNECTIONS: u8 = 2;
 const MAX_ROUTING_TABLE_SIZE: u16 = 512;
 const MAX_CONTROL_PACKET_PAYLOAD_SIZE: u8 = 255;
 const MAX_DATA_PACKET_PAYLOAD_SIZE: u8 = 255;
 const NUMBER_OF_CREDITS: u8 = 1;
+/// Value of the initial number of credits indicating that data flow
+/// control is not used on a logical connection.
+const CREDITS_FLOW_CONTROL_DISABLED: u8 = 0xff;
+/// Proprietary OID of the command releasing the credits withheld from
+/// the DH when credit starvation is enabled.
+const RELEASE_CREDITS_OID: u8 = 0x3f;
+/// Proprietary OID of the command reading back the RF discovery map
+/// stored by RF_DISCOVER_MAP_CMD, for test introspection.
+const GET_DISCOVER_MAP_OID: u8 = 0x3e;
 const MAX_NFCV_RF_FRAME_SIZE: u16 = 512;
 
+/// Default size in bytes of the storage available for variable length
+/// configuration parameters.
+const MAX_CONFIG_STORAGE_SIZE: usize = 256;
+
+/// Default maximum number of mapping configurations accepted in
+/// RF_DISCOVER_MAP_CMD.
+const MAX_DISCOVER_MAP_SIZE: usize = 16;
+
+/// Time in milliseconds taken by the emulated NFCEE to complete
+/// a mode transition.
+const NFCEE_MODE_SET_DELAY: u64 = 20;
+
+/// Time in milliseconds the NFCC waits for the Remote NFC Endpoint to
+/// answer an ISO-DEP presence check, measured with the resolution of the
+/// tick handler.
+const ISO_DEP_PRESENCE_CHECK_TIMEOUT: u64 = 100;
+
+/// Time in milliseconds after the last poll command of a Remote NFC
+/// Endpoint before its RF field is reported lost, measured with the
+/// resolution of the tick handler. Longer than the default discovery
+/// period, so that the field is not lost between two polls.
+const RF_FIELD_TIMEOUT: u64 = 3000;
+
 /// Time in milliseconds that Casimir waits for poll responses after
 /// sending a poll command.
 const POLL_RESPONSE_TIMEOUT: u64 = 200;
 
+/// Default time in milliseconds between two invocations of the tick
+/// handler.
+const TICK_INTERVAL: u64 = 1000;
+
+/// Time in milliseconds after which a response held back by the response
+/// reordering mode is sent, even if no other command was received,
+/// measured with the resolution of the tick handler.
+const REORDERED_RESPONSE_TIMEOUT: u64 = 500;
+
+/// Time in milliseconds the NFC-DEP Target waits for the response of the
+/// DH before requesting a Response Timeout Extension from the Initiator,
+/// measured with the resolution of the tick handler.
+const NFC_DEP_RTOX_DELAY: u64 = 100;
+
+/// RTOX value requested by the NFC-DEP Target, multiplying the Response
+/// Waiting Time of the pending response.
+const NFC_DEP_RTOX: u8 = 0x01;
+
+/// CMD0 and CMD1 bytes of the NFC-DEP DEP_REQ Command and DEP_RES
+/// Response.
+const NFC_DEP_REQ: [u8; 2] = [0xd4, 0x06];
+const NFC_DEP_RES: [u8; 2] = [0xd5, 0x07];
+
 /// All configuration parameters of the NFCC.
 /// The configuration is filled with default values from the specification
 /// See [NCI] Table 46: Common Parameters for Discovery Configuration
@@ -144,6 +205,15 @@ pub struct ConfigParameters {
     llcp_version: u8,
     /// [NCI] Table 65: Value Field for NFCC Configuration Control.
     nfcc_config_control: u8,
+    /// Proprietary parameter enabling listen mode routing.
+    /// When set to 0 the NFCC does not advertise routing support and
+    /// rejects RF_SET_LISTEN_MODE_ROUTING_CMD.
+    prop0: u8,
+    prop1: Vec<u8>,
+    prop2: Vec<u8>,
+    prop3: Vec<u8>,
+    prop4: Vec<u8>,
+    prop5: Vec<u8>,
 }
 
 /// State of an NFCC logical connection with the DH.
@@ -151,6 +221,7 @@ pub struct ConfigParameters {
 #[allow(missing_docs)]
 pub enum LogicalConnection {
     RemoteNfcEndpoint { rf_discovery_id: u8, rf_protocol_type: nci::RfProtocolType },
+    Nfcee { nfcee_id: nci::NfceeId, nfcee_protocol_type: nci::NfceeProtocolType },
 }
 
 /// State of the RF Discovery of an NFCC instance.
@@ -211,30 +282,401 @@ pub struct RfPollResponse {
     rf_technology_specific_parameters: Vec<u8>,
 }
 
+/// NFC-DEP Protocol Data Unit exchanged in DEP_REQ Commands and DEP_RES
+/// Responses, cf [DIGITAL] PFB Format.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum NfcDepPdu {
+    /// Information PDU carrying transport data. `mi` is set when more
+    /// information PDUs follow in the chain.
+    Information { pni: u8, mi: bool, data: Vec<u8> },
+    /// ACK PDU, acknowledging a chained information PDU.
+    Ack { pni: u8 },
+    /// NACK PDU, requesting the retransmission of the last PDU.
+    Nack { pni: u8 },
+    /// Supervisory PDU for attention.
+    Attention,
+    /// Supervisory PDU for a Response Timeout Extension.
+    TimeoutExtension { rtox: u8 },
+}
+
+impl NfcDepPdu {
+    /// Parse an NFC-DEP frame starting with the command bytes `cmd`.
+    /// The LEN byte is not included in the RF frame.
+    fn parse(cmd: [u8; 2], frame: &[u8]) -> Option<NfcDepPdu> {
+        let (pfb, data) = match frame {
+            [cmd0, cmd1, pfb, data @ ..] if [*cmd0, *cmd1] == cmd => (*pfb, data),
+            _ => return None,
+        };
+        let pni = pfb & 0x3;
+        let flag = pfb & 0x10 != 0;
+        match (pfb >> 5, flag, data) {
+            (0b000, mi, data) => Some(NfcDepPdu::Information { pni, mi, data: data.to_vec() }),
+            (0b010, false, []) => Some(NfcDepPdu::Ack { pni }),
+            (0b010, true, []) => Some(NfcDepPdu::Nack { pni }),
+            (0b100, false, []) => Some(NfcDepPdu::Attention),
+            (0b100, true, [rtox]) => Some(NfcDepPdu::TimeoutExtension { rtox: *rtox }),
+            _ => None,
+        }
+    }
+
+    /// Serialize the PDU as an NFC-DEP frame starting with the command
+    /// bytes `cmd`.
+    fn to_frame(&self, cmd: [u8; 2]) -> Vec<u8> {
+        let mut frame = cmd.to_vec();
+        match self {
+            NfcDepPdu::Information { pni, mi, data } => {
+                frame.push(if *mi { 0x10 } else { 0x00 } | pni);
+                frame.extend_from_slice(data);
+            }
+            NfcDepPdu::Ack { pni } => frame.push(0x40 | pni),
+            NfcDepPdu::Nack { pni } => frame.push(0x50 | pni),
+            NfcDepPdu::Attention => frame.push(0x80),
+            NfcDepPdu::TimeoutExtension { rtox } => frame.extend_from_slice(&[0x90, *rtox]),
+        }
+        frame
+    }
+}
+
+/// State of the NFC-DEP data exchange with the activated Remote NFC
+/// Endpoint, cf [DIGITAL] Data Exchange Protocol.
+#[derive(Clone, Debug, Default)]
+pub struct NfcDepExchange {
+    /// Packet number expected in the next PDU received from the Remote
+    /// NFC Endpoint. The Initiator sends its PDUs with this number, the
+    /// Target answers with the number of the last PDU received.
+    pni: u8,
+    /// Maximum size of the transport data of one information PDU,
+    /// derived from the negotiated Length Reduction.
+    max_transport_data: usize,
+    /// Transport data of the DH message remaining to be sent.
+    tx_data: Vec<u8>,
+    /// Transport data of the chained information PDUs received so far.
+    rx_data: Vec<u8>,
+    /// Last PDU sent, retransmitted when the Remote NFC Endpoint
+    /// requests it with a NACK PDU.
+    last_pdu: Option<NfcDepPdu>,
+    /// Deadline for the response of the DH to the message received by the
+    /// Target, after which the Target requests a timeout extension.
+    rtox_deadline: Option<time::Instant>,
+}
+
+impl NfcDepExchange {
+    fn new(lr: u8) -> Self {
+        // [DIGITAL] The maximum frame size selected by LR includes
+        // the LEN byte and the CMD0, CMD1 and PFB bytes.
+        let max_frame_size = [64, 128, 192, 254][lr as usize & 0x3];
+        NfcDepExchange { max_transport_data: max_frame_size - 4, ..Default::default() }
+    }
+
+    /// Return the next information PDU of the DH message, sent with the
+    /// packet number `pni`.
+    fn next_information_pdu(&mut self, pni: u8) -> NfcDepPdu {
+        let len = std::cmp::min(self.max_transport_data, self.tx_data.len());
+        let data = self.tx_data.drain(..len).collect();
+        NfcDepPdu::Information { pni, mi: !self.tx_data.is_empty(), data }
+    }
+}
+
 /// State of an NFCC instance.
 #[allow(missing_docs)]
 pub struct State {
+    /// Set once the NFCC has completed a CORE_RESET_CMD, CORE_INIT_CMD
+    /// is rejected until then.
+    pub reset_completed: bool,
     pub config_parameters: ConfigParameters,
-    pub logical_connections: [Option<LogicalConnection>; MAX_LOGICAL_CONNECTIONS as usize],
+    /// Dynamic logical connections, indexed by the offset of the Conn ID
+    /// in the dynamic range. Sized by the maximum number of connections.
+    pub logical_connections: Vec<Option<LogicalConnection>>,
+    /// Credits currently available to the DH on each dynamic logical
+    /// connection.
+    pub logical_connection_credits: Vec<u8>,
+    /// Credits withheld from the DH on each dynamic logical connection
+    /// while credit starvation is enabled.
+    pub withheld_credits: Vec<u8>,
     pub discover_configuration: Vec<nci::DiscoverConfiguration>,
+    /// Number of discovery periods started since the last RF_DISCOVER_CMD.
+    pub discovery_period: usize,
+    /// Start of the next discovery period. Periods last TOTAL_DURATION
+    /// milliseconds, measured with the resolution of the tick handler.
+    pub next_discovery_period: time::Instant,
     pub discover_map: Vec<nci::MappingConfiguration>,
     pub nfcee_state: NfceeState,
+    /// Power supply and communication link configuration of the emulated
+    /// NFCEE requested by the DH.
+    pub nfcee_power_and_link_configuration: nci::NfceePowerAndLinkConfiguration,
     pub rf_state: RfState,
     pub rf_poll_responses: Vec<RfPollResponse>,
     pub rf_activation_parameters: Vec<u8>,
     pub passive_observe_mode: nci::PassiveObserveMode,
-    pub start_time: std::time::Instant,
+    pub start_time: time::Instant,
+    /// Listen mode routing table configured by the DH.
+    pub listen_mode_routing: Vec<nci::ListenModeRoutingEntry>,
+    /// Set when the last RF_SET_LISTEN_MODE_ROUTING_CMD announced more
+    /// routing entries to follow.
+    pub listen_mode_routing_more_to_follow: bool,
+    /// NFCEE selected by the last SELECT command received in listen mode,
+    /// None when the APDUs are routed to the DH.
+    pub listen_mode_route: Option<nci::NfceeId>,
+    /// Deadline of the pending RF_ISO_DEP_NAK_PRESENCE_CMD, None when no
+    /// presence check is in progress.
+    pub presence_check_deadline: Option<time::Instant>,
+    /// Time at which the RF field of a Remote NFC Endpoint in Poll Mode
+    /// was last detected, None when no field is detected.
+    pub rf_field_detected_at: Option<time::Instant>,
+    /// Set once the RF channel is closed. The NFCC keeps serving the NCI
+    /// commands, but Remote NFC Endpoints can no longer be reached.
+    pub rf_closed: bool,
+    /// State of the data exchange on the NFC-DEP RF interface.
+    pub nfc_dep: NfcDepExchange,
+}
+
+/// Handler for proprietary commands. The handler receives the OID and
+/// payload of the command, and returns the payload of the response.
+pub type ProprietaryCommandHandler = Arc<dyn Fn(u8, &[u8]) -> Vec<u8> + Send + Sync>;
+
+/// Handlers registered for ranges of proprietary OIDs.
+#[derive(Clone, Default)]
+pub struct ProprietaryCommandHandlers(Vec<(RangeInclusive<u8>, ProprietaryCommandHandler)>);
+
+impl std::fmt::Debug for ProprietaryCommandHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|(oids, _)| oids)).finish()
+    }
+}
+
+impl ProprietaryCommandHandlers {
+    /// Return the first handler registered for the OID `oid`.
+    fn get(&self, oid: u8) -> Option<&ProprietaryCommandHandler> {
+        self.0.iter().find(|(oids, _)| oids.contains(&oid)).map(|(_, handler)| handler)
+    }
+}
+
+/// Runtime options of an NFCC instance.
+#[derive(Clone, Debug)]
+pub struct ControllerOptions {
+    /// Log the decoded content of the NCI packets exchanged with the DH.
+    pub log_packets: bool,
+    /// Size in bytes of the storage available for variable length
+    /// configuration parameters. CORE_SET_CONFIG_CMD commands exceeding
+    /// this budget are rejected with STATUS_MESSAGE_SIZE_EXCEEDED.
+    pub max_config_storage_size: usize,
+    /// Manufacturer ID reported in CORE_RESET_NTF.
+    pub manufacturer_id: u8,
+    /// Manufacturer specific information reported in CORE_RESET_NTF.
+    pub manufacturer_specific_information: Vec<u8>,
+    /// Keep the configuration parameters on CORE_RESET_CMD with the
+    /// reset type Reset Configuration, while still reporting the
+    /// configuration as reset. Emulates a misbehaving NFCC.
+    pub ignore_reset_config: bool,
+    /// Configuration parameters set at startup, and restored on
+    /// CORE_RESET_CMD with the reset type Reset Configuration.
+    pub config_parameters: ConfigParameters,
+    /// Initial number of credits granted in CORE_CONN_CREATE_RSP for
+    /// connections to a Remote NFC Endpoint. The value 0xff disables
+    /// data flow control on the connection.
+    pub remote_endpoint_credits: u8,
+    /// RF interfaces advertised in CORE_INIT_RSP. RF_DISCOVER_MAP_CMD
+    /// mappings to other interfaces are rejected.
+    pub rf_interfaces: Vec<nci::RfInterfaceType>,
+    /// RF interface extensions advertised in CORE_INIT_RSP, as pairs of
+    /// an RF interface and one of its extensions. The extensions of
+    /// interfaces missing from `rf_interfaces` are not advertised.
+    pub rf_interface_extensions: Vec<(nci::RfInterfaceType, nci::RfInterfaceExtensionType)>,
+    /// Maximum number of mapping configurations held by the NFCC.
+    /// RF_DISCOVER_MAP_CMD commands with more mappings are rejected
+    /// without modifying the current mapping.
+    pub max_discover_map_size: usize,
+    /// Time between two invocations of the tick handler, which drives
+    /// the discovery loop.
+    pub tick_interval: Duration,
+    /// Handlers for the proprietary commands other than the Android
+    /// extensions. Commands without a handler are rejected.
+    pub proprietary_handlers: ProprietaryCommandHandlers,
+    /// Exchange the RF frames of the Frame RF interface with their CRC.
+    /// The NFCC appends the CRC to the frames sent by the DH, and reports
+    /// received frames with an invalid CRC with the status
+    /// STATUS_RF_TRANSMISSION_EXCEPTION.
+    pub rf_frame_crc: bool,
+    /// Emulate a congested NFCC: the credits of dynamic logical connections
+    /// using flow control are withheld, both initially and after each data
+    /// message, until the DH sends the proprietary command with the OID
+    /// `RELEASE_CREDITS_OID`.
+    pub starve_credits: bool,
+    /// Maximum payload size of the Control Packets sent to the DH,
+    /// advertised in CORE_INIT_RSP. Longer messages are segmented.
+    pub max_control_packet_payload_size: u8,
+    /// Maximum payload size of the Data Packets exchanged on the logical
+    /// connections. Longer messages sent to the DH are segmented.
+    pub max_data_packet_payload_size: u8,
+    /// Omit RF_DEACTIVATE_NTF when the DH deactivates an active RF
+    /// interface to RFST_IDLE, and complete the deactivation with the
+    /// response alone. [NCI] requires the notification, this emulates
+    /// NFCCs that do not send it.
+    pub suppress_idle_deactivate_ntf: bool,
+    /// Time taken by the NFCC to come back up after CORE_RESET_CMD,
+    /// between CORE_RESET_RSP and CORE_RESET_NTF. Emulates slow hardware.
+    pub boot_delay: Duration,
+    /// Delay of the RF_DISCOVER_NTF reporting a Remote NFC Endpoint
+    /// polled with the given technology, counted from the end of the
+    /// discovery period. The notifications are sent in order of delay,
+    /// emulating targets detected over time. Technologies without a delay
+    /// are reported immediately, the last entry for a technology applies.
+    pub discover_notification_delays: Vec<(rf::Technology, Duration)>,
+    /// Answer the commands that are not implemented with a response with
+    /// the same GID and OID and an empty payload, instead of aborting.
+    /// Lets the DH go past unimplemented commands during exploratory
+    /// testing; the response does not have the format of the command's.
+    pub lenient: bool,
+    /// Hold back every other response until the response to the next
+    /// command is sent, or until a timeout. Violates the one command at a
+    /// time flow of [NCI] 4.3 to stress the command and response matching
+    /// of the DH, which only sends the next command after a timeout.
+    pub reorder_responses: bool,
+    /// Maximum number of dynamic logical connections advertised in
+    /// CORE_INIT_RSP, between 0 and 14 for the dynamic Conn IDs 2h to Fh.
+    /// CORE_CONN_CREATE_CMD is rejected once all are open.
+    pub max_logical_connections: u8,
+}
+
+impl ControllerOptions {
+    /// Register a handler for the proprietary commands with an OID in the
+    /// range `oids`. Handlers registered first take precedence.
+    pub fn with_proprietary_handler(
+        mut self,
+        oids: RangeInclusive<u8>,
+        handler: impl Fn(u8, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    ) -> Self {
+        self.proprietary_handlers.0.push((oids, Arc::new(handler)));
+        self
+    }
+}
+
+impl Default for ControllerOptions {
+    fn default() -> Self {
+        ControllerOptions {
+            log_packets: false,
+            max_config_storage_size: MAX_CONFIG_STORAGE_SIZE,
+            manufacturer_id: MANUFACTURER_ID,
+            manufacturer_specific_information: MANUFACTURER_SPECIFIC_INFORMATION.to_vec(),
+            ignore_reset_config: false,
+            config_parameters: Default::default(),
+            remote_endpoint_credits: CREDITS_FLOW_CONTROL_DISABLED,
+            rf_interfaces: vec![
+                nci::RfInterfaceType::Frame,
+                nci::RfInterfaceType::IsoDep,
+                nci::RfInterfaceType::NfcDep,
+                nci::RfInterfaceType::NfceeDirect,
+            ],
+            rf_interface_extensions: vec![],
+            max_discover_map_size: MAX_DISCOVER_MAP_SIZE,
+            tick_interval: Duration::from_millis(TICK_INTERVAL),
+            proprietary_handlers: Default::default(),
+            rf_frame_crc: false,
+            starve_credits: false,
+            max_control_packet_payload_size: MAX_CONTROL_PACKET_PAYLOAD_SIZE,
+            max_data_packet_payload_size: MAX_DATA_PACKET_PAYLOAD_SIZE,
+            suppress_idle_deactivate_ntf: false,
+            boot_delay: Duration::ZERO,
+            discover_notification_delays: vec![],
+            lenient: false,
+            reorder_responses: false,
+            max_logical_connections: MAX_LOGICAL_CONNECTIONS,
+        }
+    }
 }
 
 /// State of an NFCC instance.
 pub struct Controller {
     id: u16,
+    options: ControllerOptions,
     nci_writer: nci::Writer,
     rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
     state: Mutex<State>,
+    /// Response held back by the response reordering mode, with the time
+    /// at which it must be sent at the latest.
+    held_response: Mutex<Option<(nci::ControlPacket, time::Instant)>>,
 }
 
 impl ConfigParameters {
+    /// Parse configuration parameters encoded with the TLV format of
+    /// CORE_SET_CONFIG_CMD: Parameter ID (1 octet), Parameter Len
+    /// (1 octet), Parameter Value (Len octets). Parameters absent from
+    /// the encoding keep their default value. Fails if the configuration
+    /// exceeds `max_storage_size` bytes, see
+    /// [`ControllerOptions::max_config_storage_size`].
+    pub fn from_tlv(mut bytes: &[u8], max_storage_size: usize) -> Result<ConfigParameters> {
+        let mut config_parameters = ConfigParameters::default();
+        while let [id, len, rest @ ..] = bytes {
+            let len = *len as usize;
+            if rest.len() < len {
+                anyhow::bail!("truncated value for the config parameter {:#x}", id);
+            }
+            let id = nci::ConfigParameterId::try_from(*id)
+                .map_err(|_| anyhow::anyhow!("invalid config parameter ID {:#x}", id))?;
+            config_parameters
+                .set(id, &rest[..len])
+                .map_err(|err| anyhow::anyhow!("invalid value for {:?}: {}", id, err))?;
+            bytes = &rest[len..];
+        }
+        if !bytes.is_empty() {
+            anyhow::bail!("truncated config parameter header");
+        }
+        if config_parameters.storage_size() > max_storage_size {
+            anyhow::bail!("configuration exceeds the storage size of {} bytes", max_storage_size);
+        }
+        Ok(config_parameters)
+    }
+
+    /// Return the storage size in bytes used by variable length
+    /// configuration parameters.
+    fn storage_size(&self) -> usize {
+        self.pi_b_h_info.len()
+            + self.pn_atr_req_gen_bytes.len()
+            + self.la_nfcid1.len()
+            + self.li_a_hist_by.len()
+            + self.li_b_h_info_resp.len()
+            + self.ln_atr_res_gen_bytes.len()
+            + self.prop1.len()
+            + self.prop2.len()
+            + self.prop3.len()
+            + self.prop4.len()
+            + self.prop5.len()
+    }
+
+    /// Return true if listen mode routing is enabled by the proprietary
+    /// configuration parameter PROP0. Casimir reserves PROP0 for this
+    /// purpose, [NCI] has no parameter enabling listen mode routing.
+    fn listen_mode_routing_enabled(&self) -> bool {
+        self.prop0 != 0
+    }
+
+    /// Return the maximum number of Remote NFC Endpoints detected with
+    /// the technology `technology` in Poll Mode, from the configuration
+    /// parameters PA_DEVICES_LIMIT, PB_DEVICES_LIMIT, PF_DEVICES_LIMIT and
+    /// PV_DEVICES_LIMIT. [NCI] Table 47: when the bail out parameter is
+    /// enabled, the NFCC stops the collision resolution after the first
+    /// Remote NFC Endpoint is detected. A limit of 0 excludes the
+    /// technology from the discovery.
+    fn devices_limit(&self, technology: rf::Technology) -> u8 {
+        let (devices_limit, bail_out) = match technology {
+            rf::Technology::NfcA => (self.pa_devices_limit, self.pa_bail_out),
+            rf::Technology::NfcB => (self.pb_devices_limit, self.pb_bail_out),
+            rf::Technology::NfcF => (self.pf_devices_limit, self.pf_bail_out),
+            rf::Technology::NfcV => (self.pv_devices_limit, 0x00),
+        };
+        if bail_out == 0x01 {
+            std::cmp::min(devices_limit, 1)
+        } else {
+            devices_limit
+        }
+    }
+
+    /// Return true if RF_FIELD_INFO_NTF is enabled by the configuration
+    /// parameter RF_FIELD_INFO.
+    fn rf_field_info_enabled(&self) -> bool {
+        self.rf_field_info == 0x01
+    }
+
     fn get(&self, id: nci::ConfigParameterId) -> Result<Vec<u8>> {
         match id {
             nci::ConfigParameterId::TotalDuration => Ok(self.total_duration.to_le_bytes().to_vec()),
@@ -305,6 +747,12 @@ impl ConfigParameters {
             nci::ConfigParameterId::NfcdepOp => Ok(vec![self.nfcdep_op]),
             nci::ConfigParameterId::LlcpVersion => Ok(vec![self.llcp_version]),
             nci::ConfigParameterId::NfccConfigControl => Ok(vec![self.nfcc_config_control]),
+            nci::ConfigParameterId::Prop0 => Ok(vec![self.prop0]),
+            nci::ConfigParameterId::Prop1 => Ok(self.prop1.clone()),
+            nci::ConfigParameterId::Prop2 => Ok(self.prop2.clone()),
+            nci::ConfigParameterId::Prop3 => Ok(self.prop3.clone()),
+            nci::ConfigParameterId::Prop4 => Ok(self.prop4.clone()),
+            nci::ConfigParameterId::Prop5 => Ok(self.prop5.clone()),
             _ => Err(anyhow::anyhow!("unknown config parameter ID")),
         }
     }
@@ -564,6 +1012,30 @@ impl ConfigParameters {
                 self.nfcc_config_control = u8::from_le_bytes(value.try_into()?);
                 Ok(())
             }
+            nci::ConfigParameterId::Prop0 => {
+                self.prop0 = u8::from_le_bytes(value.try_into()?);
+                Ok(())
+            }
+            nci::ConfigParameterId::Prop1 => {
+                self.prop1 = value.to_vec();
+                Ok(())
+            }
+            nci::ConfigParameterId::Prop2 => {
+                self.prop2 = value.to_vec();
+                Ok(())
+            }
+            nci::ConfigParameterId::Prop3 => {
+                self.prop3 = value.to_vec();
+                Ok(())
+            }
+            nci::ConfigParameterId::Prop4 => {
+                self.prop4 = value.to_vec();
+                Ok(())
+            }
+            nci::ConfigParameterId::Prop5 => {
+                self.prop5 = value.to_vec();
+                Ok(())
+            }
             _ => Err(anyhow::anyhow!("unknown config parameter ID")),
         }
     }
@@ -637,10 +1109,82 @@ impl Default for ConfigParameters {
             nfcdep_op: 0x1f,
             llcp_version: LLCP_VERSION,
             nfcc_config_control: 0x00,
+            prop0: 0x01,
+            prop1: vec![],
+            prop2: vec![],
+            prop3: vec![],
+            prop4: vec![],
+            prop5: vec![],
         }
     }
 }
 
+/// Return true if the value of the routing entry has the expected length.
+/// The value of routing entries starts with Route (1 octet) and Power
+/// State (1 octet), followed by:
+/// - the RF technology (1 octet) for technology based entries,
+/// - the RF protocol (1 octet) for protocol based entries,
+/// - the AID (0 to 16 octets) for AID based entries,
+/// - the FeliCa system code (2 octets) for system code based entries,
+/// - the reference data and mask for APDU pattern based entries.
+fn routing_entry_is_valid(entry: &nci::ListenModeRoutingEntry) -> bool {
+    let len = entry.value.len();
+    match entry.r#type {
+        nci::ListenModeRoutingEntryType::TechnologyBasedRouting => len == 3,
+        nci::ListenModeRoutingEntryType::ProtocolBasedRouting => len == 3,
+        nci::ListenModeRoutingEntryType::AidBasedRouting => (2..=18).contains(&len),
+        nci::ListenModeRoutingEntryType::SystemCodeBasedRouting => len == 4,
+        nci::ListenModeRoutingEntryType::ApduPatternBasedRouting => len >= 2,
+    }
+}
+
+/// Return the response of the emulated NFCEE to an APDU.
+/// The NFCEE does not host any application: SELECT commands are
+/// accepted, and other APDUs are answered with the status word 6D00
+/// (instruction not supported).
+fn nfcee_apdu_response(apdu: &[u8]) -> Vec<u8> {
+    match apdu {
+        [_, 0xa4, 0x04, ..] => vec![0x90, 0x00],
+        _ => vec![0x6d, 0x00],
+    }
+}
+
+/// Return true if the AID routing entry matches the selected AID.
+/// The value of AID routing entries is formatted as Route (1 octet),
+/// Power State (1 octet), AID (0 to 16 octets).
+fn aid_matches(entry: &nci::ListenModeRoutingEntry, aid: &[u8]) -> bool {
+    let [_, _, entry_aid @ ..] = entry.value.as_slice() else {
+        return false;
+    };
+    entry.r#type == nci::ListenModeRoutingEntryType::AidBasedRouting
+        && (entry_aid == aid
+            || (entry.match_longer_aids == nci::FeatureFlag::Enabled && aid.starts_with(entry_aid))
+            || (entry.match_shorter_aids == nci::FeatureFlag::Enabled
+                && entry_aid.starts_with(aid)))
+}
+
+/// Return the technology whose CRC protects the RF frames of the protocol
+/// `rf_protocol`. T1T frames use the CRC_B of NFC-B, although T1T
+/// Listeners are NFC-A Listeners.
+fn frame_crc_technology(
+    rf_protocol: rf::Protocol,
+    rf_technology: rf::Technology,
+) -> rf::Technology {
+    match rf_protocol {
+        rf::Protocol::T1t => rf::Technology::NfcB,
+        _ => rf_technology,
+    }
+}
+
+/// Craft the NFCID3 used by the instance `id` in the ATR_REQ Command and
+/// ATR_RES Response. The NFCID3 is derived from the instance identifier,
+/// which is unique among the devices connected to Casimir.
+fn nfcid3(id: u16) -> Vec<u8> {
+    let mut nfcid3 = vec![0x01, 0xfe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    nfcid3.extend_from_slice(&id.to_be_bytes());
+    nfcid3
+}
+
 impl State {
     /// Craft the NFCID1 used by this instance in NFC-A poll responses.
     /// Returns a dynamically generated NFCID1 (4 byte long and starts with 08h).
@@ -691,13 +1235,72 @@ impl State {
         }
     }
 
+    /// Update the listen mode route for an APDU received from the remote
+    /// reader, and return the NFCEE the APDU is routed to.
+    /// [NCI] 6.3 Listen Mode Routing: the NFCC routes SELECT commands
+    /// with an AID to the matching AID routing entry. The following APDUs
+    /// are routed to the same destination until the next SELECT command.
+    fn route_apdu(&mut self, apdu: &[u8]) -> Option<nci::NfceeId> {
+        // ISO/IEC 7816-4 SELECT by DF name: CLA A4 04 P2 Lc AID.
+        if let [_, 0xa4, 0x04, _, lc, aid @ ..] = apdu {
+            let aid = &aid[..std::cmp::min(*lc as usize, aid.len())];
+            self.listen_mode_route = self
+                .listen_mode_routing
+                .iter()
+                .find(|entry| aid_matches(entry, aid))
+                .and_then(|entry| nci::NfceeId::try_from(entry.value[0]).ok())
+                .filter(|nfcee_id| *nfcee_id != nci::NfceeId::Dh)
+                .filter(|_| self.nfcee_state == NfceeState::Enabled);
+        }
+        self.listen_mode_route
+    }
+
+    /// Close a dynamic logical connection and discard the credits
+    /// associated with it, so that a later connection reusing the same
+    /// Conn ID starts from a clean state.
+    fn close_logical_connection(&mut self, conn_id: u8) {
+        self.logical_connections[conn_id as usize] = None;
+        self.logical_connection_credits[conn_id as usize] = 0;
+        self.withheld_credits[conn_id as usize] = 0;
+    }
+
+    /// Close all logical connections opened with a Remote NFC Endpoint.
+    fn close_remote_connections(&mut self) {
+        for conn_id in 0..self.logical_connections.len() as u8 {
+            if matches!(
+                self.logical_connections[conn_id as usize],
+                Some(LogicalConnection::RemoteNfcEndpoint { .. })
+            ) {
+                self.close_logical_connection(conn_id);
+            }
+        }
+    }
+
     /// Insert a poll response into the discovery list.
     /// The response is not inserted if the device was already discovered
-    /// with the same parameters.
+    /// with the same parameters, or if the devices limit configured for
+    /// the technology is reached by other devices.
     fn add_poll_response(&mut self, poll_response: RfPollResponse) {
-        if !self.rf_poll_responses.contains(&poll_response) {
-            self.rf_poll_responses.push(poll_response);
+        if self.rf_poll_responses.contains(&poll_response) {
+            return;
+        }
+        let mut devices = self
+            .rf_poll_responses
+            .iter()
+            .filter(|r| r.rf_technology == poll_response.rf_technology)
+            .map(|r| r.id)
+            .collect::<Vec<_>>();
+        devices.sort();
+        devices.dedup();
+        let devices_limit = self.config_parameters.devices_limit(poll_response.rf_technology);
+        if !devices.contains(&poll_response.id) && devices.len() >= devices_limit as usize {
+            debug!(
+                "ignoring poll response from #{}: limit of {} {:?} devices reached",
+                poll_response.id, devices_limit, poll_response.rf_technology
+            );
+            return;
         }
+        self.rf_poll_responses.push(poll_response);
     }
 }
 
@@ -705,41 +1308,163 @@ impl Controller {
     /// Create a new NFCC instance with default configuration.
     pub fn new(
         id: u16,
+        options: ControllerOptions,
         nci_writer: nci::Writer,
         rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
     ) -> Controller {
         Controller {
             id,
-            nci_writer,
+            nci_writer: nci_writer.with_max_payload_sizes(
+                options.max_control_packet_payload_size,
+                options.max_data_packet_payload_size,
+            ),
             rf_tx,
             state: Mutex::new(State {
-                config_parameters: Default::default(),
-                logical_connections: [None; MAX_LOGICAL_CONNECTIONS as usize],
+                reset_completed: false,
+                config_parameters: options.config_parameters.clone(),
+                logical_connections: vec![None; options.max_logical_connections as usize],
+                logical_connection_credits: vec![0; options.max_logical_connections as usize],
+                withheld_credits: vec![0; options.max_logical_connections as usize],
                 discover_map: vec![],
                 discover_configuration: vec![],
+                discovery_period: 0,
+                next_discovery_period: time::Instant::now(),
                 nfcee_state: NfceeState::Disabled,
+                nfcee_power_and_link_configuration:
+                    nci::NfceePowerAndLinkConfiguration::NfccDecides,
                 rf_state: RfState::Idle,
                 rf_poll_responses: vec![],
                 rf_activation_parameters: vec![],
                 passive_observe_mode: nci::PassiveObserveMode::Disable,
-                start_time: Instant::now(),
+                start_time: time::Instant::now(),
+                listen_mode_routing: vec![],
+                listen_mode_routing_more_to_follow: false,
+                listen_mode_route: None,
+                presence_check_deadline: None,
+                rf_field_detected_at: None,
+                rf_closed: false,
+                nfc_dep: Default::default(),
             }),
+            held_response: Mutex::new(None),
+            options,
+        }
+    }
+
+    /// Return a human readable summary of the NFCC state,
+    /// used for debugging wedged test sessions.
+    pub async fn describe(&self) -> String {
+        use std::fmt::Write;
+        let state = self.state.lock().await;
+        let mut out = String::new();
+        let _ = writeln!(out, "[{}] NFCC state:", self.id);
+        let _ = writeln!(out, "  RF state: {:?}", state.rf_state);
+        for (index, connection) in state.logical_connections.iter().enumerate() {
+            if let Some(connection) = connection {
+                let conn_id = nci::ConnId::from_dynamic(index as u8);
+                let _ = writeln!(
+                    out,
+                    "  Logical connection {:?}: {:?}, credits: {}, withheld credits: {}",
+                    conn_id,
+                    connection,
+                    state.logical_connection_credits[index],
+                    state.withheld_credits[index]
+                );
+            }
         }
+        let _ = writeln!(out, "  Discover configuration: {:?}", state.discover_configuration);
+        let _ = writeln!(out, "  Discover map: {:?}", state.discover_map);
+        let _ = writeln!(
+            out,
+            "  NFCEE 0x86: {:?}, {:?}",
+            state.nfcee_state, state.nfcee_power_and_link_configuration
+        );
+        let _ = writeln!(out, "  Passive observe mode: {:?}", state.passive_observe_mode);
+        let _ = write!(out, "  Config parameters: {:?}", state.config_parameters);
+        out
     }
 
     async fn send_control(&self, packet: impl Into<nci::ControlPacket>) -> Result<()> {
-        self.nci_writer.write(&packet.into().to_vec()).await
+        let packet = packet.into();
+        if self.options.reorder_responses && packet.get_mt() == nci::MessageType::Response {
+            let mut held_response = self.held_response.lock().await;
+            match held_response.take() {
+                // The held response is sent after the response to the
+                // next command.
+                Some((held_packet, _)) => {
+                    self.write_control(packet).await?;
+                    info!("[{}] sending reordered response", self.id);
+                    return self.write_control(held_packet).await;
+                }
+                None => {
+                    info!("[{}] holding back response", self.id);
+                    let deadline =
+                        time::Instant::now() + Duration::from_millis(REORDERED_RESPONSE_TIMEOUT);
+                    *held_response = Some((packet, deadline));
+                    return Ok(());
+                }
+            }
+        }
+        self.write_control(packet).await
+    }
+
+    async fn write_control(&self, packet: nci::ControlPacket) -> Result<()> {
+        if self.options.log_packets {
+            debug!("[{}] NCI TX {:?}", self.id, packet);
+        }
+        self.nci_writer.write_and_flush(&packet.to_vec()).await
     }
 
     async fn send_data(&self, packet: impl Into<nci::DataPacket>) -> Result<()> {
-        self.nci_writer.write(&packet.into().to_vec()).await
+        let packet = packet.into();
+        if self.options.log_packets {
+            debug!("[{}] NCI TX {:?}", self.id, packet);
+        }
+        self.nci_writer.write_and_flush(&packet.to_vec()).await
     }
 
     async fn send_rf(&self, packet: impl Into<rf::RfPacket>) -> Result<()> {
-        self.rf_tx.send(packet.into())?;
+        if self.rf_tx.send(packet.into()).is_err() {
+            debug!("[{}] rf_tx channel closed, dropping RF packet", self.id);
+        }
         Ok(())
     }
 
+    /// Handle the closing of the RF channel. The active RF interface, if
+    /// any, is deactivated as after a loss of the RF link.
+    async fn rf_closed(&self) -> Result<()> {
+        warn!("[{}] rf_rx channel closed, RF is no longer available", self.id);
+
+        let peer = {
+            let mut state = self.state.lock().await;
+            state.rf_closed = true;
+            match state.rf_state {
+                RfState::PollActive { id, rf_technology, rf_protocol, .. }
+                | RfState::ListenActive { id, rf_technology, rf_protocol, .. } => {
+                    Some((id, rf_technology, rf_protocol))
+                }
+                _ => None,
+            }
+        };
+
+        match peer {
+            Some((id, technology, protocol)) => {
+                self.deactivate_notification(
+                    rf::DeactivateNotificationBuilder {
+                        type_: rf::DeactivateType::Discovery,
+                        reason: rf::DeactivateReason::RfLinkLoss,
+                        sender: id,
+                        receiver: self.id,
+                        technology,
+                        protocol,
+                    }
+                    .build(),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+
     async fn core_reset(&self, cmd: nci::CoreResetCommand) -> Result<()> {
         info!("[{}] CORE_RESET_CMD", self.id);
         info!("         ResetType: {:?}", cmd.get_reset_type());
@@ -748,20 +1473,41 @@ impl Controller {
 
         match cmd.get_reset_type() {
             nci::ResetType::KeepConfig => (),
-            nci::ResetType::ResetConfig => state.config_parameters = Default::default(),
+            nci::ResetType::ResetConfig if self.options.ignore_reset_config => {
+                warn!("[{}] ignoring configuration reset", self.id)
+            }
+            nci::ResetType::ResetConfig => {
+                state.config_parameters = self.options.config_parameters.clone()
+            }
         }
 
-        for i in 0..MAX_LOGICAL_CONNECTIONS {
-            state.logical_connections[i as usize] = None;
+        for conn_id in 0..state.logical_connections.len() as u8 {
+            state.close_logical_connection(conn_id);
         }
 
         state.discover_map.clear();
         state.discover_configuration.clear();
         state.rf_state = RfState::Idle;
         state.rf_poll_responses.clear();
+        state.rf_activation_parameters.clear();
+        state.listen_mode_routing.clear();
+        state.listen_mode_routing_more_to_follow = false;
+        state.listen_mode_route = None;
+        state.presence_check_deadline = None;
+        state.nfc_dep = Default::default();
+        state.nfcee_power_and_link_configuration = nci::NfceePowerAndLinkConfiguration::NfccDecides;
+        state.reset_completed = true;
 
         self.send_control(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await?;
 
+        // [NCI] 4.1 Reset of the NFCC
+        // CORE_RESET_NTF indicates that the NFCC is ready to receive
+        // CORE_INIT_CMD. Commands are not processed during the boot delay.
+        drop(state);
+        if !self.options.boot_delay.is_zero() {
+            time::sleep(self.options.boot_delay).await;
+        }
+
         self.send_control(nci::CoreResetNotificationBuilder {
             trigger: nci::ResetTrigger::ResetCommand,
             config_status: match cmd.get_reset_type() {
@@ -769,67 +1515,115 @@ impl Controller {
                 nci::ResetType::ResetConfig => nci::ConfigStatus::ConfigReset,
             },
             nci_version: NCI_VERSION,
-            manufacturer_id: MANUFACTURER_ID,
-            manufacturer_specific_information: MANUFACTURER_SPECIFIC_INFORMATION.to_vec(),
+            manufacturer_id: self.options.manufacturer_id,
+            manufacturer_specific_information: self
+                .options
+                .manufacturer_specific_information
+                .clone(),
         })
         .await?;
 
         Ok(())
     }
 
-    async fn core_init(&self, _cmd: nci::CoreInitCommand) -> Result<()> {
+    async fn core_init(&self, cmd: nci::CoreInitCommand) -> Result<()> {
         info!("[{}] CORE_INIT_CMD", self.id);
+        let feature_enable = cmd.get_feature_enable();
+        info!("         Feature Enable: {:?}", feature_enable);
+
+        let state = self.state.lock().await;
+        if !state.reset_completed {
+            // [NCI] 4.2 Initialization
+            // The DH SHALL send CORE_INIT_CMD only after the NFCC has been
+            // reset. The response carries no additional fields on error.
+            warn!("[{}] core_init received before core_reset", self.id);
+            self.send_control(nci::CorePacketBuilder {
+                mt: nci::MessageType::Response,
+                oid: nci::CoreOpcodeId::Init,
+                payload: Some(bytes::Bytes::from(vec![nci::Status::SemanticError.into()])),
+            })
+            .await?;
+            return Ok(());
+        }
+
+        let routing = if state.config_parameters.listen_mode_routing_enabled() {
+            nci::FeatureFlag::Enabled
+        } else {
+            nci::FeatureFlag::Disabled
+        };
 
         self.send_control(nci::CoreInitResponseBuilder {
             status: nci::Status::Ok,
             nfcc_features: nci::NfccFeatures {
-                discovery_frequency_configuration: nci::FeatureFlag::Disabled,
+                discovery_frequency_configuration: feature_enable.discovery_frequency_configuration,
                 discovery_configuration_mode: nci::DiscoveryConfigurationMode::DhOnly,
                 hci_network_support: nci::FeatureFlag::Enabled,
                 active_communication_mode: nci::FeatureFlag::Enabled,
-                technology_based_routing: nci::FeatureFlag::Enabled,
-                protocol_based_routing: nci::FeatureFlag::Enabled,
-                aid_based_routing: nci::FeatureFlag::Enabled,
-                system_code_based_routing: nci::FeatureFlag::Enabled,
-                apdu_pattern_based_routing: nci::FeatureFlag::Enabled,
-                forced_nfcee_routing: nci::FeatureFlag::Enabled,
-                battery_off_state: nci::FeatureFlag::Disabled,
+                technology_based_routing: routing,
+                protocol_based_routing: routing,
+                aid_based_routing: routing,
+                system_code_based_routing: routing,
+                apdu_pattern_based_routing: routing,
+                forced_nfcee_routing: routing,
+                battery_off_state: feature_enable.battery_off_state,
                 switched_off_state: nci::FeatureFlag::Enabled,
                 switched_on_substates: nci::FeatureFlag::Enabled,
-                rf_configuration_in_switched_off_state: nci::FeatureFlag::Disabled,
+                rf_configuration_in_switched_off_state: feature_enable
+                    .rf_configuration_in_switched_off_state,
                 proprietary_capabilities: 0,
             },
-            max_logical_connections: MAX_LOGICAL_CONNECTIONS,
+            max_logical_connections: self.options.max_logical_connections,
             max_routing_table_size: MAX_ROUTING_TABLE_SIZE,
-            max_control_packet_payload_size: MAX_CONTROL_PACKET_PAYLOAD_SIZE,
-            max_data_packet_payload_size: MAX_DATA_PACKET_PAYLOAD_SIZE,
+            max_control_packet_payload_size: self.options.max_control_packet_payload_size,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
             number_of_credits: NUMBER_OF_CREDITS,
             max_nfcv_rf_frame_size: MAX_NFCV_RF_FRAME_SIZE,
-            supported_rf_interfaces: vec![
-                nci::RfInterface { interface: nci::RfInterfaceType::Frame, extensions: vec![] },
-                nci::RfInterface { interface: nci::RfInterfaceType::IsoDep, extensions: vec![] },
-                nci::RfInterface { interface: nci::RfInterfaceType::NfcDep, extensions: vec![] },
-                nci::RfInterface {
-                    interface: nci::RfInterfaceType::NfceeDirect,
-                    extensions: vec![],
-                },
-            ],
+            supported_rf_interfaces: self
+                .options
+                .rf_interfaces
+                .iter()
+                .map(|interface| nci::RfInterface {
+                    interface: *interface,
+                    extensions: self.rf_interface_extensions(*interface),
+                })
+                .collect(),
         })
         .await?;
 
         Ok(())
     }
 
+    /// Return the extensions advertised for the RF interface `interface`,
+    /// without duplicates.
+    fn rf_interface_extensions(
+        &self,
+        interface: nci::RfInterfaceType,
+    ) -> Vec<nci::RfInterfaceExtensionType> {
+        let mut extensions = vec![];
+        for (_, extension) in
+            self.options.rf_interface_extensions.iter().filter(|(i, _)| *i == interface)
+        {
+            if !extensions.contains(extension) {
+                extensions.push(*extension);
+            }
+        }
+        extensions
+    }
+
     async fn core_set_config(&self, cmd: nci::CoreSetConfigCommand) -> Result<()> {
         info!("[{}] CORE_SET_CONFIG_CMD", self.id);
 
         let mut state = self.state.lock().await;
+        let mut config_parameters = state.config_parameters.clone();
         let mut invalid_parameters = vec![];
         for parameter in cmd.get_parameters().iter() {
             info!("         Type: {:?}", parameter.id);
             info!("         Value: {:?}", parameter.value);
             match parameter.id {
-                nci::ConfigParameterId::Rfu(_) => invalid_parameters.push(parameter.id),
+                nci::ConfigParameterId::Rfu(_) => {
+                    warn!("[{}] rejecting reserved parameter id {:?}", self.id, parameter.id);
+                    invalid_parameters.push(parameter.id)
+                }
                 // TODO(henrichataing):
                 // [NCI] 5.2.1 State RFST_IDLE
                 // Unless otherwise specified, discovery related configuration
@@ -843,13 +1637,40 @@ impl Controller {
                 // with a Status value of STATUS_SEMANTIC_ERROR and no
                 // additional fields.
                 _ => {
-                    if state.config_parameters.set(parameter.id, &parameter.value).is_err() {
+                    // Parameters that are unknown, read-only, or that have a
+                    // value of invalid length are rejected. The other
+                    // parameters are applied regardless.
+                    if let Err(err) = config_parameters.set(parameter.id, &parameter.value) {
+                        warn!(
+                            "[{}] rejecting parameter {:?} with {}-byte value: {}",
+                            self.id,
+                            parameter.id,
+                            parameter.value.len(),
+                            err
+                        );
                         invalid_parameters.push(parameter.id)
                     }
                 }
             }
         }
 
+        // The new configuration is discarded altogether when it does not fit
+        // in the NFCC storage, the previous configuration is kept unchanged.
+        if config_parameters.storage_size() > self.options.max_config_storage_size {
+            warn!(
+                "[{}] rejecting configuration exceeding the storage size of {} bytes",
+                self.id, self.options.max_config_storage_size
+            );
+            self.send_control(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::MessageSizeExceeded,
+                parameters: vec![],
+            })
+            .await?;
+            return Ok(());
+        }
+
+        state.config_parameters = config_parameters;
+
         self.send_control(nci::CoreSetConfigResponseBuilder {
             status: if invalid_parameters.is_empty() {
                 // A Status of STATUS_OK SHALL indicate that all configuration parameters
@@ -862,7 +1683,7 @@ impl Controller {
                 // All other configuration parameters SHALL have been set to the new values
                 // in the NFCC.
                 warn!(
-                    "[{}] rejecting unknown configuration parameter ids: {:?}",
+                    "[{}] rejecting configuration parameter ids: {:?}",
                     self.id, invalid_parameters
                 );
                 nci::Status::InvalidParam
@@ -878,37 +1699,33 @@ impl Controller {
         info!("[{}] CORE_GET_CONFIG_CMD", self.id);
 
         let state = self.state.lock().await;
-        let mut valid_parameters = vec![];
-        let mut invalid_parameters = vec![];
+        let mut parameters = vec![];
+        let mut status = nci::Status::Ok;
         for id in cmd.get_parameters() {
             info!("         ID: {:?}", id);
             match state.config_parameters.get(*id) {
-                Ok(value) => {
-                    valid_parameters.push(nci::ConfigParameter { id: *id, value: value.to_vec() })
+                Ok(value) => parameters.push(nci::ConfigParameter { id: *id, value }),
+                Err(_) => {
+                    status = nci::Status::InvalidParam;
+                    parameters.push(nci::ConfigParameter { id: *id, value: vec![] })
                 }
-                Err(_) => invalid_parameters.push(nci::ConfigParameter { id: *id, value: vec![] }),
             }
         }
 
-        self.send_control(if invalid_parameters.is_empty() {
-            // If the NFCC is able to respond with all requested parameters, the
-            // NFCC SHALL respond with the CORE_GET_CONFIG_RSP with a Status
-            // of STATUS_OK.
-            nci::CoreGetConfigResponseBuilder {
-                status: nci::Status::Ok,
-                parameters: valid_parameters,
-            }
-        } else {
-            // If the DH tries to retrieve any parameter(s) that are not available
-            // in the NFCC, the NFCC SHALL respond with a CORE_GET_CONFIG_RSP with
-            // a Status field of STATUS_INVALID_PARAM, containing each unavailable
-            // Parameter ID with a Parameter Len field of value zero.
-            nci::CoreGetConfigResponseBuilder {
-                status: nci::Status::InvalidParam,
-                parameters: invalid_parameters,
-            }
-        })
-        .await?;
+        // If the NFCC is able to respond with all requested parameters, the
+        // NFCC SHALL respond with the CORE_GET_CONFIG_RSP with a Status
+        // of STATUS_OK.
+        //
+        // If the DH tries to retrieve any parameter(s) that are not available
+        // in the NFCC, the NFCC SHALL respond with a CORE_GET_CONFIG_RSP with
+        // a Status field of STATUS_INVALID_PARAM, containing each unavailable
+        // Parameter ID with a Parameter Len field of value zero.
+        // Like some NFCC implementations, the available parameters are
+        // returned as well, all parameters are kept in the requested order.
+        if status != nci::Status::Ok {
+            warn!("[{}] CORE_GET_CONFIG_CMD requested unavailable parameters", self.id);
+        }
+        self.send_control(nci::CoreGetConfigResponseBuilder { status, parameters }).await?;
 
         Ok(())
     }
@@ -917,12 +1734,22 @@ impl Controller {
         info!("[{}] CORE_CONN_CREATE_CMD", self.id);
 
         let mut state = self.state.lock().await;
-        let result: std::result::Result<u8, nci::Status> = (|| {
+        let result: std::result::Result<(u8, LogicalConnection), nci::Status> = (|| {
             // Retrieve an unused connection ID for the logical connection.
+            // Running out of connections is reported with STATUS_REJECTED,
+            // which the DH stacks interpret as a lack of capacity; this check
+            // runs first so that it takes precedence over destination errors.
             let conn_id = {
-                (0..MAX_LOGICAL_CONNECTIONS)
+                (0..state.logical_connections.len() as u8)
                     .find(|conn_id| state.logical_connections[*conn_id as usize].is_none())
-                    .ok_or(nci::Status::Rejected)?
+                    .ok_or_else(|| {
+                        warn!(
+                            "[{}] rejecting logical connection: maximum of {} connections reached",
+                            self.id,
+                            state.logical_connections.len()
+                        );
+                        nci::Status::Rejected
+                    })?
             };
 
             // Check that the selected destination type is supported and validate
@@ -954,35 +1781,83 @@ impl Controller {
                         rf_protocol_type: rf_protocol_type.ok_or(nci::Status::Rejected)?,
                     }
                 }
-                nci::DestinationType::NfccLoopback | nci::DestinationType::Nfcee => {
-                    return Err(nci::Status::Rejected)
+                // If the value of Destination Type is that of an NFCEE (0x03),
+                // then only the Destination-specific Parameter with Type 0x01
+                // or proprietary parameters SHALL be present.
+                nci::DestinationType::Nfcee => {
+                    let mut nfcee: Option<(nci::NfceeId, nci::NfceeProtocolType)> = None;
+
+                    for parameter in cmd.get_parameters() {
+                        match (parameter.id, parameter.value.as_slice()) {
+                            (
+                                nci::DestinationSpecificParameterId::Nfcee,
+                                [nfcee_id, nfcee_protocol_type],
+                            ) => {
+                                nfcee = nci::NfceeId::try_from(*nfcee_id).ok().zip(
+                                    nci::NfceeProtocolType::try_from(*nfcee_protocol_type).ok(),
+                                )
+                            }
+                            _ => return Err(nci::Status::Rejected),
+                        }
+                    }
+
+                    // The emulated NFCEE only exchanges APDUs, and must
+                    // be enabled to accept a connection.
+                    match nfcee {
+                        Some((nfcee_id, nfcee_protocol_type @ nci::NfceeProtocolType::Apdu))
+                            if nfcee_id == nci::NfceeId::hci_nfcee(0x86)
+                                && state.nfcee_state == NfceeState::Enabled =>
+                        {
+                            LogicalConnection::Nfcee { nfcee_id, nfcee_protocol_type }
+                        }
+                        _ => return Err(nci::Status::Rejected),
+                    }
                 }
+                nci::DestinationType::NfccLoopback => return Err(nci::Status::Rejected),
             };
 
             // The combination of Destination Type and Destination Specific
             // Parameters SHALL uniquely identify a single destination for the
             // Logical Connection.
+            // A second connection to the same destination is reported with
+            // a distinct status from the lack of available connections.
             if state.logical_connections.iter().any(|c| c.as_ref() == Some(&logical_connection)) {
-                return Err(nci::Status::Rejected);
+                warn!(
+                    "[{}] rejecting logical connection: destination {:?} is already connected",
+                    self.id, logical_connection
+                );
+                return Err(nci::Status::SemanticError);
             }
 
             // Create the connection.
             state.logical_connections[conn_id as usize] = Some(logical_connection);
+            let credits = self.initial_credits(&logical_connection);
+            if self.options.starve_credits && credits != CREDITS_FLOW_CONTROL_DISABLED {
+                state.logical_connection_credits[conn_id as usize] = 0;
+                state.withheld_credits[conn_id as usize] = credits;
+            } else {
+                state.logical_connection_credits[conn_id as usize] = credits;
+                state.withheld_credits[conn_id as usize] = 0;
+            }
 
-            Ok(conn_id)
+            Ok((conn_id, logical_connection))
         })();
 
         self.send_control(match result {
-            Ok(conn_id) => nci::CoreConnCreateResponseBuilder {
+            Ok((conn_id, _)) => nci::CoreConnCreateResponseBuilder {
                 status: nci::Status::Ok,
-                max_data_packet_payload_size: MAX_DATA_PACKET_PAYLOAD_SIZE,
-                initial_number_of_credits: 0xff,
+                max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+                // One credit is granted back for each data message received
+                // on the connection, cf `dynamic_conn_data`.
+                initial_number_of_credits: state.logical_connection_credits[conn_id as usize],
                 conn_id: nci::ConnId::from_dynamic(conn_id),
             },
+            // The remaining fields carry no information on failure:
+            // no connection is reserved, and no data can be sent.
             Err(status) => nci::CoreConnCreateResponseBuilder {
                 status,
                 max_data_packet_payload_size: 0,
-                initial_number_of_credits: 0xff,
+                initial_number_of_credits: CREDITS_FLOW_CONTROL_DISABLED,
                 conn_id: 0.try_into().unwrap(),
             },
         })
@@ -1007,7 +1882,7 @@ impl Controller {
             nci::ConnId::Dynamic(id) => nci::ConnId::to_dynamic(id),
         };
 
-        let status = if conn_id >= MAX_LOGICAL_CONNECTIONS
+        let status = if conn_id as usize >= state.logical_connections.len()
             || state.logical_connections[conn_id as usize].is_none()
         {
             // If there is no connection associated to the Conn ID in the CORE_CONN_CLOSE_CMD, the
@@ -1018,7 +1893,7 @@ impl Controller {
             // When it receives a CORE_CONN_CLOSE_CMD for an existing connection, the NFCC SHALL
             // accept the connection closure request by sending a CORE_CONN_CLOSE_RSP with a Status of
             // STATUS_OK, and the Logical Connection is closed.
-            state.logical_connections[conn_id as usize] = None;
+            state.close_logical_connection(conn_id);
             nci::Status::Ok
         };
 
@@ -1041,20 +1916,61 @@ impl Controller {
         info!("[{}] RF_DISCOVER_MAP_CMD", self.id);
 
         let mut state = self.state.lock().await;
-        state.discover_map = cmd.get_mapping_configurations().clone();
-        self.send_control(nci::RfDiscoverMapResponseBuilder { status: nci::Status::Ok }).await?;
+        let mapping_configurations = cmd.get_mapping_configurations();
+        let status = if mapping_configurations.len() > self.options.max_discover_map_size {
+            warn!(
+                "[{}] rejecting {} mapping configurations: maximum of {} mappings exceeded",
+                self.id,
+                mapping_configurations.len(),
+                self.options.max_discover_map_size
+            );
+            nci::Status::Rejected
+        } else if let Some(mapping) = mapping_configurations
+            .iter()
+            .find(|mapping| !self.options.rf_interfaces.contains(&mapping.rf_interface))
+        {
+            warn!("[{}] RF interface {:?} is not supported", self.id, mapping.rf_interface);
+            nci::Status::Rejected
+        } else {
+            state.discover_map = mapping_configurations.clone();
+            nci::Status::Ok
+        };
+        self.send_control(nci::RfDiscoverMapResponseBuilder { status }).await?;
 
         Ok(())
     }
 
     async fn rf_set_listen_mode_routing(
         &self,
-        _cmd: nci::RfSetListenModeRoutingCommand,
+        cmd: nci::RfSetListenModeRoutingCommand,
     ) -> Result<()> {
         info!("[{}] RF_SET_LISTEN_MODE_ROUTING_CMD", self.id);
+        info!("         More To Follow: {}", cmd.get_more_to_follow());
+        for entry in cmd.get_routing_entries() {
+            info!("         Entry: {:?} {:02x?}", entry.r#type, entry.value);
+        }
 
-        self.send_control(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
-            .await?;
+        let mut state = self.state.lock().await;
+        let status = if !state.config_parameters.listen_mode_routing_enabled() {
+            warn!("[{}] listen mode routing is disabled by configuration", self.id);
+            nci::Status::Rejected
+        } else if let Some(entry) =
+            cmd.get_routing_entries().iter().find(|entry| !routing_entry_is_valid(entry))
+        {
+            warn!("[{}] invalid listen mode routing entry {:?}", self.id, entry);
+            nci::Status::SyntaxError
+        } else {
+            // The routing table is replaced by the entries of the
+            // command sequence ending with More To Follow set to 0.
+            if !state.listen_mode_routing_more_to_follow {
+                state.listen_mode_routing.clear();
+            }
+            state.listen_mode_routing.extend(cmd.get_routing_entries().iter().cloned());
+            state.listen_mode_routing_more_to_follow = cmd.get_more_to_follow() != 0;
+            nci::Status::Ok
+        };
+
+        self.send_control(nci::RfSetListenModeRoutingResponseBuilder { status }).await?;
 
         Ok(())
     }
@@ -1065,12 +1981,37 @@ impl Controller {
     ) -> Result<()> {
         info!("[{}] RF_GET_LISTEN_MODE_ROUTING_CMD", self.id);
 
-        self.send_control(nci::RfGetListenModeRoutingResponseBuilder {
-            status: nci::Status::Ok,
-            more_to_follow: 0,
-            routing_entries: vec![],
-        })
-        .await?;
+        let state = self.state.lock().await;
+        self.send_control(nci::RfGetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
+            .await?;
+
+        // [NCI] Reading the current Listen Mode Routing
+        // The routing table is returned in one or more
+        // RF_GET_LISTEN_MODE_ROUTING_NTF, each fitting in a single
+        // Control Packet. More To Follow is set on all but the last one.
+        const HEADER_SIZE: usize = 2;
+        const ENTRY_HEADER_SIZE: usize = 2;
+        let max_payload_size = self.options.max_control_packet_payload_size as usize;
+        let mut chunks: Vec<Vec<nci::ListenModeRoutingEntry>> = vec![vec![]];
+        let mut chunk_size = HEADER_SIZE;
+        for entry in state.listen_mode_routing.iter() {
+            let entry_size = ENTRY_HEADER_SIZE + entry.value.len();
+            if chunk_size + entry_size > max_payload_size && !chunks.last().unwrap().is_empty() {
+                chunks.push(vec![]);
+                chunk_size = HEADER_SIZE;
+            }
+            chunks.last_mut().unwrap().push(entry.clone());
+            chunk_size += entry_size;
+        }
+
+        let last_index = chunks.len() - 1;
+        for (index, routing_entries) in chunks.into_iter().enumerate() {
+            self.send_control(nci::RfGetListenModeRoutingNotificationBuilder {
+                more_to_follow: (index != last_index) as u8,
+                routing_entries,
+            })
+            .await?;
+        }
 
         Ok(())
     }
@@ -1082,6 +2023,12 @@ impl Controller {
         }
 
         let mut state = self.state.lock().await;
+        if state.rf_closed {
+            warn!("[{}] rf_discover received after the RF channel was closed", self.id);
+            self.send_control(nci::RfDiscoverResponseBuilder { status: nci::Status::Failed })
+                .await?;
+            return Ok(());
+        }
         if state.rf_state != RfState::Idle {
             warn!("[{}] rf_discover received in {:?} state", self.id, state.rf_state);
             self.send_control(nci::RfDiscoverResponseBuilder {
@@ -1092,6 +2039,8 @@ impl Controller {
         }
 
         state.discover_configuration = cmd.get_configurations().clone();
+        state.discovery_period = 0;
+        state.next_discovery_period = time::Instant::now();
         state.rf_state = RfState::Discovery;
 
         self.send_control(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await?;
@@ -1149,6 +2098,25 @@ impl Controller {
             return Ok(());
         }
 
+        // The selected interface must be advertised in CORE_INIT_RSP and
+        // compatible with the selected protocol. The Frame RF Interface
+        // is compatible with all protocols.
+        let rf_interface_valid = self.options.rf_interfaces.contains(&cmd.get_rf_interface())
+            && matches!(
+                (cmd.get_rf_interface(), cmd.get_rf_protocol()),
+                (nci::RfInterfaceType::Frame, _)
+                    | (nci::RfInterfaceType::IsoDep, nci::RfProtocolType::IsoDep)
+                    | (nci::RfInterfaceType::NfcDep, nci::RfProtocolType::NfcDep)
+            );
+        if !rf_interface_valid {
+            warn!("[{}] rf_discover_select with invalid rf_interface", self.id);
+            self.send_control(nci::RfDiscoverSelectResponseBuilder {
+                status: nci::Status::Rejected,
+            })
+            .await?;
+            return Ok(());
+        }
+
         self.send_control(nci::RfDiscoverSelectResponseBuilder { status: nci::Status::Ok }).await?;
 
         // Send RF select command to the peer to activate the device.
@@ -1201,11 +2169,27 @@ impl Controller {
         // completing if a remote device is being selected.
         (next_state, state.rf_state) = (state.rf_state, next_state);
 
+        // The pending presence check is abandoned with the RF interface.
+        if next_state != state.rf_state {
+            state.presence_check_deadline = None;
+        }
+
+        // Forget the Remote NFC Endpoints discovered so far when the
+        // discovery is stopped.
+        if state.rf_state == RfState::Idle {
+            state.rf_poll_responses.clear();
+        }
+
         self.send_control(nci::RfDeactivateResponseBuilder { status }).await?;
 
         // Deactivate the active RF interface if applicable
         // (next_state is the previous state in this context).
+        let suppress_ntf =
+            self.options.suppress_idle_deactivate_ntf && cmd.get_deactivation_type() == IdleMode;
         match next_state {
+            RfState::PollActive { .. } | RfState::ListenActive { .. } if suppress_ntf => {
+                debug!("[{}] RF_DEACTIVATE_NTF suppressed", self.id)
+            }
             RfState::PollActive { .. } | RfState::ListenActive { .. } => {
                 info!("[{}] RF_DEACTIVATE_NTF", self.id);
                 info!("         Type: {:?}", cmd.get_deactivation_type());
@@ -1216,6 +2200,9 @@ impl Controller {
                 })
                 .await?
             }
+            // No RF_DEACTIVATE_NTF is sent when the discovery is stopped
+            // before an RF interface was activated, the response completes
+            // the transition to RFST_IDLE.
             _ => (),
         }
 
@@ -1239,17 +2226,52 @@ impl Controller {
         Ok(())
     }
 
-    async fn nfcee_discover(&self, _cmd: nci::NfceeDiscoverCommand) -> Result<()> {
-        info!("[{}] NFCEE_DISCOVER_CMD", self.id);
-
-        self.send_control(nci::NfceeDiscoverResponseBuilder {
-            status: nci::Status::Ok,
-            number_of_nfcees: 1,
-        })
-        .await?;
+    async fn rf_iso_dep_nak_presence(&self, _cmd: nci::RfIsoDepNakPresenceCommand) -> Result<()> {
+        info!("[{}] RF_ISO_DEP_NAK_PRESENCE_CMD", self.id);
 
-        self.send_control(nci::NfceeDiscoverNotificationBuilder {
-            nfcee_id: nci::NfceeId::hci_nfcee(0x86),
+        // The presence check is only available when the ISO-DEP RF
+        // Interface is activated in Poll mode, one at a time.
+        let mut state = self.state.lock().await;
+        let target = match state.rf_state {
+            RfState::PollActive {
+                id,
+                rf_technology,
+                rf_protocol: rf::Protocol::IsoDep,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+            } if state.presence_check_deadline.is_none() => Some((id, rf_technology)),
+            _ => None,
+        };
+        let status = if target.is_some() { nci::Status::Ok } else { nci::Status::SemanticError };
+
+        self.send_control(nci::RfIsoDepNakPresenceResponseBuilder { status }).await?;
+
+        // Send the R(NAK) Block, the notification is sent when the Remote
+        // NFC Endpoint answers, or when the presence check times out.
+        if let Some((id, technology)) = target {
+            state.presence_check_deadline =
+                Some(time::Instant::now() + Duration::from_millis(ISO_DEP_PRESENCE_CHECK_TIMEOUT));
+            self.send_rf(rf::IsoDepPresenceCheckCommandBuilder {
+                sender: self.id,
+                receiver: id,
+                technology,
+            })
+            .await?
+        }
+
+        Ok(())
+    }
+
+    async fn nfcee_discover(&self, _cmd: nci::NfceeDiscoverCommand) -> Result<()> {
+        info!("[{}] NFCEE_DISCOVER_CMD", self.id);
+
+        self.send_control(nci::NfceeDiscoverResponseBuilder {
+            status: nci::Status::Ok,
+            number_of_nfcees: 1,
+        })
+        .await?;
+
+        self.send_control(nci::NfceeDiscoverNotificationBuilder {
+            nfcee_id: nci::NfceeId::hci_nfcee(0x86),
             nfcee_status: nci::NfceeStatus::Disabled,
             supported_nfcee_protocols: vec![],
             nfcee_information: vec![nci::NfceeInformation {
@@ -1270,21 +2292,26 @@ impl Controller {
 
         if cmd.get_nfcee_id() != nci::NfceeId::hci_nfcee(0x86) {
             warn!("[{}] nfcee_mode_set with invalid nfcee_id", self.id);
-            self.send_control(nci::NfceeModeSetResponseBuilder { status: nci::Status::Ok }).await?;
+            self.send_control(nci::NfceeModeSetResponseBuilder { status: nci::Status::Rejected })
+                .await?;
             return Ok(());
         }
 
-        let mut state = self.state.lock().await;
-        state.nfcee_state = match cmd.get_nfcee_mode() {
+        let nfcee_state = match cmd.get_nfcee_mode() {
             nci::NfceeMode::Enable => NfceeState::Enabled,
             nci::NfceeMode::Disable => NfceeState::Disabled,
         };
+        self.state.lock().await.nfcee_state = nfcee_state;
 
         self.send_control(nci::NfceeModeSetResponseBuilder { status: nci::Status::Ok }).await?;
 
+        // [NCI] 10.2 NFCEE Mode Set
+        // When the NFCC has completed the mode transition of the NFCEE,
+        // it SHALL send NFCEE_MODE_SET_NTF to the DH.
+        time::sleep(Duration::from_millis(NFCEE_MODE_SET_DELAY)).await;
         self.send_control(nci::NfceeModeSetNotificationBuilder { status: nci::Status::Ok }).await?;
 
-        if state.nfcee_state == NfceeState::Enabled {
+        if nfcee_state == NfceeState::Enabled {
             // Android host stack expects this notification to know when the
             // NFCEE completes start-up. The list of information entries is
             // filled with defaults observed on real phones.
@@ -1324,6 +2351,27 @@ impl Controller {
         Ok(())
     }
 
+    async fn nfcee_power_and_link_control(
+        &self,
+        cmd: nci::NfceePowerAndLinkControlCommand,
+    ) -> Result<()> {
+        info!("[{}] NFCEE_POWER_AND_LINK_CNTRL_CMD", self.id);
+        info!("         NFCEE ID: {:?}", cmd.get_nfcee_id());
+        info!("         Configuration: {:?}", cmd.get_configuration());
+
+        let status = if cmd.get_nfcee_id() != nci::NfceeId::hci_nfcee(0x86) {
+            warn!("[{}] nfcee_power_and_link_control with invalid nfcee_id", self.id);
+            nci::Status::Rejected
+        } else {
+            self.state.lock().await.nfcee_power_and_link_configuration = cmd.get_configuration();
+            nci::Status::Ok
+        };
+
+        self.send_control(nci::NfceePowerAndLinkControlResponseBuilder { status }).await?;
+
+        Ok(())
+    }
+
     async fn android_get_caps(&self, _cmd: nci::AndroidGetCapsCommand) -> Result<()> {
         info!("[{}] ANDROID_GET_CAPS_CMD", self.id);
         let cap_tlvs = vec![
@@ -1378,6 +2426,7 @@ impl Controller {
         use nci::ProprietaryPacketChild::*;
         use nci::RfPacketChild::*;
 
+        let command = packet.clone();
         match packet.specialize() {
             CorePacket(packet) => match packet.specialize() {
                 CoreResetCommand(cmd) => self.core_reset(cmd).await,
@@ -1387,7 +2436,10 @@ impl Controller {
                 CoreConnCreateCommand(cmd) => self.core_conn_create(cmd).await,
                 CoreConnCloseCommand(cmd) => self.core_conn_close(cmd).await,
                 CoreSetPowerSubStateCommand(cmd) => self.core_set_power_sub_state(cmd).await,
-                _ => unimplemented!("unsupported core oid {:?}", packet.get_oid()),
+                _ => {
+                    let description = format!("core oid {:?}", packet.get_oid());
+                    self.unsupported_command(command, description).await
+                }
             },
             RfPacket(packet) => match packet.specialize() {
                 RfDiscoverMapCommand(cmd) => self.rf_discover_map(cmd).await,
@@ -1396,12 +2448,22 @@ impl Controller {
                 RfDiscoverCommand(cmd) => self.rf_discover(cmd).await,
                 RfDiscoverSelectCommand(cmd) => self.rf_discover_select(cmd).await,
                 RfDeactivateCommand(cmd) => self.rf_deactivate(cmd).await,
-                _ => unimplemented!("unsupported rf oid {:?}", packet.get_oid()),
+                RfIsoDepNakPresenceCommand(cmd) => self.rf_iso_dep_nak_presence(cmd).await,
+                _ => {
+                    let description = format!("rf oid {:?}", packet.get_oid());
+                    self.unsupported_command(command, description).await
+                }
             },
             NfceePacket(packet) => match packet.specialize() {
                 NfceeDiscoverCommand(cmd) => self.nfcee_discover(cmd).await,
                 NfceeModeSetCommand(cmd) => self.nfcee_mode_set(cmd).await,
-                _ => unimplemented!("unsupported nfcee oid {:?}", packet.get_oid()),
+                NfceePowerAndLinkControlCommand(cmd) => {
+                    self.nfcee_power_and_link_control(cmd).await
+                }
+                _ => {
+                    let description = format!("nfcee oid {:?}", packet.get_oid());
+                    self.unsupported_command(command, description).await
+                }
             },
             ProprietaryPacket(packet) => match packet.specialize() {
                 AndroidPacket(packet) => match packet.specialize() {
@@ -1413,20 +2475,135 @@ impl Controller {
                         self.android_query_passive_observe_mode(cmd).await
                     }
                     _ => {
-                        unimplemented!("unsupported android oid {:?}", packet.get_android_sub_oid())
+                        let description = format!("android oid {:?}", packet.get_android_sub_oid());
+                        self.unsupported_command(command, description).await
                     }
                 },
-                _ => unimplemented!("unsupported proprietary oid {:?}", packet.get_oid()),
+                nci::ProprietaryPacketChild::Payload(payload) => {
+                    self.proprietary_command(packet.get_oid(), &payload).await
+                }
+                nci::ProprietaryPacketChild::None => {
+                    self.proprietary_command(packet.get_oid(), &[]).await
+                }
             },
-            _ => unimplemented!("unsupported gid {:?}", packet.get_gid()),
+            _ => {
+                let description = format!("gid {:?}", packet.get_gid());
+                self.unsupported_command(command, description).await
+            }
+        }
+    }
+
+    /// Handle a command that is not implemented. In lenient mode, the
+    /// command is answered with an empty response.
+    async fn unsupported_command(
+        &self,
+        command: nci::ControlPacket,
+        description: String,
+    ) -> Result<()> {
+        const MT_SHIFT: u8 = 5;
+        const GID_MASK: u8 = 0xf;
+        if !self.options.lenient {
+            unimplemented!("unsupported {}", description)
+        }
+
+        warn!("[{}] answering unsupported {} with an empty response", self.id, description);
+        let bytes = command.to_vec();
+        let response =
+            [u8::from(nci::MessageType::Response) << MT_SHIFT | (bytes[0] & GID_MASK), bytes[1], 0];
+        self.send_control(nci::ControlPacket::parse(&response)?).await
+    }
+
+    async fn proprietary_command(
+        &self,
+        proprietary_oid: nci::ProprietaryOpcodeId,
+        payload: &[u8],
+    ) -> Result<()> {
+        let oid = u8::from(proprietary_oid);
+        info!("[{}] PROPRIETARY_CMD", self.id);
+        info!("         OID: {:#x}", oid);
+        info!("         Payload: {:02x?}", payload);
+
+        if self.options.starve_credits && oid == RELEASE_CREDITS_OID {
+            return self.release_credits(proprietary_oid).await;
+        }
+
+        let payload = match self.options.proprietary_handlers.get(oid) {
+            Some(handler) => handler(oid, payload),
+            None if oid == GET_DISCOVER_MAP_OID => self.get_discover_map().await,
+            None => {
+                warn!("[{}] no handler for the proprietary OID {:#x}", self.id, oid);
+                vec![nci::Status::Rejected.into()]
+            }
+        };
+
+        self.send_control(nci::ProprietaryPacketBuilder {
+            mt: nci::MessageType::Response,
+            oid: proprietary_oid,
+            payload: Some(payload.into()),
+        })
+        .await
+    }
+
+    /// Return the response payload of the proprietary command reading back
+    /// the RF discovery map: the status followed by the mapping
+    /// configurations, encoded as in RF_DISCOVER_MAP_CMD.
+    async fn get_discover_map(&self) -> Vec<u8> {
+        const HEADER_SIZE: usize = 3;
+        let state = self.state.lock().await;
+        info!("[{}] reading back the discover map: {:?}", self.id, state.discover_map);
+        let encoded_map =
+            nci::RfDiscoverMapCommandBuilder { mapping_configurations: state.discover_map.clone() }
+                .build()
+                .to_vec();
+        let mut payload = vec![nci::Status::Ok.into()];
+        payload.extend_from_slice(&encoded_map[HEADER_SIZE..]);
+        payload
+    }
+
+    /// Grant the DH the credits withheld on all dynamic logical connections.
+    async fn release_credits(&self, proprietary_oid: nci::ProprietaryOpcodeId) -> Result<()> {
+        let mut state = self.state.lock().await;
+        let mut connections = vec![];
+        for conn_id in 0..state.logical_connections.len() as u8 {
+            let credits = std::mem::take(&mut state.withheld_credits[conn_id as usize]);
+            if credits > 0 && state.logical_connections[conn_id as usize].is_some() {
+                state.logical_connection_credits[conn_id as usize] += credits;
+                connections.push(nci::ConnectionCredits {
+                    conn_id: nci::ConnId::from_dynamic(conn_id),
+                    credits,
+                });
+            }
+        }
+        info!("[{}] releasing withheld credits: {:?}", self.id, connections);
+
+        self.send_control(nci::ProprietaryPacketBuilder {
+            mt: nci::MessageType::Response,
+            oid: proprietary_oid,
+            payload: Some(vec![nci::Status::Ok.into()].into()),
+        })
+        .await?;
+
+        if !connections.is_empty() {
+            self.send_control(nci::CoreConnCreditsNotificationBuilder { connections }.build())
+                .await?;
         }
+        Ok(())
     }
 
     async fn rf_conn_data(&self, packet: nci::DataPacket) -> Result<()> {
         info!("[{}] received data on RF logical connection", self.id);
 
         // TODO(henrichataing) implement credit based control flow.
-        let state = self.state.lock().await;
+        let mut state = self.state.lock().await;
+        if state.rf_closed {
+            warn!("[{}] dropping data packet sent after the RF channel was closed", self.id);
+            return self
+                .send_control(nci::CoreInterfaceErrorNotificationBuilder {
+                    status: nci::Status::RfTransmissionException,
+                    conn_id: nci::ConnId::StaticRf,
+                })
+                .await;
+        }
         match state.rf_state {
             RfState::PollActive {
                 id,
@@ -1462,6 +2639,48 @@ impl Controller {
                 )
                 .await
             }
+            RfState::PollActive {
+                id,
+                rf_technology,
+                rf_protocol: rf_protocol @ (rf::Protocol::T1t | rf::Protocol::T5t),
+                rf_interface: nci::RfInterfaceType::Frame,
+            } => {
+                // The DH is responsible for keeping the RF frames within
+                // the limit advertised in CORE_INIT_RSP.
+                if rf_protocol == rf::Protocol::T5t
+                    && packet.get_payload().len() > MAX_NFCV_RF_FRAME_SIZE as usize
+                {
+                    warn!(
+                        "[{}] dropping NFC-V frame of {} bytes exceeding the maximum frame size",
+                        self.id,
+                        packet.get_payload().len()
+                    );
+                } else {
+                    let mut data = packet.get_payload().to_vec();
+                    if self.options.rf_frame_crc {
+                        rf::append_crc(frame_crc_technology(rf_protocol, rf_technology), &mut data);
+                    }
+                    self.send_rf(rf::DataBuilder {
+                        receiver: id,
+                        sender: self.id,
+                        protocol: rf_protocol,
+                        technology: rf_technology,
+                        data,
+                    })
+                    .await?;
+                }
+                // Resplenish the credit count for the RF Connection.
+                self.send_control(
+                    nci::CoreConnCreditsNotificationBuilder {
+                        connections: vec![nci::ConnectionCredits {
+                            conn_id: nci::ConnId::StaticRf,
+                            credits: 1,
+                        }],
+                    }
+                    .build(),
+                )
+                .await
+            }
             RfState::PollActive {
                 rf_protocol: rf::Protocol::IsoDep,
                 rf_interface: nci::RfInterfaceType::Frame,
@@ -1507,6 +2726,43 @@ impl Controller {
                 )
                 .await
             }
+            RfState::PollActive {
+                id,
+                rf_protocol: rf::Protocol::NfcDep,
+                rf_interface: nci::RfInterfaceType::NfcDep,
+                ..
+            }
+            | RfState::ListenActive {
+                id,
+                rf_protocol: rf::Protocol::NfcDep,
+                rf_interface: nci::RfInterfaceType::NfcDep,
+                ..
+            } => {
+                // [NCI] 8.4 NFC-DEP RF Interface
+                // The NFCC sends the DH message in a chain of information
+                // PDUs. The Target answers with the packet number of the
+                // last PDU received from the Initiator.
+                let (cmd, pni) = if matches!(state.rf_state, RfState::PollActive { .. }) {
+                    (NFC_DEP_REQ, state.nfc_dep.pni)
+                } else {
+                    state.nfc_dep.rtox_deadline = None;
+                    (NFC_DEP_RES, state.nfc_dep.pni.wrapping_sub(1) & 0x3)
+                };
+                state.nfc_dep.tx_data = packet.get_payload().to_vec();
+                let pdu = state.nfc_dep.next_information_pdu(pni);
+                self.send_nfc_dep_pdu(&mut state, id, cmd, pdu).await?;
+                // Resplenish the credit count for the RF Connection.
+                self.send_control(
+                    nci::CoreConnCreditsNotificationBuilder {
+                        connections: vec![nci::ConnectionCredits {
+                            conn_id: nci::ConnId::StaticRf,
+                            credits: 1,
+                        }],
+                    }
+                    .build(),
+                )
+                .await
+            }
             RfState::PollActive { rf_protocol, rf_interface, .. }
             | RfState::ListenActive { rf_protocol, rf_interface, .. } => unimplemented!(
                 "unsupported combination of RF protocol {:?} and interface {:?}",
@@ -1542,9 +2798,13 @@ impl Controller {
             | [0x81, 0x01, 0x01, _, 0x00, 0x00, 0x00, _, 0x00, 0x00, 0x00] => vec![0x81, 0x80],
             // ADM_CLEAR_ALL_PIPE()
             [0x81, 0x14, 0x02, 0x01] => vec![0x81, 0x80],
+            // Unknown requests stop the NFCC with an error rather than
+            // a panic, which would abort the emulator.
             _ => {
-                error!("unimplemented HCI command : {:?}", packet.get_payload());
-                unimplemented!()
+                return Err(anyhow::anyhow!(
+                    "unimplemented HCI command : {:?}",
+                    packet.get_payload()
+                ))
             }
         };
 
@@ -1569,9 +2829,92 @@ impl Controller {
         .await
     }
 
-    async fn dynamic_conn_data(&self, _conn_id: u8, _packet: nci::DataPacket) -> Result<()> {
+    async fn dynamic_conn_data(
+        &self,
+        conn_id: u8,
+        connection: LogicalConnection,
+        packet: nci::DataPacket,
+    ) -> Result<()> {
         info!("[{}] received data on dynamic logical connection", self.id);
-        todo!()
+
+        let mut state = self.state.lock().await;
+        let flow_control = self.initial_credits(&connection) != CREDITS_FLOW_CONTROL_DISABLED;
+
+        // [NCI] 4.4.4 Data Flow Control: the DH SHALL NOT send a Data
+        // Message on a connection for which it has no credits left.
+        if flow_control {
+            let credits = &mut state.logical_connection_credits[conn_id as usize];
+            if *credits == 0 {
+                warn!(
+                    "[{}] ignored data packet on logical connection {} without credits",
+                    self.id, conn_id
+                );
+                return Ok(());
+            }
+            *credits -= 1;
+        }
+
+        match (connection, state.rf_state) {
+            (
+                LogicalConnection::RemoteNfcEndpoint { rf_protocol_type, .. },
+                RfState::PollActive { id, rf_technology, .. }
+                | RfState::ListenActive { id, rf_technology, .. },
+            ) => {
+                self.send_rf(rf::DataBuilder {
+                    receiver: id,
+                    sender: self.id,
+                    protocol: rf_protocol_type.into(),
+                    technology: rf_technology,
+                    data: packet.get_payload().into(),
+                })
+                .await?
+            }
+            (LogicalConnection::RemoteNfcEndpoint { .. }, _) => warn!(
+                "[{}] ignored data packet on logical connection {} while not in active listen or poll mode",
+                self.id, conn_id
+            ),
+            (LogicalConnection::Nfcee { .. }, _) => {
+                self.send_data(nci::DataPacketBuilder {
+                    mt: nci::MessageType::Data,
+                    conn_id: nci::ConnId::from_dynamic(conn_id),
+                    cr: 0,
+                    payload: Some(bytes::Bytes::from(nfcee_apdu_response(packet.get_payload()))),
+                })
+                .await?
+            }
+        }
+
+        if !flow_control {
+            return Ok(());
+        }
+
+        if self.options.starve_credits {
+            debug!("[{}] withholding credit on logical connection {}", self.id, conn_id);
+            state.withheld_credits[conn_id as usize] += 1;
+            return Ok(());
+        }
+
+        // Resplenish the credit count for the logical connection.
+        state.logical_connection_credits[conn_id as usize] += 1;
+        self.send_control(
+            nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::from_dynamic(conn_id),
+                    credits: 1,
+                }],
+            }
+            .build(),
+        )
+        .await
+    }
+
+    /// Return the initial number of credits granted to the DH for
+    /// a logical connection.
+    fn initial_credits(&self, connection: &LogicalConnection) -> u8 {
+        match connection {
+            LogicalConnection::RemoteNfcEndpoint { .. } => self.options.remote_endpoint_credits,
+            LogicalConnection::Nfcee { .. } => NUMBER_OF_CREDITS,
+        }
     }
 
     async fn receive_data(&self, packet: nci::DataPacket) -> Result<()> {
@@ -1580,14 +2923,32 @@ impl Controller {
         match packet.get_conn_id() {
             nci::ConnId::StaticRf => self.rf_conn_data(packet).await,
             nci::ConnId::StaticHci => self.hci_conn_data(packet).await,
-            nci::ConnId::Dynamic(id) => self.dynamic_conn_data(*id, packet).await,
+            nci::ConnId::Dynamic(id) => {
+                // [NCI] 4.4 Logical Connection Management: data packets are only
+                // exchanged on established logical connections. Data received
+                // on any other Conn ID is discarded without returning credits.
+                let conn_id = nci::ConnId::to_dynamic(id);
+                let connection =
+                    self.state.lock().await.logical_connections.get(conn_id as usize).cloned();
+                match connection.flatten() {
+                    Some(connection) => self.dynamic_conn_data(conn_id, connection, packet).await,
+                    None => {
+                        warn!(
+                            "[{}] ignored data packet on unknown logical connection {}",
+                            self.id, *id
+                        );
+                        Ok(())
+                    }
+                }
+            }
         }
     }
 
     async fn poll_command(&self, cmd: rf::PollCommand) -> Result<()> {
         trace!("[{}] poll_command()", self.id);
 
-        let state = self.state.lock().await;
+        let mut state = self.state.lock().await;
+        self.update_rf_field(&mut state, true).await?;
         if state.rf_state != RfState::Discovery {
             return Ok(());
         }
@@ -1643,7 +3004,27 @@ impl Controller {
                     })
                     .await?
                 }
-                rf::Technology::NfcB => todo!(),
+                rf::Technology::NfcB => {
+                    // [DIGITAL] 7.6.2 SENSB_RES Response
+                    // Construct the response from the values passed in the
+                    // configuration parameters. The maximum frame size is
+                    // 256 bytes, and the protocol type indicates compliance
+                    // with ISO-DEP when advertised by LB_SENSB_INFO.
+                    let config_parameters = &state.config_parameters;
+                    self.send_rf(rf::NfcBPollResponseBuilder {
+                        protocol: rf::Protocol::Undetermined,
+                        receiver: cmd.get_sender(),
+                        sender: self.id,
+                        pupi: config_parameters.lb_nfcid0,
+                        application_data: config_parameters.lb_application_data.to_le_bytes(),
+                        protocol_info: [
+                            0x00,
+                            0x80 | (config_parameters.lb_sensb_info & 0x01),
+                            config_parameters.lb_fwi_adc_fo,
+                        ],
+                    })
+                    .await?
+                }
                 rf::Technology::NfcF => todo!(),
                 _ => (),
             }
@@ -1695,6 +3076,93 @@ impl Controller {
         Ok(())
     }
 
+    async fn t1t_poll_response(&self, cmd: rf::T1TPollResponse) -> Result<()> {
+        info!("[{}] t1t_poll_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        if state.rf_state != RfState::Discovery {
+            return Ok(());
+        }
+
+        // [DIGITAL] Table 10 and Table 11: the SENS_RES Response of a T1T
+        // Listener has the platform configuration 1100b, and no bit
+        // frame SDD. The UID of the RID Response is reported as NFCID1.
+        let sens_res = 0x0c00;
+        state.add_poll_response(RfPollResponse {
+            id: cmd.get_sender(),
+            rf_protocol: rf::Protocol::T1t,
+            rf_technology: rf::Technology::NfcA,
+            rf_technology_specific_parameters: pdl_runtime::Packet::to_vec(
+                nci::NfcAT1TPollModeTechnologySpecificParametersBuilder {
+                    sens_res,
+                    nfcid1: cmd.get_uid().to_vec(),
+                    hr: *cmd.get_hr(),
+                }
+                .build(),
+            ),
+        });
+
+        Ok(())
+    }
+
+    async fn nfcb_poll_response(&self, cmd: rf::NfcBPollResponse) -> Result<()> {
+        info!("[{}] nfcb_poll_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        if state.rf_state != RfState::Discovery {
+            return Ok(());
+        }
+
+        // Only Type 4B Tag Platform Listeners are supported, they advertise
+        // the protocol type 0001b in the second byte of the Protocol Info.
+        if cmd.get_protocol_info()[1] & 0x0f != 0x01 {
+            debug!("[{}] ignoring NFC-B Listener not compliant with ISO-DEP", self.id);
+            return Ok(());
+        }
+
+        // [NCI] Table 70: Specific Parameters for NFC-B Poll Mode
+        // SENSB_RES Response bytes 2 to 12, after the command code.
+        let mut sensb_res = cmd.get_pupi().to_vec();
+        sensb_res.extend_from_slice(cmd.get_application_data());
+        sensb_res.extend_from_slice(cmd.get_protocol_info());
+
+        state.add_poll_response(RfPollResponse {
+            id: cmd.get_sender(),
+            rf_protocol: rf::Protocol::IsoDep,
+            rf_technology: rf::Technology::NfcB,
+            rf_technology_specific_parameters: pdl_runtime::Packet::to_vec(
+                nci::NfcBPollModeTechnologySpecificParametersBuilder { sensb_res }.build(),
+            ),
+        });
+
+        Ok(())
+    }
+
+    async fn nfcv_poll_response(&self, cmd: rf::NfcVPollResponse) -> Result<()> {
+        info!("[{}] nfcv_poll_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        if state.rf_state != RfState::Discovery {
+            return Ok(());
+        }
+
+        state.add_poll_response(RfPollResponse {
+            id: cmd.get_sender(),
+            rf_protocol: rf::Protocol::T5t,
+            rf_technology: rf::Technology::NfcV,
+            rf_technology_specific_parameters: pdl_runtime::Packet::to_vec(
+                nci::NfcVPollModeTechnologySpecificParametersBuilder {
+                    res_flag: cmd.get_resp_flags(),
+                    dsfid: cmd.get_dsfid(),
+                    uid: *cmd.get_uid(),
+                }
+                .build(),
+            ),
+        });
+
+        Ok(())
+    }
+
     async fn t4at_select_command(&self, cmd: rf::T4ATSelectCommand) -> Result<()> {
         info!("[{}] t4at_select_command()", self.id);
 
@@ -1717,6 +3185,7 @@ impl Controller {
             rf_protocol: rf::Protocol::IsoDep,
             rf_interface: nci::RfInterfaceType::IsoDep,
         };
+        state.listen_mode_route = None;
 
         // [DIGITAL] 14.6.2 RATS Response (Answer To Select)
         // Construct the response from the values passed in the configuration
@@ -1749,13 +3218,13 @@ impl Controller {
             rf_interface: nci::RfInterfaceType::IsoDep,
             rf_protocol: nci::RfProtocolType::IsoDep,
             activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassiveListenMode,
-            max_data_packet_payload_size: MAX_DATA_PACKET_PAYLOAD_SIZE,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
             initial_number_of_credits: 1,
             // No parameters are currently defined for NFC-A Listen Mode.
             rf_technology_specific_parameters: vec![],
             data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassiveListenMode,
-            data_exchange_transmit_bit_rate: nci::BitRate::BitRate106KbitS,
-            data_exchange_receive_bit_rate: nci::BitRate::BitRate106KbitS,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcA.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcA.into(),
             activation_parameters: nci::NfcAIsoDepListenModeActivationParametersBuilder {
                 param: cmd.get_param(),
             }
@@ -1767,30 +3236,163 @@ impl Controller {
         Ok(())
     }
 
-    async fn t4at_select_response(&self, cmd: rf::T4ATSelectResponse) -> Result<()> {
-        info!("[{}] t4at_select_response()", self.id);
+    async fn t4bt_select_command(&self, cmd: rf::T4BTSelectCommand) -> Result<()> {
+        info!("[{}] t4bt_select_command()", self.id);
 
         let mut state = self.state.lock().await;
-        let (id, rf_discovery_id, rf_interface, rf_protocol) = match state.rf_state {
-            RfState::WaitForSelectResponse {
-                id,
-                rf_discovery_id,
-                rf_interface,
-                rf_protocol,
-                ..
-            } => (id, rf_discovery_id, rf_interface, rf_protocol),
+        match state.rf_state {
+            RfState::Discovery => (),
+            RfState::ListenSleep { id } if id == cmd.get_sender() => (),
             _ => return Ok(()),
         };
 
-        if cmd.get_sender() != id {
+        // [DIGITAL] 14.7.1 ATTRIB Command
+        // The ATTRIB Command is addressed to the Listener with the matching
+        // NFCID0, other Listeners remain silent.
+        if *cmd.get_nfcid0() != state.config_parameters.lb_nfcid0 {
+            debug!("[{}] ignoring ATTRIB command for NFCID0 {:02x?}", self.id, cmd.get_nfcid0());
             return Ok(());
         }
 
-        state.rf_state = RfState::PollActive {
-            id,
-            rf_protocol: state.rf_poll_responses[rf_discovery_id].rf_protocol,
-            rf_technology: state.rf_poll_responses[rf_discovery_id].rf_technology,
-            rf_interface,
+        state.rf_state = RfState::ListenActive {
+            id: cmd.get_sender(),
+            rf_technology: rf::Technology::NfcB,
+            rf_protocol: rf::Protocol::IsoDep,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+        };
+        state.listen_mode_route = None;
+
+        // [DIGITAL] 14.7.2 ATTRIB Response
+        // MBLI is not indicated, and the DID echoes the DID of Param 4.
+        let param = cmd.get_param();
+        self.send_rf(rf::T4BTSelectResponseBuilder {
+            receiver: cmd.get_sender(),
+            sender: self.id,
+            attrib_response: vec![param[3] & 0x0f],
+        })
+        .await?;
+
+        info!("[{}] RF_INTF_ACTIVATED_NTF", self.id);
+        info!("         DiscoveryID: {:?}", nci::RfDiscoveryId::from_index(0));
+        info!("         Interface: ISO-DEP");
+        info!("         Protocol: ISO-DEP");
+        info!("         ActivationTechnology: NFC_B_PASSIVE_LISTEN");
+        info!("         ATTRIB: {:02x?}", param);
+
+        // [NCI] Table 99: the activation parameters contain the ATTRIB
+        // Command, starting from the command code.
+        let mut attrib_command = vec![0x1d];
+        attrib_command.extend_from_slice(cmd.get_nfcid0());
+        attrib_command.extend_from_slice(param);
+
+        self.send_control(nci::RfIntfActivatedNotificationBuilder {
+            rf_discovery_id: nci::RfDiscoveryId::from_index(0),
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_protocol: nci::RfProtocolType::IsoDep,
+            activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcBPassiveListenMode,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+            initial_number_of_credits: 1,
+            // The SENSB_REQ parameters of the Poller are not emulated.
+            rf_technology_specific_parameters: vec![],
+            data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcBPassiveListenMode,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcB.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcB.into(),
+            activation_parameters: nci::NfcBIsoDepListenModeActivationParametersBuilder {
+                attrib_command,
+            }
+            .build()
+            .to_vec(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn t4bt_select_response(&self, cmd: rf::T4BTSelectResponse) -> Result<()> {
+        info!("[{}] t4bt_select_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        let (id, rf_discovery_id, rf_interface, rf_protocol) = match state.rf_state {
+            RfState::WaitForSelectResponse {
+                id,
+                rf_discovery_id,
+                rf_interface,
+                rf_protocol,
+                rf_technology: rf::Technology::NfcB,
+            } => (id, rf_discovery_id, rf_interface, rf_protocol),
+            _ => return Ok(()),
+        };
+
+        if cmd.get_sender() != id {
+            return Ok(());
+        }
+
+        state.rf_state = RfState::PollActive {
+            id,
+            rf_protocol: state.rf_poll_responses[rf_discovery_id].rf_protocol,
+            rf_technology: rf::Technology::NfcB,
+            rf_interface,
+        };
+
+        // The RATS activation parameters only apply to NFC-A.
+        state.rf_activation_parameters.clear();
+
+        info!("[{}] RF_INTF_ACTIVATED_NTF", self.id);
+        info!("         DiscoveryID: {:?}", nci::RfDiscoveryId::from_index(rf_discovery_id));
+        info!("         Interface: {:?}", rf_interface);
+        info!("         Protocol: {:?}", rf_protocol);
+        info!("         ActivationTechnology: NFC_B_PASSIVE_POLL");
+        info!("         ATTRIB: {:02x?}", cmd.get_attrib_response());
+
+        self.send_control(nci::RfIntfActivatedNotificationBuilder {
+            rf_discovery_id: nci::RfDiscoveryId::from_index(rf_discovery_id),
+            rf_interface,
+            rf_protocol: rf_protocol.into(),
+            activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcBPassivePollMode,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+            initial_number_of_credits: 1,
+            rf_technology_specific_parameters: state.rf_poll_responses[rf_discovery_id]
+                .rf_technology_specific_parameters
+                .clone(),
+            data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcBPassivePollMode,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcB.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcB.into(),
+            activation_parameters: pdl_runtime::Packet::to_vec(
+                nci::NfcBIsoDepPollModeActivationParametersBuilder {
+                    attrib_response: cmd.get_attrib_response().clone(),
+                }
+                .build(),
+            ),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn t4at_select_response(&self, cmd: rf::T4ATSelectResponse) -> Result<()> {
+        info!("[{}] t4at_select_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        let (id, rf_discovery_id, rf_interface, rf_protocol) = match state.rf_state {
+            RfState::WaitForSelectResponse {
+                id,
+                rf_discovery_id,
+                rf_interface,
+                rf_protocol,
+                ..
+            } => (id, rf_discovery_id, rf_interface, rf_protocol),
+            _ => return Ok(()),
+        };
+
+        if cmd.get_sender() != id {
+            return Ok(());
+        }
+
+        state.rf_state = RfState::PollActive {
+            id,
+            rf_protocol: state.rf_poll_responses[rf_discovery_id].rf_protocol,
+            rf_technology: state.rf_poll_responses[rf_discovery_id].rf_technology,
+            rf_interface,
         };
 
         // Save the activation parameters for the RF frame interface
@@ -1811,14 +3413,14 @@ impl Controller {
             rf_interface,
             rf_protocol: rf_protocol.into(),
             activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
-            max_data_packet_payload_size: MAX_DATA_PACKET_PAYLOAD_SIZE,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
             initial_number_of_credits: 1,
             rf_technology_specific_parameters: state.rf_poll_responses[rf_discovery_id]
                 .rf_technology_specific_parameters
                 .clone(),
             data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
-            data_exchange_transmit_bit_rate: nci::BitRate::BitRate106KbitS,
-            data_exchange_receive_bit_rate: nci::BitRate::BitRate106KbitS,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcA.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcA.into(),
             // TODO(hchataing) the activation parameters should be empty
             // when the RF frame interface is used, since the protocol
             // activation is managed by the DH.
@@ -1834,10 +3436,312 @@ impl Controller {
         Ok(())
     }
 
+    async fn nfc_dep_select_command(&self, cmd: rf::NfcDepSelectCommand) -> Result<()> {
+        info!("[{}] nfc_dep_select_command()", self.id);
+
+        let mut state = self.state.lock().await;
+        match state.rf_state {
+            RfState::Discovery => (),
+            RfState::ListenSleep { id } if id == cmd.get_sender() => (),
+            _ => return Ok(()),
+        };
+
+        // The NFC-DEP protocol can only be activated when advertised in
+        // the SEL_RES Response.
+        if state.config_parameters.la_sel_info & 0x40 == 0 {
+            debug!("[{}] ignoring ATR_REQ command, NFC-DEP is not supported", self.id);
+            return Ok(());
+        }
+
+        state.rf_state = RfState::ListenActive {
+            id: cmd.get_sender(),
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::NfcDep,
+            rf_interface: nci::RfInterfaceType::NfcDep,
+        };
+        state.listen_mode_route = None;
+
+        // [DIGITAL] ATR_RES Response
+        // Construct the response from the values passed in the configuration
+        // parameters. The response is sent without PSL_RES, the Length
+        // Reduction requested in FSL is applied if lower than LRt.
+        let config_parameters = &state.config_parameters;
+        let lr_t = (config_parameters.ln_atr_res_config >> 4) & 0x3;
+        let gen_bytes = &config_parameters.ln_atr_res_gen_bytes;
+        let ppt = lr_t << 4 | if gen_bytes.is_empty() { 0x00 } else { 0x02 };
+        let mut atr_response = nfcid3(self.id);
+        atr_response.extend_from_slice(&[
+            0x00,                           // DIDt
+            0x00,                           // BSt
+            0x00,                           // BRt
+            config_parameters.ln_wt & 0x0f, // TO
+            ppt,                            // PPt
+        ]);
+        atr_response.extend_from_slice(gen_bytes);
+        let lr = std::cmp::min(cmd.get_lr(), lr_t);
+        state.nfc_dep = NfcDepExchange::new(lr);
+
+        self.send_rf(rf::NfcDepSelectResponseBuilder {
+            receiver: cmd.get_sender(),
+            sender: self.id,
+            technology: rf::Technology::NfcA,
+            atr_response,
+        })
+        .await?;
+
+        info!("[{}] RF_INTF_ACTIVATED_NTF", self.id);
+        info!("         DiscoveryID: {:?}", nci::RfDiscoveryId::from_index(0));
+        info!("         Interface: NFC-DEP");
+        info!("         Protocol: NFC-DEP");
+        info!("         ActivationTechnology: NFC_A_PASSIVE_LISTEN");
+        info!("         ATR_REQ: {:02x?}", cmd.get_atr_request());
+
+        self.send_control(nci::RfIntfActivatedNotificationBuilder {
+            rf_discovery_id: nci::RfDiscoveryId::from_index(0),
+            rf_interface: nci::RfInterfaceType::NfcDep,
+            rf_protocol: nci::RfProtocolType::NfcDep,
+            activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassiveListenMode,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+            initial_number_of_credits: 1,
+            // No parameters are currently defined for NFC-A Listen Mode.
+            rf_technology_specific_parameters: vec![],
+            data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassiveListenMode,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcA.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcA.into(),
+            // The Length Reduction is reported in bits 5-4, as in PPt.
+            activation_parameters: nci::NfcDepListenModeActivationParametersBuilder {
+                atr_req: cmd.get_atr_request().clone(),
+                data_exchange_length_reduction: lr << 4,
+            }
+            .build()
+            .to_vec(),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn nfc_dep_select_response(&self, cmd: rf::NfcDepSelectResponse) -> Result<()> {
+        info!("[{}] nfc_dep_select_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        let (id, rf_discovery_id, rf_interface) = match state.rf_state {
+            RfState::WaitForSelectResponse {
+                id,
+                rf_discovery_id,
+                rf_interface,
+                rf_protocol: rf::Protocol::NfcDep,
+                rf_technology: rf::Technology::NfcA,
+            } => (id, rf_discovery_id, rf_interface),
+            _ => return Ok(()),
+        };
+
+        if cmd.get_sender() != id {
+            return Ok(());
+        }
+
+        state.rf_state = RfState::PollActive {
+            id,
+            rf_protocol: rf::Protocol::NfcDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_interface,
+        };
+
+        // The RATS activation parameters only apply to ISO-DEP.
+        state.rf_activation_parameters.clear();
+
+        // The frames are exchanged with the lowest of the Length Reductions
+        // indicated in PPi and PPt. PPt follows NFCID3t, DIDt, BSt, BRt and
+        // TO in the ATR_RES Response.
+        let lr_i = (state.config_parameters.pn_atr_req_config >> 4) & 0x3;
+        let lr_t = cmd.get_atr_response().get(14).map(|ppt| (ppt >> 4) & 0x3).unwrap_or(0);
+        let lr = std::cmp::min(lr_i, lr_t);
+        state.nfc_dep = NfcDepExchange::new(lr);
+
+        info!("[{}] RF_INTF_ACTIVATED_NTF", self.id);
+        info!("         DiscoveryID: {:?}", nci::RfDiscoveryId::from_index(rf_discovery_id));
+        info!("         Interface: {:?}", rf_interface);
+        info!("         Protocol: NFC-DEP");
+        info!("         ActivationTechnology: NFC_A_PASSIVE_POLL");
+        info!("         ATR_RES: {:02x?}", cmd.get_atr_response());
+
+        self.send_control(nci::RfIntfActivatedNotificationBuilder {
+            rf_discovery_id: nci::RfDiscoveryId::from_index(rf_discovery_id),
+            rf_interface,
+            rf_protocol: nci::RfProtocolType::NfcDep,
+            activation_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+            initial_number_of_credits: 1,
+            rf_technology_specific_parameters: state.rf_poll_responses[rf_discovery_id]
+                .rf_technology_specific_parameters
+                .clone(),
+            data_exchange_rf_technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+            data_exchange_transmit_bit_rate: rf::Technology::NfcA.into(),
+            data_exchange_receive_bit_rate: rf::Technology::NfcA.into(),
+            activation_parameters: pdl_runtime::Packet::to_vec(
+                nci::NfcDepPollModeActivationParametersBuilder {
+                    atr_res: cmd.get_atr_response().clone(),
+                    data_exchange_length_reduction: lr << 4,
+                }
+                .build(),
+            ),
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    /// Send an NFC-DEP PDU to the activated Remote NFC Endpoint.
+    /// Information and ACK PDUs are saved for retransmission.
+    async fn send_nfc_dep_pdu(
+        &self,
+        state: &mut State,
+        receiver: u16,
+        cmd: [u8; 2],
+        pdu: NfcDepPdu,
+    ) -> Result<()> {
+        debug!("[{}] NFC-DEP TX {:?}", self.id, pdu);
+        let data = pdu.to_frame(cmd);
+        if matches!(pdu, NfcDepPdu::Information { .. } | NfcDepPdu::Ack { .. }) {
+            state.nfc_dep.last_pdu = Some(pdu);
+        }
+        self.send_rf(rf::DataBuilder {
+            receiver,
+            sender: self.id,
+            protocol: rf::Protocol::NfcDep,
+            technology: rf::Technology::NfcA,
+            data,
+        })
+        .await
+    }
+
+    /// Handle a DEP_RES Response received by the NFC-DEP Initiator.
+    async fn nfc_dep_response(&self, state: &mut State, id: u16, frame: &[u8]) -> Result<()> {
+        let Some(pdu) = NfcDepPdu::parse(NFC_DEP_RES, frame) else {
+            warn!("[{}] ignored invalid DEP_RES frame {:02x?}", self.id, frame);
+            return Ok(());
+        };
+        debug!("[{}] NFC-DEP RX {:?}", self.id, pdu);
+
+        // [DIGITAL] The Initiator increments the packet number after each
+        // information or ACK PDU received from the Target.
+        let pni = state.nfc_dep.pni;
+        match pdu {
+            NfcDepPdu::Information { pni: rx_pni, mi, data } if rx_pni == pni => {
+                state.nfc_dep.pni = (pni + 1) & 0x3;
+                state.nfc_dep.rx_data.extend_from_slice(&data);
+                if mi {
+                    // Acknowledge the PDU to receive the next PDU of the chain.
+                    let ack = NfcDepPdu::Ack { pni: state.nfc_dep.pni };
+                    self.send_nfc_dep_pdu(state, id, NFC_DEP_REQ, ack).await
+                } else {
+                    let payload = std::mem::take(&mut state.nfc_dep.rx_data);
+                    self.send_data(nci::DataPacketBuilder {
+                        mt: nci::MessageType::Data,
+                        conn_id: nci::ConnId::StaticRf,
+                        cr: 0,
+                        payload: Some(bytes::Bytes::from(payload)),
+                    })
+                    .await
+                }
+            }
+            NfcDepPdu::Ack { pni: rx_pni }
+                if rx_pni == pni && !state.nfc_dep.tx_data.is_empty() =>
+            {
+                state.nfc_dep.pni = (pni + 1) & 0x3;
+                let pdu = state.nfc_dep.next_information_pdu(state.nfc_dep.pni);
+                self.send_nfc_dep_pdu(state, id, NFC_DEP_REQ, pdu).await
+            }
+            NfcDepPdu::TimeoutExtension { rtox } => {
+                // The timeout extension is accepted by returning the
+                // same RTOX value.
+                let pdu = NfcDepPdu::TimeoutExtension { rtox };
+                self.send_nfc_dep_pdu(state, id, NFC_DEP_REQ, pdu).await
+            }
+            NfcDepPdu::Information { .. } | NfcDepPdu::Ack { .. } => {
+                warn!("[{}] received NFC-DEP PDU with an unexpected packet number", self.id);
+                self.send_nfc_dep_pdu(state, id, NFC_DEP_REQ, NfcDepPdu::Nack { pni }).await
+            }
+            NfcDepPdu::Attention | NfcDepPdu::Nack { .. } => {
+                warn!("[{}] ignored unexpected NFC-DEP PDU", self.id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Handle a DEP_REQ Command received by the NFC-DEP Target.
+    async fn nfc_dep_request(&self, state: &mut State, id: u16, frame: &[u8]) -> Result<()> {
+        let Some(pdu) = NfcDepPdu::parse(NFC_DEP_REQ, frame) else {
+            warn!("[{}] ignored invalid DEP_REQ frame {:02x?}", self.id, frame);
+            return Ok(());
+        };
+        debug!("[{}] NFC-DEP RX {:?}", self.id, pdu);
+
+        // [DIGITAL] The Target answers information and ACK PDUs with the
+        // packet number of the received PDU.
+        let pni = state.nfc_dep.pni;
+        match pdu {
+            NfcDepPdu::Information { pni: rx_pni, mi, data } if rx_pni == pni => {
+                state.nfc_dep.pni = (pni + 1) & 0x3;
+                state.nfc_dep.rx_data.extend_from_slice(&data);
+                if mi {
+                    // Acknowledge the PDU to receive the next PDU of the chain.
+                    self.send_nfc_dep_pdu(state, id, NFC_DEP_RES, NfcDepPdu::Ack { pni }).await
+                } else {
+                    // The DH is expected to answer the message, a timeout
+                    // extension is requested if the response is late.
+                    state.nfc_dep.rtox_deadline =
+                        Some(time::Instant::now() + Duration::from_millis(NFC_DEP_RTOX_DELAY));
+                    let payload = std::mem::take(&mut state.nfc_dep.rx_data);
+                    self.send_data(nci::DataPacketBuilder {
+                        mt: nci::MessageType::Data,
+                        conn_id: nci::ConnId::StaticRf,
+                        cr: 0,
+                        payload: Some(bytes::Bytes::from(payload)),
+                    })
+                    .await
+                }
+            }
+            NfcDepPdu::Ack { pni: rx_pni }
+                if rx_pni == pni && !state.nfc_dep.tx_data.is_empty() =>
+            {
+                state.nfc_dep.pni = (pni + 1) & 0x3;
+                let pdu = state.nfc_dep.next_information_pdu(pni);
+                self.send_nfc_dep_pdu(state, id, NFC_DEP_RES, pdu).await
+            }
+            NfcDepPdu::Nack { .. } => match state.nfc_dep.last_pdu.clone() {
+                Some(pdu) => self.send_nfc_dep_pdu(state, id, NFC_DEP_RES, pdu).await,
+                None => Ok(()),
+            },
+            NfcDepPdu::Attention => {
+                self.send_nfc_dep_pdu(state, id, NFC_DEP_RES, NfcDepPdu::Attention).await
+            }
+            NfcDepPdu::TimeoutExtension { rtox } => {
+                debug!("[{}] NFC-DEP timeout extension {} accepted", self.id, rtox);
+                Ok(())
+            }
+            NfcDepPdu::Information { .. } | NfcDepPdu::Ack { .. } => {
+                warn!("[{}] ignored NFC-DEP PDU with an unexpected packet number", self.id);
+                Ok(())
+            }
+        }
+    }
+
     async fn data_packet(&self, data: rf::Data) -> Result<()> {
         info!("[{}] data_packet()", self.id);
 
-        let state = self.state.lock().await;
+        let mut state = self.state.lock().await;
+        let nfcee_route = match (state.rf_state, data.get_protocol()) {
+            (
+                RfState::ListenActive { id, rf_protocol: rf::Protocol::IsoDep, .. },
+                rf::Protocol::IsoDep,
+            ) if data.get_sender() == id => state.route_apdu(data.get_data()),
+            _ => None,
+        };
+        if let Some(nfcee_id) = nfcee_route {
+            return self.nfcee_apdu(nfcee_id, data).await;
+        }
+
         match (state.rf_state, data.get_protocol()) {
             (
                 RfState::PollActive {
@@ -1859,14 +3763,72 @@ impl Controller {
                 })
                 .await
             }
-            (RfState::PollActive { id, .. }, _) | (RfState::ListenActive { id, .. }, _)
-                if id != data.get_sender() =>
+            (
+                RfState::PollActive {
+                    id,
+                    rf_technology,
+                    rf_protocol: rf_protocol @ (rf::Protocol::T1t | rf::Protocol::T5t),
+                    rf_interface: nci::RfInterfaceType::Frame,
+                },
+                protocol,
+            ) if data.get_sender() == id
+                && data.get_technology() == rf_technology
+                && protocol == rf_protocol =>
             {
-                warn!("[{}] ignored RF data packet sent from an un-selected device", self.id);
-                Ok(())
-            }
-            (RfState::PollActive { .. }, _) | (RfState::ListenActive { .. }, _) => {
-                unimplemented!("unsupported combination of technology and protocol")
+                // [NCI] 8.2 Frame RF Interface
+                // The RF frame received from the Remote NFC Endpoint is
+                // followed by a Status octet.
+                let mut payload = data.get_data().clone();
+                let status = if !self.options.rf_frame_crc {
+                    nci::Status::Ok
+                } else if let Some(frame) =
+                    rf::check_crc(frame_crc_technology(rf_protocol, rf_technology), &payload)
+                {
+                    payload.truncate(frame.len());
+                    nci::Status::Ok
+                } else {
+                    warn!("[{}] received RF frame with an invalid CRC", self.id);
+                    nci::Status::RfTransmissionException
+                };
+                payload.push(status.into());
+                self.send_data(nci::DataPacketBuilder {
+                    mt: nci::MessageType::Data,
+                    conn_id: nci::ConnId::StaticRf,
+                    cr: 0,
+                    payload: Some(bytes::Bytes::from(payload)),
+                })
+                .await
+            }
+            (
+                RfState::PollActive {
+                    id,
+                    rf_protocol: rf::Protocol::NfcDep,
+                    rf_interface: nci::RfInterfaceType::NfcDep,
+                    ..
+                },
+                rf::Protocol::NfcDep,
+            ) if data.get_sender() == id => {
+                self.nfc_dep_response(&mut state, id, data.get_data()).await
+            }
+            (
+                RfState::ListenActive {
+                    id,
+                    rf_protocol: rf::Protocol::NfcDep,
+                    rf_interface: nci::RfInterfaceType::NfcDep,
+                    ..
+                },
+                rf::Protocol::NfcDep,
+            ) if data.get_sender() == id => {
+                self.nfc_dep_request(&mut state, id, data.get_data()).await
+            }
+            (RfState::PollActive { id, .. }, _) | (RfState::ListenActive { id, .. }, _)
+                if id != data.get_sender() =>
+            {
+                warn!("[{}] ignored RF data packet sent from an un-selected device", self.id);
+                Ok(())
+            }
+            (RfState::PollActive { .. }, _) | (RfState::ListenActive { .. }, _) => {
+                unimplemented!("unsupported combination of technology and protocol")
             }
             (_, _) => {
                 warn!("[{}] ignored RF data packet received in inactive state", self.id);
@@ -1875,6 +3837,95 @@ impl Controller {
         }
     }
 
+    /// Respond to an APDU routed to the emulated NFCEE.
+    async fn nfcee_apdu(&self, nfcee_id: nci::NfceeId, data: rf::Data) -> Result<()> {
+        if let [_, 0xa4, 0x04, _, lc, aid @ ..] = data.get_data().as_slice() {
+            let aid = &aid[..std::cmp::min(*lc as usize, aid.len())];
+            info!("[{}] routing SELECT {:02x?} to NFCEE {:?}", self.id, aid, nfcee_id);
+            self.send_control(nci::RfNfceeActionNotificationBuilder {
+                nfcee_id,
+                trigger: nci::NfceeActionTrigger::SelectCommandWithAid,
+                supporting_data: aid.to_vec(),
+            })
+            .await?;
+        }
+        let response = nfcee_apdu_response(data.get_data());
+
+        self.send_rf(rf::DataBuilder {
+            receiver: data.get_sender(),
+            sender: self.id,
+            protocol: rf::Protocol::IsoDep,
+            technology: data.get_technology(),
+            data: response,
+        })
+        .await
+    }
+
+    async fn iso_dep_presence_check_command(
+        &self,
+        cmd: rf::IsoDepPresenceCheckCommand,
+    ) -> Result<()> {
+        info!("[{}] iso_dep_presence_check_command()", self.id);
+
+        // [DIGITAL] 13.2.2 The activated Listener answers the R(NAK) Block
+        // with an R(ACK) Block. This is handled by the NFCC without
+        // involving the DH.
+        let state = self.state.lock().await;
+        match state.rf_state {
+            RfState::ListenActive {
+                id, rf_technology, rf_protocol: rf::Protocol::IsoDep, ..
+            } if id == cmd.get_sender() => {
+                self.send_rf(rf::IsoDepPresenceCheckResponseBuilder {
+                    sender: self.id,
+                    receiver: id,
+                    technology: rf_technology,
+                })
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn iso_dep_presence_check_response(
+        &self,
+        cmd: rf::IsoDepPresenceCheckResponse,
+    ) -> Result<()> {
+        info!("[{}] iso_dep_presence_check_response()", self.id);
+
+        let mut state = self.state.lock().await;
+        match state.rf_state {
+            RfState::PollActive { id, rf_protocol: rf::Protocol::IsoDep, .. }
+                if id == cmd.get_sender() && state.presence_check_deadline.is_some() =>
+            {
+                state.presence_check_deadline = None;
+                info!("[{}] RF_ISO_DEP_NAK_PRESENCE_NTF", self.id);
+                info!("         Status: Ok");
+                self.send_control(nci::RfIsoDepNakPresenceNotificationBuilder {
+                    status: nci::Status::Ok,
+                })
+                .await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Update the status of the RF field generated by a Remote NFC Endpoint
+    /// in Poll Mode. Changes of the status are reported with
+    /// RF_FIELD_INFO_NTF when enabled by the configuration.
+    async fn update_rf_field(&self, state: &mut State, detected: bool) -> Result<()> {
+        let was_detected = state.rf_field_detected_at.is_some();
+        state.rf_field_detected_at = detected.then(time::Instant::now);
+        if was_detected == detected || !state.config_parameters.rf_field_info_enabled() {
+            return Ok(());
+        }
+
+        let rf_field_status =
+            if detected { nci::RfFieldStatus::FieldDetected } else { nci::RfFieldStatus::NoField };
+        info!("[{}] RF_FIELD_INFO_NTF", self.id);
+        info!("         Status: {:?}", rf_field_status);
+        self.send_control(nci::RfFieldInfoNotificationBuilder { rf_field_status }).await
+    }
+
     async fn deactivate_notification(&self, cmd: rf::DeactivateNotification) -> Result<()> {
         info!("[{}] deactivate_notification()", self.id);
 
@@ -1909,8 +3960,31 @@ impl Controller {
         // completing if a remote device is being selected.
         (next_state, state.rf_state) = (state.rf_state, next_state);
 
+        // The Remote NFC Endpoint left before answering the pending
+        // presence check.
+        if next_state != state.rf_state && state.presence_check_deadline.take().is_some() {
+            info!("[{}] RF_ISO_DEP_NAK_PRESENCE_NTF", self.id);
+            info!("         Status: Failed");
+            self.send_control(nci::RfIsoDepNakPresenceNotificationBuilder {
+                status: nci::Status::Failed,
+            })
+            .await?
+        }
+
+        // The Remote NFC Endpoint in Poll Mode was removed, its RF field
+        // is lost with the link.
+        if next_state != state.rf_state
+            && cmd.get_reason() == rf::DeactivateReason::RfLinkLoss
+            && matches!(next_state, RfState::ListenActive { .. } | RfState::ListenSleep { .. })
+        {
+            self.update_rf_field(&mut state, false).await?
+        }
+
         // Deactivate the active RF interface if applicable.
         if next_state != state.rf_state {
+            info!("[{}] RF_DEACTIVATE_NTF", self.id);
+            info!("         Type: {:?}", cmd.get_type_());
+            info!("         Reason: {:?}", cmd.get_reason());
             self.send_control(nci::RfDeactivateNotificationBuilder {
                 deactivation_type: cmd.get_type_().into(),
                 deactivation_reason: cmd.get_reason().into(),
@@ -1918,6 +3992,16 @@ impl Controller {
             .await?
         }
 
+        // The Remote NFC Endpoint is no longer reachable after the loss of
+        // the RF link or when the RF state returns to Idle or Discovery:
+        // close the logical connections created for this endpoint.
+        if next_state != state.rf_state
+            && (cmd.get_reason() == rf::DeactivateReason::RfLinkLoss
+                || matches!(state.rf_state, RfState::Idle | RfState::Discovery))
+        {
+            state.close_remote_connections();
+        }
+
         Ok(())
     }
 
@@ -1926,7 +4010,10 @@ impl Controller {
 
         match packet.specialize() {
             PollCommand(cmd) => self.poll_command(cmd).await,
+            T1TPollResponse(cmd) => self.t1t_poll_response(cmd).await,
             NfcAPollResponse(cmd) => self.nfca_poll_response(cmd).await,
+            NfcBPollResponse(cmd) => self.nfcb_poll_response(cmd).await,
+            NfcVPollResponse(cmd) => self.nfcv_poll_response(cmd).await,
             // [NCI] 5.2.2 State RFST_DISCOVERY
             // If discovered by a Remote NFC Endpoint in Listen mode, once the
             // Remote NFC Endpoint has established any underlying protocol(s) needed
@@ -1935,8 +4022,14 @@ impl Controller {
             // changed to RFST_LISTEN_ACTIVE.
             T4ATSelectCommand(cmd) => self.t4at_select_command(cmd).await,
             T4ATSelectResponse(cmd) => self.t4at_select_response(cmd).await,
+            T4BTSelectCommand(cmd) => self.t4bt_select_command(cmd).await,
+            T4BTSelectResponse(cmd) => self.t4bt_select_response(cmd).await,
+            NfcDepSelectCommand(cmd) => self.nfc_dep_select_command(cmd).await,
+            NfcDepSelectResponse(cmd) => self.nfc_dep_select_response(cmd).await,
             SelectCommand(_) => unimplemented!(),
             DeactivateNotification(cmd) => self.deactivate_notification(cmd).await,
+            IsoDepPresenceCheckCommand(cmd) => self.iso_dep_presence_check_command(cmd).await,
+            IsoDepPresenceCheckResponse(cmd) => self.iso_dep_presence_check_response(cmd).await,
             Data(cmd) => self.data_packet(cmd).await,
             _ => unimplemented!(),
         }
@@ -1983,15 +4076,59 @@ impl Controller {
                 })
                 .await?
             }
+            (nci::RfProtocolType::IsoDep, rf::Technology::NfcB) => {
+                // The ATTRIB Command addresses the Listener by the NFCID0
+                // returned in the SENSB_RES Response.
+                let parameters = nci::NfcBPollModeTechnologySpecificParameters::parse(
+                    &state.rf_poll_responses[rf_discovery_id].rf_technology_specific_parameters,
+                )?;
+                self.send_rf(rf::T4BTSelectCommandBuilder {
+                    sender: self.id,
+                    receiver: state.rf_poll_responses[rf_discovery_id].id,
+                    nfcid0: parameters.get_sensb_res()[0..4].try_into()?,
+                    // [DIGITAL] 14.7.1 ATTRIB Command
+                    // Param 1 is configured with PB_ATTRIB_PARAM1, Param 2
+                    // selects the FSD of 256 bytes and 106 kbit/s in both
+                    // directions, Param 3 the ISO-DEP protocol type, and
+                    // Param 4 the DID 0.
+                    param: [state.config_parameters.pb_attrib_param1, 0x08, 0x01, 0x00],
+                })
+                .await?
+            }
             (nci::RfProtocolType::NfcDep, rf::Technology::NfcA) => {
+                // [DIGITAL] ATR_REQ Command
+                // Construct the command from the values passed in the
+                // configuration parameters. The Length Reduction LRi is
+                // also requested in FSL.
+                let config_parameters = &state.config_parameters;
+                let lr = (config_parameters.pn_atr_req_config >> 4) & 0x3;
+                let gen_bytes = &config_parameters.pn_atr_req_gen_bytes;
+                let ppi = lr << 4 | if gen_bytes.is_empty() { 0x00 } else { 0x02 };
+                let mut atr_request = nfcid3(self.id);
+                atr_request.extend_from_slice(&[
+                    0x00, // DIDi
+                    0x00, // BSi
+                    0x00, // BRi
+                    ppi,  // PPi
+                ]);
+                atr_request.extend_from_slice(gen_bytes);
                 self.send_rf(rf::NfcDepSelectCommandBuilder {
                     sender: self.id,
                     receiver: state.rf_poll_responses[rf_discovery_id].id,
                     technology: rf::Technology::NfcA,
-                    lr: 0,
+                    lr,
+                    atr_request,
                 })
                 .await?
             }
+            (nci::RfProtocolType::T1t, rf::Technology::NfcA)
+            | (nci::RfProtocolType::T5t, rf::Technology::NfcV) => {
+                // T1T and NFC-V Listeners are ready for data exchange as
+                // soon as they are discovered, no select command is sent.
+                return self
+                    .activate_unselected_interface(state, rf_discovery_id, rf_interface)
+                    .await;
+            }
             _ => todo!(),
         }
 
@@ -2005,11 +4142,125 @@ impl Controller {
         Ok(())
     }
 
+    /// Activate the RF interface for a discovered T1T or NFC-V Listener,
+    /// which does not need a select command.
+    async fn activate_unselected_interface(
+        &self,
+        state: &mut State,
+        rf_discovery_id: usize,
+        rf_interface: nci::RfInterfaceType,
+    ) -> Result<()> {
+        let rf_protocol = state.rf_poll_responses[rf_discovery_id].rf_protocol;
+        let rf_technology = state.rf_poll_responses[rf_discovery_id].rf_technology;
+        let rf_technology_and_mode = match rf_technology {
+            rf::Technology::NfcA => nci::RfTechnologyAndMode::NfcAPassivePollMode,
+            _ => nci::RfTechnologyAndMode::NfcVPassivePollMode,
+        };
+        state.rf_state = RfState::PollActive {
+            id: state.rf_poll_responses[rf_discovery_id].id,
+            rf_protocol,
+            rf_technology,
+            rf_interface,
+        };
+        state.rf_activation_parameters.clear();
+
+        info!("[{}] RF_INTF_ACTIVATED_NTF", self.id);
+        info!("         DiscoveryID: {:?}", nci::RfDiscoveryId::from_index(rf_discovery_id));
+        info!("         Interface: {:?}", rf_interface);
+        info!("         Protocol: {:?}", rf_protocol);
+        info!("         ActivationTechnology: {:?}", rf_technology_and_mode);
+
+        self.send_control(nci::RfIntfActivatedNotificationBuilder {
+            rf_discovery_id: nci::RfDiscoveryId::from_index(rf_discovery_id),
+            rf_interface,
+            rf_protocol: rf_protocol.into(),
+            activation_rf_technology_and_mode: rf_technology_and_mode,
+            max_data_packet_payload_size: self.options.max_data_packet_payload_size,
+            initial_number_of_credits: 1,
+            rf_technology_specific_parameters: state.rf_poll_responses[rf_discovery_id]
+                .rf_technology_specific_parameters
+                .clone(),
+            data_exchange_rf_technology_and_mode: rf_technology_and_mode,
+            data_exchange_transmit_bit_rate: rf_technology.into(),
+            data_exchange_receive_bit_rate: rf_technology.into(),
+            // No activation parameters are defined for T1T and NFC-V.
+            activation_parameters: vec![],
+        })
+        .await
+    }
+
+    /// Return the delay of the RF_DISCOVER_NTF reporting a Remote NFC
+    /// Endpoint polled with the technology `technology`.
+    fn discover_notification_delay(&self, technology: rf::Technology) -> Duration {
+        self.options
+            .discover_notification_delays
+            .iter()
+            .rev()
+            .find(|(t, _)| *t == technology)
+            .map(|(_, delay)| *delay)
+            .unwrap_or(Duration::ZERO)
+    }
+
     /// Timer handler method. This function is invoked at regular interval
     /// on the NFCC instance and is used to drive internal timers.
     async fn tick(&self) -> Result<()> {
+        // Send the response held back past its deadline, the DH did not
+        // send another command in the meantime.
+        {
+            let mut held_response = self.held_response.lock().await;
+            if held_response.as_ref().is_some_and(|(_, deadline)| time::Instant::now() >= *deadline)
+            {
+                let (packet, _) = held_response.take().unwrap();
+                info!("[{}] sending held back response after timeout", self.id);
+                self.write_control(packet).await?
+            }
+        }
         {
             let mut state = self.state.lock().await;
+
+            // Fail the presence check the Remote NFC Endpoint did not
+            // answer in time.
+            if state
+                .presence_check_deadline
+                .is_some_and(|deadline| time::Instant::now() >= deadline)
+            {
+                state.presence_check_deadline = None;
+                info!("[{}] RF_ISO_DEP_NAK_PRESENCE_NTF", self.id);
+                info!("         Status: Failed");
+                self.send_control(nci::RfIsoDepNakPresenceNotificationBuilder {
+                    status: nci::Status::Failed,
+                })
+                .await?
+            }
+
+            // Request a timeout extension from the NFC-DEP Initiator while
+            // the DH has not answered the last message received.
+            if let RfState::ListenActive { id, rf_protocol: rf::Protocol::NfcDep, .. } =
+                state.rf_state
+            {
+                let now = time::Instant::now();
+                if state.nfc_dep.rtox_deadline.is_some_and(|deadline| now >= deadline) {
+                    state.nfc_dep.rtox_deadline =
+                        Some(now + Duration::from_millis(NFC_DEP_RTOX_DELAY));
+                    let pdu = NfcDepPdu::TimeoutExtension { rtox: NFC_DEP_RTOX };
+                    self.send_nfc_dep_pdu(&mut state, id, NFC_DEP_RES, pdu).await?
+                }
+            }
+
+            // The Remote NFC Endpoint in Poll Mode does not poll while
+            // the NFCC is activated in Listen Mode, its RF field is
+            // otherwise reported lost when the polls stop.
+            let now = time::Instant::now();
+            if matches!(state.rf_state, RfState::ListenActive { .. } | RfState::ListenSleep { .. })
+                && state.rf_field_detected_at.is_some()
+            {
+                state.rf_field_detected_at = Some(now);
+            } else if state.rf_field_detected_at.is_some_and(|detected_at| {
+                now >= detected_at + Duration::from_millis(RF_FIELD_TIMEOUT)
+            }) {
+                self.update_rf_field(&mut state, false).await?
+            }
+
             if state.rf_state != RfState::Discovery {
                 return Ok(());
             }
@@ -2027,21 +4278,50 @@ impl Controller {
             // Discover is implicitly implemented in response to poll and
             // select commands.
 
+            // [NCI] 5.2.2 The discovery process is a sequence of discovery
+            // periods lasting TOTAL_DURATION. Wait for the start of the
+            // next period.
+            let now = time::Instant::now();
+            if now < state.next_discovery_period {
+                return Ok(());
+            }
+            let total_duration = u64::from(state.config_parameters.total_duration);
+            state.next_discovery_period += Duration::from_millis(total_duration);
+            let discovery_period = state.discovery_period;
+            state.discovery_period += 1;
+
             // RF Discovery is ongoing and no peer device has been discovered
-            // so far. Send a RF poll command for all enabled technologies.
+            // so far. Send a RF poll command for all the technologies enabled
+            // in this discovery period.
             state.rf_poll_responses.clear();
             for configuration in state.discover_configuration.iter() {
+                // [NCI] RF_DISCOVER_CMD: the discovery frequency 0x01 polls in every
+                // discovery period, values 0x02-0x0A poll once every N
+                // periods. RFU values are handled as 0x01.
+                let frequency = match configuration.discovery_frequency {
+                    frequency @ 0x02..=0x0a => frequency as usize,
+                    _ => 1,
+                };
+                if discovery_period % frequency != 0 {
+                    continue;
+                }
+                let technology = match configuration.technology_and_mode {
+                    nci::RfTechnologyAndMode::NfcAPassivePollMode => rf::Technology::NfcA,
+                    nci::RfTechnologyAndMode::NfcBPassivePollMode => rf::Technology::NfcB,
+                    nci::RfTechnologyAndMode::NfcFPassivePollMode => rf::Technology::NfcF,
+                    nci::RfTechnologyAndMode::NfcVPassivePollMode => rf::Technology::NfcV,
+                    _ => continue,
+                };
+                // Technologies configured with a devices limit of 0 do
+                // not participate in the discovery.
+                if state.config_parameters.devices_limit(technology) == 0 {
+                    continue;
+                }
                 self.send_rf(rf::PollCommandBuilder {
                     sender: self.id,
                     receiver: u16::MAX,
                     protocol: rf::Protocol::Undetermined,
-                    technology: match configuration.technology_and_mode {
-                        nci::RfTechnologyAndMode::NfcAPassivePollMode => rf::Technology::NfcA,
-                        nci::RfTechnologyAndMode::NfcBPassivePollMode => rf::Technology::NfcB,
-                        nci::RfTechnologyAndMode::NfcFPassivePollMode => rf::Technology::NfcF,
-                        nci::RfTechnologyAndMode::NfcVPassivePollMode => rf::Technology::NfcV,
-                        _ => continue,
-                    },
+                    technology,
                 })
                 .await?
             }
@@ -2082,14 +4362,35 @@ impl Controller {
         // Protocol, it SHALL start sending RF_DISCOVER_NTF messages to the DH.
         // At this point, the state is changed to RFST_W4_ALL_DISCOVERIES.
         state.rf_state = RfState::WaitForHostSelect;
-        let last_index = state.rf_poll_responses.len() - 1;
-        for (index, response) in state.rf_poll_responses.clone().iter().enumerate() {
+
+        // Order the discoveries by notification delay, so that the RF
+        // Discovery IDs follow the order of the notifications.
+        let mut rf_poll_responses = state.rf_poll_responses.clone();
+        rf_poll_responses.sort_by_key(|r| self.discover_notification_delay(r.rf_technology));
+        state.rf_poll_responses = rf_poll_responses.clone();
+
+        let start = time::Instant::now();
+        let last_index = rf_poll_responses.len() - 1;
+        for (index, response) in rf_poll_responses.iter().enumerate() {
+            // Release the state while waiting, the DH can stop the
+            // discovery before all the notifications are sent.
+            let delay = self.discover_notification_delay(response.rf_technology);
+            if !delay.is_zero() {
+                drop(state);
+                time::sleep_until(start + delay).await;
+                state = self.state.lock().await;
+                if state.rf_state != RfState::WaitForHostSelect {
+                    return Ok(());
+                }
+            }
+
             self.send_control(nci::RfDiscoverNotificationBuilder {
                 rf_discovery_id: nci::RfDiscoveryId::from_index(index),
                 rf_protocol: response.rf_protocol.into(),
                 rf_technology_and_mode: match response.rf_technology {
                     rf::Technology::NfcA => nci::RfTechnologyAndMode::NfcAPassivePollMode,
                     rf::Technology::NfcB => nci::RfTechnologyAndMode::NfcBPassivePollMode,
+                    rf::Technology::NfcV => nci::RfTechnologyAndMode::NfcVPassivePollMode,
                     _ => todo!(),
                 },
                 rf_technology_specific_parameters: response
@@ -2107,21 +4408,37 @@ impl Controller {
         Ok(())
     }
 
+    /// Invoke the tick handler at the interval configured in the
+    /// options, until it fails.
+    async fn run_timer(&self) -> Result<()> {
+        let mut timer = time::interval(self.options.tick_interval);
+        loop {
+            timer.tick().await;
+            self.tick().await?
+        }
+    }
+
     /// Main NFCC instance routine.
+    /// The NFCC stays silent until the first command: CORE_RESET_NTF is
+    /// only sent in response to CORE_RESET_CMD.
+    /// Timers and timestamps use the tokio clock, which tests can pause
+    /// and advance manually with `tokio::time::pause` and `advance`.
     pub async fn run(
         id: u16,
+        options: ControllerOptions,
         nci_reader: nci::Reader,
         nci_writer: nci::Writer,
         mut rf_rx: mpsc::UnboundedReceiver<rf::RfPacket>,
         rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
     ) -> Result<()> {
         // Local controller state.
-        let nfcc = Controller::new(id, nci_writer, rf_tx);
+        let nfcc = Controller::new(id, options, nci_writer, rf_tx);
 
-        // Timer for tick events.
-        let mut timer = time::interval(Duration::from_millis(1000));
+        // Dump the NFCC state on SIGUSR1.
+        let mut sigusr1 =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
 
-        let result: Result<((), (), ())> = futures::future::try_join3(
+        let result: Result<((), (), (), ())> = futures::future::try_join4(
             // NCI event handler.
             async {
                 loop {
@@ -2129,10 +4446,18 @@ impl Controller {
                     let header = nci::PacketHeader::parse(&packet[0..3])?;
                     match header.get_mt() {
                         nci::MessageType::Data => {
-                            nfcc.receive_data(nci::DataPacket::parse(&packet)?).await?
+                            let packet = nci::DataPacket::parse(&packet)?;
+                            if nfcc.options.log_packets {
+                                debug!("[{}] NCI RX {:?}", id, packet);
+                            }
+                            nfcc.receive_data(packet).await?
                         }
                         nci::MessageType::Command => {
-                            nfcc.receive_command(nci::ControlPacket::parse(&packet)?).await?
+                            let packet = nci::ControlPacket::parse(&packet)?;
+                            if nfcc.options.log_packets {
+                                debug!("[{}] NCI RX {:?}", id, packet);
+                            }
+                            nfcc.receive_command(packet).await?
                         }
                         mt => {
                             return Err(anyhow::anyhow!(
@@ -2143,21 +4468,23 @@ impl Controller {
                     }
                 }
             },
-            // RF event handler.
+            // RF event handler. The NCI commands are still served after
+            // the RF channel is closed.
             async {
-                loop {
-                    nfcc.receive_rf(
-                        rf_rx.recv().await.ok_or(anyhow::anyhow!("rf_rx channel closed"))?,
-                    )
-                    .await?
+                while let Some(packet) = rf_rx.recv().await {
+                    nfcc.receive_rf(packet).await?
                 }
+                nfcc.rf_closed().await?;
+                futures::future::pending().await
             },
             // Timer event handler.
+            nfcc.run_timer(),
+            // Signal event handler.
             async {
-                loop {
-                    timer.tick().await;
-                    nfcc.tick().await?
+                while sigusr1.recv().await.is_some() {
+                    info!("{}", nfcc.describe().await);
                 }
+                Ok(())
             },
         )
         .await;
@@ -2165,3 +4492,2561 @@ impl Controller {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packets::nci::test_support::{capture, expect_control, expect_data};
+
+    /// Id of the NFCC under test.
+    const NFCC_ID: u16 = 0;
+
+    thread_local! {
+        /// Messages logged by the test running on this thread, recorded by
+        /// [`CaptureLogger`].
+        static LOGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(vec![]) };
+    }
+
+    /// Logger recording all messages, to check the logs of the NFCC.
+    struct CaptureLogger;
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            LOGS.with(|logs| logs.borrow_mut().push(record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Start recording the messages logged by the current test. Tests run
+    /// concurrently on separate threads, the messages are recorded per thread.
+    fn capture_logs() {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+        LOGS.with(|logs| logs.borrow_mut().clear());
+    }
+
+    /// Return the recorded messages starting with `prefix`, once stripped of
+    /// the `[id]` of the NFCC.
+    fn captured_logs(prefix: &str) -> Vec<String> {
+        LOGS.with(|logs| {
+            logs.borrow()
+                .iter()
+                .map(|log| match log.strip_prefix('[').and_then(|log| log.split_once("] ")) {
+                    Some((_, message)) => message,
+                    None => log,
+                })
+                .filter(|message| message.starts_with(prefix))
+                .map(String::from)
+                .collect()
+        })
+    }
+
+    /// NFCC under test, with the DH end of its NCI transport and the
+    /// receiver of the RF packets it sends.
+    struct Harness {
+        nfcc: Controller,
+        dh: nci::Reader,
+        rf: mpsc::UnboundedReceiver<rf::RfPacket>,
+    }
+
+    impl Harness {
+        fn new(id: u16, options: ControllerOptions) -> Self {
+            let (nci_writer, dh) = capture();
+            let (rf_tx, rf) = mpsc::unbounded_channel();
+            Harness { nfcc: Controller::new(id, options, nci_writer, rf_tx), dh, rf }
+        }
+
+        /// Process a command received from the DH.
+        async fn command(&self, packet: impl Into<nci::ControlPacket>) {
+            self.nfcc.receive_command(packet.into()).await.unwrap()
+        }
+
+        /// Check the next control packet sent to the DH.
+        async fn expect(&self, expected: impl Into<nci::ControlPacket>) {
+            expect_control(&self.dh, expected).await.unwrap()
+        }
+
+        /// Return the next control packet sent to the DH.
+        async fn next_control(&self) -> nci::ControlPacket {
+            nci::ControlPacket::parse(&self.dh.read().await.unwrap()).unwrap()
+        }
+
+        /// Reset and initialize the NFCC.
+        async fn reset_and_init(&self) {
+            self.command(core_reset(nci::ResetType::ResetConfig)).await;
+            self.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+            self.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+            self.command(core_init()).await;
+            let response = self.next_control().await;
+            assert_eq!(response.get_mt(), nci::MessageType::Response);
+        }
+    }
+
+    fn core_reset(reset_type: nci::ResetType) -> nci::ControlPacket {
+        nci::CoreResetCommandBuilder { reset_type }.build().into()
+    }
+
+    fn core_reset_notification(config_status: nci::ConfigStatus) -> nci::ControlPacket {
+        nci::CoreResetNotificationBuilder {
+            trigger: nci::ResetTrigger::ResetCommand,
+            config_status,
+            nci_version: NCI_VERSION,
+            manufacturer_id: MANUFACTURER_ID,
+            manufacturer_specific_information: MANUFACTURER_SPECIFIC_INFORMATION.to_vec(),
+        }
+        .build()
+        .into()
+    }
+
+    fn core_init() -> nci::ControlPacket {
+        nci::CoreInitCommandBuilder {
+            feature_enable: nci::FeatureEnable {
+                discovery_frequency_configuration: nci::FeatureFlag::Disabled,
+                battery_off_state: nci::FeatureFlag::Disabled,
+                rf_configuration_in_switched_off_state: nci::FeatureFlag::Disabled,
+            },
+        }
+        .build()
+        .into()
+    }
+
+    fn data(conn_id: nci::ConnId, payload: &[u8]) -> nci::DataPacket {
+        nci::DataPacketBuilder {
+            mt: nci::MessageType::Data,
+            conn_id,
+            cr: 0,
+            payload: Some(bytes::Bytes::copy_from_slice(payload)),
+        }
+        .build()
+    }
+
+    fn core_conn_create(
+        destination_type: nci::DestinationType,
+        id: nci::DestinationSpecificParameterId,
+        value: &[u8],
+    ) -> nci::ControlPacket {
+        nci::CoreConnCreateCommandBuilder {
+            destination_type,
+            parameters: vec![nci::DestinationSpecificParameter { id, value: value.to_vec() }],
+        }
+        .build()
+        .into()
+    }
+
+    fn core_conn_create_nfcee() -> nci::ControlPacket {
+        core_conn_create(
+            nci::DestinationType::Nfcee,
+            nci::DestinationSpecificParameterId::Nfcee,
+            &[nci::NfceeId::hci_nfcee(0x86).into(), nci::NfceeProtocolType::Apdu.into()],
+        )
+    }
+
+    fn core_conn_create_remote(rf_discovery_id: u8) -> nci::ControlPacket {
+        core_conn_create(
+            nci::DestinationType::RemoteNfcEndpoint,
+            nci::DestinationSpecificParameterId::RfDiscovery,
+            &[rf_discovery_id, nci::RfProtocolType::IsoDep.into()],
+        )
+    }
+
+    fn core_conn_create_failure(status: nci::Status) -> nci::CoreConnCreateResponseBuilder {
+        nci::CoreConnCreateResponseBuilder {
+            status,
+            max_data_packet_payload_size: 0,
+            initial_number_of_credits: CREDITS_FLOW_CONTROL_DISABLED,
+            conn_id: 0.try_into().unwrap(),
+        }
+    }
+
+    fn routing_entry(
+        r#type: nci::ListenModeRoutingEntryType,
+        value: &[u8],
+    ) -> nci::ListenModeRoutingEntry {
+        nci::ListenModeRoutingEntry {
+            r#type,
+            match_longer_aids: nci::FeatureFlag::Disabled,
+            match_shorter_aids: nci::FeatureFlag::Disabled,
+            routing_blocked_for_unsupported_power_modes: nci::FeatureFlag::Disabled,
+            value: value.to_vec(),
+        }
+    }
+
+    fn rf_discover(technology_and_mode: nci::RfTechnologyAndMode) -> nci::ControlPacket {
+        nci::RfDiscoverCommandBuilder {
+            configurations: vec![nci::DiscoverConfiguration {
+                technology_and_mode,
+                discovery_frequency: 1,
+            }],
+        }
+        .into()
+    }
+
+    fn core_set_config(parameters: &[(nci::ConfigParameterId, &[u8])]) -> nci::ControlPacket {
+        nci::CoreSetConfigCommandBuilder {
+            parameters: parameters
+                .iter()
+                .map(|(id, value)| nci::ConfigParameter { id: *id, value: value.to_vec() })
+                .collect(),
+        }
+        .build()
+        .into()
+    }
+
+    #[tokio::test]
+    async fn log_packets_logs_decoded_packets() {
+        capture_logs();
+        let (dh_writer, nfcc_reader) = capture();
+        let (nfcc_writer, dh_reader) = capture();
+        let (rf_tx, rf_rx) = mpsc::unbounded_channel();
+        let options = ControllerOptions { log_packets: true, ..Default::default() };
+        let nfcc = Controller::run(NFCC_ID, options, nfcc_reader, nfcc_writer, rf_rx, rf_tx);
+        let dh = async {
+            dh_writer.write(&core_reset(nci::ResetType::KeepConfig).to_vec()).await?;
+            expect_control(&dh_reader, nci::CoreResetResponseBuilder { status: nci::Status::Ok })
+                .await?;
+            expect_control(&dh_reader, core_reset_notification(nci::ConfigStatus::ConfigKept)).await
+        };
+        tokio::select! {
+            result = nfcc => panic!("NFCC stopped: {:?}", result),
+            result = dh => result.unwrap(),
+        }
+
+        let logs = captured_logs("NCI");
+        assert_eq!(logs.len(), 3);
+        assert!(logs[0].starts_with("NCI RX") && logs[0].contains("reset_type: KeepConfig"));
+        assert!(logs[1].starts_with("NCI TX") && logs[1].contains("status: Ok"));
+        assert!(logs[2].starts_with("NCI TX") && logs[2].contains("ConfigKept"));
+    }
+
+    #[tokio::test]
+    async fn core_reset_clears_the_rf_activation() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.rf_state = RfState::PollActive {
+                id: 1,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::IsoDep,
+            };
+            state.rf_activation_parameters = vec![0x05, 0x78];
+            state.logical_connections[0] = Some(LogicalConnection::RemoteNfcEndpoint {
+                rf_discovery_id: 1,
+                rf_protocol_type: nci::RfProtocolType::IsoDep,
+            });
+        }
+
+        harness.reset_and_init().await;
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.rf_state, RfState::Idle);
+        assert!(state.rf_activation_parameters.is_empty());
+        assert_eq!(state.logical_connections[0], None);
+    }
+
+    #[tokio::test]
+    async fn describe_reports_connection_credits() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.logical_connections[1] = Some(LogicalConnection::Nfcee {
+                nfcee_id: nci::NfceeId::nfcee(0x86),
+                nfcee_protocol_type: nci::NfceeProtocolType::Apdu,
+            });
+            state.logical_connection_credits[1] = 2;
+            state.withheld_credits[1] = 1;
+        }
+
+        let description = harness.nfcc.describe().await;
+        let connection = description
+            .lines()
+            .find(|line| line.trim_start().starts_with("Logical connection"))
+            .unwrap();
+        assert!(connection.ends_with("credits: 2, withheld credits: 1"), "{}", connection);
+        assert_eq!(description.matches("Logical connection").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn core_set_config_rejects_configuration_exceeding_storage() {
+        let options = ControllerOptions { max_config_storage_size: 8, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness.command(core_set_config(&[(nci::ConfigParameterId::Prop1, &[1; 4])])).await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+
+        harness
+            .command(core_set_config(&[
+                (nci::ConfigParameterId::TotalDuration, &[0xf4, 0x01]),
+                (nci::ConfigParameterId::Prop2, &[2; 5]),
+            ]))
+            .await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::MessageSizeExceeded,
+                parameters: vec![],
+            })
+            .await;
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.config_parameters.prop1, vec![1; 4]);
+        assert!(state.config_parameters.prop2.is_empty());
+        assert_eq!(state.config_parameters.total_duration, TOTAL_DURATION);
+    }
+
+    #[tokio::test]
+    async fn dynamic_connection_data_is_forwarded_and_credited() {
+        let options = ControllerOptions { remote_endpoint_credits: 1, ..Default::default() };
+        let mut harness = Harness::new(NFCC_ID, options);
+        let rf_state = RfState::PollActive {
+            id: 7,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.rf_state = rf_state;
+            state.logical_connections[0] = Some(LogicalConnection::RemoteNfcEndpoint {
+                rf_discovery_id: 1,
+                rf_protocol_type: nci::RfProtocolType::IsoDep,
+            });
+            state.logical_connection_credits[0] = 1;
+        }
+
+        harness.nfcc.receive_data(data(nci::ConnId::from_dynamic(0), &[1, 2, 3])).await.unwrap();
+        let expected: rf::RfPacket = rf::DataBuilder {
+            receiver: 7,
+            sender: NFCC_ID,
+            protocol: rf::Protocol::IsoDep,
+            technology: rf::Technology::NfcA,
+            data: vec![1, 2, 3],
+        }
+        .build()
+        .into();
+        assert_eq!(harness.rf.try_recv().unwrap(), expected);
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::from_dynamic(0),
+                    credits: 1,
+                }],
+            })
+            .await;
+        assert_eq!(harness.nfcc.state.lock().await.logical_connection_credits[0], 1);
+
+        // Data received on a connection that is not open is discarded
+        // without returning credits.
+        harness.nfcc.receive_data(data(nci::ConnId::from_dynamic(1), &[4])).await.unwrap();
+        assert!(harness.rf.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn nfcee_mode_set_rejects_unknown_nfcee() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness
+            .command(nci::NfceeModeSetCommandBuilder {
+                nfcee_id: nci::NfceeId::hci_nfcee(0x42),
+                nfcee_mode: nci::NfceeMode::Enable,
+            })
+            .await;
+        harness.expect(nci::NfceeModeSetResponseBuilder { status: nci::Status::Rejected }).await;
+        assert_eq!(harness.nfcc.state.lock().await.nfcee_state, NfceeState::Disabled);
+
+        harness
+            .command(nci::NfceeModeSetCommandBuilder {
+                nfcee_id: nci::NfceeId::hci_nfcee(0x86),
+                nfcee_mode: nci::NfceeMode::Disable,
+            })
+            .await;
+        harness.expect(nci::NfceeModeSetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(nci::NfceeModeSetNotificationBuilder { status: nci::Status::Ok }).await;
+        assert_eq!(harness.nfcc.state.lock().await.nfcee_state, NfceeState::Disabled);
+    }
+
+    #[tokio::test]
+    async fn core_conn_create_distinguishes_duplicate_destinations() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.nfcc.state.lock().await.nfcee_state = NfceeState::Enabled;
+        harness.command(core_conn_create_nfcee()).await;
+        let response = harness.next_control().await;
+        let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+
+        // A second connection to the same NFCEE is a semantic error.
+        harness.command(core_conn_create_nfcee()).await;
+        harness.expect(core_conn_create_failure(nci::Status::SemanticError)).await;
+
+        // Running out of connections is reported as a rejection.
+        let options = ControllerOptions { max_logical_connections: 1, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness.command(core_conn_create_remote(1)).await;
+        let response = harness.next_control().await;
+        let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+        harness.command(core_conn_create_remote(2)).await;
+        harness.expect(core_conn_create_failure(nci::Status::Rejected)).await;
+    }
+
+    #[tokio::test]
+    async fn nfcv_tag_is_activated_and_read() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcVPassivePollMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The tag answers the INVENTORY poll of the discovery period.
+        let uid = [1, 2, 3, 4, 5, 6, 7, 0xe0];
+        let tag = async {
+            let poll = harness.rf.recv().await.unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcV);
+            harness
+                .nfcc
+                .receive_rf(
+                    rf::NfcVPollResponseBuilder {
+                        sender: 2,
+                        receiver: NFCC_ID,
+                        resp_flags: 0,
+                        dsfid: 0,
+                        uid,
+                    }
+                    .build()
+                    .into(),
+                )
+                .await
+                .unwrap()
+        };
+        let (result, ()) = tokio::join!(harness.nfcc.tick(), tag);
+        result.unwrap();
+        let activated = harness.next_control().await;
+        let activated = nci::RfIntfActivatedNotification::try_from(activated).unwrap();
+        assert_eq!(activated.get_rf_protocol(), nci::RfProtocolType::T5t);
+        assert_eq!(
+            activated.get_activation_rf_technology_and_mode(),
+            nci::RfTechnologyAndMode::NfcVPassivePollMode
+        );
+        let mut parameters = vec![0, 0];
+        parameters.extend(uid);
+        assert_eq!(activated.get_rf_technology_specific_parameters(), &parameters);
+
+        // READ SINGLE BLOCK of the block 0 is forwarded to the tag, and the
+        // block content returned to the DH followed by the status.
+        let read_single_block = [0x02, 0x20, 0x00];
+        harness.nfcc.receive_data(data(nci::ConnId::StaticRf, &read_single_block)).await.unwrap();
+        let expected: rf::RfPacket = rf::DataBuilder {
+            receiver: 2,
+            sender: NFCC_ID,
+            protocol: rf::Protocol::T5t,
+            technology: rf::Technology::NfcV,
+            data: read_single_block.to_vec(),
+        }
+        .build()
+        .into();
+        assert_eq!(harness.rf.try_recv().unwrap(), expected);
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::StaticRf,
+                    credits: 1,
+                }],
+            })
+            .await;
+
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DataBuilder {
+                    receiver: NFCC_ID,
+                    sender: 2,
+                    protocol: rf::Protocol::T5t,
+                    technology: rf::Technology::NfcV,
+                    data: vec![0x00, 0xca, 0xfe, 0xca, 0xfe],
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        expect_data(
+            &harness.dh,
+            data(nci::ConnId::StaticRf, &[0x00, 0xca, 0xfe, 0xca, 0xfe, 0x00]),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn dual_technology_tag_is_discovered_on_each_technology() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness
+            .command(nci::RfDiscoverCommandBuilder {
+                configurations: vec![
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcVPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                ],
+            })
+            .await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The same tag answers the NFC-A and NFC-V polls, with identifiers
+        // built from the same serial number.
+        let serial = [0x12, 0x34, 0x56];
+        let Harness { nfcc, dh, rf } = &mut harness;
+        let tag = async {
+            let poll = rf::PollCommand::try_from(rf.recv().await.unwrap()).unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcA);
+            let nfca_response = rf::NfcAPollResponseBuilder {
+                protocol: rf::Protocol::Undetermined,
+                sender: 2,
+                receiver: NFCC_ID,
+                nfcid1: [&[0x08], &serial[..]].concat(),
+                int_protocol: 0b01,
+                bit_frame_sdd: 0x04,
+            };
+            nfcc.receive_rf(nfca_response.build().into()).await.unwrap();
+            let poll = rf::PollCommand::try_from(rf.recv().await.unwrap()).unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcV);
+            let nfcv_response = rf::NfcVPollResponseBuilder {
+                sender: 2,
+                receiver: NFCC_ID,
+                resp_flags: 0,
+                dsfid: 0,
+                uid: [serial[0], serial[1], serial[2], 0, 0, 0, 0x04, 0xe0],
+            };
+            nfcc.receive_rf(nfcv_response.build().into()).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(nfcc.tick(), tag);
+        result.unwrap();
+
+        // The tag is reported once per technology, both identifiers
+        // carrying the serial number.
+        for (rf_protocol, technology_and_mode) in [
+            (nci::RfProtocolType::IsoDep, nci::RfTechnologyAndMode::NfcAPassivePollMode),
+            (nci::RfProtocolType::T5t, nci::RfTechnologyAndMode::NfcVPassivePollMode),
+        ] {
+            let notification = nci::ControlPacket::parse(&dh.read().await.unwrap()).unwrap();
+            let notification = nci::RfDiscoverNotification::try_from(notification).unwrap();
+            assert_eq!(notification.get_rf_protocol(), rf_protocol);
+            assert_eq!(notification.get_rf_technology_and_mode(), technology_and_mode);
+            let parameters = notification.get_rf_technology_specific_parameters();
+            assert!(parameters.windows(3).any(|bytes| bytes == serial), "{:02x?}", parameters);
+        }
+
+        // The DH selects the NFC-A technology of the tag.
+        harness
+            .command(nci::RfDiscoverSelectCommandBuilder {
+                rf_discovery_id: nci::RfDiscoveryId::from_index(0),
+                rf_protocol: nci::RfProtocolType::IsoDep,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+            })
+            .await;
+        harness.expect(nci::RfDiscoverSelectResponseBuilder { status: nci::Status::Ok }).await;
+        let select = rf::T4ATSelectCommand::try_from(harness.rf.try_recv().unwrap()).unwrap();
+        assert_eq!(select.get_receiver(), 2);
+    }
+
+    #[tokio::test]
+    async fn t1t_tag_is_activated_and_read() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcAPassivePollMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The tag answers the poll of the discovery period with its
+        // header ROM and UID.
+        let hr = [0x11, 0x48];
+        let uid = [1, 2, 3, 4];
+        let tag = async {
+            let poll = harness.rf.recv().await.unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcA);
+            harness
+                .nfcc
+                .receive_rf(
+                    rf::T1TPollResponseBuilder { sender: 2, receiver: NFCC_ID, hr, uid }
+                        .build()
+                        .into(),
+                )
+                .await
+                .unwrap()
+        };
+        let (result, ()) = tokio::join!(harness.nfcc.tick(), tag);
+        result.unwrap();
+        let activated = harness.next_control().await;
+        let activated = nci::RfIntfActivatedNotification::try_from(activated).unwrap();
+        assert_eq!(activated.get_rf_protocol(), nci::RfProtocolType::T1t);
+        assert_eq!(activated.get_rf_interface(), nci::RfInterfaceType::Frame);
+        assert_eq!(
+            activated.get_activation_rf_technology_and_mode(),
+            nci::RfTechnologyAndMode::NfcAPassivePollMode
+        );
+        let mut parameters = vec![0x00, 0x0c, 4];
+        parameters.extend(uid);
+        parameters.extend([0, 2]);
+        parameters.extend(hr);
+        assert_eq!(activated.get_rf_technology_specific_parameters(), &parameters);
+
+        // READ of the byte 8 is forwarded to the tag, and the address and
+        // content of the byte returned to the DH followed by the status.
+        let read = [&[0x01, 0x08, 0x00][..], &uid].concat();
+        harness.nfcc.receive_data(data(nci::ConnId::StaticRf, &read)).await.unwrap();
+        let expected: rf::RfPacket = rf::DataBuilder {
+            receiver: 2,
+            sender: NFCC_ID,
+            protocol: rf::Protocol::T1t,
+            technology: rf::Technology::NfcA,
+            data: read.clone(),
+        }
+        .build()
+        .into();
+        assert_eq!(harness.rf.try_recv().unwrap(), expected);
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::StaticRf,
+                    credits: 1,
+                }],
+            })
+            .await;
+
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DataBuilder {
+                    receiver: NFCC_ID,
+                    sender: 2,
+                    protocol: rf::Protocol::T1t,
+                    technology: rf::Technology::NfcA,
+                    data: vec![0x08, 0xe1],
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        expect_data(&harness.dh, data(nci::ConnId::StaticRf, &[0x08, 0xe1, 0x00])).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rf_link_loss_closes_remote_endpoint_connections() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let nfcee = LogicalConnection::Nfcee {
+            nfcee_id: nci::NfceeId::hci_nfcee(0x86),
+            nfcee_protocol_type: nci::NfceeProtocolType::Apdu,
+        };
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.rf_state = RfState::PollActive {
+                id: 2,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::IsoDep,
+            };
+            state.logical_connections[0] = Some(LogicalConnection::RemoteNfcEndpoint {
+                rf_discovery_id: 1,
+                rf_protocol_type: nci::RfProtocolType::IsoDep,
+            });
+            state.logical_connections[1] = Some(nfcee);
+        }
+
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DeactivateNotificationBuilder {
+                    sender: 2,
+                    receiver: NFCC_ID,
+                    protocol: rf::Protocol::IsoDep,
+                    technology: rf::Technology::NfcA,
+                    type_: rf::DeactivateType::Discovery,
+                    reason: rf::DeactivateReason::RfLinkLoss,
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        harness
+            .expect(nci::RfDeactivateNotificationBuilder {
+                deactivation_type: nci::DeactivationType::Discovery,
+                deactivation_reason: nci::DeactivationReason::RfLinkLoss,
+            })
+            .await;
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.rf_state, RfState::Discovery);
+        assert_eq!(state.logical_connections[0], None);
+        assert_eq!(state.logical_connections[1], Some(nfcee));
+    }
+
+    #[tokio::test]
+    async fn core_init_advertises_requested_features() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        harness
+            .command(nci::CoreInitCommandBuilder {
+                feature_enable: nci::FeatureEnable {
+                    discovery_frequency_configuration: nci::FeatureFlag::Disabled,
+                    battery_off_state: nci::FeatureFlag::Enabled,
+                    rf_configuration_in_switched_off_state: nci::FeatureFlag::Disabled,
+                },
+            })
+            .await;
+
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        let features = response.get_nfcc_features();
+        assert_eq!(features.battery_off_state, nci::FeatureFlag::Enabled);
+        assert_eq!(features.discovery_frequency_configuration, nci::FeatureFlag::Disabled);
+        assert_eq!(features.rf_configuration_in_switched_off_state, nci::FeatureFlag::Disabled);
+    }
+
+    #[tokio::test]
+    async fn listen_mode_routing_is_disabled_by_prop0() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let set_routing = || nci::RfSetListenModeRoutingCommandBuilder {
+            more_to_follow: 0,
+            routing_entries: vec![],
+        };
+        harness.command(set_routing()).await;
+        harness
+            .expect(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
+            .await;
+
+        harness.command(core_set_config(&[(nci::ConfigParameterId::Prop0, &[0])])).await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+        harness.command(set_routing()).await;
+        harness
+            .expect(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::Rejected })
+            .await;
+
+        // The routing features are no longer advertised after a reset
+        // keeping the configuration.
+        harness.command(core_reset(nci::ResetType::KeepConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigKept)).await;
+        harness.command(core_init()).await;
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_nfcc_features().aid_based_routing, nci::FeatureFlag::Disabled);
+    }
+
+    #[tokio::test]
+    async fn core_reset_notification_reports_manufacturer() {
+        let options = ControllerOptions {
+            manufacturer_id: 0x42,
+            manufacturer_specific_information: vec![0xca, 0xfe],
+            ..Default::default()
+        };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.command(core_reset(nci::ResetType::KeepConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness
+            .expect(nci::CoreResetNotificationBuilder {
+                trigger: nci::ResetTrigger::ResetCommand,
+                config_status: nci::ConfigStatus::ConfigKept,
+                nci_version: NCI_VERSION,
+                manufacturer_id: 0x42,
+                manufacturer_specific_information: vec![0xca, 0xfe],
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn listen_mode_select_is_routed_to_nfcee_by_aid() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let nfcee_id = nci::NfceeId::hci_nfcee(0x86);
+        harness
+            .command(nci::RfSetListenModeRoutingCommandBuilder {
+                more_to_follow: 0,
+                routing_entries: vec![routing_entry(
+                    nci::ListenModeRoutingEntryType::AidBasedRouting,
+                    &[0x86, 0x01, 0xa0, 0x00, 0x00, 0x01],
+                )],
+            })
+            .await;
+        harness
+            .expect(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
+            .await;
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.nfcee_state = NfceeState::Enabled;
+            state.rf_state = RfState::ListenActive {
+                id: 2,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::IsoDep,
+            };
+        }
+        let apdu = |data: &[u8]| -> rf::RfPacket {
+            rf::DataBuilder {
+                receiver: NFCC_ID,
+                sender: 2,
+                protocol: rf::Protocol::IsoDep,
+                technology: rf::Technology::NfcA,
+                data: data.to_vec(),
+            }
+            .build()
+            .into()
+        };
+        let rf_response = |rf: &mut mpsc::UnboundedReceiver<rf::RfPacket>| {
+            rf::Data::try_from(rf.try_recv().unwrap()).unwrap().get_data().to_vec()
+        };
+
+        // A SELECT matching no routing entry is delivered to the DH.
+        let select_other = [0x00, 0xa4, 0x04, 0x00, 0x02, 0xa0, 0x01];
+        harness.nfcc.receive_rf(apdu(&select_other)).await.unwrap();
+        let forwarded = nci::DataPacket::parse(&harness.dh.read().await.unwrap()).unwrap();
+        assert_eq!(forwarded.get_payload(), &select_other[..]);
+        assert!(harness.rf.try_recv().is_err());
+
+        // A SELECT matching the AID routing entry is answered by the
+        // NFCEE, and so are the following APDUs.
+        let select = [0x00, 0xa4, 0x04, 0x00, 0x04, 0xa0, 0x00, 0x00, 0x01];
+        harness.nfcc.receive_rf(apdu(&select)).await.unwrap();
+        harness
+            .expect(nci::RfNfceeActionNotificationBuilder {
+                nfcee_id,
+                trigger: nci::NfceeActionTrigger::SelectCommandWithAid,
+                supporting_data: vec![0xa0, 0x00, 0x00, 0x01],
+            })
+            .await;
+        assert_eq!(rf_response(&mut harness.rf), [0x90, 0x00]);
+        harness.nfcc.receive_rf(apdu(&[0x00, 0xb0, 0x00, 0x00, 0x00])).await.unwrap();
+        assert_eq!(rf_response(&mut harness.rf), [0x6d, 0x00]);
+        assert_eq!(harness.nfcc.state.lock().await.listen_mode_route, Some(nfcee_id));
+    }
+
+    #[tokio::test]
+    async fn rf_discover_is_rejected_while_discovering() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let discover = || rf_discover(nci::RfTechnologyAndMode::NfcAPassivePollMode);
+        harness.command(discover()).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+        harness.command(discover()).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::SemanticError }).await;
+        assert_eq!(harness.nfcc.state.lock().await.rf_state, RfState::Discovery);
+
+        // Discovery can be started again once stopped.
+        harness
+            .command(nci::RfDeactivateCommandBuilder {
+                deactivation_type: nci::DeactivationType::IdleMode,
+            })
+            .await;
+        harness.expect(nci::RfDeactivateResponseBuilder { status: nci::Status::Ok }).await;
+        harness.command(discover()).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+    }
+
+    #[tokio::test]
+    async fn core_get_config_keeps_available_parameters_in_order() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let unknown = nci::ConfigParameterId::try_from(0x7f).unwrap();
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![
+                    nci::ConfigParameterId::PowerState,
+                    unknown,
+                    nci::ConfigParameterId::TotalDuration,
+                ],
+            })
+            .await;
+
+        let defaults = ConfigParameters::default();
+        let value = |id| defaults.get(id).unwrap();
+        harness
+            .expect(nci::CoreGetConfigResponseBuilder {
+                status: nci::Status::InvalidParam,
+                parameters: vec![
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::PowerState,
+                        value: value(nci::ConfigParameterId::PowerState),
+                    },
+                    nci::ConfigParameter { id: unknown, value: vec![] },
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::TotalDuration,
+                        value: value(nci::ConfigParameterId::TotalDuration),
+                    },
+                ],
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn ignore_reset_config_keeps_configuration() {
+        let options = ControllerOptions { ignore_reset_config: true, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness
+            .command(core_set_config(&[(nci::ConfigParameterId::TotalDuration, &[0x10, 0x00])]))
+            .await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+
+        // The configuration is reported as reset, but kept.
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        assert_eq!(harness.nfcc.state.lock().await.config_parameters.total_duration, 0x10);
+    }
+
+    #[tokio::test]
+    async fn data_on_unknown_connection_is_discarded() {
+        capture_logs();
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.nfcc.receive_data(data(nci::ConnId::from_dynamic(3), &[0x00, 0xa4])).await.unwrap();
+
+        // Neither forwarded nor credited.
+        assert!(harness.rf.try_recv().is_err());
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, harness.dh.read()).await.is_err());
+        assert_eq!(
+            captured_logs("ignored"),
+            vec!["ignored data packet on unknown logical connection 5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn system_code_routing_entries_are_validated() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let system_code = |value: &[u8]| {
+            routing_entry(nci::ListenModeRoutingEntryType::SystemCodeBasedRouting, value)
+        };
+        let valid = system_code(&[0x86, 0x01, 0x12, 0xfc]);
+        harness
+            .command(nci::RfSetListenModeRoutingCommandBuilder {
+                more_to_follow: 0,
+                routing_entries: vec![valid.clone()],
+            })
+            .await;
+        harness
+            .expect(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
+            .await;
+
+        // A system code must be two octets long, the whole command is
+        // rejected and the routing table is left unchanged.
+        harness
+            .command(nci::RfSetListenModeRoutingCommandBuilder {
+                more_to_follow: 0,
+                routing_entries: vec![valid.clone(), system_code(&[0x86, 0x01, 0x12])],
+            })
+            .await;
+        harness
+            .expect(nci::RfSetListenModeRoutingResponseBuilder { status: nci::Status::SyntaxError })
+            .await;
+        assert_eq!(harness.nfcc.state.lock().await.listen_mode_routing, vec![valid]);
+    }
+
+    #[tokio::test]
+    async fn remote_endpoint_connections_grant_configured_credits() {
+        let options = ControllerOptions { remote_endpoint_credits: 2, ..Default::default() };
+        let mut harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness.command(core_conn_create_remote(1)).await;
+        let response = harness.next_control().await;
+        let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+        assert_eq!(response.get_initial_number_of_credits(), 2);
+        assert_eq!(response.get_conn_id(), nci::ConnId::from_dynamic(0));
+        harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+            id: 7,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+
+        // The credit used by a forwarded packet is returned.
+        harness.nfcc.receive_data(data(nci::ConnId::from_dynamic(0), &[1])).await.unwrap();
+        assert_eq!(rf::Data::try_from(harness.rf.try_recv().unwrap()).unwrap().get_data(), &[1]);
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::from_dynamic(0),
+                    credits: 1,
+                }],
+            })
+            .await;
+        assert_eq!(harness.nfcc.state.lock().await.logical_connection_credits[0], 2);
+
+        // A packet sent after the DH ran out of credits is dropped.
+        harness.nfcc.state.lock().await.logical_connection_credits[0] = 0;
+        harness.nfcc.receive_data(data(nci::ConnId::from_dynamic(0), &[2])).await.unwrap();
+        assert!(harness.rf.try_recv().is_err());
+        let wait = Duration::from_millis(10);
+        assert!(tokio::time::timeout(wait, harness.dh.read()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn core_conn_create_validates_nfcee_parameter() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let nfcee = |value: &[u8]| {
+            core_conn_create(
+                nci::DestinationType::Nfcee,
+                nci::DestinationSpecificParameterId::Nfcee,
+                value,
+            )
+        };
+        let apdu = nci::NfceeProtocolType::Apdu.into();
+        let transparent = nci::NfceeProtocolType::Transparent.into();
+
+        // The NFCEE must be enabled.
+        harness.command(core_conn_create_nfcee()).await;
+        harness.expect(core_conn_create_failure(nci::Status::Rejected)).await;
+
+        harness.nfcc.state.lock().await.nfcee_state = NfceeState::Enabled;
+        for malformed in [
+            nfcee(&[0x86]),
+            nfcee(&[0x86, apdu, 0x00]),
+            nfcee(&[0x87, apdu]),
+            nfcee(&[0x86, transparent]),
+            core_conn_create(
+                nci::DestinationType::Nfcee,
+                nci::DestinationSpecificParameterId::RfDiscovery,
+                &[0x86, apdu],
+            ),
+            nci::CoreConnCreateCommandBuilder {
+                destination_type: nci::DestinationType::Nfcee,
+                parameters: vec![],
+            }
+            .into(),
+        ] {
+            harness.command(malformed).await;
+            harness.expect(core_conn_create_failure(nci::Status::Rejected)).await;
+        }
+
+        harness.command(nfcee(&[0x86, apdu])).await;
+        let response = harness.next_control().await;
+        let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+        assert_eq!(
+            harness.nfcc.state.lock().await.logical_connections[0],
+            Some(LogicalConnection::Nfcee {
+                nfcee_id: nci::NfceeId::hci_nfcee(0x86),
+                nfcee_protocol_type: nci::NfceeProtocolType::Apdu,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn core_get_config_returns_preloaded_parameters() {
+        let tlv = [0x00, 0x02, 0x10, 0x00, 0xa1, 0x04, 0x01, 0x02, 0x03, 0x04];
+        assert!(ConfigParameters::from_tlv(&tlv, 4).is_err());
+        let options = ControllerOptions {
+            max_config_storage_size: 8,
+            config_parameters: ConfigParameters::from_tlv(&tlv, 8).unwrap(),
+            ..Default::default()
+        };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![
+                    nci::ConfigParameterId::TotalDuration,
+                    nci::ConfigParameterId::Prop1,
+                ],
+            })
+            .await;
+        harness
+            .expect(nci::CoreGetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::TotalDuration,
+                        value: vec![0x10, 0x00],
+                    },
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::Prop1,
+                        value: vec![0x01, 0x02, 0x03, 0x04],
+                    },
+                ],
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn core_init_advertises_selected_rf_interfaces() {
+        let options = ControllerOptions {
+            rf_interfaces: vec![nci::RfInterfaceType::IsoDep, nci::RfInterfaceType::NfcDep],
+            ..Default::default()
+        };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        harness.command(core_init()).await;
+
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        let interfaces: Vec<_> =
+            response.get_supported_rf_interfaces().iter().map(|i| i.interface).collect();
+        assert_eq!(interfaces, vec![nci::RfInterfaceType::IsoDep, nci::RfInterfaceType::NfcDep]);
+    }
+
+    #[tokio::test]
+    async fn nfcee_power_and_link_control_is_recorded() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let configuration = nci::NfceePowerAndLinkConfiguration::PowerSupplyAlwaysOn;
+        harness
+            .command(nci::NfceePowerAndLinkControlCommandBuilder {
+                nfcee_id: nci::NfceeId::hci_nfcee(0x42),
+                configuration,
+            })
+            .await;
+        harness
+            .expect(nci::NfceePowerAndLinkControlResponseBuilder { status: nci::Status::Rejected })
+            .await;
+        assert_eq!(
+            harness.nfcc.state.lock().await.nfcee_power_and_link_configuration,
+            nci::NfceePowerAndLinkConfiguration::NfccDecides
+        );
+
+        harness
+            .command(nci::NfceePowerAndLinkControlCommandBuilder {
+                nfcee_id: nci::NfceeId::hci_nfcee(0x86),
+                configuration,
+            })
+            .await;
+        harness
+            .expect(nci::NfceePowerAndLinkControlResponseBuilder { status: nci::Status::Ok })
+            .await;
+        assert_eq!(
+            harness.nfcc.state.lock().await.nfcee_power_and_link_configuration,
+            configuration
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn presence_check_times_out_on_tick() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+            id: 1,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+        let start = time::Instant::now();
+        harness.command(nci::RfIsoDepNakPresenceCommandBuilder {}.build()).await;
+        harness.expect(nci::RfIsoDepNakPresenceResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The clock is paused, and only advances when the NFCC is idle.
+        tokio::select! {
+            result = harness.nfcc.run_timer() => panic!("timer stopped: {:?}", result),
+            () = harness.expect(nci::RfIsoDepNakPresenceNotificationBuilder {
+                status: nci::Status::Failed,
+            }) => (),
+        }
+        let elapsed = time::Instant::now() - start;
+        let timeout = Duration::from_millis(ISO_DEP_PRESENCE_CHECK_TIMEOUT);
+        assert!(elapsed >= timeout);
+        assert!(elapsed < timeout + Duration::from_millis(TICK_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn proprietary_commands_are_dispatched_to_handlers() {
+        let options = ControllerOptions::default()
+            .with_proprietary_handler(0x10..=0x1f, |oid, payload| {
+                vec![0, oid, payload.len() as u8]
+            });
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        let proprietary = |mt, oid: u8, payload: &[u8]| nci::ProprietaryPacketBuilder {
+            mt,
+            oid: nci::ProprietaryOpcodeId::try_from(oid).unwrap(),
+            payload: Some(payload.to_vec().into()),
+        };
+
+        harness.command(proprietary(nci::MessageType::Command, 0x12, &[1, 2, 3])).await;
+        harness.expect(proprietary(nci::MessageType::Response, 0x12, &[0, 0x12, 3])).await;
+
+        harness.command(proprietary(nci::MessageType::Command, 0x20, &[])).await;
+        harness
+            .expect(proprietary(nci::MessageType::Response, 0x20, &[nci::Status::Rejected.into()]))
+            .await;
+    }
+
+    #[tokio::test]
+    async fn core_get_config_returns_defaults_of_unset_parameters() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![
+                    nci::ConfigParameterId::ConDiscoveryParam,
+                    nci::ConfigParameterId::PfBitRate,
+                    nci::ConfigParameterId::PnAtrReqConfig,
+                ],
+            })
+            .await;
+        harness
+            .expect(nci::CoreGetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::ConDiscoveryParam,
+                        value: vec![0x01],
+                    },
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::PfBitRate,
+                        value: vec![0x01],
+                    },
+                    nci::ConfigParameter {
+                        id: nci::ConfigParameterId::PnAtrReqConfig,
+                        value: vec![0x30],
+                    },
+                ],
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn core_init_is_rejected_before_core_reset() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.command(core_init()).await;
+        harness
+            .expect(nci::CorePacketBuilder {
+                mt: nci::MessageType::Response,
+                oid: nci::CoreOpcodeId::Init,
+                payload: Some(vec![nci::Status::SemanticError.into()].into()),
+            })
+            .await;
+
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        harness.command(core_init()).await;
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn rf_frame_crc_is_appended_and_validated() {
+        let options = ControllerOptions { rf_frame_crc: true, ..Default::default() };
+        let mut harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+            id: 2,
+            rf_interface: nci::RfInterfaceType::Frame,
+            rf_technology: rf::Technology::NfcV,
+            rf_protocol: rf::Protocol::T5t,
+        };
+        let rf_data = |sender, receiver, data: &[u8]| -> rf::RfPacket {
+            rf::DataBuilder {
+                receiver,
+                sender,
+                protocol: rf::Protocol::T5t,
+                technology: rf::Technology::NfcV,
+                data: data.to_vec(),
+            }
+            .build()
+            .into()
+        };
+
+        // The CRC is appended to the frames sent by the DH.
+        let read_single_block = [0x02, 0x20, 0x00];
+        harness.nfcc.receive_data(data(nci::ConnId::StaticRf, &read_single_block)).await.unwrap();
+        let mut frame = read_single_block.to_vec();
+        rf::append_crc(rf::Technology::NfcV, &mut frame);
+        assert_eq!(harness.rf.try_recv().unwrap(), rf_data(NFCC_ID, 2, &frame));
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits {
+                    conn_id: nci::ConnId::StaticRf,
+                    credits: 1,
+                }],
+            })
+            .await;
+
+        // The CRC is removed from the valid frames received from the tag.
+        let mut response = vec![0x00, 0xca, 0xfe];
+        rf::append_crc(rf::Technology::NfcV, &mut response);
+        harness.nfcc.receive_rf(rf_data(2, NFCC_ID, &response)).await.unwrap();
+        expect_data(&harness.dh, data(nci::ConnId::StaticRf, &[0x00, 0xca, 0xfe, 0x00]))
+            .await
+            .unwrap();
+
+        // Frames with an invalid CRC are reported with a transmission error.
+        response[1] ^= 0xff;
+        harness.nfcc.receive_rf(rf_data(2, NFCC_ID, &response)).await.unwrap();
+        let mut payload = response.clone();
+        payload.push(nci::Status::RfTransmissionException.into());
+        expect_data(&harness.dh, data(nci::ConnId::StaticRf, &payload)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rf_deactivate_stops_discovery_before_activation() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcAPassivePollMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+        harness.nfcc.state.lock().await.rf_poll_responses.push(RfPollResponse {
+            id: 2,
+            rf_protocol: rf::Protocol::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_technology_specific_parameters: vec![],
+        });
+        let deactivate = |deactivation_type| nci::RfDeactivateCommandBuilder { deactivation_type };
+
+        // Only the Idle Mode is valid before activation.
+        harness.command(deactivate(nci::DeactivationType::SleepMode)).await;
+        harness
+            .expect(nci::RfDeactivateResponseBuilder { status: nci::Status::SemanticError })
+            .await;
+        assert_eq!(harness.nfcc.state.lock().await.rf_state, RfState::Discovery);
+
+        harness.command(deactivate(nci::DeactivationType::IdleMode)).await;
+        harness.expect(nci::RfDeactivateResponseBuilder { status: nci::Status::Ok }).await;
+        {
+            let state = harness.nfcc.state.lock().await;
+            assert_eq!(state.rf_state, RfState::Idle);
+            assert!(state.rf_poll_responses.is_empty());
+        }
+
+        // No RF_DEACTIVATE_NTF follows the response.
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![nci::ConfigParameterId::PfBitRate],
+            })
+            .await;
+        let response = nci::CoreGetConfigResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn starved_credits_are_granted_on_release() {
+        let options = ControllerOptions {
+            starve_credits: true,
+            remote_endpoint_credits: 2,
+            ..Default::default()
+        };
+        let mut harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness.command(core_conn_create_remote(1)).await;
+        let response = nci::CoreConnCreateResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+        assert_eq!(response.get_initial_number_of_credits(), 0);
+        harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+            id: 7,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+        let conn_id = nci::ConnId::from_dynamic(0);
+
+        // Nothing is delivered without credits.
+        harness.nfcc.receive_data(data(conn_id, &[1])).await.unwrap();
+        assert!(harness.rf.try_recv().is_err());
+
+        let release_credits = |mt, payload: Vec<u8>| nci::ProprietaryPacketBuilder {
+            mt,
+            oid: nci::ProprietaryOpcodeId::try_from(RELEASE_CREDITS_OID).unwrap(),
+            payload: Some(payload.into()),
+        };
+        harness.command(release_credits(nci::MessageType::Command, vec![])).await;
+        harness
+            .expect(release_credits(nci::MessageType::Response, vec![nci::Status::Ok.into()]))
+            .await;
+        harness
+            .expect(nci::CoreConnCreditsNotificationBuilder {
+                connections: vec![nci::ConnectionCredits { conn_id, credits: 2 }],
+            })
+            .await;
+
+        // The data is delivered, the credit it used is withheld again.
+        harness.nfcc.receive_data(data(conn_id, &[2])).await.unwrap();
+        assert_eq!(rf::Data::try_from(harness.rf.try_recv().unwrap()).unwrap().get_data(), &[2]);
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.logical_connection_credits[0], 1);
+        assert_eq!(state.withheld_credits[0], 1);
+    }
+
+    #[tokio::test]
+    async fn core_conn_close_resets_connection_credits() {
+        let options = ControllerOptions {
+            starve_credits: true,
+            remote_endpoint_credits: 2,
+            ..Default::default()
+        };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        let conn_id = nci::ConnId::from_dynamic(0);
+        let create = || async {
+            harness.command(core_conn_create_remote(1)).await;
+            let response = harness.next_control().await;
+            let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+            assert_eq!(response.get_status(), nci::Status::Ok);
+            assert_eq!(response.get_conn_id(), conn_id);
+        };
+
+        create().await;
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.logical_connection_credits[0] = 1;
+            state.withheld_credits[0] = 3;
+        }
+        harness.command(nci::CoreConnCloseCommandBuilder { conn_id }).await;
+        harness.expect(nci::CoreConnCloseResponseBuilder { status: nci::Status::Ok }).await;
+        {
+            let state = harness.nfcc.state.lock().await;
+            assert!(state.logical_connections[0].is_none());
+            assert_eq!(state.logical_connection_credits[0], 0);
+            assert_eq!(state.withheld_credits[0], 0);
+        }
+
+        // The connection opened in the same slot starts afresh.
+        create().await;
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.logical_connection_credits[0], 0);
+        assert_eq!(state.withheld_credits[0], 2);
+    }
+
+    #[tokio::test]
+    async fn rf_discover_select_rejects_invalid_targets() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcAPassivePollMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+        while harness.rf.try_recv().is_ok() {}
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.rf_state = RfState::WaitForHostSelect;
+            state.rf_poll_responses.push(RfPollResponse {
+                id: 2,
+                rf_protocol: rf::Protocol::IsoDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_technology_specific_parameters: vec![],
+            });
+        }
+
+        // Unknown RF Discovery ID, interface incompatible with the
+        // protocol, and interface not advertised in CORE_INIT_RSP.
+        for (index, rf_interface) in [
+            (1, nci::RfInterfaceType::IsoDep),
+            (0, nci::RfInterfaceType::NfcDep),
+            (0, nci::RfInterfaceType::Ndef),
+        ] {
+            harness
+                .command(nci::RfDiscoverSelectCommandBuilder {
+                    rf_discovery_id: nci::RfDiscoveryId::from_index(index),
+                    rf_protocol: nci::RfProtocolType::IsoDep,
+                    rf_interface,
+                })
+                .await;
+            harness
+                .expect(nci::RfDiscoverSelectResponseBuilder { status: nci::Status::Rejected })
+                .await;
+            assert_eq!(harness.nfcc.state.lock().await.rf_state, RfState::WaitForHostSelect);
+        }
+
+        // The target is not selected, and no RF_INTF_ACTIVATED_NTF follows
+        // the responses.
+        assert!(harness.rf.try_recv().is_err());
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![nci::ConfigParameterId::PfBitRate],
+            })
+            .await;
+        let response = nci::CoreGetConfigResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_status(), nci::Status::Ok);
+    }
+
+    #[tokio::test]
+    async fn core_reset_uses_the_nci_2_layout() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+
+        // [NCI] 2.x: CORE_RESET_RSP only carries the status, the NCI
+        // version is reported in CORE_RESET_NTF.
+        assert_eq!(harness.dh.read().await.unwrap(), [0x40, 0x00, 0x01, 0x00]);
+        let mut expected = vec![
+            0x60,
+            0x00,
+            5 + MANUFACTURER_SPECIFIC_INFORMATION.len() as u8,
+            nci::ResetTrigger::ResetCommand.into(),
+            nci::ConfigStatus::ConfigReset.into(),
+            0x20,
+            MANUFACTURER_ID,
+            MANUFACTURER_SPECIFIC_INFORMATION.len() as u8,
+        ];
+        expected.extend_from_slice(&MANUFACTURER_SPECIFIC_INFORMATION);
+        assert_eq!(harness.dh.read().await.unwrap(), expected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discovery_periods_follow_total_duration_and_frequency() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        // TOTAL_DURATION of 500 ms.
+        harness
+            .command(core_set_config(&[(nci::ConfigParameterId::TotalDuration, &[0xf4, 0x01])]))
+            .await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+        // NFC-A is polled in every discovery period, NFC-B every other one.
+        harness
+            .command(nci::RfDiscoverCommandBuilder {
+                configurations: vec![
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcBPassivePollMode,
+                        discovery_frequency: 2,
+                    },
+                ],
+            })
+            .await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+        let start = time::Instant::now();
+
+        let mut polled_technologies = vec![];
+        for period in 1..=3 {
+            // The second tick, after the poll responses timeout, falls in
+            // the same discovery period.
+            harness.nfcc.tick().await.unwrap();
+            harness.nfcc.tick().await.unwrap();
+            assert!(time::Instant::now() < start + Duration::from_millis(500 * period));
+            let mut technologies = vec![];
+            while let Ok(packet) = harness.rf.try_recv() {
+                technologies.push(rf::PollCommand::try_from(packet).unwrap().get_technology());
+            }
+            polled_technologies.push(technologies);
+            time::sleep_until(start + Duration::from_millis(500 * period)).await;
+        }
+        assert_eq!(
+            polled_technologies,
+            vec![
+                vec![rf::Technology::NfcA, rf::Technology::NfcB],
+                vec![rf::Technology::NfcA],
+                vec![rf::Technology::NfcA, rf::Technology::NfcB],
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn core_set_config_applies_valid_parameters_and_returns_rejected_ones() {
+        capture_logs();
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let unknown = nci::ConfigParameterId::try_from(0x7f).unwrap();
+        let default_pf_bit_rate = harness.nfcc.state.lock().await.config_parameters.pf_bit_rate;
+        harness
+            .command(core_set_config(&[
+                (nci::ConfigParameterId::TotalDuration, &[0xf4, 0x01]),
+                (unknown, &[0x01]),
+                (nci::ConfigParameterId::PfBitRate, &[0x01, 0x02]),
+            ]))
+            .await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::InvalidParam,
+                parameters: vec![unknown, nci::ConfigParameterId::PfBitRate],
+            })
+            .await;
+
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.config_parameters.total_duration, 500);
+        assert_eq!(state.config_parameters.pf_bit_rate, default_pf_bit_rate);
+        let logs = captured_logs("rejecting");
+        assert_eq!(logs.len(), 3);
+        assert!(logs[0].contains("reserved parameter id"));
+        assert!(logs[1].contains("PfBitRate with 2-byte value"));
+        assert!(logs[2].starts_with("rejecting configuration parameter ids"));
+        assert!(logs[2].contains(&format!("{:?}", [unknown, nci::ConfigParameterId::PfBitRate])));
+    }
+
+    #[tokio::test]
+    async fn idle_deactivate_ntf_is_suppressed_for_dh_requests() {
+        let options =
+            ControllerOptions { suppress_idle_deactivate_ntf: true, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        let activate = || async {
+            harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+                id: 2,
+                rf_interface: nci::RfInterfaceType::IsoDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::IsoDep,
+            };
+        };
+        let get_config = || nci::CoreGetConfigCommandBuilder {
+            parameters: vec![nci::ConfigParameterId::PfBitRate],
+        };
+
+        // The DH deactivation to Idle Mode is completed by the response
+        // alone, the other deactivation types are still notified.
+        for deactivation_type in [
+            nci::DeactivationType::IdleMode,
+            nci::DeactivationType::SleepMode,
+            nci::DeactivationType::Discovery,
+        ] {
+            activate().await;
+            harness.command(nci::RfDeactivateCommandBuilder { deactivation_type }).await;
+            harness.expect(nci::RfDeactivateResponseBuilder { status: nci::Status::Ok }).await;
+            if deactivation_type != nci::DeactivationType::IdleMode {
+                harness
+                    .expect(nci::RfDeactivateNotificationBuilder {
+                        deactivation_type,
+                        deactivation_reason: nci::DeactivationReason::DhRequest,
+                    })
+                    .await;
+            }
+            harness.command(get_config()).await;
+            let response = harness.next_control().await;
+            assert!(nci::CoreGetConfigResponse::try_from(response).is_ok());
+        }
+
+        // Deactivations initiated by the NFCC are always notified.
+        activate().await;
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DeactivateNotificationBuilder {
+                    sender: 2,
+                    receiver: NFCC_ID,
+                    protocol: rf::Protocol::IsoDep,
+                    technology: rf::Technology::NfcA,
+                    type_: rf::DeactivateType::IdleMode,
+                    reason: rf::DeactivateReason::RfLinkLoss,
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        harness
+            .expect(nci::RfDeactivateNotificationBuilder {
+                deactivation_type: nci::DeactivationType::IdleMode,
+                deactivation_reason: nci::DeactivationReason::RfLinkLoss,
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn rf_field_loss_is_notified_before_deactivation() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(core_set_config(&[(nci::ConfigParameterId::RfFieldInfo, &[1])])).await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcAPassiveListenMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The first poll of the Remote NFC Endpoint turns the field on.
+        harness
+            .nfcc
+            .receive_rf(
+                rf::PollCommandBuilder {
+                    sender: 2,
+                    receiver: u16::MAX,
+                    protocol: rf::Protocol::Undetermined,
+                    technology: rf::Technology::NfcA,
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        harness
+            .expect(nci::RfFieldInfoNotificationBuilder {
+                rf_field_status: nci::RfFieldStatus::FieldDetected,
+            })
+            .await;
+        let polling_loop = harness.next_control().await;
+        assert!(nci::AndroidPollingLoopNotification::try_from(polling_loop).is_ok());
+
+        // The Remote NFC Endpoint is removed while the NFCC is activated
+        // in Listen Mode: the field loss is notified, then the
+        // deactivation.
+        harness.nfcc.state.lock().await.rf_state = RfState::ListenActive {
+            id: 2,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DeactivateNotificationBuilder {
+                    sender: 2,
+                    receiver: NFCC_ID,
+                    protocol: rf::Protocol::IsoDep,
+                    technology: rf::Technology::NfcA,
+                    type_: rf::DeactivateType::Discovery,
+                    reason: rf::DeactivateReason::RfLinkLoss,
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        harness
+            .expect(nci::RfFieldInfoNotificationBuilder {
+                rf_field_status: nci::RfFieldStatus::NoField,
+            })
+            .await;
+        harness
+            .expect(nci::RfDeactivateNotificationBuilder {
+                deactivation_type: nci::DeactivationType::Discovery,
+                deactivation_reason: nci::DeactivationReason::RfLinkLoss,
+            })
+            .await;
+        assert_eq!(harness.nfcc.state.lock().await.rf_state, RfState::Discovery);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn core_reset_notification_is_delayed_by_boot_delay() {
+        let boot_delay = Duration::from_millis(300);
+        let options = ControllerOptions { boot_delay, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        let start = time::Instant::now();
+        tokio::join!(harness.command(core_reset(nci::ResetType::ResetConfig)), async {
+            harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+            assert_eq!(time::Instant::now(), start);
+            harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+            assert!(time::Instant::now() - start >= boot_delay);
+        });
+    }
+
+    #[tokio::test]
+    async fn unknown_hci_command_stops_the_nfcc_with_an_error() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let result = harness.nfcc.receive_data(data(nci::ConnId::StaticHci, &[0x81, 0x7f])).await;
+        assert!(result.unwrap_err().to_string().contains("unimplemented HCI command"));
+    }
+
+    #[tokio::test]
+    async fn listen_mode_routing_is_chained_in_notifications() {
+        let options =
+            ControllerOptions { max_control_packet_payload_size: 32, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        let get_routing = || async {
+            harness.command(nci::RfGetListenModeRoutingCommandBuilder {}).await;
+            harness
+                .expect(nci::RfGetListenModeRoutingResponseBuilder { status: nci::Status::Ok })
+                .await;
+        };
+
+        // An empty table is reported in a single notification.
+        get_routing().await;
+        harness
+            .expect(nci::RfGetListenModeRoutingNotificationBuilder {
+                more_to_follow: 0,
+                routing_entries: vec![],
+            })
+            .await;
+
+        // Three 10-byte AID entries fill the 32-byte payload of the
+        // first notification, the last two follow in a second one.
+        let entries: Vec<_> = (0..5)
+            .map(|aid| routing_entry(nci::ListenModeRoutingEntryType::AidBasedRouting, &[aid; 8]))
+            .collect();
+        harness.nfcc.state.lock().await.listen_mode_routing = entries.clone();
+        get_routing().await;
+        harness
+            .expect(nci::RfGetListenModeRoutingNotificationBuilder {
+                more_to_follow: 1,
+                routing_entries: entries[..3].to_vec(),
+            })
+            .await;
+        harness
+            .expect(nci::RfGetListenModeRoutingNotificationBuilder {
+                more_to_follow: 0,
+                routing_entries: entries[3..].to_vec(),
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn nfcb_tag_is_activated_with_attrib() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(rf_discover(nci::RfTechnologyAndMode::NfcBPassivePollMode)).await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The Type 4B tag answers the SENSB_REQ of the discovery period.
+        let pupi = [1, 2, 3, 4];
+        let protocol_info = [0x00, 0x81, 0x70];
+        let tag = async {
+            let poll = harness.rf.recv().await.unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcB);
+            harness
+                .nfcc
+                .receive_rf(
+                    rf::NfcBPollResponseBuilder {
+                        protocol: rf::Protocol::Undetermined,
+                        sender: 2,
+                        receiver: NFCC_ID,
+                        pupi,
+                        application_data: [0; 4],
+                        protocol_info,
+                    }
+                    .build()
+                    .into(),
+                )
+                .await
+                .unwrap()
+        };
+        let (result, ()) = tokio::join!(harness.nfcc.tick(), tag);
+        result.unwrap();
+
+        // The tag is selected with an ATTRIB Command addressed to its PUPI.
+        let attrib = rf::T4BTSelectCommand::try_from(harness.rf.recv().await.unwrap()).unwrap();
+        assert_eq!(attrib.get_receiver(), 2);
+        assert_eq!(attrib.get_nfcid0(), &pupi);
+        let pb_attrib_param1 = harness.nfcc.state.lock().await.config_parameters.pb_attrib_param1;
+        assert_eq!(attrib.get_param(), &[pb_attrib_param1, 0x08, 0x01, 0x00]);
+        harness
+            .nfcc
+            .receive_rf(
+                rf::T4BTSelectResponseBuilder {
+                    sender: 2,
+                    receiver: NFCC_ID,
+                    attrib_response: vec![0x00],
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+
+        let activated = harness.next_control().await;
+        let activated = nci::RfIntfActivatedNotification::try_from(activated).unwrap();
+        assert_eq!(activated.get_rf_interface(), nci::RfInterfaceType::IsoDep);
+        assert_eq!(activated.get_rf_protocol(), nci::RfProtocolType::IsoDep);
+        assert_eq!(
+            activated.get_activation_rf_technology_and_mode(),
+            nci::RfTechnologyAndMode::NfcBPassivePollMode
+        );
+        // SENSB_RES bytes 2 to 12 are reported after their length.
+        let mut parameters = vec![11];
+        parameters.extend(pupi);
+        parameters.extend([0; 4]);
+        parameters.extend(protocol_info);
+        assert_eq!(activated.get_rf_technology_specific_parameters(), &parameters);
+        // The ATTRIB Response is reported after its length.
+        assert_eq!(activated.get_activation_parameters(), &[1, 0x00]);
+        assert!(matches!(
+            harness.nfcc.state.lock().await.rf_state,
+            RfState::PollActive { id: 2, rf_technology: rf::Technology::NfcB, .. }
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn core_reset_notification_is_only_sent_on_command() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut dh, nfcc) = tokio::io::duplex(1024);
+        let (nci_rx, nci_tx) = tokio::io::split(nfcc);
+        let (_rf_tx, rf_rx) = mpsc::unbounded_channel();
+        let (rf_tx, _rf_rx) = mpsc::unbounded_channel();
+        let nfcc = Controller::run(
+            NFCC_ID,
+            Default::default(),
+            nci::Reader::new(nci_rx),
+            nci::Writer::new(nci_tx),
+            rf_rx,
+            rf_tx,
+        );
+        let dh = async {
+            // Nothing is sent after connecting, across several ticks.
+            let mut header = [0; 3];
+            let silence =
+                time::timeout(Duration::from_millis(3 * TICK_INTERVAL), dh.read_exact(&mut header))
+                    .await;
+            assert!(silence.is_err(), "unexpected packet {:02x?}", header);
+
+            // CORE_RESET_CMD with the reset type Keep Configuration.
+            dh.write_all(&[0x20, 0x00, 0x01, 0x00]).await.unwrap();
+            let mut response = [0; 4];
+            dh.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [0x40, 0x00, 0x01, 0x00]);
+            // CORE_RESET_NTF follows the response.
+            dh.read_exact(&mut header).await.unwrap();
+            assert_eq!(header[0..2], [0x60, 0x00]);
+        };
+        tokio::select! {
+            result = nfcc => panic!("NFCC stopped: {:?}", result),
+            () = dh => (),
+        }
+    }
+
+    #[tokio::test]
+    async fn presence_check_is_answered_until_the_card_is_removed() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.nfcc.state.lock().await.rf_state = RfState::PollActive {
+            id: 2,
+            rf_interface: nci::RfInterfaceType::IsoDep,
+            rf_technology: rf::Technology::NfcA,
+            rf_protocol: rf::Protocol::IsoDep,
+        };
+        async fn presence_check(harness: &Harness) {
+            harness.command(nci::RfIsoDepNakPresenceCommandBuilder {}.build()).await;
+            harness
+                .expect(nci::RfIsoDepNakPresenceResponseBuilder { status: nci::Status::Ok })
+                .await;
+        }
+
+        // The card answers the R(NAK) Block of each presence check.
+        for _ in 0..2 {
+            presence_check(&harness).await;
+            let check = rf::IsoDepPresenceCheckCommand::try_from(harness.rf.try_recv().unwrap());
+            assert_eq!(check.unwrap().get_receiver(), 2);
+            harness
+                .nfcc
+                .receive_rf(
+                    rf::IsoDepPresenceCheckResponseBuilder {
+                        sender: 2,
+                        receiver: NFCC_ID,
+                        technology: rf::Technology::NfcA,
+                    }
+                    .build()
+                    .into(),
+                )
+                .await
+                .unwrap();
+            harness
+                .expect(nci::RfIsoDepNakPresenceNotificationBuilder { status: nci::Status::Ok })
+                .await;
+        }
+
+        // The card is removed before answering the next presence check.
+        presence_check(&harness).await;
+        assert!(harness.rf.try_recv().is_ok());
+        harness
+            .nfcc
+            .receive_rf(
+                rf::DeactivateNotificationBuilder {
+                    sender: 2,
+                    receiver: NFCC_ID,
+                    protocol: rf::Protocol::IsoDep,
+                    technology: rf::Technology::NfcA,
+                    type_: rf::DeactivateType::Discovery,
+                    reason: rf::DeactivateReason::RfLinkLoss,
+                }
+                .build()
+                .into(),
+            )
+            .await
+            .unwrap();
+        harness
+            .expect(nci::RfIsoDepNakPresenceNotificationBuilder { status: nci::Status::Failed })
+            .await;
+        harness
+            .expect(nci::RfDeactivateNotificationBuilder {
+                deactivation_type: nci::DeactivationType::Discovery,
+                deactivation_reason: nci::DeactivationReason::RfLinkLoss,
+            })
+            .await;
+        let state = harness.nfcc.state.lock().await;
+        assert_eq!(state.rf_state, RfState::Discovery);
+        assert_eq!(state.presence_check_deadline, None);
+    }
+
+    #[tokio::test]
+    async fn core_init_advertises_rf_interface_extensions() {
+        let options = ControllerOptions {
+            rf_interfaces: vec![nci::RfInterfaceType::Frame, nci::RfInterfaceType::IsoDep],
+            rf_interface_extensions: vec![
+                (nci::RfInterfaceType::Frame, nci::RfInterfaceExtensionType::FrameAggregated),
+                (nci::RfInterfaceType::Frame, nci::RfInterfaceExtensionType::FrameAggregated),
+                // The NFC-DEP RF interface is not advertised.
+                (nci::RfInterfaceType::NfcDep, nci::RfInterfaceExtensionType::LlcpSymmetry),
+            ],
+            ..Default::default()
+        };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        harness.command(core_init()).await;
+
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        let interfaces: Vec<_> = response
+            .get_supported_rf_interfaces()
+            .iter()
+            .map(|i| (i.interface, i.extensions.clone()))
+            .collect();
+        assert_eq!(
+            interfaces,
+            vec![
+                (nci::RfInterfaceType::Frame, vec![nci::RfInterfaceExtensionType::FrameAggregated]),
+                (nci::RfInterfaceType::IsoDep, vec![]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn nci_commands_are_served_after_the_rf_channel_closes() {
+        let (dh_writer, nfcc_reader) = capture();
+        let (nfcc_writer, dh_reader) = capture();
+        let (rf_tx, _rf_rx) = mpsc::unbounded_channel();
+        // The scene is shutting down: the RF channel is already closed.
+        let (_, rf_rx) = mpsc::unbounded_channel();
+        let nfcc =
+            Controller::run(NFCC_ID, Default::default(), nfcc_reader, nfcc_writer, rf_rx, rf_tx);
+        let dh = async {
+            dh_writer.write(&core_reset(nci::ResetType::KeepConfig).to_vec()).await?;
+            expect_control(&dh_reader, nci::CoreResetResponseBuilder { status: nci::Status::Ok })
+                .await?;
+            expect_control(&dh_reader, core_reset_notification(nci::ConfigStatus::ConfigKept))
+                .await?;
+
+            let get_config = nci::CoreGetConfigCommandBuilder {
+                parameters: vec![nci::ConfigParameterId::TotalDuration],
+            };
+            dh_writer.write(&get_config.build().to_vec()).await?;
+            let response = nci::ControlPacket::parse(&dh_reader.read().await?)?;
+            let response = nci::CoreGetConfigResponse::try_from(response)?;
+            assert_eq!(response.get_status(), nci::Status::Ok);
+            assert_eq!(response.get_parameters()[0].id, nci::ConfigParameterId::TotalDuration);
+
+            // The discovery is rejected, as no Remote NFC Endpoint can be
+            // reached.
+            let discover = rf_discover(nci::RfTechnologyAndMode::NfcAPassivePollMode);
+            dh_writer.write(&discover.to_vec()).await?;
+            expect_control(
+                &dh_reader,
+                nci::RfDiscoverResponseBuilder { status: nci::Status::Failed },
+            )
+            .await
+        };
+        tokio::select! {
+            result = nfcc => panic!("NFCC stopped: {:?}", result),
+            result = dh => result.unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn core_conn_create_is_rejected_when_all_connections_are_used() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        for rf_discovery_id in 1..=MAX_LOGICAL_CONNECTIONS {
+            harness.command(core_conn_create_remote(rf_discovery_id)).await;
+            let response = harness.next_control().await;
+            let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+            assert_eq!(response.get_status(), nci::Status::Ok);
+        }
+
+        // The lack of capacity takes precedence over the invalid
+        // destination, and the response carries no connection.
+        for command in
+            [core_conn_create_remote(MAX_LOGICAL_CONNECTIONS + 1), core_conn_create_nfcee()]
+        {
+            harness.command(command).await;
+            let response = harness.next_control().await;
+            let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+            assert_eq!(response.get_status(), nci::Status::Rejected);
+            assert_eq!(response.get_max_data_packet_payload_size(), 0);
+            assert_eq!(u8::from(response.get_conn_id()), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn max_logical_connections_is_configurable() {
+        let options = ControllerOptions { max_logical_connections: 4, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+        harness.command(core_init()).await;
+        let response = nci::CoreInitResponse::try_from(harness.next_control().await).unwrap();
+        assert_eq!(response.get_max_logical_connections(), 4);
+
+        let mut conn_ids = vec![];
+        for rf_discovery_id in 1..=4 {
+            harness.command(core_conn_create_remote(rf_discovery_id)).await;
+            let response = harness.next_control().await;
+            let response = nci::CoreConnCreateResponse::try_from(response).unwrap();
+            assert_eq!(response.get_status(), nci::Status::Ok);
+            conn_ids.push(u8::from(response.get_conn_id()));
+        }
+        assert_eq!(conn_ids, [2, 3, 4, 5]);
+
+        harness.command(core_conn_create_remote(5)).await;
+        harness.expect(core_conn_create_failure(nci::Status::Rejected)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn discover_notifications_are_delayed_per_technology() {
+        let options = ControllerOptions {
+            discover_notification_delays: vec![
+                (rf::Technology::NfcA, Duration::from_millis(300)),
+                (rf::Technology::NfcB, Duration::from_millis(100)),
+            ],
+            ..Default::default()
+        };
+        let mut harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        harness
+            .command(nci::RfDiscoverCommandBuilder {
+                configurations: vec![
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcBPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                ],
+            })
+            .await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // An NFC-A tag answers before an NFC-B tag.
+        let Harness { nfcc, dh, rf } = &mut harness;
+        let tags = async {
+            let poll = rf::PollCommand::try_from(rf.recv().await.unwrap()).unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcA);
+            let nfca_response = rf::NfcAPollResponseBuilder {
+                protocol: rf::Protocol::Undetermined,
+                sender: 2,
+                receiver: NFCC_ID,
+                nfcid1: vec![1, 2, 3, 4],
+                int_protocol: 0b01,
+                bit_frame_sdd: 0x04,
+            };
+            nfcc.receive_rf(nfca_response.build().into()).await.unwrap();
+            let poll = rf::PollCommand::try_from(rf.recv().await.unwrap()).unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcB);
+            let nfcb_response = rf::NfcBPollResponseBuilder {
+                protocol: rf::Protocol::Undetermined,
+                sender: 3,
+                receiver: NFCC_ID,
+                pupi: [1, 2, 3, 4],
+                application_data: [0; 4],
+                protocol_info: [0x00, 0x81, 0x70],
+            };
+            nfcc.receive_rf(nfcb_response.build().into()).await.unwrap();
+        };
+        let notifications = async {
+            let mut notifications = vec![];
+            for _ in 0..2 {
+                let notification = nci::ControlPacket::parse(&dh.read().await.unwrap()).unwrap();
+                let notification = nci::RfDiscoverNotification::try_from(notification).unwrap();
+                notifications.push((
+                    notification.get_rf_technology_and_mode(),
+                    u8::from(notification.get_rf_discovery_id()),
+                    time::Instant::now(),
+                ));
+            }
+            notifications
+        };
+        let start = time::Instant::now();
+        let (result, (), notifications) = tokio::join!(nfcc.tick(), tags, notifications);
+        result.unwrap();
+
+        // The NFC-B tag is reported first, each after its own delay from
+        // the end of the discovery period.
+        let end_of_period = start + Duration::from_millis(POLL_RESPONSE_TIMEOUT);
+        assert_eq!(
+            notifications,
+            vec![
+                (
+                    nci::RfTechnologyAndMode::NfcBPassivePollMode,
+                    1,
+                    end_of_period + Duration::from_millis(100)
+                ),
+                (
+                    nci::RfTechnologyAndMode::NfcAPassivePollMode,
+                    2,
+                    end_of_period + Duration::from_millis(300)
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn technologies_with_no_devices_limit_are_not_polled() {
+        let mut harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        harness.command(core_set_config(&[(nci::ConfigParameterId::PbDevicesLimit, &[0])])).await;
+        harness
+            .expect(nci::CoreSetConfigResponseBuilder {
+                status: nci::Status::Ok,
+                parameters: vec![],
+            })
+            .await;
+        harness
+            .command(nci::RfDiscoverCommandBuilder {
+                configurations: vec![
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcAPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                    nci::DiscoverConfiguration {
+                        technology_and_mode: nci::RfTechnologyAndMode::NfcBPassivePollMode,
+                        discovery_frequency: 1,
+                    },
+                ],
+            })
+            .await;
+        harness.expect(nci::RfDiscoverResponseBuilder { status: nci::Status::Ok }).await;
+
+        // Two NFC-A tags answer the only poll command; the response of an
+        // NFC-B tag is ignored.
+        let Harness { nfcc, dh, rf } = &mut harness;
+        let tags = async {
+            let poll = rf::PollCommand::try_from(rf.recv().await.unwrap()).unwrap();
+            assert_eq!(poll.get_technology(), rf::Technology::NfcA);
+            for sender in [2, 3] {
+                let nfca_response = rf::NfcAPollResponseBuilder {
+                    protocol: rf::Protocol::Undetermined,
+                    sender,
+                    receiver: NFCC_ID,
+                    nfcid1: vec![1, 2, 3, sender as u8],
+                    int_protocol: 0b01,
+                    bit_frame_sdd: 0x04,
+                };
+                nfcc.receive_rf(nfca_response.build().into()).await.unwrap();
+            }
+            let nfcb_response = rf::NfcBPollResponseBuilder {
+                protocol: rf::Protocol::Undetermined,
+                sender: 4,
+                receiver: NFCC_ID,
+                pupi: [1, 2, 3, 4],
+                application_data: [0; 4],
+                protocol_info: [0x00, 0x81, 0x70],
+            };
+            nfcc.receive_rf(nfcb_response.build().into()).await.unwrap();
+        };
+        let (result, ()) = tokio::join!(nfcc.tick(), tags);
+        result.unwrap();
+        assert!(rf.try_recv().is_err());
+
+        for notification_type in [
+            nci::DiscoverNotificationType::MoreNotifications,
+            nci::DiscoverNotificationType::LastNotification,
+        ] {
+            let notification = nci::ControlPacket::parse(&dh.read().await.unwrap()).unwrap();
+            let notification = nci::RfDiscoverNotification::try_from(notification).unwrap();
+            assert_eq!(
+                notification.get_rf_technology_and_mode(),
+                nci::RfTechnologyAndMode::NfcAPassivePollMode
+            );
+            assert_eq!(notification.get_notification_type(), notification_type);
+        }
+    }
+
+    #[tokio::test]
+    async fn rf_discover_map_exceeding_capacity_is_rejected() {
+        let options = ControllerOptions { max_discover_map_size: 2, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+        harness.reset_and_init().await;
+        let mapping = |rf_protocol, rf_interface| nci::MappingConfiguration {
+            rf_protocol,
+            mode: nci::MappingConfigurationMode {
+                listen_mode: nci::FeatureFlag::Disabled,
+                poll_mode: nci::FeatureFlag::Enabled,
+            },
+            rf_interface,
+        };
+        let mapping_configurations = vec![
+            mapping(nci::RfProtocolType::IsoDep, nci::RfInterfaceType::IsoDep),
+            mapping(nci::RfProtocolType::NfcDep, nci::RfInterfaceType::NfcDep),
+        ];
+        harness
+            .command(nci::RfDiscoverMapCommandBuilder {
+                mapping_configurations: mapping_configurations.clone(),
+            })
+            .await;
+        harness.expect(nci::RfDiscoverMapResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The current mapping is kept when the new one has too many entries.
+        harness
+            .command(nci::RfDiscoverMapCommandBuilder {
+                mapping_configurations: vec![
+                    mapping(nci::RfProtocolType::T2t, nci::RfInterfaceType::Frame),
+                    mapping(nci::RfProtocolType::T3t, nci::RfInterfaceType::Frame),
+                    mapping(nci::RfProtocolType::T5t, nci::RfInterfaceType::Frame),
+                ],
+            })
+            .await;
+        harness.expect(nci::RfDiscoverMapResponseBuilder { status: nci::Status::Rejected }).await;
+        assert_eq!(harness.nfcc.state.lock().await.discover_map, mapping_configurations);
+    }
+
+    #[tokio::test]
+    async fn stored_discover_map_is_read_back() {
+        let harness = Harness::new(NFCC_ID, Default::default());
+        harness.reset_and_init().await;
+        let get_discover_map = || nci::ProprietaryPacketBuilder {
+            mt: nci::MessageType::Command,
+            oid: nci::ProprietaryOpcodeId::try_from(GET_DISCOVER_MAP_OID).unwrap(),
+            payload: None,
+        };
+        let discover_map = |payload: &[u8]| nci::ProprietaryPacketBuilder {
+            mt: nci::MessageType::Response,
+            oid: nci::ProprietaryOpcodeId::try_from(GET_DISCOVER_MAP_OID).unwrap(),
+            payload: Some(payload.to_vec().into()),
+        };
+        harness.command(get_discover_map()).await;
+        harness.expect(discover_map(&[0x00, 0x00])).await;
+
+        let mapping = |rf_protocol, rf_interface| nci::MappingConfiguration {
+            rf_protocol,
+            mode: nci::MappingConfigurationMode {
+                listen_mode: nci::FeatureFlag::Disabled,
+                poll_mode: nci::FeatureFlag::Enabled,
+            },
+            rf_interface,
+        };
+        let set_discover_map = nci::RfDiscoverMapCommandBuilder {
+            mapping_configurations: vec![
+                mapping(nci::RfProtocolType::IsoDep, nci::RfInterfaceType::IsoDep),
+                mapping(nci::RfProtocolType::NfcDep, nci::RfInterfaceType::NfcDep),
+            ],
+        }
+        .build();
+        harness.command(set_discover_map.clone()).await;
+        harness.expect(nci::RfDiscoverMapResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The status is followed by the mappings, encoded as in the
+        // payload of RF_DISCOVER_MAP_CMD.
+        let mut payload = vec![0x00];
+        payload.extend_from_slice(&set_discover_map.to_vec()[3..]);
+        assert_eq!(payload.len(), 8);
+        harness.command(get_discover_map()).await;
+        harness.expect(discover_map(&payload)).await;
+    }
+
+    #[tokio::test]
+    async fn core_reset_packets_are_captured_in_memory() {
+        let (nci_writer, dh) = capture();
+        let (rf_tx, _rf_rx) = mpsc::unbounded_channel();
+        let nfcc = Controller::new(NFCC_ID, Default::default(), nci_writer, rf_tx);
+        nfcc.receive_command(core_reset(nci::ResetType::KeepConfig)).await.unwrap();
+
+        expect_control(&dh, nci::CoreResetResponseBuilder { status: nci::Status::Ok })
+            .await
+            .unwrap();
+        // The next packet is the notification, not another response.
+        let error = expect_control(&dh, nci::CoreResetResponseBuilder { status: nci::Status::Ok })
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("CoreResetNotification"), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn lenient_mode_answers_unsupported_commands() {
+        use crate::packets::nci::test_support::raw_control;
+
+        let (dh_writer, nfcc_reader) = capture();
+        let (nfcc_writer, dh_reader) = capture();
+        let (rf_tx, rf_rx) = mpsc::unbounded_channel();
+        let options = ControllerOptions { lenient: true, ..Default::default() };
+        let nfcc = Controller::run(NFCC_ID, options, nfcc_reader, nfcc_writer, rf_rx, rf_tx);
+        let dh = async {
+            // RF_PARAMETER_UPDATE_CMD is not implemented, the OID 20h is RFU.
+            for oid in [0x0b, 0x20] {
+                dh_writer.write(&raw_control(0x1, oid, &[0x00])).await?;
+                assert_eq!(dh_reader.read().await?, [0x41, oid, 0x00]);
+            }
+            // The connection is still served.
+            dh_writer.write(&core_reset(nci::ResetType::KeepConfig).to_vec()).await?;
+            expect_control(&dh_reader, nci::CoreResetResponseBuilder { status: nci::Status::Ok })
+                .await
+        };
+        tokio::select! {
+            result = nfcc => panic!("NFCC stopped: {:?}", result),
+            result = dh => result.unwrap(),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reordered_responses_are_sent_after_the_next_response() {
+        let options = ControllerOptions { reorder_responses: true, ..Default::default() };
+        let harness = Harness::new(NFCC_ID, options);
+
+        // CORE_RESET_RSP is held back, CORE_RESET_NTF is not.
+        harness.command(core_reset(nci::ResetType::ResetConfig)).await;
+        harness.expect(core_reset_notification(nci::ConfigStatus::ConfigReset)).await;
+
+        // CORE_INIT_RSP is sent ahead of the held back CORE_RESET_RSP.
+        harness.command(core_init()).await;
+        let response = harness.next_control().await;
+        assert!(nci::CoreInitResponse::try_from(response).is_ok());
+        harness.expect(nci::CoreResetResponseBuilder { status: nci::Status::Ok }).await;
+
+        // The next response is held back again, and sent by the tick
+        // handler when the DH sends no other command.
+        harness
+            .command(nci::CoreGetConfigCommandBuilder {
+                parameters: vec![nci::ConfigParameterId::RfFieldInfo],
+            })
+            .await;
+        harness.nfcc.tick().await.unwrap();
+        let wait = Duration::from_millis(10);
+        assert!(time::timeout(wait, harness.dh.read()).await.is_err());
+        time::advance(Duration::from_millis(REORDERED_RESPONSE_TIMEOUT)).await;
+        harness.nfcc.tick().await.unwrap();
+        let response = harness.next_control().await;
+        assert!(nci::CoreGetConfigResponse::try_from(response).is_ok());
+    }
+
+    #[tokio::test]
+    async fn nfc_dep_messages_are_chained_between_initiator_and_target() {
+        let mut initiator = Harness::new(1, Default::default());
+        let mut target = Harness::new(2, Default::default());
+        initiator.reset_and_init().await;
+        target.reset_and_init().await;
+        // Both ends are activated with frames of 64 bytes, carrying 60
+        // bytes of transport data.
+        {
+            let mut state = initiator.nfcc.state.lock().await;
+            state.rf_state = RfState::PollActive {
+                id: 2,
+                rf_interface: nci::RfInterfaceType::NfcDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::NfcDep,
+            };
+            state.nfc_dep = NfcDepExchange::new(0);
+        }
+        {
+            let mut state = target.nfcc.state.lock().await;
+            state.rf_state = RfState::ListenActive {
+                id: 1,
+                rf_interface: nci::RfInterfaceType::NfcDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::NfcDep,
+            };
+            state.nfc_dep = NfcDepExchange::new(0);
+        }
+
+        /// Send a message from the DH of `sender`, and relay the RF
+        /// frames between the two ends until the chain is complete.
+        /// Return the PFB bytes of the relayed frames.
+        async fn exchange(sender: &mut Harness, receiver: &mut Harness, message: &[u8]) -> Vec<u8> {
+            sender.nfcc.receive_data(data(nci::ConnId::StaticRf, message)).await.unwrap();
+            sender
+                .expect(nci::CoreConnCreditsNotificationBuilder {
+                    connections: vec![nci::ConnectionCredits {
+                        conn_id: nci::ConnId::StaticRf,
+                        credits: 1,
+                    }],
+                })
+                .await;
+            let mut pfbs = vec![];
+            loop {
+                let (from, to) = match (sender.rf.try_recv(), receiver.rf.try_recv()) {
+                    (Ok(packet), Err(_)) => (packet, &receiver.nfcc),
+                    (Err(_), Ok(packet)) => (packet, &sender.nfcc),
+                    (Err(_), Err(_)) => break,
+                    packets => panic!("unexpected concurrent frames {:?}", packets),
+                };
+                pfbs.push(rf::Data::try_from(from.clone()).unwrap().get_data()[2]);
+                to.receive_rf(from).await.unwrap();
+            }
+            expect_data(&receiver.dh, data(nci::ConnId::StaticRf, message)).await.unwrap();
+            pfbs
+        }
+
+        // The DEP_REQ information PDUs are chained with the MI bit, and
+        // each acknowledged with the same packet number.
+        let request: Vec<u8> = (0..150).map(|i| i as u8).collect();
+        assert_eq!(
+            exchange(&mut initiator, &mut target, &request).await,
+            [0x10, 0x40, 0x11, 0x41, 0x02]
+        );
+
+        // The DEP_RES information PDUs carry the number of the last DEP_REQ
+        // PDU, the Initiator acknowledges with the next number.
+        let response: Vec<u8> = (0..100).map(|i| i as u8).collect();
+        assert_eq!(exchange(&mut target, &mut initiator, &response).await, [0x12, 0x43, 0x03]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn nfc_dep_target_requests_timeout_extensions_and_retransmits() {
+        let mut harness = Harness::new(2, Default::default());
+        harness.reset_and_init().await;
+        {
+            let mut state = harness.nfcc.state.lock().await;
+            state.rf_state = RfState::ListenActive {
+                id: 1,
+                rf_interface: nci::RfInterfaceType::NfcDep,
+                rf_technology: rf::Technology::NfcA,
+                rf_protocol: rf::Protocol::NfcDep,
+            };
+            state.nfc_dep = NfcDepExchange::new(0);
+        }
+        let frame = |sender, receiver, data: &[u8]| -> rf::RfPacket {
+            rf::DataBuilder {
+                sender,
+                receiver,
+                protocol: rf::Protocol::NfcDep,
+                technology: rf::Technology::NfcA,
+                data: data.to_vec(),
+            }
+            .build()
+            .into()
+        };
+
+        harness.nfcc.receive_rf(frame(1, 2, &[0xd4, 0x06, 0x00, 0xaa])).await.unwrap();
+        expect_data(&harness.dh, data(nci::ConnId::StaticRf, &[0xaa])).await.unwrap();
+
+        // The DH does not answer in time: the Target requests a timeout
+        // extension.
+        time::advance(Duration::from_millis(NFC_DEP_RTOX_DELAY)).await;
+        harness.nfcc.tick().await.unwrap();
+        assert_eq!(harness.rf.try_recv().unwrap(), frame(2, 1, &[0xd5, 0x07, 0x90, NFC_DEP_RTOX]));
+
+        // The response of the DH is retransmitted on NACK, and no other
+        // timeout extension is requested.
+        harness.nfcc.receive_data(data(nci::ConnId::StaticRf, &[0xbb])).await.unwrap();
+        let response = frame(2, 1, &[0xd5, 0x07, 0x00, 0xbb]);
+        assert_eq!(harness.rf.try_recv().unwrap(), response);
+        harness.nfcc.receive_rf(frame(1, 2, &[0xd4, 0x06, 0x50])).await.unwrap();
+        assert_eq!(harness.rf.try_recv().unwrap(), response);
+        time::advance(Duration::from_millis(NFC_DEP_RTOX_DELAY)).await;
+        harness.nfcc.tick().await.unwrap();
+        assert!(harness.rf.try_recv().is_err());
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Decoder for captured NCI packets.
+
+use crate::packets::{nci, ConnectionClosed};
+use anyhow::{anyhow, Result};
+use std::io::{Cursor, Write};
+
+/// Parse the content of an NCI capture file. Each line contains the
+/// hexadecimal bytes of an NCI packet or segment, optionally separated
+/// by whitespace. Empty lines and comments starting with `#` are ignored.
+fn parse_capture(capture: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    for (index, line) in capture.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let digits = line.chars().filter(|c| !c.is_whitespace()).collect::<Vec<char>>();
+        for pair in digits.chunks(2) {
+            let byte = match pair {
+                [hi, lo] => hi.to_digit(16).zip(lo.to_digit(16)).map(|(hi, lo)| hi << 4 | lo),
+                _ => None,
+            };
+            bytes.push(byte.ok_or(anyhow!("invalid hexadecimal bytes at line {}", index + 1))?
+                as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+/// Decode the NCI packets of a capture, and print their structure to
+/// `output`. Segmented packets are reassembled before being decoded, as
+/// done by the NCI reader of the NFCC.
+pub async fn decode(capture: &str, output: &mut impl Write) -> Result<()> {
+    let reader = nci::Reader::new(Cursor::new(parse_capture(capture)?));
+    for index in 0.. {
+        let packet = match reader.read().await {
+            Ok(packet) => packet,
+            Err(err) if err.is::<ConnectionClosed>() => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let hex = packet.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        writeln!(output, "#{} {}", index, hex)?;
+        match nci::PacketHeader::parse(&packet[0..3])?.get_mt() {
+            nci::MessageType::Data => match nci::DataPacket::parse(&packet) {
+                Ok(packet) => writeln!(output, "{:#?}", packet)?,
+                Err(err) => writeln!(output, "malformed Data Packet: {}", err)?,
+            },
+            _ => match nci::ControlPacket::parse(&packet) {
+                Ok(packet) => writeln!(output, "{:#?}", packet)?,
+                Err(err) => writeln!(output, "malformed Control Packet: {}", err)?,
+            },
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reset_and_init_exchange_is_decoded() {
+        let capture = "\
+            # CORE_RESET_CMD and CORE_RESET_RSP
+            20 00 01 00
+            40000100
+            # CORE_INIT_CMD, segmented in two packets
+            30 01 01 00
+            20 01 01 00
+        ";
+        let mut output = vec![];
+        decode(capture, &mut output).await.unwrap();
+        let output = String::from_utf8(output).unwrap();
+        // The reassembled packet keeps the header of the last segment.
+        let headers: Vec<&str> = output.lines().filter(|line| line.starts_with('#')).collect();
+        assert_eq!(headers, ["#0 20000100", "#1 40000100", "#2 2001010000"]);
+        assert!(output.contains("CoreResetCommand"));
+        assert!(output.contains("CoreResetResponse"));
+        assert!(output.contains("CoreInitCommand"));
+    }
+
+    #[tokio::test]
+    async fn invalid_hexadecimal_bytes_are_rejected() {
+        let err = decode("20 00 01 00\n20 0g", &mut vec![]).await.unwrap_err();
+        assert_eq!(err.to_string(), "invalid hexadecimal bytes at line 2");
+    }
+}
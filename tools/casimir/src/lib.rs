@@ -0,0 +1,812 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! NFCC and RF emulator, serving NFCCs on the NCI port and RF devices on
+//! the RF port. [`Casimir`] runs the emulator in-process.
+
+use anyhow::Result;
+use log::{error, info, warn};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Context;
+use std::task::Poll;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::select;
+use tokio::sync::mpsc;
+
+pub mod controller;
+pub mod decode;
+pub mod options;
+pub mod packets;
+
+use controller::{Controller, ControllerOptions};
+pub use options::{Command, DecodeCommand, Opt};
+use packets::{nci, rf, ConnectionClosed};
+
+const MAX_DEVICES: usize = 128;
+/// Identifier of a device of the scene, the sender and receiver of its
+/// RF packets.
+pub type Id = u16;
+
+/// RF technologies supported by the emulated NFCCs, and by default
+/// by the devices connected to the RF server.
+const RF_TECHNOLOGIES: [rf::Technology; 4] =
+    [rf::Technology::NfcA, rf::Technology::NfcB, rf::Technology::NfcF, rf::Technology::NfcV];
+
+/// Capacity of the in-memory streams connecting the logical devices of
+/// a multiplexed NCI connection.
+const MULTIPLEX_BUFFER_SIZE: usize = 4096;
+
+/// Read RF Control and Data packets received on the RF transport.
+/// Performs recombination of the segmented packets.
+pub struct RfReader {
+    socket: Pin<Box<dyn AsyncRead>>,
+}
+
+/// Write RF Control and Data packets received to the RF transport.
+/// Performs segmentation of the packets.
+pub struct RfWriter {
+    socket: Pin<Box<dyn AsyncWrite>>,
+}
+
+impl RfReader {
+    /// Create a new RF reader from an RF transport, e.g. a TCP socket
+    /// half or an in-memory stream.
+    pub fn new<T: AsyncRead + 'static>(socket: T) -> Self {
+        RfReader { socket: Box::pin(socket) }
+    }
+
+    /// Read a single RF packet from the reader.
+    /// RF packets are framed with the byte size encoded as little-endian u16.
+    pub async fn read(&mut self) -> Result<Vec<u8>> {
+        const HEADER_SIZE: usize = 2;
+        let mut header_bytes = [0; HEADER_SIZE];
+
+        // Read the header bytes. The transport closing before the header
+        // is a clean disconnection.
+        self.socket.read_exact(&mut header_bytes[0..HEADER_SIZE]).await.map_err(|err| match err
+            .kind()
+        {
+            std::io::ErrorKind::UnexpectedEof => anyhow::Error::from(ConnectionClosed),
+            _ => err.into(),
+        })?;
+        let packet_length = u16::from_le_bytes(header_bytes) as usize;
+
+        // Read the packet data.
+        let mut packet_bytes = vec![0; packet_length];
+        self.socket.read_exact(&mut packet_bytes).await?;
+
+        Ok(packet_bytes)
+    }
+}
+
+impl RfWriter {
+    /// Create a new RF writer from an RF transport, e.g. a TCP socket
+    /// half or an in-memory stream.
+    pub fn new<T: AsyncWrite + 'static>(socket: T) -> Self {
+        RfWriter { socket: Box::pin(socket) }
+    }
+
+    /// Write a single RF packet to the writer.
+    /// RF packets are framed with the byte size encoded as little-endian u16.
+    async fn write(&mut self, packet: &[u8]) -> Result<()> {
+        let packet_length: u16 = packet.len().try_into()?;
+        let header_bytes = packet_length.to_le_bytes();
+
+        // Write the header bytes.
+        self.socket.write_all(&header_bytes).await?;
+
+        // Write the packet data.
+        self.socket.write_all(packet).await?;
+
+        Ok(())
+    }
+}
+
+/// Represent a generic NFC device interacting on the RF transport.
+/// Devices communicate together through the RF mpsc channel.
+/// NFCCs are an instance of Device.
+pub struct Device {
+    // Unique identifier associated with the device.
+    // The identifier is assured never to be reused in the lifetime of
+    // the emulator, except by a device reconnecting from the same host
+    // when sticky slots are enabled.
+    id: u16,
+    // Async task running the controller main loop.
+    task: Pin<Box<dyn Future<Output = Result<()>>>>,
+    // Channel for injecting RF data packets into the controller instance.
+    rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
+    // RF technologies supported by the device. RF packets of other
+    // technologies are not routed to the device.
+    technologies: Vec<rf::Technology>,
+}
+
+impl Device {
+    fn nci<S: AsyncRead + AsyncWrite + 'static>(
+        id: Id,
+        options: ControllerOptions,
+        socket: S,
+        controller_rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
+    ) -> Device {
+        let (rf_tx, rf_rx) = mpsc::unbounded_channel();
+        Device {
+            id,
+            rf_tx,
+            technologies: RF_TECHNOLOGIES.to_vec(),
+            task: Box::pin(async move {
+                let (nci_rx, nci_tx) = tokio::io::split(socket);
+                Controller::run(
+                    id,
+                    options,
+                    nci::Reader::new(nci_rx),
+                    nci::Writer::new(nci_tx),
+                    rf_rx,
+                    controller_rf_tx,
+                )
+                .await
+            }),
+        }
+    }
+
+    fn rf(
+        id: Id,
+        socket: TcpStream,
+        technologies: Vec<rf::Technology>,
+        controller_rf_tx: mpsc::UnboundedSender<rf::RfPacket>,
+    ) -> Device {
+        let (rf_tx, mut rf_rx) = mpsc::unbounded_channel();
+        Device {
+            id,
+            rf_tx,
+            technologies,
+            task: Box::pin(async move {
+                let (socket_rx, socket_tx) = socket.into_split();
+                let mut rf_reader = RfReader::new(socket_rx);
+                let mut rf_writer = RfWriter::new(socket_tx);
+
+                let result: Result<((), ())> = futures::future::try_join(
+                    async {
+                        loop {
+                            // Replace the sender identifier in the packet
+                            // with the assigned number for the RF connection.
+                            // TODO: currently the generated API does not allow
+                            // modifying the parsed fields so the change needs to be
+                            // applied to the unparsed packet.
+                            let mut packet_bytes = rf_reader.read().await?;
+                            packet_bytes[0..2].copy_from_slice(&id.to_le_bytes());
+
+                            // Parse the input packet.
+                            let packet = rf::RfPacket::parse(&packet_bytes)?;
+
+                            // Forward the packet to other devices.
+                            controller_rf_tx.send(packet)?;
+                        }
+                    },
+                    async {
+                        loop {
+                            // Forward the packet to the socket connection.
+                            use pdl_runtime::Packet;
+                            let packet = rf_rx
+                                .recv()
+                                .await
+                                .ok_or(anyhow::anyhow!("rf_rx channel closed"))?;
+                            rf_writer.write(&packet.to_vec()).await?;
+                        }
+                    },
+                )
+                .await;
+
+                result?;
+                Ok(())
+            }),
+        }
+    }
+}
+
+/// Demultiplex a multiplexed NCI connection. Every NCI packet exchanged
+/// on the connection is prefixed with the one-byte number of the logical
+/// device it belongs to. A logical device is created when the first packet
+/// with its number is received, and reported on `devices_tx` with the
+/// stream carrying its NCI packets.
+async fn multiplex(
+    socket: TcpStream,
+    addr: SocketAddr,
+    devices_tx: mpsc::UnboundedSender<(SocketAddr, u8, DuplexStream)>,
+) -> Result<()> {
+    let (mut socket_rx, mut socket_tx) = socket.into_split();
+    let (packet_tx, mut packet_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+    let mut devices: HashMap<u8, WriteHalf<DuplexStream>> = HashMap::new();
+
+    let result: Result<((), ())> = futures::future::try_join(
+        async {
+            loop {
+                // The connection closing before the device number is a
+                // clean disconnection.
+                let device = socket_rx.read_u8().await.map_err(|err| match err.kind() {
+                    std::io::ErrorKind::UnexpectedEof => anyhow::Error::from(ConnectionClosed),
+                    _ => err.into(),
+                })?;
+                let packet = read_nci_packet(&mut socket_rx).await?;
+                let stream_tx = match devices.entry(device) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => {
+                        let (stream, device_stream) = tokio::io::duplex(MULTIPLEX_BUFFER_SIZE);
+                        let (mut stream_rx, stream_tx) = tokio::io::split(stream);
+                        devices_tx.send((addr, device, device_stream))?;
+                        // Forward the packets sent by the logical device,
+                        // until either the device or the connection is closed.
+                        let packet_tx = packet_tx.clone();
+                        tokio::spawn(async move {
+                            while let Ok(packet) = read_nci_packet(&mut stream_rx).await {
+                                if packet_tx.send([&[device], &packet[..]].concat()).is_err() {
+                                    break;
+                                }
+                            }
+                        });
+                        entry.insert(stream_tx)
+                    }
+                };
+                // The logical device is closed when its controller stops,
+                // the next packet with its number creates a new device.
+                if let Err(err) = stream_tx.write_all(&packet).await {
+                    warn!("dropping packet for closed logical device {}: {}", device, err);
+                    devices.remove(&device);
+                }
+            }
+        },
+        async {
+            loop {
+                let packet =
+                    packet_rx.recv().await.ok_or(anyhow::anyhow!("packet_rx channel closed"))?;
+                socket_tx.write_all(&packet).await?;
+            }
+        },
+    )
+    .await;
+
+    // Close the streams of the logical devices, the forwarding tasks
+    // still hold their read halves.
+    for stream_tx in devices.values_mut() {
+        let _ = stream_tx.shutdown().await;
+    }
+    result?;
+    Ok(())
+}
+
+/// Read a single NCI packet from an NCI transport.
+/// Segmented messages are not re-assembled.
+async fn read_nci_packet<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    const HEADER_SIZE: usize = 3;
+    let mut packet = vec![0; HEADER_SIZE];
+    reader.read_exact(&mut packet).await?;
+    let payload_length = packet[HEADER_SIZE - 1] as usize;
+    packet.resize(HEADER_SIZE + payload_length, 0);
+    reader.read_exact(&mut packet[HEADER_SIZE..]).await?;
+    Ok(packet)
+}
+
+/// Kind of a device connected to the scene.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum DeviceKind {
+    Nci,
+    /// Logical NCI device of a multiplexed connection, with its number.
+    MultiplexedNci(u8),
+    Rf,
+}
+
+/// Client reconnecting to the scene, identified by its host rather than
+/// its socket address: the port changes on every connection.
+type Client = (IpAddr, DeviceKind);
+
+/// Type of a device connected to the scene.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Nci,
+    Rf,
+}
+
+impl From<DeviceKind> for DeviceType {
+    fn from(kind: DeviceKind) -> Self {
+        match kind {
+            DeviceKind::Nci | DeviceKind::MultiplexedNci(_) => DeviceType::Nci,
+            DeviceKind::Rf => DeviceType::Rf,
+        }
+    }
+}
+
+/// Description of a device connected to the scene.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub id: Id,
+    /// RF packets are only exchanged between devices at the same position.
+    pub position: u32,
+    pub r#type: DeviceType,
+}
+
+/// Devices connected to the scene, indexed by identifier. The positions
+/// can be changed while the scene is running.
+pub type Devices = Arc<Mutex<HashMap<Id, DeviceInformation>>>;
+
+struct Scene {
+    next_id: u16,
+    waker: Option<std::task::Waker>,
+    devices: [Option<Device>; MAX_DEVICES],
+    context: Devices,
+    // Place each new device at the position of its identifier rather
+    // than at the position 0 shared with the other devices.
+    separate_devices: bool,
+    // Slots and identifiers assigned to each client, present only when
+    // sticky slot assignment is enabled.
+    sticky_slots: Option<HashMap<Client, Vec<(usize, Id)>>>,
+}
+
+impl Default for Scene {
+    fn default() -> Self {
+        const NONE: Option<Device> = None;
+        Scene {
+            next_id: 0,
+            waker: None,
+            devices: [NONE; MAX_DEVICES],
+            context: Default::default(),
+            separate_devices: false,
+            sticky_slots: None,
+        }
+    }
+}
+
+impl Scene {
+    fn new(sticky_slots: bool) -> Scene {
+        Scene { sticky_slots: sticky_slots.then(HashMap::new), ..Default::default() }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake()
+        }
+    }
+
+    /// Select the slot and identifier of a new device of the client
+    /// `client`. When sticky slots are enabled, a device reconnecting from
+    /// the same host reclaims a free slot previously assigned to a device
+    /// of the same kind from this host, along with its identifier. NCI and
+    /// RF devices paired on the same host thus both get their slots back.
+    fn allocate_slot(&self, client: Client) -> Option<(usize, Id)> {
+        let sticky_slots = self.sticky_slots.as_ref();
+        if let Some(&(n, id)) = sticky_slots
+            .and_then(|slots| slots.get(&client))
+            .and_then(|slots| slots.iter().find(|&&(n, _)| self.devices[n].is_none()))
+        {
+            return Some((n, id));
+        }
+        // Prefer slots that are not reserved for a reconnecting device.
+        let reserved = |n: usize| {
+            sticky_slots.is_some_and(|slots| slots.values().flatten().any(|&(m, _)| m == n))
+        };
+        (0..MAX_DEVICES)
+            .find(|&n| self.devices[n].is_none() && !reserved(n))
+            .or_else(|| (0..MAX_DEVICES).find(|&n| self.devices[n].is_none()))
+            .map(|n| (n, self.next_id))
+    }
+
+    fn add_device(&mut self, client: Client, builder: impl FnOnce(Id) -> Device) -> Result<Id> {
+        let (n, id) = self
+            .allocate_slot(client)
+            .ok_or(anyhow::anyhow!("max number of connections reached"))?;
+        self.devices[n] = Some(builder(id));
+        let position = if self.separate_devices { id as u32 } else { 0 };
+        self.context
+            .lock()
+            .unwrap()
+            .insert(id, DeviceInformation { id, position, r#type: client.1.into() });
+        if id == self.next_id {
+            self.next_id += 1;
+            if let Some(ref mut slots) = self.sticky_slots {
+                slots.entry(client).or_default().push((n, id));
+            }
+        }
+        self.wake();
+        Ok(n as Id)
+    }
+
+    fn disconnect(&mut self, n: usize) {
+        let id = self.devices[n].as_ref().unwrap().id;
+        self.devices[n] = None;
+        self.context.lock().unwrap().remove(&id);
+        for other_n in 0..MAX_DEVICES {
+            let Some(ref device) = self.devices[other_n] else { continue };
+            assert!(n != other_n);
+            device
+                .rf_tx
+                .send(
+                    rf::DeactivateNotificationBuilder {
+                        type_: rf::DeactivateType::Discovery,
+                        reason: rf::DeactivateReason::RfLinkLoss,
+                        sender: id,
+                        receiver: device.id,
+                        technology: rf::Technology::NfcA,
+                        protocol: rf::Protocol::Undetermined,
+                    }
+                    .into(),
+                )
+                .expect("failed to send deactive notification")
+        }
+    }
+
+    /// Route a parsed RF packet to the devices of the scene placed at the
+    /// same position as the sender. Packets sent to the broadcast identifier
+    /// `u16::MAX` are delivered to all such devices but the sender, other
+    /// packets only to the selected receiver.
+    fn send(&self, packet: &rf::RfPacket) -> Result<()> {
+        let context = self.context.lock().unwrap();
+        let position = |id| context.get(&id).map(|info| info.position);
+        for n in 0..MAX_DEVICES {
+            let Some(ref device) = self.devices[n] else { continue };
+            if packet.get_sender() != device.id
+                && (packet.get_receiver() == u16::MAX || packet.get_receiver() == device.id)
+                && device.technologies.contains(&packet.get_technology())
+                && position(device.id) == position(packet.get_sender())
+            {
+                device.rf_tx.send(packet.to_owned())?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Future for Scene {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        for n in 0..MAX_DEVICES {
+            let dropped = match self.devices[n] {
+                Some(ref mut device) => match device.task.as_mut().poll(cx) {
+                    Poll::Ready(Ok(_)) => unreachable!(),
+                    Poll::Ready(Err(err)) if err.is::<ConnectionClosed>() => {
+                        info!("device {} disconnected", n);
+                        true
+                    }
+                    Poll::Ready(Err(err)) => {
+                        warn!("dropping device {}: {}", n, err);
+                        true
+                    }
+                    Poll::Pending => false,
+                },
+                None => false,
+            };
+            if dropped {
+                self.disconnect(n)
+            }
+        }
+        self.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+/// Emulator instance with NCI and RF servers bound to their ports.
+pub struct Casimir {
+    opt: Opt,
+    nci_listener: TcpListener,
+    rf_listener: TcpListener,
+    devices: Devices,
+    separate_devices: bool,
+}
+
+impl Casimir {
+    /// Bind the NCI and RF servers to the ports selected in `opt`.
+    /// The port number 0 selects an ephemeral port, which can be retrieved
+    /// with `nci_port` and `rf_port`.
+    pub async fn bind(opt: Opt) -> Result<Casimir> {
+        let nci_listener =
+            TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, opt.nci_port)).await?;
+        let rf_listener =
+            TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, opt.rf_port)).await?;
+        Ok(Casimir {
+            opt,
+            nci_listener,
+            rf_listener,
+            devices: Default::default(),
+            separate_devices: false,
+        })
+    }
+
+    /// Place each new device at a distinct position, the value of its
+    /// identifier, instead of placing all the devices at the position 0.
+    /// The devices only exchange RF packets once moved to the same
+    /// position through [`Casimir::devices`].
+    pub fn separate_devices(self) -> Casimir {
+        Casimir { separate_devices: true, ..self }
+    }
+
+    /// Return the devices connected to the emulator, updated as devices
+    /// connect and disconnect.
+    pub fn devices(&self) -> Devices {
+        self.devices.clone()
+    }
+
+    /// Return the port number bound for the NCI server.
+    pub fn nci_port(&self) -> Result<u16> {
+        Ok(self.nci_listener.local_addr()?.port())
+    }
+
+    /// Return the port number bound for the RF server.
+    pub fn rf_port(&self) -> Result<u16> {
+        Ok(self.rf_listener.local_addr()?.port())
+    }
+
+    /// Serve NCI and RF connections until the `shutdown` future completes.
+    pub async fn run_with(self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        let options = self.opt.controller_options();
+        let rf_technologies = self.opt.rf_technologies();
+        let (rf_tx, mut rf_rx) = mpsc::unbounded_channel();
+        let (devices_tx, mut devices_rx) = mpsc::unbounded_channel();
+        let mut scene = Scene {
+            context: self.devices.clone(),
+            separate_devices: self.separate_devices,
+            ..Scene::new(self.opt.sticky_slots)
+        };
+        info!("Listening for NCI connections at address 127.0.0.1:{}", self.nci_port()?);
+        info!("Listening for RF connections at address 127.0.0.1:{}", self.rf_port()?);
+        tokio::pin!(shutdown);
+        loop {
+            select! {
+                result = self.nci_listener.accept() => {
+                    let (socket, addr) = result?;
+                    info!("Incoming NCI connection from {}", addr);
+                    if self.opt.multiplex {
+                        let devices_tx = devices_tx.clone();
+                        tokio::spawn(async move {
+                            match multiplex(socket, addr, devices_tx).await {
+                                Err(err) if err.is::<ConnectionClosed>() => info!("Multiplexed NCI connection from {} closed", addr),
+                                Err(err) => warn!("Multiplexed NCI connection from {} failed: {}", addr, err),
+                                Ok(()) => (),
+                            }
+                        });
+                        continue;
+                    }
+                    match scene.add_device((addr.ip(), DeviceKind::Nci), |id| Device::nci(id, options.clone(), socket, rf_tx.clone())) {
+                        Ok(id) => info!("Accepted NCI connection from {} in slot {}", addr, id),
+                        Err(err) => error!("Failed to accept NCI connection from {}: {}", addr, err)
+                    }
+                },
+                Some((addr, device, stream)) = devices_rx.recv() => {
+                    match scene.add_device((addr.ip(), DeviceKind::MultiplexedNci(device)), |id| Device::nci(id, options.clone(), stream, rf_tx.clone())) {
+                        Ok(id) => info!("Accepted logical NCI device {} from {} in slot {}", device, addr, id),
+                        Err(err) => error!("Failed to accept logical NCI device {} from {}: {}", device, addr, err)
+                    }
+                },
+                result = self.rf_listener.accept() => {
+                    let (socket, addr) = result?;
+                    info!("Incoming RF connection from {}", addr);
+                    match scene.add_device((addr.ip(), DeviceKind::Rf), |id| Device::rf(id, socket, rf_technologies.clone(), rf_tx.clone())) {
+                        Ok(id) => info!("Accepted RF connection from {} in slot {}", addr, id),
+                        Err(err) => error!("Failed to accept RF connection from {}: {}", addr, err)
+                    }
+                },
+                _ = &mut scene => (),
+                result = rf_rx.recv() => {
+                    let packet = result.ok_or(anyhow::anyhow!("rf_rx channel closed"))?;
+                    scene.send(&packet)?
+                },
+                _ = &mut shutdown => {
+                    info!("Shutting down");
+                    return Ok(())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return a builder for an idle device supporting all RF technologies,
+    /// whose RF packets are kept in `rf_rx`.
+    fn device(
+        rf_rx: &mut Vec<mpsc::UnboundedReceiver<rf::RfPacket>>,
+    ) -> impl FnOnce(Id) -> Device + '_ {
+        device_with_technologies(rf_rx, &RF_TECHNOLOGIES)
+    }
+
+    /// Return a builder for an idle device supporting the RF technologies
+    /// `technologies`, whose RF packets are kept in `rf_rx`.
+    fn device_with_technologies<'a>(
+        rf_rx: &'a mut Vec<mpsc::UnboundedReceiver<rf::RfPacket>>,
+        technologies: &'a [rf::Technology],
+    ) -> impl FnOnce(Id) -> Device + 'a {
+        |id| {
+            let (rf_tx, device_rf_rx) = mpsc::unbounded_channel();
+            rf_rx.push(device_rf_rx);
+            Device {
+                id,
+                task: Box::pin(futures::future::pending()),
+                rf_tx,
+                technologies: technologies.to_vec(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rf_packets_are_framed_over_duplex() {
+        let (tx, rx) = tokio::io::duplex(1024);
+        let mut writer = RfWriter::new(tx);
+        let mut reader = RfReader::new(rx);
+        writer.write(&[0xca, 0xfe]).await.unwrap();
+        writer.write(&[]).await.unwrap();
+        assert_eq!(reader.read().await.unwrap(), [0xca, 0xfe]);
+        assert!(reader.read().await.unwrap().is_empty());
+
+        // Closing the writer between two packets is a clean disconnection.
+        drop(writer);
+        assert!(reader.read().await.unwrap_err().is::<ConnectionClosed>());
+    }
+
+    #[test]
+    fn sticky_slots_are_reclaimed_by_host() {
+        let host: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let other_host: IpAddr = Ipv4Addr::new(10, 0, 0, 1).into();
+        let mut rf_rx = vec![];
+        let mut scene = Scene::new(true);
+        assert_eq!(scene.add_device((host, DeviceKind::Nci), device(&mut rf_rx)).unwrap(), 0);
+        assert_eq!(scene.add_device((host, DeviceKind::Rf), device(&mut rf_rx)).unwrap(), 1);
+        scene.disconnect(0);
+        scene.disconnect(1);
+
+        // The free slots stay reserved for the devices of the host.
+        assert_eq!(scene.add_device((other_host, DeviceKind::Nci), device(&mut rf_rx)).unwrap(), 2);
+        assert_eq!(scene.add_device((host, DeviceKind::Rf), device(&mut rf_rx)).unwrap(), 1);
+        assert_eq!(scene.add_device((host, DeviceKind::Nci), device(&mut rf_rx)).unwrap(), 0);
+        assert_eq!(scene.devices[0].as_ref().unwrap().id, 0);
+        assert_eq!(scene.devices[1].as_ref().unwrap().id, 1);
+        assert_eq!(scene.devices[2].as_ref().unwrap().id, 2);
+
+        // Without sticky slots, free slots are reused with new identifiers.
+        let mut scene = Scene::new(false);
+        scene.add_device((host, DeviceKind::Nci), device(&mut rf_rx)).unwrap();
+        scene.disconnect(0);
+        assert_eq!(scene.add_device((other_host, DeviceKind::Nci), device(&mut rf_rx)).unwrap(), 0);
+        assert_eq!(scene.devices[0].as_ref().unwrap().id, 1);
+    }
+
+    #[test]
+    fn scene_routes_rf_packets_by_technology() {
+        let host: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let mut rf_rx = vec![];
+        let mut scene = Scene::new(false);
+        scene.add_device((host, DeviceKind::Nci), device(&mut rf_rx)).unwrap();
+        scene
+            .add_device(
+                (host, DeviceKind::Rf),
+                device_with_technologies(&mut rf_rx, &[rf::Technology::NfcA]),
+            )
+            .unwrap();
+        scene
+            .add_device(
+                (host, DeviceKind::Rf),
+                device_with_technologies(&mut rf_rx, &[rf::Technology::NfcB]),
+            )
+            .unwrap();
+
+        let poll_command = |technology| -> rf::RfPacket {
+            rf::PollCommandBuilder {
+                sender: 0,
+                receiver: u16::MAX,
+                protocol: rf::Protocol::Undetermined,
+                technology,
+            }
+            .build()
+            .into()
+        };
+        scene.send(&poll_command(rf::Technology::NfcA)).unwrap();
+        scene.send(&poll_command(rf::Technology::NfcF)).unwrap();
+
+        // The NFC-A frame only reaches the NFC-A capable device, the NFC-F
+        // frame none of them. The sender does not receive its own packets.
+        assert!(rf_rx[0].try_recv().is_err());
+        assert_eq!(rf_rx[1].try_recv().unwrap(), poll_command(rf::Technology::NfcA));
+        assert!(rf_rx[1].try_recv().is_err());
+        assert!(rf_rx[2].try_recv().is_err());
+    }
+
+    #[test]
+    fn separate_devices_exchange_rf_packets_once_moved_together() {
+        let host: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let mut rf_rx = vec![];
+        let mut scene = Scene { separate_devices: true, ..Scene::new(false) };
+        scene.add_device((host, DeviceKind::Nci), device(&mut rf_rx)).unwrap();
+        scene.add_device((host, DeviceKind::Rf), device(&mut rf_rx)).unwrap();
+        assert_eq!(
+            scene.context.lock().unwrap()[&1],
+            DeviceInformation { id: 1, position: 1, r#type: DeviceType::Rf }
+        );
+
+        let poll_command: rf::RfPacket = rf::PollCommandBuilder {
+            sender: 0,
+            receiver: u16::MAX,
+            protocol: rf::Protocol::Undetermined,
+            technology: rf::Technology::NfcA,
+        }
+        .build()
+        .into();
+        scene.send(&poll_command).unwrap();
+        assert!(rf_rx[1].try_recv().is_err());
+        scene.context.lock().unwrap().get_mut(&1).unwrap().position = 0;
+        scene.send(&poll_command).unwrap();
+        assert_eq!(rf_rx[1].try_recv().unwrap(), poll_command);
+
+        // Disconnected devices are removed from the context.
+        scene.disconnect(1);
+        assert!(!scene.context.lock().unwrap().contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn closed_nci_connection_disconnects_device() {
+        let host: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let mut rf_rx = vec![];
+        let (controller_rf_tx, _controller_rf_rx) = mpsc::unbounded_channel();
+        let (dh, nfcc) = tokio::io::duplex(1024);
+        let mut scene = Scene::new(false);
+        scene
+            .add_device((host, DeviceKind::Nci), |id| {
+                Device::nci(id, Default::default(), nfcc, controller_rf_tx)
+            })
+            .unwrap();
+        scene.add_device((host, DeviceKind::Rf), device(&mut rf_rx)).unwrap();
+
+        // The remaining device is notified of the link loss.
+        drop(dh);
+        let packet = tokio::select! {
+            () = &mut scene => unreachable!(),
+            packet = rf_rx[0].recv() => packet.unwrap(),
+        };
+        let deactivate = rf::DeactivateNotification::try_from(packet).unwrap();
+        assert_eq!(deactivate.get_sender(), 0);
+        assert_eq!(deactivate.get_reason(), rf::DeactivateReason::RfLinkLoss);
+        assert!(scene.devices[0].is_none());
+    }
+
+    #[tokio::test]
+    async fn half_closed_nci_connection_terminates_cleanly() {
+        let (controller_rf_tx, _controller_rf_rx) = mpsc::unbounded_channel();
+        let (dh, nfcc) = tokio::io::duplex(1024);
+        let device = Device::nci(0, Default::default(), nfcc, controller_rf_tx);
+        let (mut dh_rx, mut dh_tx) = tokio::io::split(dh);
+        let dh = async {
+            // CORE_RESET_CMD with the reset type Keep Configuration.
+            dh_tx.write_all(&[0x20, 0x00, 0x01, 0x00]).await.unwrap();
+            let mut response = [0; 4];
+            dh_rx.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [0x40, 0x00, 0x01, 0x00]);
+            // Terminate the connection between two packets.
+            dh_tx.shutdown().await.unwrap();
+        };
+        let (result, ()) = tokio::join!(device.task, dh);
+        assert!(result.unwrap_err().is::<ConnectionClosed>());
+
+        // Terminating the connection in the middle of a packet is an error.
+        let (controller_rf_tx, _controller_rf_rx) = mpsc::unbounded_channel();
+        let (dh, nfcc) = tokio::io::duplex(1024);
+        let device = Device::nci(1, Default::default(), nfcc, controller_rf_tx);
+        let (_dh_rx, mut dh_tx) = tokio::io::split(dh);
+        dh_tx.write_all(&[0x20, 0x00, 0x01]).await.unwrap();
+        dh_tx.shutdown().await.unwrap();
+        assert!(!device.task.await.unwrap_err().is::<ConnectionClosed>());
+    }
+}
@@ -0,0 +1,430 @@
+// Copyright 2023, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Command line options of the emulator, shared by the casimir and
+//! casimir-grpc binaries.
+
+use argh::FromArgs;
+use std::time::Duration;
+
+use crate::controller::{ConfigParameters, ControllerOptions};
+use crate::packets::{nci, rf};
+use crate::RF_TECHNOLOGIES;
+
+#[derive(FromArgs, Debug)]
+/// Nfc emulator.
+pub struct Opt {
+    #[argh(option, default = "7000")]
+    /// configure the TCP port for the NCI server.
+    pub nci_port: u16,
+    #[argh(option, default = "7001")]
+    /// configure the TCP port for the RF server.
+    pub rf_port: u16,
+    #[argh(switch)]
+    /// log the decoded NCI packets exchanged with the DH.
+    log_packets: bool,
+    #[argh(switch)]
+    /// reassign the previous slot to devices reconnecting from the same host.
+    pub sticky_slots: bool,
+    #[argh(option)]
+    /// configure the manufacturer ID reported in CORE_RESET_NTF.
+    manufacturer_id: Option<u8>,
+    #[argh(option, from_str_fn(parse_manufacturer_info))]
+    /// configure the manufacturer specific information reported in
+    /// CORE_RESET_NTF, as a hexadecimal string.
+    manufacturer_info: Option<Vec<u8>>,
+    #[argh(switch)]
+    /// keep the configuration on CORE_RESET_CMD while reporting it as reset.
+    ignore_reset_config: bool,
+    #[argh(option)]
+    /// configure the initial number of credits granted on logical
+    /// connections to a remote NFC endpoint, 255 disables flow control.
+    conn_credits: Option<u8>,
+    #[argh(option, from_str_fn(parse_config_file))]
+    /// preload the configuration parameters from a file containing
+    /// CORE_SET_CONFIG_CMD parameters in TLV format. The proprietary
+    /// parameter PROP0 (0xa0) is reserved: 0 disables listen mode routing.
+    config: Option<ConfigParameters>,
+    #[argh(option, from_str_fn(parse_rf_interface))]
+    /// advertise an RF interface in CORE_INIT_RSP, one of nfcee-direct,
+    /// frame, iso-dep, nfc-dep, ndef. Can be repeated, defaults to
+    /// nfcee-direct, frame, iso-dep and nfc-dep.
+    rf_interface: Vec<nci::RfInterfaceType>,
+    #[argh(option, from_str_fn(parse_rf_interface_extension))]
+    /// advertise an RF interface extension in CORE_INIT_RSP, as
+    /// frame:frame-aggregated or nfc-dep:llcp-symmetry. Can be repeated.
+    rf_interface_extension: Vec<(nci::RfInterfaceType, nci::RfInterfaceExtensionType)>,
+    #[argh(option)]
+    /// configure the maximum number of mapping configurations accepted
+    /// in RF_DISCOVER_MAP_CMD.
+    max_discover_map_size: Option<usize>,
+    #[argh(switch)]
+    /// exchange the RF frames of the Frame RF interface with their CRC.
+    rf_crc: bool,
+    #[argh(switch)]
+    /// withhold the credits of dynamic logical connections until the DH
+    /// sends the proprietary command with the OID 0x3f.
+    starve_credits: bool,
+    #[argh(option, from_str_fn(parse_max_control_payload_size))]
+    /// configure the maximum payload size of Control Packets advertised
+    /// in CORE_INIT_RSP, between 32 and 255.
+    max_control_payload_size: Option<u8>,
+    #[argh(option, from_str_fn(parse_max_data_payload_size))]
+    /// configure the maximum payload size of Data Packets, between 1 and 255.
+    max_data_payload_size: Option<u8>,
+    #[argh(switch)]
+    /// do not send RF_DEACTIVATE_NTF when the DH deactivates an active
+    /// RF interface to Idle mode.
+    no_idle_deactivate_ntf: bool,
+    #[argh(switch)]
+    /// answer unimplemented commands with an empty response instead of
+    /// aborting.
+    lenient: bool,
+    #[argh(switch)]
+    /// hold back every other response until the response to the next
+    /// command, to test the command and response matching of the DH.
+    reorder_responses: bool,
+    #[argh(option, from_str_fn(parse_max_logical_connections))]
+    /// configure the maximum number of dynamic logical connections
+    /// advertised in CORE_INIT_RSP, between 0 and 14.
+    max_logical_connections: Option<u8>,
+    #[argh(option)]
+    /// delay CORE_RESET_NTF by the given number of milliseconds after
+    /// CORE_RESET_RSP, to emulate a slow NFCC bring-up.
+    boot_delay_ms: Option<u64>,
+    #[argh(option, from_str_fn(parse_discover_ntf_delay))]
+    /// delay the RF_DISCOVER_NTF reporting targets of a technology, as
+    /// nfc-a:100 for 100 milliseconds. The technology is one of nfc-a,
+    /// nfc-b, nfc-f, nfc-v. Can be repeated.
+    discover_ntf_delay: Vec<(rf::Technology, Duration)>,
+    #[argh(option, from_str_fn(parse_rf_technology))]
+    /// restrict the RF packets routed to the devices connected to the RF
+    /// server to a technology, one of nfc-a, nfc-b, nfc-f, nfc-v. Can be
+    /// repeated, defaults to all technologies.
+    rf_technology: Vec<rf::Technology>,
+    #[argh(switch)]
+    /// serve multiple logical NCI devices on each NCI connection, every
+    /// NCI packet being prefixed with the one-byte device number.
+    pub multiplex: bool,
+    #[argh(option, default = "50051")]
+    /// configure the TCP port for the gRPC server of casimir-grpc.
+    pub grpc_port: u16,
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(FromArgs, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    Decode(DecodeCommand),
+}
+
+#[derive(FromArgs, Debug)]
+/// Decode the NCI packets of a capture file, without starting the emulator.
+#[argh(subcommand, name = "decode")]
+pub struct DecodeCommand {
+    #[argh(positional)]
+    /// capture file with the hexadecimal bytes of an NCI packet or
+    /// segment on each line.
+    pub path: String,
+}
+
+/// Read the configuration parameters from a file. The storage size is
+/// not configurable from the command line, the default limit applies.
+fn parse_config_file(path: &str) -> std::result::Result<ConfigParameters, String> {
+    let bytes = std::fs::read(path).map_err(|err| format!("failed to read {}: {}", path, err))?;
+    ConfigParameters::from_tlv(&bytes, ControllerOptions::default().max_config_storage_size)
+        .map_err(|err| format!("invalid config file {}: {}", path, err))
+}
+
+/// Parse a maximum Control Packet payload size.
+/// [NCI] Table 10: the size is in the range 32 to 255.
+fn parse_max_control_payload_size(value: &str) -> std::result::Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(size) if size >= 32 => Ok(size),
+        _ => Err(format!("invalid maximum control payload size: {}", value)),
+    }
+}
+
+/// Parse a maximum Data Packet payload size.
+/// [NCI] Table 10: the size is in the range 1 to 255.
+fn parse_max_data_payload_size(value: &str) -> std::result::Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(size) if size >= 1 => Ok(size),
+        _ => Err(format!("invalid maximum data payload size: {}", value)),
+    }
+}
+
+/// Parse a maximum number of dynamic logical connections.
+/// [NCI] Table 9: the dynamic Conn IDs are in the range 2h to Fh.
+fn parse_max_logical_connections(value: &str) -> std::result::Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(count) if count <= 14 => Ok(count),
+        _ => Err(format!("invalid maximum number of logical connections: {}", value)),
+    }
+}
+
+/// Parse the name of an RF interface.
+fn parse_rf_interface(value: &str) -> std::result::Result<nci::RfInterfaceType, String> {
+    match value {
+        "nfcee-direct" => Ok(nci::RfInterfaceType::NfceeDirect),
+        "frame" => Ok(nci::RfInterfaceType::Frame),
+        "iso-dep" => Ok(nci::RfInterfaceType::IsoDep),
+        "nfc-dep" => Ok(nci::RfInterfaceType::NfcDep),
+        "ndef" => Ok(nci::RfInterfaceType::Ndef),
+        _ => Err(format!("unknown RF interface: {}", value)),
+    }
+}
+
+/// Parse an RF interface extension, prefixed with the name of the
+/// interface it applies to. [NCI] The Frame Aggregated extension is only
+/// defined for the Frame RF Interface, and the LLCP Symmetry extension
+/// for the NFC-DEP RF Interface.
+fn parse_rf_interface_extension(
+    value: &str,
+) -> std::result::Result<(nci::RfInterfaceType, nci::RfInterfaceExtensionType), String> {
+    match value {
+        "frame:frame-aggregated" => {
+            Ok((nci::RfInterfaceType::Frame, nci::RfInterfaceExtensionType::FrameAggregated))
+        }
+        "nfc-dep:llcp-symmetry" => {
+            Ok((nci::RfInterfaceType::NfcDep, nci::RfInterfaceExtensionType::LlcpSymmetry))
+        }
+        _ => Err(format!("unsupported RF interface extension: {}", value)),
+    }
+}
+
+/// Parse a discovery notification delay, as the name of the polled
+/// technology and the delay in milliseconds.
+fn parse_discover_ntf_delay(
+    value: &str,
+) -> std::result::Result<(rf::Technology, Duration), String> {
+    let (technology, delay) =
+        value.split_once(':').ok_or(format!("invalid discovery notification delay: {}", value))?;
+    let technology = parse_rf_technology(technology)?;
+    let delay = delay.parse::<u64>().map_err(|_| format!("invalid delay: {}", delay))?;
+    Ok((technology, Duration::from_millis(delay)))
+}
+
+/// Parse an RF technology, one of nfc-a, nfc-b, nfc-f, nfc-v.
+fn parse_rf_technology(value: &str) -> std::result::Result<rf::Technology, String> {
+    match value {
+        "nfc-a" => Ok(rf::Technology::NfcA),
+        "nfc-b" => Ok(rf::Technology::NfcB),
+        "nfc-f" => Ok(rf::Technology::NfcF),
+        "nfc-v" => Ok(rf::Technology::NfcV),
+        _ => Err(format!("unsupported technology: {}", value)),
+    }
+}
+
+/// Parse the hexadecimal manufacturer specific information.
+/// The information is limited to 255 bytes by the CORE_RESET_NTF format.
+fn parse_manufacturer_info(value: &str) -> std::result::Result<Vec<u8>, String> {
+    let info = value
+        .as_bytes()
+        .chunks(2)
+        .map(|digits| match digits {
+            [hi, lo] => {
+                Some(((*hi as char).to_digit(16)? << 4 | (*lo as char).to_digit(16)?) as u8)
+            }
+            _ => None,
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or(format!("invalid hexadecimal string: {}", value))?;
+    if info.len() > 255 {
+        return Err("manufacturer specific information exceeds 255 bytes".to_owned());
+    }
+    Ok(info)
+}
+
+impl Opt {
+    /// Return the RF technologies supported by the devices connected to
+    /// the RF server.
+    pub fn rf_technologies(&self) -> Vec<rf::Technology> {
+        if self.rf_technology.is_empty() {
+            RF_TECHNOLOGIES.to_vec()
+        } else {
+            self.rf_technology.clone()
+        }
+    }
+
+    /// Return the NFCC options selected by the command line.
+    pub fn controller_options(&self) -> ControllerOptions {
+        let default = ControllerOptions::default();
+        ControllerOptions {
+            log_packets: self.log_packets,
+            ignore_reset_config: self.ignore_reset_config,
+            rf_frame_crc: self.rf_crc,
+            starve_credits: self.starve_credits,
+            max_control_packet_payload_size: self
+                .max_control_payload_size
+                .unwrap_or(default.max_control_packet_payload_size),
+            max_data_packet_payload_size: self
+                .max_data_payload_size
+                .unwrap_or(default.max_data_packet_payload_size),
+            suppress_idle_deactivate_ntf: self.no_idle_deactivate_ntf,
+            lenient: self.lenient,
+            reorder_responses: self.reorder_responses,
+            max_logical_connections: self
+                .max_logical_connections
+                .unwrap_or(default.max_logical_connections),
+            boot_delay: self.boot_delay_ms.map(Duration::from_millis).unwrap_or(default.boot_delay),
+            remote_endpoint_credits: self.conn_credits.unwrap_or(default.remote_endpoint_credits),
+            config_parameters: self.config.clone().unwrap_or(default.config_parameters),
+            rf_interfaces: if self.rf_interface.is_empty() {
+                default.rf_interfaces
+            } else {
+                self.rf_interface.clone()
+            },
+            rf_interface_extensions: self.rf_interface_extension.clone(),
+            max_discover_map_size: self
+                .max_discover_map_size
+                .unwrap_or(default.max_discover_map_size),
+            discover_notification_delays: self.discover_ntf_delay.clone(),
+            manufacturer_id: self.manufacturer_id.unwrap_or(default.manufacturer_id),
+            manufacturer_specific_information: self
+                .manufacturer_info
+                .clone()
+                .unwrap_or(default.manufacturer_specific_information),
+            ..default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::Controller;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn manufacturer_info_is_validated() {
+        assert_eq!(parse_manufacturer_info("cafe01"), Ok(vec![0xca, 0xfe, 0x01]));
+        assert_eq!(parse_manufacturer_info(""), Ok(vec![]));
+        assert!(parse_manufacturer_info("caf").is_err());
+        assert!(parse_manufacturer_info("xy").is_err());
+        assert!(parse_manufacturer_info(&"00".repeat(256)).is_err());
+    }
+
+    #[test]
+    fn max_logical_connections_is_validated() {
+        let opt = Opt::from_args(&["casimir"], &["--max-logical-connections", "4"]).unwrap();
+        assert_eq!(opt.controller_options().max_logical_connections, 4);
+        assert!(Opt::from_args(&["casimir"], &["--max-logical-connections", "15"]).is_err());
+    }
+
+    #[test]
+    fn rf_interfaces_are_selected_by_name() {
+        let opt = Opt::from_args(
+            &["casimir"],
+            &["--rf-interface", "iso-dep", "--rf-interface", "nfc-dep"],
+        )
+        .unwrap();
+        assert_eq!(
+            opt.controller_options().rf_interfaces,
+            vec![nci::RfInterfaceType::IsoDep, nci::RfInterfaceType::NfcDep]
+        );
+        assert!(Opt::from_args(&["casimir"], &["--rf-interface", "iso-7816"]).is_err());
+    }
+
+    #[test]
+    fn rf_interface_extensions_are_validated() {
+        let opt =
+            Opt::from_args(&["casimir"], &["--rf-interface-extension", "frame:frame-aggregated"])
+                .unwrap();
+        assert_eq!(
+            opt.controller_options().rf_interface_extensions,
+            vec![(nci::RfInterfaceType::Frame, nci::RfInterfaceExtensionType::FrameAggregated)]
+        );
+        // The LLCP Symmetry extension only applies to the NFC-DEP RF interface.
+        assert!(Opt::from_args(&["casimir"], &["--rf-interface-extension", "frame:llcp-symmetry"])
+            .is_err());
+        assert!(Opt::from_args(&["casimir"], &["--rf-interface-extension", "frame-aggregated"])
+            .is_err());
+    }
+
+    #[test]
+    fn discover_ntf_delays_are_parsed() {
+        let opt = Opt::from_args(
+            &["casimir"],
+            &["--discover-ntf-delay", "nfc-a:100", "--discover-ntf-delay", "nfc-v:0"],
+        )
+        .unwrap();
+        assert_eq!(
+            opt.controller_options().discover_notification_delays,
+            vec![
+                (rf::Technology::NfcA, Duration::from_millis(100)),
+                (rf::Technology::NfcV, Duration::ZERO)
+            ]
+        );
+        for invalid in ["nfc-a", "nfc-x:100", "nfc-b:-1"] {
+            assert!(Opt::from_args(&["casimir"], &["--discover-ntf-delay", invalid]).is_err());
+        }
+    }
+
+    #[test]
+    fn max_discover_map_size_is_configurable() {
+        let opt = Opt::from_args(&["casimir"], &["--max-discover-map-size", "4"]).unwrap();
+        assert_eq!(opt.controller_options().max_discover_map_size, 4);
+        let opt = Opt::from_args(&["casimir"], &[]).unwrap();
+        assert_eq!(
+            opt.controller_options().max_discover_map_size,
+            ControllerOptions::default().max_discover_map_size
+        );
+    }
+
+    #[tokio::test]
+    async fn control_messages_are_segmented_at_max_payload_size() {
+        let manufacturer_info = "00".repeat(40);
+        let opt = Opt::from_args(
+            &["casimir"],
+            &["--max-control-payload-size", "32", "--manufacturer-info", &manufacturer_info],
+        )
+        .unwrap();
+        assert!(Opt::from_args(&["casimir"], &["--max-control-payload-size", "31"]).is_err());
+
+        let (mut dh, nfcc) = tokio::io::duplex(1024);
+        let (nci_rx, nci_tx) = tokio::io::split(nfcc);
+        let (_rf_tx, rf_rx) = mpsc::unbounded_channel();
+        let (rf_tx, _rf_rx) = mpsc::unbounded_channel();
+        let nfcc = Controller::run(
+            0,
+            opt.controller_options(),
+            nci::Reader::new(nci_rx),
+            nci::Writer::new(nci_tx),
+            rf_rx,
+            rf_tx,
+        );
+        let dh = async {
+            // CORE_RESET_CMD with the reset type Keep Configuration.
+            dh.write_all(&[0x20, 0x00, 0x01, 0x00]).await.unwrap();
+            let mut response = [0; 4];
+            dh.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [0x40, 0x00, 0x01, 0x00]);
+
+            // The 45 bytes payload of CORE_RESET_NTF is split in a first
+            // segment of 32 bytes, with the PBF set, and a final segment.
+            let mut notification = [0; 3 + 32 + 3 + 13];
+            dh.read_exact(&mut notification).await.unwrap();
+            assert_eq!(notification[0..3], [0x70, 0x00, 32]);
+            assert_eq!(notification[35..38], [0x60, 0x00, 13]);
+            assert_eq!(notification[43..], [0; 8]);
+        };
+        tokio::select! {
+            result = nfcc => panic!("NFCC stopped: {:?}", result),
+            () = dh => (),
+        }
+    }
+}
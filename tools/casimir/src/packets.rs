@@ -67,6 +67,46 @@ pub mod nci {
     use tokio::io::{AsyncRead, AsyncWrite};
     use tokio::sync::Mutex;
 
+    /// Errors returned by the NCI reader for malformed packet headers.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub enum HeaderError {
+        /// The Message Type of the header uses a reserved value.
+        ReservedMessageType(u8),
+        /// A segment with a different Message Type was received before
+        /// the final segment of the message, e.g. a Data Packet
+        /// interleaved with the segments of a Control Message.
+        InterleavedSegment { expected_mt: u8, mt: u8 },
+        /// The header of a segment does not match the header of the
+        /// first segment of the message.
+        InconsistentSegment,
+        /// The transport was closed before the payload length announced
+        /// in the header could be read.
+        TruncatedPayload { payload_length: usize },
+    }
+
+    impl std::fmt::Display for HeaderError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            match self {
+                HeaderError::ReservedMessageType(mt) => {
+                    write!(f, "reserved message type {:#x} in NCI header", mt)
+                }
+                HeaderError::InterleavedSegment { expected_mt, mt } => write!(
+                    f,
+                    "NCI segment with message type {:#x} interleaved in a message of type {:#x}",
+                    mt, expected_mt
+                ),
+                HeaderError::InconsistentSegment => {
+                    write!(f, "NCI segment header does not match the message header")
+                }
+                HeaderError::TruncatedPayload { payload_length } => {
+                    write!(f, "NCI transport closed before the {} bytes payload", payload_length)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for HeaderError {}
+
     /// Read NCI Control and Data packets received on the NCI transport.
     /// Performs recombination of the segmented packets.
     pub struct Reader {
@@ -77,6 +117,8 @@ pub mod nci {
     /// Performs segmentation of the packets.
     pub struct Writer {
         socket: Mutex<Pin<Box<dyn AsyncWrite>>>,
+        max_control_payload_size: u8,
+        max_data_payload_size: u8,
     }
 
     impl Reader {
@@ -87,12 +129,17 @@ pub mod nci {
 
         /// Read a single NCI packet from the reader. The packet is automatically
         /// re-assembled if segmented on the NCI transport.
+        /// Malformed headers are reported with a [`HeaderError`].
         pub async fn read(&self) -> anyhow::Result<Vec<u8>> {
             use tokio::io::AsyncReadExt;
 
             const HEADER_SIZE: usize = 3;
+            const MT_SHIFT: u8 = 5;
+            const PBF_MASK: u8 = 0x10;
+            const OID_MASK: u8 = 0x3f;
             let mut socket = self.socket.lock().await;
             let mut complete_packet = vec![0; HEADER_SIZE];
+            let mut first_header: Option<[u8; 2]> = None;
 
             // Note on reassembly:
             // - for each segment of a Control Message, the header of the
@@ -102,14 +149,53 @@ pub mod nci {
             // Thus it is correct to keep only the last header of the segmented
             // packet.
             loop {
-                // Read the common packet header.
-                socket.read_exact(&mut complete_packet[0..HEADER_SIZE]).await?;
+                // Read the common packet header. The transport closing
+                // before the first segment is a clean disconnection.
+                socket.read_exact(&mut complete_packet[0..HEADER_SIZE]).await.map_err(|err| {
+                    match err.kind() {
+                        std::io::ErrorKind::UnexpectedEof if first_header.is_none() => {
+                            anyhow::Error::from(super::ConnectionClosed)
+                        }
+                        _ => err.into(),
+                    }
+                })?;
+                let mt = complete_packet[0] >> MT_SHIFT;
+                if MessageType::try_from(mt).is_err() {
+                    return Err(HeaderError::ReservedMessageType(mt).into());
+                }
                 let header = PacketHeader::parse(&complete_packet[0..HEADER_SIZE])?;
 
+                // Check the segment header against the first segment: MT
+                // and GID or Conn ID, and the OID for Control Packets.
+                let mut segment_header = [complete_packet[0] & !PBF_MASK, complete_packet[1]];
+                if header.get_mt() == MessageType::Data {
+                    segment_header[1] = 0;
+                } else {
+                    segment_header[1] &= OID_MASK;
+                }
+                match first_header {
+                    Some(first_header) if first_header[0] >> MT_SHIFT != mt => {
+                        return Err(HeaderError::InterleavedSegment {
+                            expected_mt: first_header[0] >> MT_SHIFT,
+                            mt,
+                        }
+                        .into())
+                    }
+                    Some(first_header) if first_header != segment_header => {
+                        return Err(HeaderError::InconsistentSegment.into())
+                    }
+                    _ => first_header = Some(segment_header),
+                }
+
                 // Read the packet payload.
                 let payload_length = header.get_payload_length() as usize;
                 let mut payload_bytes = vec![0; payload_length];
-                socket.read_exact(&mut payload_bytes).await?;
+                socket.read_exact(&mut payload_bytes).await.map_err(|err| match err.kind() {
+                    std::io::ErrorKind::UnexpectedEof => {
+                        anyhow::Error::from(HeaderError::TruncatedPayload { payload_length })
+                    }
+                    _ => err.into(),
+                })?;
                 complete_packet.extend(payload_bytes);
 
                 // Check the Packet Boundary Flag.
@@ -124,7 +210,18 @@ pub mod nci {
     impl Writer {
         /// Create an NCI writer from an NCI transport.
         pub fn new<T: AsyncWrite + 'static>(rx: T) -> Self {
-            Writer { socket: Mutex::new(Box::pin(rx)) }
+            Writer {
+                socket: Mutex::new(Box::pin(rx)),
+                max_control_payload_size: 255,
+                max_data_payload_size: 255,
+            }
+        }
+
+        /// Set the maximum payload sizes of the Control and Data Packets
+        /// written by the writer. The sizes must not be zero.
+        pub fn with_max_payload_sizes(self, control: u8, data: u8) -> Self {
+            assert!(control > 0 && data > 0, "invalid maximum payload size");
+            Writer { max_control_payload_size: control, max_data_payload_size: data, ..self }
         }
 
         /// Write a single NCI packet to the writer. The packet is automatically
@@ -132,13 +229,19 @@ pub mod nci {
         pub async fn write(&self, mut packet: &[u8]) -> anyhow::Result<()> {
             use tokio::io::AsyncWriteExt;
 
+            const MT_SHIFT: u8 = 5;
             let mut socket = self.socket.lock().await;
             let mut header_bytes = [packet[0], packet[1], 0];
+            let max_payload_size = if packet[0] >> MT_SHIFT == u8::from(MessageType::Data) {
+                self.max_data_payload_size
+            } else {
+                self.max_control_payload_size
+            };
             packet = &packet[3..];
 
             loop {
                 // Update header with framing information.
-                let chunk_length = std::cmp::min(255, packet.len());
+                let chunk_length = std::cmp::min(max_payload_size as usize, packet.len());
                 let pbf = if chunk_length < packet.len() {
                     PacketBoundaryFlag::Incomplete
                 } else {
@@ -159,6 +262,243 @@ pub mod nci {
                 }
             }
         }
+
+        /// Flush the NCI transport, ensuring that all written packets
+        /// are delivered to the DH.
+        pub async fn flush(&self) -> anyhow::Result<()> {
+            use tokio::io::AsyncWriteExt;
+
+            self.socket.lock().await.flush().await?;
+            Ok(())
+        }
+
+        /// Write a single NCI packet to the writer and flush the transport.
+        /// Flush failures are reported separately from write failures.
+        pub async fn write_and_flush(&self, packet: &[u8]) -> anyhow::Result<()> {
+            use anyhow::Context;
+
+            self.write(packet).await.context("failed to write NCI packet")?;
+            self.flush().await.context("failed to flush NCI transport")
+        }
+    }
+
+    /// Helpers for building and parsing arbitrary control packets in tests,
+    /// including packets that have no builder.
+    #[cfg(test)]
+    pub mod test_support {
+        use super::*;
+
+        /// Encode an NCI control command with the selected GID, OID and
+        /// payload. The framing information is filled in from the payload.
+        pub fn raw_control(gid: u8, oid: u8, payload: &[u8]) -> Vec<u8> {
+            const MT_SHIFT: u8 = 5;
+            let mut bytes = vec![
+                u8::from(MessageType::Command) << MT_SHIFT | (gid & 0xf),
+                oid & 0x3f,
+                payload.len() as u8,
+            ];
+            bytes.extend_from_slice(payload);
+            bytes
+        }
+
+        /// Parse an encoded NCI control packet. The packet can be
+        /// specialized by the caller to the expected type.
+        pub fn parse_control(bytes: &[u8]) -> anyhow::Result<ControlPacket> {
+            Ok(ControlPacket::parse(bytes)?)
+        }
+
+        /// Size of the in-memory transport backing capture writers. Writes
+        /// block once the buffer is full, until the packets are read back.
+        const CAPTURE_BUFFER_SIZE: usize = 64 * 1024;
+
+        /// Create an NCI writer backed by an in-memory transport, and the
+        /// reader returning the packets written to it. The writer can be
+        /// passed to [`crate::controller::Controller`] to capture the packets
+        /// sent to the DH without a socket.
+        pub fn capture() -> (Writer, Reader) {
+            let (tx, rx) = tokio::io::duplex(CAPTURE_BUFFER_SIZE);
+            (Writer::new(tx), Reader::new(rx))
+        }
+
+        /// Read the next packet from a capture reader, and check that it
+        /// is the control packet `expected`. The framing information is
+        /// not compared: the payload length is only set by the writer.
+        pub async fn expect_control(
+            reader: &Reader,
+            expected: impl Into<ControlPacket>,
+        ) -> anyhow::Result<()> {
+            let expected = expected.into();
+            let bytes = reader.read().await?;
+            match ControlPacket::parse(&bytes) {
+                Ok(packet) if packet == expected => Ok(()),
+                packet => anyhow::bail!(
+                    "unexpected NCI packet {:?}, expected {:?}",
+                    packet.map_err(|_| bytes),
+                    expected
+                ),
+            }
+        }
+
+        /// Read the next packet from a capture reader, and check that it
+        /// is the data packet `expected`. The framing information is
+        /// not compared: the payload length is only set by the writer.
+        pub async fn expect_data(
+            reader: &Reader,
+            expected: impl Into<DataPacket>,
+        ) -> anyhow::Result<()> {
+            let expected = expected.into();
+            let bytes = reader.read().await?;
+            match DataPacket::parse(&bytes) {
+                Ok(packet) if packet == expected => Ok(()),
+                packet => anyhow::bail!(
+                    "unexpected NCI packet {:?}, expected {:?}",
+                    packet.map_err(|_| bytes),
+                    expected
+                ),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::test_support::{parse_control, raw_control};
+        use super::*;
+        use pdl_runtime::Packet;
+
+        #[test]
+        fn raw_control_round_trips_core_get_config() {
+            // CORE_GET_CONFIG_CMD: GID 0h, OID 3h, two parameters.
+            let bytes = raw_control(0x0, 0x3, &[0x02, 0x00, 0x03]);
+            let command = CoreGetConfigCommandBuilder {
+                parameters: vec![ConfigParameterId::TotalDuration, ConfigParameterId::PowerState],
+            }
+            .build();
+            // The builders leave the payload length to the writer.
+            assert_eq!(bytes[3..], command.clone().to_vec()[3..]);
+
+            let parsed = CoreGetConfigCommand::try_from(parse_control(&bytes).unwrap()).unwrap();
+            assert_eq!(parsed, command);
+        }
+
+        #[cfg(feature = "serde")]
+        #[test]
+        fn core_reset_response_serializes_to_json() {
+            let response = CoreResetResponseBuilder { status: Status::Ok }.build();
+            // The header fields are serialized with their numeric values,
+            // the specialized variant is nested under its name.
+            assert_eq!(
+                serde_json::to_value(&response).unwrap(),
+                serde_json::json!({
+                    "mt": 2,
+                    "gid": 0,
+                    "oid": 0,
+                    "status": 0,
+                    "child": { "CoreResetResponse": { "status": 0 } },
+                })
+            );
+        }
+
+        #[test]
+        fn parse_control_rejects_truncated_packets() {
+            let mut bytes = raw_control(0x0, 0x3, &[0x02, 0x00, 0x03]);
+            bytes.pop();
+            assert!(parse_control(&bytes).is_err());
+        }
+
+        /// Read one packet from the raw bytes received on the transport,
+        /// and return the header error it fails with.
+        async fn header_error(bytes: &[u8]) -> HeaderError {
+            let reader = Reader::new(std::io::Cursor::new(bytes.to_vec()));
+            reader.read().await.unwrap_err().downcast::<HeaderError>().unwrap()
+        }
+
+        #[tokio::test]
+        async fn read_reports_closed_connection_between_packets() {
+            let reader = Reader::new(std::io::Cursor::new(raw_control(0x0, 0x3, &[0x00])));
+            assert!(reader.read().await.is_ok());
+            assert!(reader.read().await.unwrap_err().is::<crate::packets::ConnectionClosed>());
+
+            // A transport closed in the middle of a packet is not a clean
+            // disconnection.
+            let reader = Reader::new(std::io::Cursor::new(vec![0x20, 0x03, 0x02, 0x00]));
+            assert!(!reader.read().await.unwrap_err().is::<crate::packets::ConnectionClosed>());
+        }
+
+        #[tokio::test]
+        async fn read_rejects_reserved_message_type() {
+            assert_eq!(
+                header_error(&[0x80, 0x03, 0x00]).await,
+                HeaderError::ReservedMessageType(0x4)
+            );
+        }
+
+        #[tokio::test]
+        async fn read_rejects_truncated_payload() {
+            assert_eq!(
+                header_error(&[0x20, 0x03, 0x04, 0x01, 0x00]).await,
+                HeaderError::TruncatedPayload { payload_length: 4 }
+            );
+        }
+
+        #[tokio::test]
+        async fn read_rejects_inconsistent_segments() {
+            // Second segment of a CORE_GET_CONFIG_CMD with the OID of
+            // CORE_SET_CONFIG_CMD.
+            assert_eq!(
+                header_error(&[0x30, 0x03, 0x01, 0x01, 0x20, 0x02, 0x01, 0x00]).await,
+                HeaderError::InconsistentSegment
+            );
+        }
+
+        #[tokio::test]
+        async fn read_rejects_interleaved_segments() {
+            // Data Packet received after the first segment of a
+            // CORE_GET_CONFIG_CMD.
+            assert_eq!(
+                header_error(&[0x30, 0x03, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00]).await,
+                HeaderError::InterleavedSegment { expected_mt: 0x1, mt: 0x0 }
+            );
+        }
+
+        #[tokio::test]
+        async fn write_and_flush_delivers_buffered_packets() {
+            let (tx, rx) = tokio::io::duplex(1024);
+            let writer = Writer::new(tokio::io::BufWriter::new(tx));
+            let reader = Reader::new(rx);
+            let packet = raw_control(0x0, 0x3, &[0x01, 0x00]);
+            let wait = std::time::Duration::from_millis(10);
+
+            // The packet stays in the buffer until the writer is flushed.
+            writer.write(&packet).await.unwrap();
+            assert!(tokio::time::timeout(wait, reader.read()).await.is_err());
+            writer.flush().await.unwrap();
+            assert_eq!(reader.read().await.unwrap(), packet);
+
+            writer.write_and_flush(&packet).await.unwrap();
+            assert_eq!(tokio::time::timeout(wait, reader.read()).await.unwrap().unwrap(), packet);
+        }
+
+        #[tokio::test]
+        async fn segmented_packets_are_reassembled_over_duplex() {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            // Two segments of a CORE_GET_CONFIG_CMD written on one end
+            // are read as a single packet from the other end.
+            let (mut tx, rx) = tokio::io::duplex(1024);
+            let reader = Reader::new(rx);
+            tx.write_all(&[0x30, 0x03, 0x02, 0x02, 0x00, 0x20, 0x03, 0x01, 0x03]).await.unwrap();
+            let packet = reader.read().await.unwrap();
+            assert_eq!(packet[..2], [0x20, 0x03]);
+            assert_eq!(packet[3..], [0x02, 0x00, 0x03]);
+
+            // The writer segments the packet at the maximum payload size.
+            let (tx, mut rx) = tokio::io::duplex(1024);
+            let writer = Writer::new(tx).with_max_payload_sizes(2, 2);
+            writer.write(&raw_control(0x0, 0x3, &[0x02, 0x00, 0x03])).await.unwrap();
+            let mut segments = [0; 9];
+            rx.read_exact(&mut segments).await.unwrap();
+            assert_eq!(segments, [0x30, 0x03, 0x02, 0x02, 0x00, 0x20, 0x03, 0x01, 0x03]);
+        }
     }
 }
 
@@ -169,8 +509,69 @@ pub mod rf {
     #![allow(missing_docs)]
 
     include!(concat!(env!("OUT_DIR"), "/rf_packets.rs"));
+
+    /// Compute the CRC of an RF frame for the selected technology,
+    /// in transmission order.
+    /// Cf [DIGITAL] 6.4.1.3 CRC_A, [DIGITAL] 7.4.1.3 CRC_B,
+    /// [DIGITAL] 8.4.1.2 CRC_F. NFC-V frames use the same CRC as NFC-B,
+    /// cf ISO/IEC 15693-3 Annex C.
+    pub fn crc(technology: Technology, frame: &[u8]) -> [u8; 2] {
+        match technology {
+            Technology::NfcA => crc_itu_v41(0x6363, frame).to_le_bytes(),
+            Technology::NfcB | Technology::NfcV => (!crc_itu_v41(0xffff, frame)).to_le_bytes(),
+            Technology::NfcF => {
+                let mut crc: u16 = 0;
+                for byte in frame {
+                    crc ^= (*byte as u16) << 8;
+                    for _ in 0..8 {
+                        crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+                    }
+                }
+                crc.to_be_bytes()
+            }
+        }
+    }
+
+    /// Reflected CRC-16 with the polynomial x^16 + x^12 + x^5 + 1.
+    fn crc_itu_v41(init: u16, frame: &[u8]) -> u16 {
+        let mut crc = init;
+        for byte in frame {
+            crc ^= *byte as u16;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+            }
+        }
+        crc
+    }
+
+    /// Append the CRC to an RF frame.
+    pub fn append_crc(technology: Technology, frame: &mut Vec<u8>) {
+        let crc = crc(technology, frame);
+        frame.extend_from_slice(&crc);
+    }
+
+    /// Validate the CRC of a received RF frame, and return the frame
+    /// without the CRC. Returns None if the frame is too short or the
+    /// CRC is invalid.
+    pub fn check_crc(technology: Technology, frame: &[u8]) -> Option<&[u8]> {
+        let (frame, received) = frame.split_at(frame.len().checked_sub(2)?);
+        (crc(technology, frame) == received).then_some(frame)
+    }
 }
 
+/// Error returned by the NCI and RF readers when the peer closed the
+/// transport cleanly, i.e. between two packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionClosed;
+
+impl std::fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection closed by the peer")
+    }
+}
+
+impl std::error::Error for ConnectionClosed {}
+
 impl From<rf::Protocol> for nci::RfProtocolType {
     fn from(protocol: rf::Protocol) -> Self {
         match protocol {
@@ -217,6 +618,19 @@ impl TryFrom<nci::RfTechnologyAndMode> for rf::Technology {
     }
 }
 
+impl From<rf::Technology> for nci::BitRate {
+    /// Return the bit rate used for data exchange with the technology:
+    /// the initial bit rate of the technology, or 212 kbit/s for NFC-F,
+    /// cf [DIGITAL] 4.3 Bit Rates.
+    fn from(technology: rf::Technology) -> Self {
+        match technology {
+            rf::Technology::NfcA | rf::Technology::NfcB => nci::BitRate::BitRate106KbitS,
+            rf::Technology::NfcF => nci::BitRate::BitRate212KbitS,
+            rf::Technology::NfcV => nci::BitRate::BitRate26KbitS,
+        }
+    }
+}
+
 impl From<rf::DeactivateType> for nci::DeactivationType {
     fn from(type_: rf::DeactivateType) -> Self {
         match type_ {
@@ -250,3 +664,45 @@ impl From<rf::DeactivateReason> for nci::DeactivationReason {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bit_rate_follows_technology() {
+        for (technology, bit_rate) in [
+            (rf::Technology::NfcA, nci::BitRate::BitRate106KbitS),
+            (rf::Technology::NfcB, nci::BitRate::BitRate106KbitS),
+            (rf::Technology::NfcF, nci::BitRate::BitRate212KbitS),
+            (rf::Technology::NfcV, nci::BitRate::BitRate26KbitS),
+        ] {
+            assert_eq!(nci::BitRate::from(technology), bit_rate);
+        }
+    }
+
+    #[test]
+    fn rf_frame_crc_matches_reference_values() {
+        // Cf ISO/IEC 14443-3 Annex B, and the check values of the
+        // CRC-16/ISO-IEC-14443-3-A, CRC-16/IBM-SDLC and CRC-16/XMODEM
+        // algorithms for the ASCII string "123456789".
+        assert_eq!(rf::crc(rf::Technology::NfcA, &[0x00, 0x00]), [0xa0, 0x1e]);
+        assert_eq!(rf::crc(rf::Technology::NfcA, &[0x12, 0x34]), [0x26, 0xcf]);
+        assert_eq!(rf::crc(rf::Technology::NfcA, b"123456789"), [0x05, 0xbf]);
+        assert_eq!(rf::crc(rf::Technology::NfcB, &[0x00, 0x00, 0x00]), [0xcc, 0xc6]);
+        assert_eq!(rf::crc(rf::Technology::NfcB, &[0x0a, 0x12, 0x34, 0x56]), [0x2c, 0xf6]);
+        assert_eq!(rf::crc(rf::Technology::NfcV, b"123456789"), [0x6e, 0x90]);
+        assert_eq!(rf::crc(rf::Technology::NfcF, b"123456789"), [0x31, 0xc3]);
+    }
+
+    #[test]
+    fn check_crc_rejects_corrupted_frames() {
+        let mut frame = vec![0x26, 0x01, 0x00];
+        rf::append_crc(rf::Technology::NfcA, &mut frame);
+        assert_eq!(rf::check_crc(rf::Technology::NfcA, &frame), Some(&frame[..3]));
+        assert_eq!(rf::check_crc(rf::Technology::NfcB, &frame), None);
+        frame[1] ^= 0x01;
+        assert_eq!(rf::check_crc(rf::Technology::NfcA, &frame), None);
+        assert_eq!(rf::check_crc(rf::Technology::NfcA, &[0x26]), None);
+    }
+}
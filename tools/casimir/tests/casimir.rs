@@ -0,0 +1,202 @@
+// Copyright 2024, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Run the emulator in-process and exchange NCI packets with it.
+
+use argh::FromArgs;
+use casimir::{Casimir, DeviceInformation, DeviceType, Opt};
+use std::net::Ipv4Addr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+
+#[tokio::test]
+async fn serves_nci_connections_on_ephemeral_port() {
+    let opt = Opt::from_args(&["casimir"], &["--nci-port", "0", "--rf-port", "0"]).unwrap();
+    let casimir = Casimir::bind(opt).await.unwrap();
+    let nci_port = casimir.nci_port().unwrap();
+    assert_ne!(nci_port, 0);
+    assert_ne!(casimir.rf_port().unwrap(), 0);
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let dh = async move {
+        let mut socket = TcpStream::connect((Ipv4Addr::LOCALHOST, nci_port)).await.unwrap();
+        // CORE_RESET_CMD with the reset type Keep Configuration.
+        socket.write_all(&[0x20, 0x00, 0x01, 0x00]).await.unwrap();
+        // CORE_RESET_RSP with the status STATUS_OK.
+        let mut response = [0; 4];
+        socket.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x40, 0x00, 0x01, 0x00]);
+        shutdown_tx.send(()).unwrap();
+    };
+
+    let (result, ()) = tokio::join!(
+        casimir.run_with(async {
+            let _ = shutdown_rx.await;
+        }),
+        dh
+    );
+    result.unwrap();
+}
+
+/// Connect to the NCI server, and reset the NFCC to make sure it has
+/// been added to the scene.
+async fn connect_nfcc(nci_port: u16) -> TcpStream {
+    let mut socket = TcpStream::connect((Ipv4Addr::LOCALHOST, nci_port)).await.unwrap();
+    // CORE_RESET_CMD with the reset type Keep Configuration.
+    socket.write_all(&[0x20, 0x00, 0x01, 0x00]).await.unwrap();
+    let mut response = [0; 4];
+    socket.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [0x40, 0x00, 0x01, 0x00]);
+    socket
+}
+
+#[tokio::test]
+async fn sticky_slots_are_reclaimed_on_reconnection_from_the_same_host() {
+    let args = ["--nci-port", "0", "--rf-port", "0", "--sticky-slots"];
+    let opt = Opt::from_args(&["casimir"], &args).unwrap();
+    let casimir = Casimir::bind(opt).await.unwrap().separate_devices();
+    let nci_port = casimir.nci_port().unwrap();
+    let devices = casimir.devices();
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let dh = async move {
+        let ids = || {
+            let mut ids = devices.lock().unwrap().keys().copied().collect::<Vec<_>>();
+            ids.sort();
+            ids
+        };
+        let first = connect_nfcc(nci_port).await;
+        let _second = connect_nfcc(nci_port).await;
+        assert_eq!(ids(), [0, 1]);
+        drop(first);
+        while ids() != [1] {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The new connection comes from another port of the same host, and
+        // gets the identifier of the disconnected device back.
+        let _third = connect_nfcc(nci_port).await;
+        assert_eq!(ids(), [0, 1]);
+        assert_eq!(devices.lock().unwrap()[&0].position, 0);
+        shutdown_tx.send(()).unwrap();
+    };
+
+    let (result, ()) = tokio::join!(
+        casimir.run_with(async {
+            let _ = shutdown_rx.await;
+        }),
+        dh
+    );
+    result.unwrap();
+}
+
+/// Read the next packet of a multiplexed NCI connection, and return
+/// the number of its logical device with the packet.
+async fn read_multiplexed(socket: &mut TcpStream) -> (u8, Vec<u8>) {
+    let device = socket.read_u8().await.unwrap();
+    let mut packet = vec![0; 3];
+    socket.read_exact(&mut packet).await.unwrap();
+    packet.resize(3 + packet[2] as usize, 0);
+    socket.read_exact(&mut packet[3..]).await.unwrap();
+    (device, packet)
+}
+
+#[tokio::test]
+async fn multiplexed_devices_are_reset_and_initialized_independently() {
+    let opt = Opt::from_args(&["casimir"], &["--nci-port", "0", "--rf-port", "0", "--multiplex"])
+        .unwrap();
+    let casimir = Casimir::bind(opt).await.unwrap();
+    let nci_port = casimir.nci_port().unwrap();
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let dh = async move {
+        let mut socket = TcpStream::connect((Ipv4Addr::LOCALHOST, nci_port)).await.unwrap();
+        // CORE_RESET_CMD with the reset type Keep Configuration, and
+        // CORE_INIT_CMD with no feature enabled.
+        let reset_command = [0x20, 0x00, 0x01, 0x00];
+        let init_command = [0x20, 0x01, 0x02, 0x00, 0x00];
+
+        // Reset and initialize the logical device 1.
+        socket.write_all(&[&[1], &reset_command[..]].concat()).await.unwrap();
+        assert_eq!(read_multiplexed(&mut socket).await, (1, vec![0x40, 0x00, 0x01, 0x00]));
+        let (device, notification) = read_multiplexed(&mut socket).await;
+        assert_eq!((device, &notification[0..2]), (1, &[0x60, 0x00][..]));
+        socket.write_all(&[&[1], &init_command[..]].concat()).await.unwrap();
+        let (device, response) = read_multiplexed(&mut socket).await;
+        assert_eq!((device, &response[0..2], response[3]), (1, &[0x40, 0x01][..], 0x00));
+
+        // The logical device 0 was not reset: CORE_INIT_CMD is rejected
+        // with the status STATUS_SEMANTIC_ERROR.
+        socket.write_all(&[&[0], &init_command[..]].concat()).await.unwrap();
+        assert_eq!(read_multiplexed(&mut socket).await, (0, vec![0x40, 0x01, 0x01, 0x06]));
+        socket.write_all(&[&[0], &reset_command[..]].concat()).await.unwrap();
+        assert_eq!(read_multiplexed(&mut socket).await, (0, vec![0x40, 0x00, 0x01, 0x00]));
+        let (device, notification) = read_multiplexed(&mut socket).await;
+        assert_eq!((device, &notification[0..2]), (0, &[0x60, 0x00][..]));
+        socket.write_all(&[&[0], &init_command[..]].concat()).await.unwrap();
+        let (device, response) = read_multiplexed(&mut socket).await;
+        assert_eq!((device, &response[0..2], response[3]), (0, &[0x40, 0x01][..], 0x00));
+        shutdown_tx.send(()).unwrap();
+    };
+
+    let (result, ()) = tokio::join!(
+        casimir.run_with(async {
+            let _ = shutdown_rx.await;
+        }),
+        dh
+    );
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn multiplexed_devices_are_listed_as_separate_nfccs() {
+    let opt = Opt::from_args(&["casimir"], &["--nci-port", "0", "--rf-port", "0", "--multiplex"])
+        .unwrap();
+    // As casimir-grpc, which lists the devices with its gRPC service.
+    let casimir = Casimir::bind(opt).await.unwrap().separate_devices();
+    let nci_port = casimir.nci_port().unwrap();
+    let devices = casimir.devices();
+
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let dh = async move {
+        let mut socket = TcpStream::connect((Ipv4Addr::LOCALHOST, nci_port)).await.unwrap();
+        // CORE_RESET_CMD with the reset type Keep Configuration.
+        let reset_command = [0x20, 0x00, 0x01, 0x00];
+        for device in [3, 1] {
+            socket.write_all(&[&[device], &reset_command[..]].concat()).await.unwrap();
+            assert_eq!(read_multiplexed(&mut socket).await, (device, vec![0x40, 0x00, 0x01, 0x00]));
+            read_multiplexed(&mut socket).await;
+        }
+
+        let mut devices = devices.lock().unwrap().values().cloned().collect::<Vec<_>>();
+        devices.sort_by_key(|info| info.id);
+        assert_eq!(
+            devices,
+            [
+                DeviceInformation { id: 0, position: 0, r#type: DeviceType::Nci },
+                DeviceInformation { id: 1, position: 1, r#type: DeviceType::Nci },
+            ]
+        );
+        shutdown_tx.send(()).unwrap();
+    };
+
+    let (result, ()) = tokio::join!(
+        casimir.run_with(async {
+            let _ = shutdown_rx.await;
+        }),
+        dh
+    );
+    result.unwrap();
+}